@@ -0,0 +1,101 @@
+use std::collections::HashSet;
+use clap::{Command, CommandFactory};
+
+use crate::cli::Cli;
+use crate::config;
+
+/// Command aliases from the config: with `alias.st = "status"`, `scm st`
+/// runs `scm status`, and with `alias.ci = "commit -m"`, `scm ci "Fix it"`
+/// runs `scm commit -m "Fix it"`. An alias may name another alias, but
+/// never a built-in command, which always means what it says. Its words are
+/// split on whitespace; quotes ('...' or "...") keep spaces in one word.
+const ALIAS_PREFIX: &str = "alias.";
+
+/// `args` (the whole command line) with an alias in the subcommand's place
+/// replaced by what it stands for.
+pub fn expand(mut args: Vec<String>) -> Vec<String> {
+    let mut command = Cli::command();
+    command.build();
+    let position = match subcommand_position(&command, &args) {
+        Some(position) => position,
+        None => return args,
+    };
+
+    let mut seen = HashSet::new();
+    while command.find_subcommand(&args[position]).is_none() && seen.insert(args[position].clone()) {
+        let words = match config::get(&format!("{}{}", ALIAS_PREFIX, args[position])) {
+            Some(expansion) => split(&expansion),
+            None => break,
+        };
+        if words.is_empty() {
+            break;
+        }
+        args.splice(position..=position, words);
+    }
+    args
+}
+
+/// Where the subcommand is: the first argument that is neither a global
+/// flag nor a flag's value.
+pub fn subcommand_position(command: &Command, args: &[String]) -> Option<usize> {
+    let takes_value = |arg: &clap::Arg| arg.get_action().takes_values();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "--" {
+            return None;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            if !long.contains('=') && command.get_arguments().any(|a| a.get_long() == Some(long) && takes_value(a)) {
+                i += 1;
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // In a cluster like `-qC dir` the first flag that takes a value
+            // takes the rest, or the next argument if nothing is left.
+            for (at, c) in shorts.char_indices() {
+                if command.get_arguments().any(|a| a.get_short() == Some(c) && takes_value(a)) {
+                    if at + c.len_utf8() == shorts.len() {
+                        i += 1;
+                    }
+                    break;
+                }
+            }
+        } else {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Splits an alias into words on whitespace, keeping quoted spaces.
+fn split(expansion: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    for c in expansion.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => word.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            None => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
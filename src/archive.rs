@@ -0,0 +1,144 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use chrono::{Datelike, Timelike};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tracing::{error, info};
+
+use crate::{exit, hash_bytes, load_manifest, resolve_version, read_stored, scm_dir, workdir, FileType, ScmError};
+
+enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+pub fn do_archive(spec: &str, output: &str, prefix: &str) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let format = if output.ends_with(".zip") {
+        Format::Zip
+    } else if output.ends_with(".tar.gz") || output.ends_with(".tgz") {
+        Format::TarGz
+    } else if output.ends_with(".tar") {
+        Format::Tar
+    } else {
+        error!("Unknown archive format for {}; use .tar, .tar.gz, .tgz or .zip.", output);
+        return;
+    };
+    let id = match resolve_version(spec) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Version {} not found.", spec);
+            return;
+        }
+    };
+
+    let manifest = match load_manifest(id) {
+        Ok(manifest) => manifest,
+        Err(e) => return exit::report(e),
+    };
+    let mut filenames: Vec<&String> = manifest.files.keys().collect();
+    filenames.sort();
+
+    // Read and verify every file up front so a corrupt backup never yields a partial archive.
+    let now = chrono::Utc::now().timestamp();
+    let mut entries = Vec::new();
+    for filename in filenames {
+        let content = match read_stored(id, filename, &manifest.files[filename]) {
+            Ok(content) => content,
+            Err(e) => return exit::report(e),
+        };
+        if hash_bytes(&content) != manifest.files[filename] {
+            return exit::report(ScmError::IntegrityFailure(format!("The stored copy of {} in version {} is corrupted.", filename, id)));
+        }
+        let meta = manifest.metadata.get(filename);
+        let symlink = meta.is_some_and(|m| m.kind == FileType::Symlink);
+        let mode = meta.and_then(|m| m.mode).unwrap_or(0o644);
+        let mtime = meta.and_then(|m| m.mtime).unwrap_or(now);
+        entries.push(Entry { name: format!("{}{}", prefix, filename), content, symlink, mode, mtime });
+    }
+
+    let path = workdir::resolve(output);
+    if let Err(e) = write(&path, format, &entries).map_err(ScmError::io(format!("Cannot write {}", output))) {
+        // Half an archive is no use to anyone.
+        let _ = fs::remove_file(&path);
+        return exit::report(e);
+    }
+    info!("Archived version {} ({} files) to {}.", id, entries.len(), output);
+}
+
+/// One file to archive. For a symlink, `content` is the link target.
+struct Entry {
+    name: String,
+    content: Vec<u8>,
+    symlink: bool,
+    mode: u32,
+    /// As recorded in the manifest, in seconds since the Unix epoch.
+    mtime: i64,
+}
+
+fn write(path: &Path, format: Format, entries: &[Entry]) -> io::Result<()> {
+    let file = File::create(path)?;
+    match format {
+        Format::Tar => write_tar(file, entries).map(drop),
+        Format::TarGz => write_tar(GzEncoder::new(file, Compression::default()), entries)?.finish().map(drop),
+        Format::Zip => write_zip(file, entries),
+    }
+}
+
+fn write_tar<W: Write>(writer: W, entries: &[Entry]) -> io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for entry in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(entry.mtime.max(0) as u64);
+        if entry.symlink {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            let target = String::from_utf8_lossy(&entry.content).to_string();
+            builder.append_link(&mut header, &entry.name, target)?;
+        } else {
+            header.set_size(entry.content.len() as u64);
+            header.set_mode(entry.mode);
+            header.set_cksum();
+            builder.append_data(&mut header, &entry.name, entry.content.as_slice())?;
+        }
+    }
+    builder.into_inner()
+}
+
+fn write_zip(file: File, entries: &[Entry]) -> io::Result<()> {
+    let mut zip = zip::ZipWriter::new(file);
+    let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for entry in entries {
+        let options = options.last_modified_time(zip_time(entry.mtime));
+        if entry.symlink {
+            let target = String::from_utf8_lossy(&entry.content).to_string();
+            zip.add_symlink(entry.name.as_str(), target, options)?;
+        } else {
+            zip.start_file(entry.name.as_str(), options.unix_permissions(entry.mode))?;
+            zip.write_all(&entry.content)?;
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// A zip entry's timestamp, in UTC to the even second. Times outside the
+/// years 1980 to 2107 the format can hold become the start of 1980.
+fn zip_time(secs: i64) -> zip::DateTime {
+    let Some(time) = chrono::DateTime::from_timestamp(secs, 0) else {
+        return zip::DateTime::default();
+    };
+    let Ok(year) = u16::try_from(time.year()) else {
+        return zip::DateTime::default();
+    };
+    zip::DateTime::from_date_and_time(year, time.month() as u8, time.day() as u8, time.hour() as u8, time.minute() as u8, time.second() as u8)
+        .unwrap_or_default()
+}
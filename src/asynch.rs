@@ -0,0 +1,76 @@
+use std::path::Path;
+use std::sync::Arc;
+use tokio::{fs, task};
+
+use crate::{CommitOptions, Result, ScmError, Status, Version, VersionId};
+
+/// `Repository` for programs that run a tokio runtime, such as a service
+/// snapshotting a directory now and then. Each call awaits the work of the
+/// one of the same name, hashing and copying included, done on tokio's
+/// blocking thread pool so the executor's threads stay free; calls from
+/// several tasks take turns as they would from several threads. It has to
+/// be used within a runtime, and is cheap to clone into each task.
+///
+/// Work that panics instead, which is a bug, fails with
+/// `ScmError::Internal` rather than bringing down the task awaiting it.
+#[derive(Clone)]
+pub struct Repository {
+    inner: Arc<crate::Repository>,
+}
+
+impl From<crate::Repository> for Repository {
+    fn from(repo: crate::Repository) -> Repository {
+        Repository { inner: Arc::new(repo) }
+    }
+}
+
+impl Repository {
+    /// See `crate::Repository::init`.
+    pub async fn init(path: impl AsRef<Path>) -> Result<Repository> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path).await.map_err(ScmError::io(format!("Cannot create {}", path.display())))?;
+        blocking(move || crate::Repository::init(path)).await.map(Repository::from)
+    }
+
+    /// See `crate::Repository::open`.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Repository> {
+        let path = path.as_ref();
+        let start = fs::canonicalize(path).await.map_err(ScmError::io(format!("Cannot open {}", path.display())))?;
+        blocking(move || crate::Repository::open(start)).await.map(Repository::from)
+    }
+
+    /// The repository to call directly, for what needs no waiting, such as
+    /// `root` or `set_observer`.
+    pub fn get_ref(&self) -> &crate::Repository {
+        &self.inner
+    }
+
+    pub async fn commit(&self, options: CommitOptions) -> Result<VersionId> {
+        self.run(move |repo| repo.commit(&options)).await
+    }
+
+    pub async fn revert(&self, version: VersionId) -> Result<()> {
+        self.run(move |repo| repo.revert(version)).await
+    }
+
+    pub async fn verify(&self) -> Result<Vec<String>> {
+        self.run(|repo| repo.verify()).await
+    }
+
+    pub async fn status(&self) -> Result<Status> {
+        self.run(|repo| repo.status()).await
+    }
+
+    pub async fn log(&self) -> Result<Vec<Version>> {
+        self.run(|repo| repo.log()).await
+    }
+
+    async fn run<T: Send + 'static>(&self, call: impl FnOnce(&crate::Repository) -> Result<T> + Send + 'static) -> Result<T> {
+        let repo = Arc::clone(&self.inner);
+        blocking(move || call(&repo)).await
+    }
+}
+
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    task::spawn_blocking(work).await.unwrap_or_else(|e| Err(ScmError::Internal(e.to_string())))
+}
@@ -0,0 +1,124 @@
+use std::fs;
+use std::sync::Arc;
+use globset::{GlobBuilder, GlobMatcher};
+use tracing::warn;
+
+use crate::{context, working_path};
+
+pub const ATTRIBUTES_FILE: &str = ".scmattributes";
+
+/// The state of one attribute for a path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrValue {
+    /// `name`
+    Set,
+    /// `-name`
+    Unset,
+    /// `name=value`
+    Value(String),
+}
+
+struct Line {
+    matcher: GlobMatcher,
+    attrs: Vec<(String, AttrValue)>,
+}
+
+/// Per-path settings from `.scmattributes` in the repository root, in the
+/// style of gitattributes: each line is a glob followed by attributes, e.g.
+///
+/// ```text
+/// *.png    binary
+/// *.txt    text eol=lf
+/// *.pdf    diff=pdf
+/// *.lock   merge=union
+/// assets/* large
+/// ```
+///
+/// The attributes understood are:
+///
+/// - `text` / `-text`: line-ending conversion and diffing (see `eol`);
+/// - `eol=lf|crlf`: the checkout line ending;
+/// - `diff` / `-diff` / `diff=<driver>`: how diffs are shown (see `diff`);
+/// - `merge=text|binary|union|<driver>`: how merges combine it (see `merge`);
+/// - `large` / `-large`: always or never use the large-object store.
+///
+/// Globs follow the `.scmignore` rules for `/`. When several lines set the
+/// same attribute, the last one wins. `binary` is shorthand for `-text -diff`.
+pub struct Attributes {
+    lines: Vec<Line>,
+}
+
+/// The repository's attributes, loaded on first use.
+pub fn current() -> Arc<Attributes> {
+    context::current().attributes.get_or_init(|| Arc::new(Attributes::load())).clone()
+}
+
+impl Attributes {
+    pub fn load() -> Self {
+        let content = fs::read_to_string(working_path(ATTRIBUTES_FILE)).unwrap_or_default();
+        let mut lines = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            let mut fields = line.split_whitespace();
+            let pattern = fields.next().unwrap();
+            let anchored = match pattern.strip_prefix('/') {
+                Some(rest) => rest.to_string(),
+                None if pattern.contains('/') => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            let matcher = match GlobBuilder::new(&anchored).literal_separator(true).build() {
+                Ok(glob) => glob.compile_matcher(),
+                Err(_) => {
+                    warn!("skipping invalid pattern in {}: {}", ATTRIBUTES_FILE, pattern);
+                    continue;
+                }
+            };
+
+            let mut attrs = Vec::new();
+            for field in fields {
+                if field == "binary" {
+                    attrs.push(("text".to_string(), AttrValue::Unset));
+                    attrs.push(("diff".to_string(), AttrValue::Unset));
+                } else if let Some(name) = field.strip_prefix('-') {
+                    attrs.push((name.to_string(), AttrValue::Unset));
+                } else if let Some((name, value)) = field.split_once('=') {
+                    attrs.push((name.to_string(), AttrValue::Value(value.to_string())));
+                } else {
+                    attrs.push((field.to_string(), AttrValue::Set));
+                }
+            }
+            lines.push(Line { matcher, attrs });
+        }
+        Attributes { lines }
+    }
+
+    /// The attribute's state for a path, or None if no line mentions it.
+    pub fn get(&self, path: &str, name: &str) -> Option<AttrValue> {
+        self.lines
+            .iter()
+            .rev()
+            .filter(|line| line.matcher.is_match(path))
+            .find_map(|line| line.attrs.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v.clone()))
+    }
+
+    /// Some(true) if the path is declared binary, Some(false) if declared
+    /// text, None to leave it to content detection.
+    pub fn binary_override(&self, path: &str) -> Option<bool> {
+        match self.get(path, "text")? {
+            AttrValue::Unset => Some(true),
+            AttrValue::Set | AttrValue::Value(_) => Some(false),
+        }
+    }
+
+    /// Some(true) if the path always goes to the large-object store,
+    /// Some(false) if it never does, None to go by the size threshold.
+    pub fn large_override(&self, path: &str) -> Option<bool> {
+        match self.get(path, "large")? {
+            AttrValue::Set => Some(true),
+            AttrValue::Unset => Some(false),
+            AttrValue::Value(_) => None,
+        }
+    }
+}
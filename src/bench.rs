@@ -0,0 +1,171 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{
+    cli::BenchArgs, config, ensure_parent_dir, exit, get_commit_path, graph, hash, init_repo, json, large, refs, restore_version, scm_dir,
+    set_jobs, snapshot_working_files, statcache, stats, symlink, working_files, working_hashes, write_manifest, Manifest, Result, VersionId,
+    MANIFEST_FORMAT, SUPPORTED_COMPRESSION,
+};
+
+/// `scm bench --json`: the run's settings and how long each phase took.
+#[derive(Serialize)]
+struct BenchReport<'a> {
+    files: usize,
+    bytes: u64,
+    hash: &'a str,
+    compression: &'a str,
+    threads: usize,
+    seconds: Seconds,
+}
+
+#[derive(Serialize)]
+struct Seconds {
+    hash: f64,
+    commit: f64,
+    restore: f64,
+}
+
+/// `scm bench`: times hashing, committing and restoring a copy of the
+/// working tree (or, with `--synthetic`, generated files of the given
+/// size) in a scratch repository under the system temp directory, so
+/// settings can be compared without touching this repository. The hash and
+/// compression default to this repository's; encryption is left out.
+pub fn do_bench(args: &BenchArgs) {
+    let synthetic = match args.synthetic.as_deref() {
+        Some([count, size]) => match (count.parse::<usize>(), large::parse_size(size)) {
+            (Ok(count), Some(size)) if count > 0 => Some((count, size)),
+            _ => {
+                error!("--synthetic needs a file count and a size, e.g. --synthetic 1000 64K.");
+                return;
+            }
+        },
+        _ => None,
+    };
+    let algorithm = args.hash.clone().or_else(|| config::get("core.hash")).unwrap_or_else(|| hash::NAMES[0].to_string());
+    let compression = args.compression.clone().or_else(|| config::get("core.compression")).unwrap_or_else(|| SUPPORTED_COMPRESSION[0].to_string());
+    if let Some(jobs) = args.jobs {
+        set_jobs(jobs);
+    }
+    if synthetic.is_none() && !scm_dir().exists() {
+        error!("No SCM repository found; run inside one or use --synthetic.");
+        return;
+    }
+
+    let original = env::current_dir().expect("Failed to read current dir");
+    let scratch = env::temp_dir().join(format!("scm-bench-{}", process::id()));
+    fs::create_dir_all(&scratch).expect("Failed to create scratch dir");
+    match synthetic {
+        Some((count, size)) => generate(&scratch, count, size),
+        None => {
+            for (filename, path) in working_files().expect("Failed to list working files") {
+                let dest = scratch.join(&filename);
+                ensure_parent_dir(&dest).expect("Failed to create directory");
+                symlink::copy(&path, &dest).expect("Failed to copy working file");
+            }
+        }
+    }
+    env::set_current_dir(&scratch).expect("Failed to enter scratch dir");
+    init_repo(refs::DEFAULT_BRANCH).expect("Failed to create scratch repository");
+    config::set("core.hash", &algorithm).expect("Failed to configure scratch repository");
+    config::set("core.compression", &compression).expect("Failed to configure scratch repository");
+    statcache::disable();
+
+    let files = working_files().expect("Failed to list working files");
+    let bytes: u64 = files.iter().map(|(_, path)| fs::symlink_metadata(path).map_or(0, |meta| meta.len())).sum();
+    let file_count = files.len();
+    if !json::enabled() {
+        println!(
+            "Benchmarking {} file(s), {} ({}, {}, {} thread(s))...",
+            file_count,
+            stats::human(bytes),
+            algorithm,
+            compression,
+            rayon::current_num_threads()
+        );
+    }
+
+    let started = Instant::now();
+    let hashed = working_hashes();
+    let hashing = started.elapsed().as_secs_f64();
+
+    let timed = hashed.and_then(|_| round_trip());
+
+    // Nothing cached about the scratch repository may reach this one.
+    graph::save();
+    env::set_current_dir(&original).expect("Failed to return to the original dir");
+    fs::remove_dir_all(&scratch).expect("Failed to remove scratch dir");
+    let (committing, restoring) = match timed {
+        Ok(seconds) => seconds,
+        Err(e) => return exit::report(e),
+    };
+
+    if json::enabled() {
+        json::print(&BenchReport {
+            files: file_count,
+            bytes,
+            hash: &algorithm,
+            compression: &compression,
+            threads: rayon::current_num_threads(),
+            seconds: Seconds { hash: hashing, commit: committing, restore: restoring },
+        });
+        return;
+    }
+    println!();
+    for (phase, seconds) in [("hash", hashing), ("commit", committing), ("restore", restoring)] {
+        println!("  {:<8} {:>8.3}s  {:>12}/s", phase, seconds, stats::human((bytes as f64 / seconds.max(0.000_001)) as u64));
+    }
+}
+
+/// Commits the scratch repository's files as version 1 and restores them,
+/// returning the seconds each took.
+fn round_trip() -> Result<(f64, f64)> {
+    let started = Instant::now();
+    let (files, metadata) = snapshot_working_files()?;
+    let committing = started.elapsed().as_secs_f64();
+    fs::create_dir_all(get_commit_path(VersionId::FIRST)).expect("Failed to create commit dir");
+    write_manifest(&Manifest {
+        format_version: MANIFEST_FORMAT,
+        version_id: VersionId::FIRST,
+        timestamp: chrono::Utc::now().to_string(),
+        message: None,
+        author_name: None,
+        author_email: None,
+        parent: None,
+        merge_parent: None,
+        removed: Vec::new(),
+        renamed: Default::default(),
+        files,
+        metadata,
+        dirs: Vec::new(),
+        changes: None,
+    })?;
+
+    let started = Instant::now();
+    restore_version(VersionId::FIRST)?;
+    Ok((committing, started.elapsed().as_secs_f64()))
+}
+
+/// Writes `count` files of `size` pseudo-random bytes, 100 to a directory.
+/// The content is the same on every run, so results are comparable.
+fn generate(root: &Path, count: usize, size: u64) {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    for n in 0..count {
+        let path: PathBuf = root.join(format!("dir{}", n / 100)).join(format!("file{}", n));
+        ensure_parent_dir(&path).expect("Failed to create directory");
+        let mut content = Vec::with_capacity(size as usize);
+        while (content.len() as u64) < size {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            content.extend_from_slice(&state.to_le_bytes());
+        }
+        content.truncate(size as usize);
+        fs::write(&path, content).expect("Failed to write benchmark file");
+    }
+}
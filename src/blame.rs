@@ -0,0 +1,148 @@
+use serde::Serialize;
+use tracing::error;
+
+use crate::attributes;
+use crate::{diff, exit, get_head, json, load_manifest, parent_of, read_stored, repo_path, resolve_version, scm_dir, Result, VersionId};
+
+/// Width of the timestamp column ("YYYY-MM-DD HH:MM:SS").
+const DATE_WIDTH: usize = 19;
+
+/// A line as `scm blame --json` prints it, with the version that last
+/// changed it.
+#[derive(Serialize)]
+struct BlameLine<'a> {
+    line: usize,
+    version: VersionId,
+    timestamp: &'a str,
+    text: &'a str,
+}
+
+pub fn do_blame(filename: &str, spec: Option<&str>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let filename = repo_path(filename);
+    let start = match spec {
+        None => match get_head() {
+            Ok(Some(id)) => id,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("{} is not tracked (no commits yet).", filename);
+                return;
+            }
+        },
+        Some(spec) => match resolve_version(spec) {
+            Ok(Some(id)) => id,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Version {} not found.", spec);
+                return;
+            }
+        },
+    };
+
+    let content = match read_version_text(start, &filename) {
+        Err(e) => return exit::report(e),
+        Ok(Some(Some(content))) => content,
+        Ok(Some(None)) => {
+            error!("{} is a binary file; cannot blame.", filename);
+            return;
+        }
+        Ok(None) => {
+            error!("{} is not tracked in version {}.", filename, start);
+            return;
+        }
+    };
+
+    let lines: Vec<&str> = content.lines().collect();
+    let owners = match blame_lines(&filename, start, &content) {
+        Ok(owners) => owners,
+        Err(e) => return exit::report(e),
+    };
+
+    for (number, (line, owner)) in lines.iter().zip(owners).enumerate() {
+        let timestamp = load_manifest(owner).map(|m| m.timestamp).unwrap_or_default();
+        if json::enabled() {
+            json::print(&BlameLine { line: number + 1, version: owner, timestamp: &timestamp, text: line });
+            continue;
+        }
+        let date: String = timestamp.chars().take(DATE_WIDTH).collect();
+        println!("{:>6}  {:<width$}  | {}", owner, date, line, width = DATE_WIDTH);
+    }
+}
+
+/// Attributes each line of `content` (the file as of version `start`) to the
+/// version that last changed it, walking first parents backwards and diffing
+/// each version of the file against its predecessor.
+fn blame_lines(filename: &str, start: VersionId, content: &str) -> Result<Vec<VersionId>> {
+    let line_count = content.lines().count();
+    let mut owners = vec![start; line_count];
+
+    // pending[i] = index in the current version's content of original line i.
+    let mut pending: Vec<(usize, usize)> = (0..line_count).map(|i| (i, i)).collect();
+    let mut version = start;
+    let mut name = filename.to_string();
+    let mut current = content.to_string();
+    let mut hash = load_manifest(start)?.files.get(filename).cloned();
+
+    while !pending.is_empty() {
+        let parent = match parent_of(version) {
+            Some(parent) => parent,
+            None => break,
+        };
+        // Follow a rename recorded by `scm mv` back to the file's previous name.
+        let parent_name = load_manifest(version)?
+            .renamed
+            .iter()
+            .find(|(_, new)| **new == name)
+            .map_or_else(|| name.clone(), |(old, _)| old.clone());
+        let parent_hash = load_manifest(parent)?.files.get(&parent_name).cloned();
+
+        // Unchanged in the parent: keep walking without diffing.
+        if parent_hash.is_some() && parent_hash == hash {
+            version = parent;
+            name = parent_name;
+            continue;
+        }
+
+        let parent_content = match read_version_text(parent, &parent_name)? {
+            Some(Some(text)) => text,
+            // Absent or binary before this version: it introduced every remaining line.
+            _ => break,
+        };
+
+        let mapping = diff::map_lines(&parent_content, &current);
+        let mut still_pending = Vec::new();
+        for (original, index) in pending {
+            match mapping.get(index).copied().flatten() {
+                Some(parent_index) => still_pending.push((original, parent_index)),
+                None => owners[original] = version,
+            }
+        }
+
+        pending = still_pending;
+        version = parent;
+        name = parent_name;
+        current = parent_content;
+        hash = parent_hash;
+    }
+
+    // Lines that survived to the oldest version reached were introduced there.
+    for (original, _) in pending {
+        owners[original] = version;
+    }
+    Ok(owners)
+}
+
+/// The stored content of a file in a version: None if it isn't tracked
+/// there, Some(None) if it isn't text.
+fn read_version_text(id: VersionId, filename: &str) -> Result<Option<Option<String>>> {
+    let manifest = load_manifest(id)?;
+    if !manifest.files.contains_key(filename) {
+        return Ok(None);
+    }
+    let bytes = read_stored(id, filename, &manifest.files[filename])?;
+    Ok(Some(diff::text_for(&attributes::current(), filename, &bytes).map(|text| text.into_owned())))
+}
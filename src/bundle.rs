@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::cli::BundleCommand;
+use crate::storage::storage;
+use crate::{
+    chunk, exit, grep, hash, hash_bytes, list_versions, load_manifest, next_version_id, read_stored, refs, scm_dir, workdir, write_manifest,
+    FileHash, Manifest, Result, ScmError, VersionId, MANIFEST_FORMAT,
+};
+
+/// A bundle carries versions between repositories without a network: a
+/// zstd-compressed tar holding `bundle.json`, the versions' manifests under
+/// `versions/`, the manifests of the versions they build on under
+/// `prerequisites/`, and every stored file they need under `objects/` (or
+/// `large/` for files kept whole from before chunking) that the
+/// prerequisites don't already have. Contents are stored in full and
+/// unencrypted, whatever the source repository does.
+const BUNDLE_FORMAT: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: u32,
+    hash: String,
+    /// Source branches whose tips are in the bundle.
+    branches: BTreeMap<String, VersionId>,
+}
+
+pub fn do_bundle(command: &BundleCommand) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    match command {
+        BundleCommand::Create { file, range } => create(&workdir::resolve(file), range.as_deref()),
+        BundleCommand::Import { file } => import(&workdir::resolve(file)),
+    }
+}
+
+fn create(path: &Path, spec: Option<&str>) {
+    let ids = match spec {
+        None => list_versions(),
+        Some(spec) => match grep::parse_range(spec) {
+            Ok(Some(ids)) => ids,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Unknown version or range: {}", spec);
+                return;
+            }
+        },
+    };
+    let versions: Vec<Manifest> = match ids.iter().map(|&id| load_manifest(id)).collect() {
+        Ok(versions) => versions,
+        Err(e) => return exit::report(e),
+    };
+    if versions.is_empty() {
+        info!("No versions to bundle.");
+        return;
+    }
+    let included: HashSet<VersionId> = versions.iter().map(|m| m.version_id).collect();
+    let mut prerequisites = BTreeMap::new();
+    for manifest in &versions {
+        for parent in manifest.parent.into_iter().chain(manifest.merge_parent) {
+            if !included.contains(&parent) && !prerequisites.contains_key(&parent) {
+                match load_manifest(parent) {
+                    Ok(prerequisite) => prerequisites.insert(parent, prerequisite),
+                    Err(e) => return exit::report(e),
+                };
+            }
+        }
+    }
+
+    let branches = refs::list_branches().into_iter().filter(|(_, id)| included.contains(id)).collect();
+    let header = Header { format: BUNDLE_FORMAT, hash: hash::current().name().to_string(), branches };
+    let written = match write(path, &header, &versions, &prerequisites) {
+        Ok(written) => written,
+        Err(e) => {
+            // Half a bundle is no use to anyone.
+            let _ = fs::remove_file(path);
+            return exit::report(e);
+        }
+    };
+    info!("Bundled {} version(s) and {} stored file(s) into {}.", versions.len(), written, path.display());
+    if !prerequisites.is_empty() {
+        let ids: Vec<String> = prerequisites.keys().map(|id| id.to_string()).collect();
+        warn!("The receiving repository must already have version(s) {}.", ids.join(", "));
+    }
+}
+
+/// Writes the bundle `create` has gathered to `path`, returning how many
+/// stored files went in.
+fn write(path: &Path, header: &Header, versions: &[Manifest], prerequisites: &BTreeMap<VersionId, Manifest>) -> Result<usize> {
+    let mut present = HashSet::new();
+    for manifest in prerequisites.values() {
+        present.extend(stored_names(manifest));
+    }
+    let unwritable = || ScmError::io(format!("Cannot write {}", path.display()));
+    let file = File::create(path).map_err(unwritable())?;
+    let encoder = zstd::Encoder::new(file, 0).map_err(unwritable())?;
+    let mut builder = tar::Builder::new(encoder);
+    append(&mut builder, "bundle.json", serde_json::to_string_pretty(header).unwrap().as_bytes()).map_err(unwritable())?;
+    for manifest in versions {
+        append(&mut builder, &format!("versions/{}.json", manifest.version_id), serde_json::to_string(manifest).unwrap().as_bytes()).map_err(unwritable())?;
+    }
+    for (id, manifest) in prerequisites {
+        append(&mut builder, &format!("prerequisites/{}.json", id), serde_json::to_string(manifest).unwrap().as_bytes()).map_err(unwritable())?;
+    }
+    let mut written = 0;
+    for manifest in versions {
+        let mut files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+        files.sort();
+        for (filename, hash) in files {
+            let meta = manifest.metadata.get(filename);
+            if let Some(chunks) = meta.and_then(|m| m.chunks.as_ref()) {
+                for chunk in chunks {
+                    if present.insert(chunk.clone()) {
+                        let missing = || ScmError::MissingObject { version: manifest.version_id, path: filename.clone() };
+                        let content = storage().get_object(chunk)?.ok_or_else(missing)?;
+                        append(&mut builder, &format!("objects/{}", chunk), &content).map_err(unwritable())?;
+                        written += 1;
+                    }
+                }
+            } else if present.insert(hash.clone()) {
+                let content = read_stored(manifest.version_id, filename, hash)?;
+                let dir = if meta.is_some_and(|m| m.large) { "large" } else { "objects" };
+                append(&mut builder, &format!("{}/{}", dir, hash), &content).map_err(unwritable())?;
+                written += 1;
+            }
+        }
+    }
+    builder.into_inner().and_then(|encoder| encoder.finish()).map_err(unwritable())?;
+    Ok(written)
+}
+
+fn import(path: &Path) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Cannot open {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let decoder = zstd::Decoder::new(file).expect("Failed to read bundle");
+    let mut archive = tar::Archive::new(decoder);
+    let mut header: Option<Header> = None;
+    let mut versions: BTreeMap<VersionId, Manifest> = BTreeMap::new();
+    let mut prerequisites: Vec<Manifest> = Vec::new();
+    let mut received = 0;
+    for entry in archive.entries().expect("Failed to read bundle") {
+        let mut entry = entry.expect("Failed to read bundle");
+        let name = entry.path().expect("Failed to read bundle").to_string_lossy().to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).expect("Failed to read bundle");
+
+        if name == "bundle.json" {
+            let parsed: Header = match serde_json::from_slice(&content) {
+                Ok(parsed) => parsed,
+                Err(_) => {
+                    error!("{} is not a bundle.", path.display());
+                    return;
+                }
+            };
+            if parsed.format > BUNDLE_FORMAT {
+                error!("{} was made by a newer version of scm; upgrade to import it.", path.display());
+                return;
+            }
+            if parsed.hash != hash::current().name() {
+                error!("The bundle uses {} hashes but this repository uses {}; it cannot be imported.", parsed.hash, hash::current().name());
+                return;
+            }
+            header = Some(parsed);
+            continue;
+        }
+        if header.is_none() {
+            error!("{} is not a bundle.", path.display());
+            return;
+        }
+        if let Some(rest) = name.strip_prefix("versions/").or_else(|| name.strip_prefix("prerequisites/")) {
+            let manifest: Manifest = match serde_json::from_slice(&content) {
+                Ok(manifest) => manifest,
+                Err(_) => {
+                    error!("Bundle entry {} is corrupt; nothing was imported.", name);
+                    return;
+                }
+            };
+            if manifest.format_version > MANIFEST_FORMAT || rest.trim_end_matches(".json").parse() != Ok(manifest.version_id) {
+                error!("Bundle entry {} is corrupt or too new; nothing was imported.", name);
+                return;
+            }
+            if name.starts_with("versions/") {
+                versions.insert(manifest.version_id, manifest);
+            } else {
+                prerequisites.push(manifest);
+            }
+        } else if let Some(hash) = name.strip_prefix("objects/").or_else(|| name.strip_prefix("large/")) {
+            // Contents are checked before anything is stored; a bad one
+            // stops the import before any version refers to it.
+            if hash_bytes(&content) != hash {
+                error!("Bundle entry {} does not match its hash; versions were not imported.", name);
+                return;
+            }
+            // Files kept whole from before chunking are objects like any other.
+            match storage().has(hash) {
+                Err(e) => return exit::report(e),
+                Ok(true) => {}
+                Ok(false) => {
+                    if let Err(e) = storage().put_object(hash, &content, None) {
+                        return exit::report(e);
+                    }
+                    received += 1;
+                }
+            }
+        }
+    }
+    let header = match header {
+        Some(header) => header,
+        None => {
+            error!("{} is not a bundle.", path.display());
+            return;
+        }
+    };
+
+    // Versions are matched by content, not id: the same version may have a
+    // different id here, and an id here may be another version entirely.
+    let mut existing = HashMap::new();
+    for id in list_versions() {
+        if let Ok(manifest) = load_manifest(id) {
+            existing.insert(identity(&manifest), id);
+        }
+    }
+    let mut mapping: HashMap<VersionId, VersionId> = HashMap::new();
+    for manifest in &prerequisites {
+        match existing.get(&identity(manifest)) {
+            Some(&id) => {
+                mapping.insert(manifest.version_id, id);
+            }
+            None => {
+                error!("This repository lacks version {} of the source, which the bundle builds on.", manifest.version_id);
+                error!("Import a bundle that includes it first; nothing was imported.");
+                return;
+            }
+        }
+    }
+    let mut new = Vec::new();
+    for (&id, manifest) in &versions {
+        match existing.get(&identity(manifest)) {
+            Some(&local) => {
+                mapping.insert(id, local);
+            }
+            None => new.push(id),
+        }
+    }
+    for &id in &new {
+        if let Err(problem) = check_stored(&versions[&id]) {
+            error!("Bundle version {}: {}; nothing was imported.", id, problem);
+            return;
+        }
+    }
+
+    // Ids are kept when all are free and stay newer than their parents;
+    // otherwise the new versions are numbered after the existing ones.
+    let local_ids: HashSet<VersionId> = list_versions().into_iter().collect();
+    let keep_ids = new.iter().all(|&id| {
+        let manifest = &versions[&id];
+        !local_ids.contains(&id)
+            && manifest.parent.into_iter().chain(manifest.merge_parent).all(|parent| mapping.get(&parent).copied().unwrap_or(parent) < id)
+    });
+    let mut next = match next_version_id() {
+        Ok(next) => next,
+        Err(e) => return exit::report(e),
+    };
+    for &id in &new {
+        if keep_ids {
+            mapping.insert(id, id);
+        } else {
+            mapping.insert(id, next);
+            next = next.next();
+        }
+    }
+    for &id in &new {
+        let mut manifest = versions.remove(&id).unwrap();
+        manifest.version_id = mapping[&id];
+        manifest.parent = manifest.parent.map(|parent| mapping[&parent]);
+        manifest.merge_parent = manifest.merge_parent.map(|parent| mapping[&parent]);
+        if let Err(e) = write_manifest(&manifest) {
+            return exit::report(e);
+        }
+        if manifest.version_id != id {
+            info!("Imported version {} as version {}.", id, manifest.version_id);
+        }
+    }
+    info!("Imported {} new version(s) and {} new stored file(s) from {}.", new.len(), received, path.display());
+
+    for (name, id) in header.branches {
+        let id = mapping[&id];
+        match refs::read_branch(&name) {
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                if let Err(e) = refs::write_branch(&name, id) {
+                    return exit::report(e);
+                }
+                info!("Created branch {} at version {}.", name, id);
+            }
+            Ok(Some(local)) if local != id => info!("Branch {} is at version {} here; the bundle's {} is version {}.", name, local, name, id),
+            Ok(Some(_)) => {}
+        }
+    }
+}
+
+fn append<W: std::io::Write>(builder: &mut tar::Builder<W>, name: &str, content: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, name, content)
+}
+
+/// The object store names a version's files are kept under.
+fn stored_names(manifest: &Manifest) -> Vec<FileHash> {
+    let mut names = Vec::new();
+    for (filename, hash) in &manifest.files {
+        match manifest.metadata.get(filename).and_then(|m| m.chunks.as_ref()) {
+            Some(chunks) => names.extend(chunks.iter().cloned()),
+            None => names.push(hash.clone()),
+        }
+    }
+    names
+}
+
+/// What makes a version the same one in two repositories, whatever its id.
+fn identity(manifest: &Manifest) -> String {
+    let mut files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+    files.sort();
+    format!("{}\n{:?}\n{:?}", manifest.timestamp, manifest.message, files)
+}
+
+/// Every file of an imported version must now be stored here, and a
+/// chunked file's chunks must add up to its hash.
+fn check_stored(manifest: &Manifest) -> Result<(), String> {
+    for (filename, hash) in &manifest.files {
+        let meta = manifest.metadata.get(filename);
+        let stored = if let Some(chunks) = meta.and_then(|m| m.chunks.as_ref()) {
+            chunk::verify(chunks, hash).map_err(|problem| format!("{}: {}", filename, problem))?;
+            true
+        } else {
+            storage().has(hash).map_err(|e| e.to_string())?
+        };
+        if !stored {
+            return Err(format!("{} is missing from the bundle", filename));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use fastcdc::v2020::StreamCDC;
+
+use crate::storage::storage;
+use crate::{hash, hash_bytes, FileHash, Result, ScmError};
+
+/// Large files are split into content-defined chunks (FastCDC), each kept
+/// in the object store under its own hash, and the manifest lists the
+/// file's chunk hashes in order. Cut points depend only on the bytes
+/// nearby, so an edit in the middle of a multi-GB file changes a chunk or
+/// two and everything else is shared with the previous version.
+const MIN_CHUNK: usize = 256 * 1024;
+const AVG_CHUNK: usize = 1024 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Chunks a file, storing every chunk not already present, and returns the
+/// chunk hashes in order. Only one chunk is held in memory at a time.
+pub fn store(src: &Path) -> Result<Vec<FileHash>> {
+    let file = File::open(src).map_err(ScmError::io(format!("Cannot read {}", src.display())))?;
+    let mut chunks = Vec::new();
+    for chunk in StreamCDC::new(file, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+        let chunk = chunk.map_err(|e| ScmError::Io { context: format!("Cannot read {}", src.display()), source: e.into() })?;
+        let hash = hash_bytes(&chunk.data);
+        storage().put_object(&hash, &chunk.data, None)?;
+        chunks.push(hash);
+    }
+    Ok(chunks)
+}
+
+/// Writes a chunked file back out, one chunk at a time.
+pub fn restore(chunks: &[FileHash], dest: &Path) -> Result<()> {
+    let failed = || ScmError::io(format!("Cannot write {}", dest.display()));
+    let mut writer = BufWriter::new(File::create(dest).map_err(failed())?);
+    for hash in chunks {
+        let data = storage().get_object(hash)?.ok_or_else(|| ScmError::IntegrityFailure(format!("Chunk {} missing!", hash)))?;
+        writer.write_all(&data).map_err(failed())?;
+    }
+    writer.flush().map_err(failed())
+}
+
+/// A chunked file's whole content, for diffs and the like; None if a
+/// chunk is missing.
+pub fn read(chunks: &[FileHash]) -> Result<Option<Vec<u8>>> {
+    let mut content = Vec::new();
+    for hash in chunks {
+        match storage().get_object(hash)? {
+            Some(data) => content.extend(data),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(content))
+}
+
+/// Checks every chunk and that together they hash to `hash`.
+pub fn verify(chunks: &[FileHash], hash: &str) -> Result<(), String> {
+    let mut hasher = hash::Hasher::new();
+    for chunk in chunks {
+        storage().verify_object(chunk).map_err(|e| format!("chunk {}: {}", chunk, e))?;
+        hasher.update(&storage().get_object(chunk).ok().flatten().ok_or_else(|| format!("chunk {}: changed while being read", chunk))?);
+    }
+    if hasher.finish() != hash {
+        return Err("corrupted (chunks do not add up to its hash)".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,536 @@
+use std::path::PathBuf;
+use clap::builder::PossibleValuesParser;
+use clap::{ArgAction, Args, Parser, Subcommand};
+use clap_complete::ArgValueCandidates;
+
+use crate::color::When;
+use crate::completions::{self, Shell};
+use crate::{hash, refs, SUPPORTED_COMPRESSION};
+
+/// Settings the environment can make, over the config files (but under
+/// flags), for CI jobs and containers.
+const ENVIRONMENT: &str = "Environment:
+  SCM_DIR           Where the repository is, instead of .scm
+  SCM_CONFIG        Settings in TOML, one per line, over the config files
+  SCM_AUTHOR_NAME   The author's name, over author.name
+  SCM_AUTHOR_EMAIL  The author's email, over author.email
+  SCM_PAGER         The pager, over core.pager
+  SCM_NO_PROGRESS   Don't draw progress bars
+  SCM_PASSPHRASE    The passphrase of an encrypted repository";
+
+/// The command line: one subcommand per operation. Handlers take the
+/// parsed values; anything clap can check (required arguments, numbers,
+/// choices, conflicting flags) is checked here rather than in each of them.
+#[derive(Parser)]
+#[command(name = "scm", version, about = "A small version control system", arg_required_else_help = true, after_help = ENVIRONMENT)]
+pub struct Cli {
+    /// Run as if started in DIR
+    // Acted on by `workdir::enter`, before the command line is parsed.
+    #[arg(short = 'C', long = "repo", value_name = "DIR")]
+    pub directories: Vec<PathBuf>,
+    /// When to color the output
+    #[arg(long, global = true, value_enum, default_value_t = When::Auto, value_name = "WHEN")]
+    pub color: When,
+    /// Print errors only
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Print what is done to each file (-vv: in more detail)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Print results and messages as JSON, one object per line
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Don't send log, diff or show output through a pager
+    #[arg(long, global = true)]
+    pub no_pager: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Create a repository
+    Init(InitArgs),
+    /// Copy a repository and check out its HEAD
+    Clone { source: PathBuf, destination: PathBuf },
+    /// Stage files (or removals) for the next commit
+    Add {
+        /// Also pick up new dotfiles
+        #[arg(long)]
+        include_hidden: bool,
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Record staged changes (-a: the whole working directory)
+    Commit(CommitArgs),
+    /// Revert to the previous (or given) state
+    Revert {
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: Option<String>,
+        /// List what would be restored, overwritten and deleted, and stop
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Discard uncommitted changes without asking
+        #[arg(short = 'y', long, visible_alias = "yes")]
+        force: bool,
+    },
+    /// Restore any committed version
+    Checkout {
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: String,
+    },
+    /// Restore individual files
+    Restore {
+        #[arg(required = true)]
+        files: Vec<String>,
+        /// The version to take them from [default: HEAD]
+        #[arg(short = 's', long, value_name = "VERSION", add = ArgValueCandidates::new(completions::versions))]
+        from: Option<String>,
+    },
+    /// Remove files not in HEAD (dry run without -f)
+    Clean {
+        /// Delete the files instead of listing them
+        #[arg(short, long, overrides_with = "dry_run")]
+        force: bool,
+        /// Only list the files (the default)
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Keep files matching a glob
+        #[arg(short, long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+    },
+    /// Check out only matching files
+    Sparse {
+        #[command(subcommand)]
+        command: SparseCommand,
+    },
+    /// Read and change settings
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Print a script that completes commands, versions, branches and tags
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Pack a version into a .tar, .tar.gz or .zip
+    Archive {
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: String,
+        /// The archive to write; its extension picks the format
+        #[arg(short, long, value_name = "FILE")]
+        output: String,
+        /// A directory to put every file under, e.g. project-1.0/
+        #[arg(long, value_name = "DIR/", default_value = "")]
+        prefix: String,
+    },
+    /// Move versions between repositories as a file
+    Bundle {
+        #[command(subcommand)]
+        command: BundleCommand,
+    },
+    /// Pack stored files into one pack file
+    Repack {
+        /// Also zstd-compress them
+        #[arg(long)]
+        compress: bool,
+    },
+    /// Delete unreachable versions and the stored files only they use
+    Gc {
+        /// How long unreachable versions are kept, e.g. 14d, 12h, 30m or now [default: gc.grace or 14d]
+        #[arg(long, value_name = "PERIOD")]
+        grace: Option<String>,
+    },
+    /// Delete old versions by depth or retention rules
+    Prune(PruneArgs),
+    /// Remove matching paths from every version
+    Filter {
+        /// Paths or ignore-style patterns to remove
+        #[arg(long, value_name = "PATTERN", required = true, num_args = 1..)]
+        remove: Vec<String>,
+    },
+    /// Make a new repository from one subdirectory's history
+    Split { subdir: String, destination: PathBuf },
+    /// Check the repository's structure and stored files
+    Fsck,
+    /// Re-hash every stored file; exits nonzero on problems
+    Verify {
+        /// A version or <from>..<to> range [default: every version]
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        range: Option<String>,
+    },
+    /// Time hashing, commit and restore
+    Bench(BenchArgs),
+    /// Watch the working tree so status and commit skip unchanged files
+    Monitor {
+        #[command(subcommand)]
+        command: MonitorCommand,
+    },
+    /// Show version count, storage sizes, compression and growth
+    Stats,
+    /// Rewrite old manifests in the current format
+    Migrate,
+    /// Finish or roll back an interrupted commit or revert
+    Recover,
+    /// Show every recorded HEAD movement
+    Oplog {
+        /// Show only the newest entries
+        #[arg(short = 'n', value_name = "COUNT")]
+        count: Option<usize>,
+    },
+    /// Roll forward to the next version after a revert
+    Redo,
+    /// Move HEAD, optionally restoring files
+    Reset {
+        /// Leave the working files alone (the default)
+        #[arg(long, overrides_with = "hard")]
+        soft: bool,
+        /// Restore the working files too
+        #[arg(long)]
+        hard: bool,
+        /// Delete the versions this leaves unreachable
+        #[arg(long)]
+        prune: bool,
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: String,
+    },
+    /// Manage tags
+    Tag(TagArgs),
+    /// Name a version relative to the nearest tag, e.g. v1.2-5
+    Describe {
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: Option<String>,
+    },
+    /// List, create or delete branches
+    Branch(BranchArgs),
+    /// Switch to (or create and switch to) a branch
+    Switch {
+        /// Create the branch at HEAD first
+        #[arg(short, long)]
+        create: bool,
+        #[arg(add = ArgValueCandidates::new(completions::branches))]
+        branch: String,
+    },
+    /// Merge another branch into the current one
+    Merge {
+        #[arg(required_unless_present = "abort", add = ArgValueCandidates::new(completions::branches))]
+        branch: Option<String>,
+        /// Give up on a merge in progress
+        #[arg(long, conflicts_with = "branch")]
+        abort: bool,
+    },
+    /// Apply one version's changes as a new commit
+    CherryPick {
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: String,
+    },
+    /// Squash, drop or reword the versions after <base>
+    Rewrite {
+        /// A version, or "root" for the whole history
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        base: String,
+        /// Take the plan from a file instead of an editor
+        #[arg(long, value_name = "FILE")]
+        todo: Option<String>,
+    },
+    /// Set aside uncommitted changes
+    Stash(StashArgs),
+    /// Show commit history, optionally for one file
+    Log {
+        /// Show only the newest versions
+        #[arg(short = 'n', value_name = "COUNT")]
+        count: Option<usize>,
+        file: Option<String>,
+        /// Print the stable, tab-separated format for scripts
+        #[arg(long)]
+        porcelain: bool,
+    },
+    /// Show changes since the last commit
+    Status {
+        /// Hash every file instead of trusting the stat cache or monitor
+        #[arg(long)]
+        no_cache: bool,
+        /// Exit with 1 if there is anything to commit
+        #[arg(long)]
+        exit_code: bool,
+        /// Print the stable, tab-separated format for scripts
+        #[arg(long)]
+        porcelain: bool,
+    },
+    /// Delete (or stop tracking) files in the next commit
+    Rm {
+        /// Keep the files on disk and stop tracking them
+        #[arg(long)]
+        cached: bool,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Rename a file, keeping its history
+    Mv { source: String, destination: String },
+    /// Show changes against HEAD, a version, or between two versions
+    Diff {
+        #[arg(num_args = 0..=2, value_name = "VERSION", add = ArgValueCandidates::new(completions::versions))]
+        versions: Vec<String>,
+        /// List the changed files' names instead of their changes
+        #[arg(long)]
+        name_only: bool,
+        /// Print the stable, tab-separated format for scripts
+        #[arg(long)]
+        porcelain: bool,
+    },
+    /// Show a commit's details and files
+    Show {
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: Option<String>,
+        /// Include the content changes
+        #[arg(short = 'p', long)]
+        diff: bool,
+    },
+    /// Show the version that last changed each line
+    Blame {
+        file: String,
+        #[arg(add = ArgValueCandidates::new(completions::versions))]
+        version: Option<String>,
+    },
+    /// Search file contents
+    Grep(GrepArgs),
+}
+
+#[derive(Args)]
+pub struct InitArgs {
+    /// The branch HEAD starts on
+    #[arg(short = 'b', long, value_name = "BRANCH", default_value = refs::DEFAULT_BRANCH)]
+    pub initial_branch: String,
+    #[arg(long, value_name = "ALGORITHM", default_value = hash::NAMES[0], value_parser = PossibleValuesParser::new(hash::NAMES.iter().copied()))]
+    pub hash: String,
+    #[arg(long, value_name = "METHOD", default_value = SUPPORTED_COMPRESSION[0], value_parser = PossibleValuesParser::new(SUPPORTED_COMPRESSION.iter().copied()))]
+    pub compression: String,
+    /// Encrypt everything stored, asking for a passphrase
+    #[arg(long)]
+    pub encrypt: bool,
+}
+
+#[derive(Args)]
+pub struct CommitArgs {
+    /// The message; given more than once, each becomes a paragraph
+    #[arg(short, long)]
+    pub message: Vec<String>,
+    /// Replace HEAD's version instead of adding one
+    #[arg(long)]
+    pub amend: bool,
+    /// Commit the whole working directory rather than the staged files
+    #[arg(short, long)]
+    pub all: bool,
+    /// With -a, also pick up new dotfiles
+    #[arg(long)]
+    pub include_hidden: bool,
+    /// Hash every file instead of trusting the stat cache or monitor
+    #[arg(long)]
+    pub no_cache: bool,
+    /// How many files to hash and store at once [default: one per CPU]
+    #[arg(short, long, value_parser = positive)]
+    pub jobs: Option<usize>,
+    /// List what the commit would record, and stop
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+    /// Pick the files to commit from a list of changed and new files
+    #[arg(short, long, conflicts_with_all = ["all", "dry_run"])]
+    pub interactive: bool,
+    /// Record a version even if nothing changed
+    #[arg(long)]
+    pub allow_empty: bool,
+    /// The author to record, as "Name <email>", over author.name and author.email
+    #[arg(long, value_name = "AUTHOR", value_parser = author)]
+    pub author: Option<(String, String)>,
+    /// Stage these files or directories first, as 'scm add' would
+    #[arg(conflicts_with_all = ["all", "interactive"])]
+    pub paths: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum SparseCommand {
+    /// Check out only files matching these patterns
+    Set {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// Show the patterns
+    List,
+    /// Check out every file again
+    Disable,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print a setting
+    Get { key: String },
+    /// Change a setting, in the repository's config unless --user
+    Set {
+        key: String,
+        value: String,
+        /// Change it in ~/.config/scm/config, for every repository
+        #[arg(long)]
+        user: bool,
+    },
+    /// Print every setting in effect and where it comes from
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum BundleCommand {
+    /// Write versions, and the files they need, to a bundle
+    Create {
+        file: PathBuf,
+        /// A version or <from>..<to> range [default: every version]
+        range: Option<String>,
+    },
+    /// Add a bundle's versions to this repository
+    Import { file: PathBuf },
+}
+
+#[derive(Args)]
+pub struct PruneArgs {
+    /// Keep the last N versions on every branch
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub depth: Option<usize>,
+    /// Keep the newest version of each of the last N hours
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub keep_hourly: Option<usize>,
+    /// Keep the newest version of each of the last N days
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub keep_daily: Option<usize>,
+    /// Keep the newest version of each of the last N weeks
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub keep_weekly: Option<usize>,
+    /// Keep the newest version of each of the last N months
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub keep_monthly: Option<usize>,
+    /// Keep the newest version of each of the last N years
+    #[arg(long, value_name = "N", value_parser = positive)]
+    pub keep_yearly: Option<usize>,
+    /// List the versions that would be deleted, and stop
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct BenchArgs {
+    /// Generate this many files of this size instead of copying the working tree
+    #[arg(long, num_args = 2, value_names = ["FILES", "SIZE"])]
+    pub synthetic: Option<Vec<String>>,
+    /// [default: this repository's]
+    #[arg(long, value_name = "ALGORITHM", value_parser = PossibleValuesParser::new(hash::NAMES.iter().copied()))]
+    pub hash: Option<String>,
+    /// [default: this repository's]
+    #[arg(long, value_name = "METHOD", value_parser = PossibleValuesParser::new(SUPPORTED_COMPRESSION.iter().copied()))]
+    pub compression: Option<String>,
+    /// How many files to hash and store at once [default: one per CPU]
+    #[arg(short, long, value_parser = positive)]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Subcommand)]
+pub enum MonitorCommand {
+    /// Start watching in the background
+    Start,
+    /// Stop watching
+    Stop,
+    /// Show whether the monitor is running
+    Status,
+    /// The monitor itself, as `start` runs it
+    #[command(hide = true)]
+    Run,
+}
+
+#[derive(Args)]
+pub struct TagArgs {
+    /// List tags (the default)
+    #[arg(short, long, conflicts_with_all = ["delete", "name"])]
+    pub list: bool,
+    /// Delete a tag
+    #[arg(short, long, value_name = "NAME", conflicts_with = "name", add = ArgValueCandidates::new(completions::tags))]
+    pub delete: Option<String>,
+    /// The tag to create
+    pub name: Option<String>,
+    /// The version to tag [default: HEAD]
+    #[arg(requires = "name", add = ArgValueCandidates::new(completions::versions))]
+    pub version: Option<String>,
+    /// Make an annotated tag with this message
+    #[arg(short, long, requires = "name")]
+    pub message: Option<String>,
+}
+
+#[derive(Args)]
+pub struct BranchArgs {
+    /// List branches (the default)
+    #[arg(short, long, conflicts_with_all = ["delete", "name"])]
+    pub list: bool,
+    /// Delete a branch
+    #[arg(short, long, value_name = "NAME", conflicts_with = "name", add = ArgValueCandidates::new(completions::branches))]
+    pub delete: Option<String>,
+    /// The branch to create
+    pub name: Option<String>,
+    /// Where it starts [default: HEAD]
+    #[arg(requires = "name", add = ArgValueCandidates::new(completions::versions))]
+    pub version: Option<String>,
+}
+
+/// `scm stash` on its own, or with just `-m`, means `scm stash push`.
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct StashArgs {
+    #[command(subcommand)]
+    pub command: Option<StashCommand>,
+    #[command(flatten)]
+    pub push: StashPushArgs,
+}
+
+#[derive(Subcommand)]
+pub enum StashCommand {
+    /// Set aside uncommitted changes and restore HEAD
+    Push(StashPushArgs),
+    /// Bring back the newest stashed changes
+    Pop,
+    /// Show stashed changes
+    List,
+}
+
+#[derive(Args)]
+pub struct StashPushArgs {
+    #[arg(short, long)]
+    pub message: Option<String>,
+}
+
+#[derive(Args)]
+pub struct GrepArgs {
+    /// Match regardless of case
+    #[arg(short, long)]
+    pub ignore_case: bool,
+    /// Print only the names of files with matches
+    #[arg(short = 'l', long)]
+    pub files_with_matches: bool,
+    /// Search every version
+    #[arg(long, conflicts_with = "range")]
+    pub all: bool,
+    /// A regular expression
+    pub pattern: String,
+    /// A version or <from>..<to> range [default: the working tree]
+    #[arg(add = ArgValueCandidates::new(completions::versions))]
+    pub range: Option<String>,
+}
+
+fn author(value: &str) -> Result<(String, String), String> {
+    let parsed = value.trim_end().strip_suffix('>').and_then(|rest| rest.split_once('<'));
+    match parsed {
+        Some((name, email)) if !name.trim().is_empty() && !email.trim().is_empty() => Ok((name.trim().to_string(), email.trim().to_string())),
+        _ => Err(format!("expected \"Name <email>\", not {:?}", value)),
+    }
+}
+
+fn positive(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("expected a positive number, not {:?}", value)),
+    }
+}
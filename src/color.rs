@@ -0,0 +1,101 @@
+use std::env;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::ValueEnum;
+
+/// Colored output: status and diff markers, diff hunks, warnings and errors.
+/// `--color=auto` (the default) colors what goes to a terminal unless
+/// `NO_COLOR` is set to something; `always` and `never` override both.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum When {
+    Auto,
+    Always,
+    Never,
+}
+
+pub const RED: &str = "31";
+pub const GREEN: &str = "32";
+pub const YELLOW: &str = "33";
+pub const CYAN: &str = "36";
+pub const BOLD: &str = "1";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn wanted(when: When, terminal: bool) -> bool {
+    match when {
+        When::Always => true,
+        When::Never => false,
+        When::Auto => terminal && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()),
+    }
+}
+
+/// Decides whether to color stdout and, separately, panics on stderr.
+pub fn init(when: When) {
+    ENABLED.store(wanted(when, io::stdout().is_terminal()), Ordering::Relaxed);
+    if wanted(when, io::stderr().is_terminal()) {
+        let report = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            eprint!("\x1b[{}m", RED);
+            report(info);
+            eprint!("\x1b[0m");
+        }));
+    }
+}
+
+/// `text` in the given SGR style, or as it is if color is off.
+pub fn paint(style: &str, text: impl Display) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// A status or diff marker: `A` green, `D` red, `M` yellow, `R` cyan.
+pub fn marker(marker: &str) -> String {
+    let style = match marker {
+        "A" => GREEN,
+        "D" => RED,
+        "M" => YELLOW,
+        _ => CYAN,
+    };
+    paint(style, marker)
+}
+
+pub fn warning() -> String {
+    paint(YELLOW, "Warning:")
+}
+
+pub fn error() -> String {
+    paint(RED, "error:")
+}
+
+/// A unified diff with its file header bold, hunk headers cyan, and
+/// removed and added lines red and green.
+pub fn diff(text: &str) -> String {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let (content, newline) = match line.strip_suffix('\n') {
+            Some(content) => (content, "\n"),
+            None => (line, ""),
+        };
+        let style = match content.as_bytes().first() {
+            _ if i < 2 => Some(BOLD),
+            Some(b'@') => Some(CYAN),
+            Some(b'-') => Some(RED),
+            Some(b'+') => Some(GREEN),
+            _ => None,
+        };
+        match style {
+            Some(style) => out.push_str(&paint(style, content)),
+            None => out.push_str(content),
+        }
+        out.push_str(newline);
+    }
+    out
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::io;
+use clap::{CommandFactory, ValueEnum};
+use clap_complete::env::{Bash, Elvish, EnvCompleter, Fish, Powershell, Zsh};
+use clap_complete::{CompleteEnv, CompletionCandidate};
+
+use crate::cli::Cli;
+use crate::{list_versions, load_header, refs, scm_dir, workdir};
+
+/// Shell completion. `scm completions <shell>` prints a script for the
+/// shell to source, e.g. `source <(scm completions bash)` in ~/.bashrc. The
+/// script runs scm with `COMPLETE` set to complete each word, so besides
+/// commands and flags it offers the version ids, branches and tags of the
+/// repository being worked in. Sourcing it anew in each shell, rather than
+/// saving it, keeps it in step with the installed scm.
+const COMPLETE_VAR: &str = "COMPLETE";
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+    Elvish,
+}
+
+/// Answers the completion script and exits, if that is who is asking.
+pub fn complete() {
+    if env::var_os(COMPLETE_VAR).is_some() {
+        workdir::find_root();
+    }
+    CompleteEnv::with_factory(Cli::command).var(COMPLETE_VAR).complete();
+}
+
+pub fn do_completions(shell: Shell) {
+    let completer: &dyn EnvCompleter = match shell {
+        Shell::Bash => &Bash,
+        Shell::Zsh => &Zsh,
+        Shell::Fish => &Fish,
+        Shell::Powershell => &Powershell,
+        Shell::Elvish => &Elvish,
+    };
+    // Call back into this very binary, wherever it is installed.
+    let program = env::current_exe().map(|path| path.to_string_lossy().into_owned()).unwrap_or_else(|_| "scm".to_string());
+    completer.write_registration(COMPLETE_VAR, "scm", "scm", &program, &mut io::stdout()).expect("Failed to write completion script");
+}
+
+/// Anything naming a version: HEAD, branches, tags and version ids, newest
+/// first, with each version's message as its description.
+pub fn versions() -> Vec<CompletionCandidate> {
+    if !scm_dir().exists() {
+        return Vec::new();
+    }
+    let mut candidates = vec![CompletionCandidate::new("HEAD").help(Some("the current version".into()))];
+    candidates.extend(branches());
+    candidates.extend(tags());
+    for id in list_versions().into_iter().rev() {
+        let summary = load_header(id).and_then(|header| Some(header.message?.lines().next()?.to_string()));
+        candidates.push(CompletionCandidate::new(id.to_string()).help(summary.map(Into::into)));
+    }
+    candidates
+}
+
+pub fn branches() -> Vec<CompletionCandidate> {
+    if !scm_dir().exists() {
+        return Vec::new();
+    }
+    refs::list_branches()
+        .into_iter()
+        .map(|(name, id)| CompletionCandidate::new(name).help(Some(format!("branch at version {}", id).into())))
+        .collect()
+}
+
+pub fn tags() -> Vec<CompletionCandidate> {
+    if !scm_dir().exists() {
+        return Vec::new();
+    }
+    refs::list_tags()
+        .into_iter()
+        .map(|(name, tag)| CompletionCandidate::new(name).help(Some(format!("tag at version {}", tag.version_id).into())))
+        .collect()
+}
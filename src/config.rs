@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::Serialize;
+use toml_edit::{DocumentMut, Item, Table, TableLike, Value};
+use tracing::{error, info, warn};
+
+use crate::cli::ConfigCommand;
+use crate::storage::{self, storage};
+use crate::{exit, json, scm_dir, Result, ScmError};
+
+/// Settings live in two TOML files: the repository's `.scm/config` and the
+/// user's `~/.config/scm/config` (`$XDG_CONFIG_HOME/scm/config` if that is
+/// set). A key set in the repository's file wins over the same key in the
+/// user's. Keys are dotted, so `author.name = "Jane Doe"` and `name = "Jane
+/// Doe"` under `[author]` are the same setting. Values are read as strings;
+/// `7` and `"7"` mean the same.
+///
+/// `$SCM_CONFIG` can hold settings too, in the same TOML, one per line (e.g.
+/// `SCM_CONFIG='author.name = "CI"'`). They win over both files, so a
+/// container or CI job can configure scm without writing any.
+const CONFIG_FILE: &str = "config";
+const CONFIG_VAR: &str = "SCM_CONFIG";
+
+/// Where a setting comes from, in order of precedence.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Env,
+    Repo,
+    User,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Scope::Env => "env",
+            Scope::Repo => "repo",
+            Scope::User => "user",
+        }
+    }
+
+    fn path(self) -> Option<PathBuf> {
+        match self {
+            Scope::Env => None,
+            Scope::Repo => Some(scm_dir().join(CONFIG_FILE)),
+            Scope::User => {
+                let base = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+                    Some(dir) => PathBuf::from(dir),
+                    None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+                };
+                Some(base.join("scm").join(CONFIG_FILE))
+            }
+        }
+    }
+
+    /// The file's content, if there is one. The repository's is one of the
+    /// files its storage keeps (see `Storage::read_file`).
+    fn read(self) -> Option<String> {
+        match self {
+            Scope::Env => env::var(CONFIG_VAR).ok(),
+            Scope::Repo => storage::read_to_string(CONFIG_FILE).ok(),
+            Scope::User => fs::read_to_string(self.path()?).ok(),
+        }
+    }
+
+    fn write(self, content: &str) -> io::Result<()> {
+        match (self, self.path()) {
+            (Scope::Repo, _) => storage().write_file(CONFIG_FILE, content.as_bytes()),
+            (_, Some(path)) => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(path, content)
+            }
+            (_, None) => Err(io::Error::new(io::ErrorKind::Unsupported, "nowhere to write settings")),
+        }
+    }
+}
+
+const SCOPES: [Scope; 3] = [Scope::Env, Scope::Repo, Scope::User];
+
+/// Whether each file has been found invalid already; once is enough to say so.
+static WARNED: [AtomicBool; 3] = [AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false)];
+
+/// The settings from `$SCM_CONFIG` or a config file, or None if there are
+/// none or they can't be parsed.
+fn load(scope: Scope) -> Option<DocumentMut> {
+    let content = scope.read()?;
+    let source = match scope.path() {
+        Some(path) => path.display().to_string(),
+        None => format!("${}", CONFIG_VAR),
+    };
+    match content.parse::<DocumentMut>() {
+        Ok(document) => Some(document),
+        Err(e) => {
+            // Not until messages are printed: `logging` reads the config first.
+            if tracing::dispatcher::has_been_set() && !WARNED[scope as usize].swap(true, Ordering::Relaxed) {
+                warn!("{} is not valid TOML, so it is ignored: {}", source, e.message());
+            }
+            None
+        }
+    }
+}
+
+/// Looks up a dotted key, e.g. `author.name`: in `$SCM_CONFIG`, then the
+/// repository's file, then the user's.
+pub fn get(key: &str) -> Option<String> {
+    SCOPES.into_iter().find_map(|scope| lookup(&load(scope)?, key))
+}
+
+fn lookup(document: &DocumentMut, key: &str) -> Option<String> {
+    let mut item = document.as_item();
+    for part in key.split('.') {
+        item = item.get(part)?;
+    }
+    item.as_value().map(display)
+}
+
+/// A value as the string callers see: strings without their quotes, other
+/// values as written.
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.value().clone(),
+        other => other.clone().decorated("", "").to_string(),
+    }
+}
+
+/// Sets a key in `.scm/config`.
+pub fn set(key: &str, value: &str) -> Result<()> {
+    set_in(Scope::Repo, key, value)
+}
+
+/// Sets a key in the given file, keeping its other lines and comments as
+/// they are. Keys that are new to the file are written dotted, one per line.
+pub fn set_in(scope: Scope, key: &str, value: &str) -> Result<()> {
+    let path = scope.path().ok_or_else(|| ScmError::Config("no home directory to keep user settings in".to_string()))?;
+    let mut document = match scope.read() {
+        Some(content) => content
+            .parse::<DocumentMut>()
+            .map_err(|e| ScmError::Config(format!("{} is not valid TOML: {}", path.display(), e.message().trim_end())))?,
+        None => DocumentMut::new(),
+    };
+
+    let parts: Vec<&str> = key.split('.').collect();
+    let (name, sections) = parts.split_last().expect("split yields at least one part");
+    let mut table: &mut dyn TableLike = document.as_table_mut();
+    for section in sections {
+        let mut dotted = Table::new();
+        dotted.set_dotted(true);
+        table = table
+            .entry(section)
+            .or_insert(Item::Table(dotted))
+            .as_table_like_mut()
+            .ok_or_else(|| ScmError::Config(format!("{} is a value, not a table", section)))?;
+    }
+    table.insert(name, toml_edit::value(value));
+
+    scope.write(&document.to_string()).map_err(ScmError::io(format!("Cannot write {}", path.display())))
+}
+
+/// Every setting in effect, by key, with where it comes from.
+fn list() -> BTreeMap<String, (String, Scope)> {
+    let mut settings = BTreeMap::new();
+    // Lowest precedence first, so the others replace them.
+    for scope in SCOPES.into_iter().rev() {
+        if let Some(document) = load(scope) {
+            flatten(document.as_table(), "", scope, &mut settings);
+        }
+    }
+    settings
+}
+
+fn flatten(table: &dyn TableLike, prefix: &str, scope: Scope, settings: &mut BTreeMap<String, (String, Scope)>) {
+    for (name, item) in table.iter() {
+        let key = format!("{}{}", prefix, name);
+        match item.as_table_like() {
+            Some(table) => flatten(table, &format!("{}.", key), scope, settings),
+            None => {
+                if let Some(value) = item.as_value() {
+                    settings.insert(key, (display(value), scope));
+                }
+            }
+        }
+    }
+}
+
+/// All the settings as written, for caches that depend on them.
+pub fn fingerprint() -> Vec<u8> {
+    let mut content = env::var(CONFIG_VAR).unwrap_or_default().into_bytes();
+    for scope in [Scope::Repo, Scope::User] {
+        content.extend(scope.read().unwrap_or_default().into_bytes());
+    }
+    content
+}
+
+fn valid_key(key: &str) -> bool {
+    key.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+}
+
+#[derive(Serialize)]
+struct Setting<'a> {
+    key: &'a str,
+    value: &'a str,
+    origin: Scope,
+}
+
+/// `scm config get <key> | set [--user] <key> <value> | list`. `get` exits
+/// with status 1 when the key isn't set, so scripts can tell.
+pub fn do_config(command: &ConfigCommand) {
+    match command {
+        ConfigCommand::Get { key } => {
+            let setting = SCOPES.into_iter().find_map(|scope| Some((lookup(&load(scope)?, key)?, scope)));
+            match setting {
+                Some((value, origin)) if json::enabled() => json::print(&Setting { key, value: &value, origin }),
+                Some((value, _)) => println!("{}", value),
+                None => exit::set(exit::FAILURE),
+            }
+        }
+        ConfigCommand::Set { key, value, user } => {
+            if !valid_key(key) {
+                error!("Invalid key: {} (use letters, digits, '_' and '-', with '.' between sections)", key);
+                return;
+            }
+            let scope = if *user { Scope::User } else { Scope::Repo };
+            if !user && !scm_dir().exists() {
+                error!("No SCM repository found; use --user to change your own settings.");
+                return;
+            }
+            match set_in(scope, key, value) {
+                Ok(()) => info!("Set {} to {:?} in the {} config.", key, value, scope.name()),
+                Err(e) => exit::report(e),
+            }
+        }
+        ConfigCommand::List => {
+            for (key, (value, origin)) in list() {
+                if json::enabled() {
+                    json::print(&Setting { key: &key, value: &value, origin });
+                } else {
+                    println!("{:<4}  {} = {:?}", origin.name(), key, value);
+                }
+            }
+        }
+    }
+}
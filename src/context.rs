@@ -0,0 +1,112 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use chacha20poly1305::XChaCha20Poly1305;
+
+use crate::attributes::Attributes;
+use crate::eol::EolPolicy;
+use crate::storage::{FsStorage, Storage};
+use crate::{graph, hash, pack, statcache, Observer, Result, ScmError, VersionId, SCM_DIR, SCM_DIR_VAR};
+
+/// The repository scm is working on: where its working tree and `.scm`
+/// are, where it keeps versions, and what has been read from them so far.
+/// The command line works on one, in the current directory (see
+/// `workdir`), so its root is empty and every path is taken from there.
+/// Each `Repository` has its own, with the root as an absolute path, which
+/// the threads of its pool work in (see `enter`); two opened on the same
+/// tree share it.
+pub(crate) struct Context {
+    pub root: PathBuf,
+    pub scm_dir: PathBuf,
+    pub storage: Arc<dyn Storage>,
+    pub hash: OnceLock<hash::Algorithm>,
+    pub attributes: OnceLock<Arc<Attributes>>,
+    pub eol: OnceLock<Arc<EolPolicy>>,
+    /// See `graph`.
+    pub graph: Mutex<Option<(BTreeMap<VersionId, graph::Entry>, bool)>>,
+    /// See `statcache`.
+    pub statcache: Mutex<Option<(statcache::Cache, bool)>>,
+    /// See `pack`.
+    pub packs: Mutex<Option<Vec<pack::Pack>>>,
+    /// The key of an encrypted repository, once unlocked (see `crypt`).
+    pub cipher: OnceLock<XChaCha20Poly1305>,
+    pub observer: RwLock<Option<Arc<dyn Observer>>>,
+    /// Held by each `Repository` call, so that calls from several threads
+    /// take turns.
+    pub busy: Mutex<()>,
+}
+
+impl Context {
+    fn new(root: PathBuf, scm_dir: PathBuf, storage: Arc<dyn Storage>) -> Context {
+        Context {
+            root,
+            scm_dir,
+            storage,
+            hash: OnceLock::new(),
+            attributes: OnceLock::new(),
+            eol: OnceLock::new(),
+            graph: Mutex::new(None),
+            statcache: Mutex::new(None),
+            packs: Mutex::new(None),
+            cipher: OnceLock::new(),
+            observer: RwLock::new(None),
+            busy: Mutex::new(()),
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Weak<Context>> = const { RefCell::new(Weak::new()) };
+}
+
+/// The command line's: the current directory, with `.scm` in it or
+/// wherever `$SCM_DIR` says, e.g. a volume a container mounts apart from the
+/// working tree.
+static DEFAULT: OnceLock<Arc<Context>> = OnceLock::new();
+
+/// Every `Repository`'s, by root, while one is open.
+static OPEN: Mutex<BTreeMap<PathBuf, Weak<Context>>> = Mutex::new(BTreeMap::new());
+
+/// The repository this thread works on.
+pub(crate) fn current() -> Arc<Context> {
+    CURRENT.with_borrow(Weak::upgrade).unwrap_or_else(|| {
+        DEFAULT
+            .get_or_init(|| {
+                let scm_dir = env::var_os(SCM_DIR_VAR).filter(|dir| !dir.is_empty()).map_or_else(|| PathBuf::from(SCM_DIR), PathBuf::from);
+                Arc::new(Context::new(PathBuf::new(), scm_dir, Arc::new(FsStorage)))
+            })
+            .clone()
+    })
+}
+
+/// Makes this thread work on `context` from now on, for as long as it is
+/// open.
+pub(crate) fn enter(context: Weak<Context>) {
+    CURRENT.set(context);
+}
+
+/// Whether a `Repository` has the working tree at `root` open.
+pub(crate) fn is_open(root: &Path) -> bool {
+    OPEN.lock().unwrap().get(root).is_some_and(|context| context.strong_count() > 0)
+}
+
+/// The context for the working tree at `root`, shared with any other
+/// `Repository` open on it; `storage` is where a new one keeps versions,
+/// `FsStorage` if None. `StorageInUse` if it is given for a tree that is
+/// already open.
+pub(crate) fn open(root: &Path, storage: Option<Arc<dyn Storage>>) -> Result<Arc<Context>> {
+    let mut open = OPEN.lock().unwrap();
+    open.retain(|_, context| context.strong_count() > 0);
+    if let Some(context) = open.get(root).and_then(Weak::upgrade) {
+        return match storage {
+            Some(_) => Err(ScmError::StorageInUse(root.to_path_buf())),
+            None => Ok(context),
+        };
+    }
+    let storage = storage.unwrap_or_else(|| Arc::new(FsStorage));
+    let context = Arc::new(Context::new(root.to_path_buf(), root.join(SCM_DIR), storage));
+    open.insert(root.to_path_buf(), Arc::downgrade(&context));
+    Ok(context)
+}
@@ -0,0 +1,171 @@
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use argon2::Argon2;
+use chacha20poly1305::aead::array::typenum::consts::U16;
+use chacha20poly1305::aead::array::Array;
+use chacha20poly1305::aead::{Aead, Generate, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use tracing::error;
+
+use crate::{config, context, Result, ScmError};
+
+/// Encryption at rest. A repository created with `scm init --encrypt` seals
+/// every stored object and manifest with XChaCha20-Poly1305, under a key
+/// derived from a passphrase with Argon2id. The config keeps the salt and a
+/// sealed check value so a wrong passphrase is caught up front; neither the
+/// key nor the passphrase is stored. Sealed data is a random 24-byte nonce
+/// followed by the ciphertext.
+///
+/// The command line takes the passphrase from `SCM_PASSPHRASE` or asks for
+/// it once per command; a program gives it to `Repository::unlock`, and
+/// until then anything that needs the key fails with `ScmError::Locked`.
+/// Object names are still content hashes, so anyone with the
+/// repository can tell whether it holds a file they already have.
+const CIPHER: &str = "xchacha20poly1305";
+const PASSPHRASE_VAR: &str = "SCM_PASSPHRASE";
+const SALT_KEY: &str = "encryption.salt";
+const CHECK_KEY: &str = "encryption.check";
+const CHECK_VALUE: &[u8] = b"scm";
+const NONCE_LEN: usize = 24;
+
+/// Whether the passphrase may be asked for, as on the command line.
+static PROMPT: AtomicBool = AtomicBool::new(false);
+/// Set once the passphrase asked for turned out wrong.
+static REFUSED: AtomicBool = AtomicBool::new(false);
+
+/// Whether this repository's objects and manifests are encrypted.
+pub fn enabled() -> bool {
+    config::get("core.encryption").as_deref() == Some(CIPHER)
+}
+
+/// Asks for a new repository's passphrase (twice, unless it comes from
+/// `SCM_PASSPHRASE`). None, after saying why, if it is empty or the two
+/// entries differ.
+pub fn new_passphrase() -> Result<Option<String>> {
+    let passphrase = match env::var(PASSPHRASE_VAR) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let first = prompt("New passphrase: ")?;
+            if prompt("Repeat passphrase: ")? != first {
+                error!("Passphrases don't match.");
+                return Ok(None);
+            }
+            first
+        }
+    };
+    if passphrase.is_empty() {
+        error!("The passphrase must not be empty.");
+        return Ok(None);
+    }
+    Ok(Some(passphrase))
+}
+
+/// Turns encryption on for a new, empty repository.
+pub fn setup(passphrase: &str) -> Result<()> {
+    let salt = Array::<u8, U16>::generate();
+    let cipher = derive(passphrase, &salt);
+    config::set("core.encryption", CIPHER)?;
+    config::set(SALT_KEY, &hex::encode(salt))?;
+    config::set(CHECK_KEY, &hex::encode(seal_with(&cipher, CHECK_VALUE)))?;
+    let _ = context::current().cipher.set(cipher);
+    Ok(())
+}
+
+/// Lets the cipher ask for the passphrase when it is first needed, for
+/// the command line.
+pub fn allow_prompt() {
+    PROMPT.store(true, Ordering::Relaxed);
+}
+
+/// Derives the key from `passphrase`, failing if it isn't this
+/// repository's. Does nothing without encryption or once unlocked.
+pub fn unlock(passphrase: &str) -> Result<()> {
+    let context = context::current();
+    if !enabled() || context.cipher.get().is_some() {
+        return Ok(());
+    }
+    let missing = |what: &str| ScmError::IntegrityFailure(format!("Encryption {} missing!", what));
+    let salt = config::get(SALT_KEY).and_then(|salt| hex::decode(salt).ok()).ok_or_else(|| missing("salt"))?;
+    let check = config::get(CHECK_KEY).and_then(|check| hex::decode(check).ok()).ok_or_else(|| missing("check value"))?;
+    let cipher = derive(passphrase, &salt);
+    if open_with(&cipher, &check).as_deref() != Some(CHECK_VALUE) {
+        return Err(ScmError::WrongPassphrase);
+    }
+    let _ = context.cipher.set(cipher);
+    Ok(())
+}
+
+/// `ScmError::Locked` if the repository is encrypted and the key isn't
+/// known yet (on the command line, asks for it).
+pub fn ensure_unlocked() -> Result<()> {
+    if enabled() {
+        cipher()?;
+    }
+    Ok(())
+}
+
+/// Encrypts data for storage, or returns it unchanged without encryption.
+pub fn seal(data: Vec<u8>) -> Result<Vec<u8>> {
+    if !enabled() {
+        return Ok(data);
+    }
+    Ok(seal_with(&cipher()?, &data))
+}
+
+/// Decrypts stored data, or returns it unchanged without encryption. None
+/// if it fails authentication, i.e. it is corrupt or was tampered with.
+pub fn open(data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    if !enabled() {
+        return Ok(Some(data));
+    }
+    Ok(open_with(&cipher()?, &data))
+}
+
+fn seal_with(cipher: &XChaCha20Poly1305, data: &[u8]) -> Vec<u8> {
+    let nonce = XNonce::generate();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(&nonce, data).expect("Failed to encrypt"));
+    sealed
+}
+
+fn open_with(cipher: &XChaCha20Poly1305, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    cipher.decrypt(&XNonce::try_from(nonce).ok()?, ciphertext).ok()
+}
+
+/// The repository's cipher, once unlocked. On the command line the
+/// passphrase is asked for on first use; once a wrong one is given, this
+/// fails with `WrongPassphrase` for the rest of the command, so nothing is
+/// read or written with it and it isn't asked for again.
+fn cipher() -> Result<XChaCha20Poly1305> {
+    let context = context::current();
+    if context.cipher.get().is_none() && PROMPT.load(Ordering::Relaxed) {
+        if REFUSED.load(Ordering::Relaxed) {
+            return Err(ScmError::WrongPassphrase);
+        }
+        let passphrase = match env::var(PASSPHRASE_VAR) {
+            Ok(passphrase) => passphrase,
+            Err(_) => prompt("Passphrase: ")?,
+        };
+        if let Err(e) = unlock(&passphrase) {
+            REFUSED.store(matches!(e, ScmError::WrongPassphrase), Ordering::Relaxed);
+            return Err(e);
+        }
+    }
+    context.cipher.get().cloned().ok_or(ScmError::Locked)
+}
+
+fn derive(passphrase: &str, salt: &[u8]) -> XChaCha20Poly1305 {
+    let mut key = Key::default();
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key).expect("Failed to derive key");
+    XChaCha20Poly1305::new(&key)
+}
+
+/// Reads a passphrase from the terminal. Without one to ask on, the
+/// repository stays locked.
+fn prompt(text: &str) -> Result<String> {
+    rpassword::prompt_password(text).map_err(|_| ScmError::Locked)
+}
@@ -0,0 +1,140 @@
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+/// Line-based deltas for storing a file as changes against an earlier copy.
+///
+/// A delta starts with a header line `SCMDELTA <base hash> <depth>`, where
+/// depth is the number of deltas to apply to reach a full copy, followed by
+/// instructions: `C` with a little-endian u64 offset and length copies bytes
+/// from the base, `I` with a u64 length inserts the bytes that follow.
+const MAGIC: &str = "SCMDELTA";
+
+pub struct Header {
+    pub base: String,
+    pub depth: usize,
+}
+
+/// Encodes `new` as a delta against `base`.
+pub fn encode(base_hash: &str, depth: usize, base: &[u8], new: &[u8]) -> Vec<u8> {
+    let old_lines = split_lines(base);
+    let new_lines = split_lines(new);
+    let mut old_offsets = Vec::with_capacity(old_lines.len() + 1);
+    let mut offset = 0;
+    for line in &old_lines {
+        old_offsets.push(offset);
+        offset += line.len();
+    }
+    old_offsets.push(offset);
+
+    let mut out = format!("{} {} {}\n", MAGIC, base_hash, depth).into_bytes();
+    let mut pending_copy: Option<(usize, usize)> = None;
+    for op in capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        match tag {
+            DiffTag::Equal => {
+                let start = old_offsets[old_range.start];
+                let len = old_offsets[old_range.end] - start;
+                pending_copy = match pending_copy {
+                    Some((s, l)) if s + l == start => Some((s, l + len)),
+                    Some((s, l)) => {
+                        push_copy(&mut out, s, l);
+                        Some((start, len))
+                    }
+                    None => Some((start, len)),
+                };
+            }
+            DiffTag::Delete => {}
+            DiffTag::Insert | DiffTag::Replace => {
+                if let Some((s, l)) = pending_copy.take() {
+                    push_copy(&mut out, s, l);
+                }
+                let bytes: Vec<u8> = new_lines[new_range].concat();
+                out.push(b'I');
+                out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+                out.extend_from_slice(&bytes);
+            }
+        }
+    }
+    if let Some((s, l)) = pending_copy {
+        push_copy(&mut out, s, l);
+    }
+    out
+}
+
+/// The header of delta content, or None if it isn't a delta.
+pub fn header(delta: &[u8]) -> Option<Header> {
+    let end = delta.iter().position(|&b| b == b'\n')?;
+    let line = std::str::from_utf8(&delta[..end]).ok()?;
+    let mut fields = line.split(' ');
+    if fields.next()? != MAGIC {
+        return None;
+    }
+    let base = fields.next()?.to_string();
+    let depth = fields.next()?.parse().ok()?;
+    Some(Header { base, depth })
+}
+
+/// Rebuilds the content a delta describes from its base, or None if the
+/// delta is malformed or doesn't fit the base.
+pub fn apply(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = delta.iter().position(|&b| b == b'\n')? + 1;
+    let mut out = Vec::with_capacity(base.len());
+    while pos < delta.len() {
+        let op = delta[pos];
+        let first = read_u64(delta, pos + 1)?;
+        pos += 9;
+        match op {
+            b'C' => {
+                let len = read_u64(delta, pos)?;
+                pos += 8;
+                out.extend_from_slice(base.get(first..first.checked_add(len)?)?);
+            }
+            b'I' => {
+                out.extend_from_slice(delta.get(pos..pos.checked_add(first)?)?);
+                pos += first;
+            }
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+fn push_copy(out: &mut Vec<u8>, offset: usize, len: usize) {
+    out.push(b'C');
+    out.extend_from_slice(&(offset as u64).to_le_bytes());
+    out.extend_from_slice(&(len as u64).to_le_bytes());
+}
+
+fn read_u64(bytes: &[u8], pos: usize) -> Option<usize> {
+    let field = bytes.get(pos..pos + 8)?;
+    Some(u64::from_le_bytes(field.try_into().unwrap()) as usize)
+}
+
+/// Lines including their `\n`; the last one may lack it.
+fn split_lines(bytes: &[u8]) -> Vec<&[u8]> {
+    bytes.split_inclusive(|&b| b == b'\n').collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trips() {
+        let base = b"one\ntwo\nthree\nfour\n";
+        let cases: [&[u8]; 5] = [b"one\ntwo\nthree\nfour\n", b"zero\none\nthree\nfour\nfive", b"", b"\0binary\xff", b"one\ntwo\nthree\nfour\n\n"];
+        for new in cases {
+            let delta = encode("abc123", 2, base, new);
+            let header = header(&delta).unwrap();
+            assert_eq!((header.base.as_str(), header.depth), ("abc123", 2));
+            assert_eq!(apply(base, &delta).as_deref(), Some(new));
+        }
+    }
+
+    #[test]
+    fn delta_that_does_not_fit_its_base_is_refused() {
+        let delta = encode("abc123", 1, b"one\ntwo\nthree\n", b"one\nthree\n");
+        assert!(apply(b"one\n", &delta).is_none());
+        assert!(apply(b"one\ntwo\nthree\n", &delta[..delta.len() - 1]).is_none());
+        assert!(header(b"one\ntwo\n").is_none());
+    }
+}
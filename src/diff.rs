@@ -0,0 +1,161 @@
+use std::borrow::Cow;
+use std::fs;
+use std::process::Command;
+use similar::{ChangeTag, TextDiff};
+use tracing::error;
+
+use crate::attributes::{self, AttrValue, Attributes};
+use crate::{color, config, scm_dir};
+
+/// Number of unchanged lines shown around each hunk.
+const CONTEXT_LINES: usize = 3;
+/// How much of a file is checked for NUL bytes, as git does.
+const BINARY_SNIFF_LEN: usize = 8000;
+/// Scratch file handed to a textconv command.
+const TEXTCONV_INPUT: &str = "TEXTCONV_INPUT";
+
+/// Prints a unified diff between two versions of `path`. The `diff`
+/// attribute decides how: `-diff` only summarizes the change, `diff` always
+/// shows it as text, and `diff=<driver>` first converts both sides with the
+/// `diff.<driver>.textconv` command from the config. Otherwise binary
+/// content (see `text_for`) is only summarized with its sizes.
+pub fn print_file_diff(path: &str, old_label: &str, new_label: &str, old: &[u8], new: &[u8]) {
+    print!("{}", file_diff(path, old_label, new_label, old, new));
+}
+
+/// The diff `print_file_diff` prints.
+fn file_diff(path: &str, old_label: &str, new_label: &str, old: &[u8], new: &[u8]) -> String {
+    let attributes = attributes::current();
+    let texts = match attributes.get(path, "diff") {
+        Some(AttrValue::Unset) => None,
+        Some(AttrValue::Set) => Some((String::from_utf8_lossy(old), String::from_utf8_lossy(new))),
+        Some(AttrValue::Value(driver)) => match config::get(&format!("diff.{}.textconv", driver)) {
+            Some(command) => match (textconv(&command, old), textconv(&command, new)) {
+                (Some(o), Some(n)) => Some((Cow::Owned(o), Cow::Owned(n))),
+                _ => None,
+            },
+            None => text_pair(&attributes, path, old, new),
+        },
+        None => text_pair(&attributes, path, old, new),
+    };
+    let (old_text, new_text) = match texts {
+        Some(texts) => texts,
+        None => return format!("Binary files {} and {} differ ({} -> {} bytes)\n", old_label, new_label, old.len(), new.len()),
+    };
+
+    let diff = TextDiff::from_lines(old_text.as_ref(), new_text.as_ref());
+    color::diff(&diff.unified_diff().context_radius(CONTEXT_LINES).header(old_label, new_label).to_string())
+}
+
+/// Both sides as text, or None if either is binary.
+fn text_pair<'a>(attributes: &Attributes, path: &str, old: &'a [u8], new: &'a [u8]) -> Option<(Cow<'a, str>, Cow<'a, str>)> {
+    match (text_for(attributes, path, old), text_for(attributes, path, new)) {
+        // Lossy decoding can hide a difference; fall back to the summary then.
+        (Some(o), Some(n)) if o != n || old == new => Some((o, n)),
+        _ => None,
+    }
+}
+
+/// Runs a textconv command on content written to a scratch file, passing the
+/// file's path as the last argument, and returns what it prints. An empty
+/// side (an added or deleted file) converts to nothing without running it.
+fn textconv(command: &str, bytes: &[u8]) -> Option<String> {
+    if bytes.is_empty() {
+        return Some(String::new());
+    }
+    let input = scm_dir().join(TEXTCONV_INPUT);
+    fs::write(&input, bytes).expect("Failed to write textconv input");
+    let output = Command::new("sh").arg("-c").arg(format!("{} \"$1\"", command)).arg("textconv").arg(&input).output();
+    let _ = fs::remove_file(&input);
+    match output {
+        Ok(output) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Ok(output) => {
+            error!("textconv command '{}' failed ({}).", command, output.status);
+            None
+        }
+        Err(e) => {
+            error!("textconv command '{}' could not be run: {}", command, e);
+            None
+        }
+    }
+}
+
+/// Whether content looks binary: a NUL byte near the start.
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_SNIFF_LEN)].contains(&0)
+}
+
+/// The content as UTF-8 text, or None if it looks binary or isn't UTF-8.
+pub fn as_text(bytes: &[u8]) -> Option<&str> {
+    if looks_binary(bytes) { return None; }
+    std::str::from_utf8(bytes).ok()
+}
+
+/// A file's content as text for display, or None if it's binary. The `text`
+/// and `binary` attributes override detection; a file declared text that
+/// isn't valid UTF-8 is decoded lossily.
+pub fn text_for<'a>(attributes: &Attributes, path: &str, bytes: &'a [u8]) -> Option<Cow<'a, str>> {
+    match attributes.binary_override(path) {
+        Some(true) => None,
+        Some(false) => Some(String::from_utf8_lossy(bytes)),
+        None => as_text(bytes).map(Cow::Borrowed),
+    }
+}
+
+/// For each line of `new`, the index of the identical line in `old` it was
+/// carried over from, or None if the line was inserted or changed.
+pub fn map_lines(old: &str, new: &str) -> Vec<Option<usize>> {
+    let diff = TextDiff::from_lines(old, new);
+    let mut mapping = Vec::new();
+    for op in diff.ops() {
+        for change in diff.iter_changes(op) {
+            match change.tag() {
+                ChangeTag::Equal => mapping.push(change.old_index()),
+                ChangeTag::Insert => mapping.push(None),
+                ChangeTag::Delete => {}
+            }
+        }
+    }
+    mapping
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::Repository;
+
+    /// `file_diff` in a fresh in-memory repository, so it has the (empty)
+    /// attributes of one to go by.
+    fn diff(name: &str, old: &[u8], new: &[u8]) -> String {
+        let dir = env::temp_dir().join(format!("scm-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init_in_memory(&dir).unwrap();
+        repo.run(|| Ok(file_diff("a.txt", "a/a.txt", "b/a.txt", old, new))).unwrap()
+    }
+
+    #[test]
+    fn text_changes_show_as_a_unified_diff() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = b"1\n2\n3\n4\nfive\n6\n7\n8\n9\n";
+        assert_eq!(
+            diff("text-diff", old, new),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+        assert_eq!(diff("added-diff", b"", b"new\n"), "--- a/a.txt\n+++ b/a.txt\n@@ -0,0 +1 @@\n+new\n");
+    }
+
+    #[test]
+    fn binary_changes_are_summarized() {
+        assert_eq!(diff("binary-diff", b"one\n", b"\0two\n"), "Binary files a/a.txt and b/a.txt differ (4 -> 5 bytes)\n");
+        // Content that isn't UTF-8 counts as binary too.
+        assert_eq!(diff("utf8-diff", b"\xff\n", b"\xfe\n"), "Binary files a/a.txt and b/a.txt differ (2 -> 2 bytes)\n");
+    }
+
+    #[test]
+    fn lines_map_to_where_they_came_from() {
+        assert_eq!(map_lines("a\nb\nc\n", "a\nx\nc\nd\n"), [Some(0), None, Some(2), None]);
+    }
+}
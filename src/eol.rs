@@ -0,0 +1,103 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use crate::attributes::{self, AttrValue, Attributes};
+use crate::{config, context, diff};
+
+/// `core.autocrlf`: "input" stores text files with LF endings, "true" also
+/// converts them to CRLF on checkout, "false" (the default) leaves them alone.
+#[derive(PartialEq)]
+enum AutoCrlf {
+    False,
+    Input,
+    True,
+}
+
+/// Line-ending handling for the repository, from `core.autocrlf` and the
+/// per-path `text` and `eol` attributes in `.scmattributes`:
+///
+/// - `-text` (or `binary`) never converts the path;
+/// - `text` or `eol=lf|crlf` always normalizes it to LF in storage, even with
+///   `core.autocrlf` off, and `eol` picks the checkout ending;
+/// - otherwise `core.autocrlf` applies to files that don't look binary.
+pub struct EolPolicy {
+    autocrlf: AutoCrlf,
+    attributes: Arc<Attributes>,
+}
+
+/// The repository's policy, loaded on first use.
+pub fn policy() -> Arc<EolPolicy> {
+    let context = context::current();
+    let policy = context.eol.get_or_init(|| {
+        Arc::new(EolPolicy {
+            autocrlf: match config::get("core.autocrlf").as_deref() {
+                Some("true") => AutoCrlf::True,
+                Some("input") => AutoCrlf::Input,
+                _ => AutoCrlf::False,
+            },
+            attributes: attributes::current(),
+        })
+    });
+    policy.clone()
+}
+
+impl EolPolicy {
+    /// Whether the path could be converted at all, before looking at content.
+    pub fn may_convert(&self, path: &str) -> bool {
+        match self.attributes.get(path, "text") {
+            Some(AttrValue::Unset) => false,
+            Some(_) => true,
+            None => self.autocrlf != AutoCrlf::False || self.attributes.get(path, "eol").is_some(),
+        }
+    }
+
+    /// Whether this content is normalized to LF in storage.
+    fn normalizes(&self, path: &str, bytes: &[u8]) -> bool {
+        if !self.may_convert(path) {
+            return false;
+        }
+        let declared_text = matches!(self.attributes.get(path, "text"), Some(AttrValue::Set))
+            || matches!(self.attributes.get(path, "eol"), Some(AttrValue::Value(_)));
+        declared_text || !diff::looks_binary(bytes)
+    }
+
+    fn checkout_crlf(&self, path: &str) -> bool {
+        match self.attributes.get(path, "eol") {
+            Some(AttrValue::Value(eol)) => eol == "crlf",
+            _ => self.autocrlf == AutoCrlf::True,
+        }
+    }
+
+    /// Working-tree content as it is stored: CRLF becomes LF for text files.
+    pub fn clean<'a>(&self, path: &str, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.normalizes(path, bytes) || !bytes.contains(&b'\r') {
+            return Cow::Borrowed(bytes);
+        }
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut iter = bytes.iter().peekable();
+        while let Some(&byte) = iter.next() {
+            if byte == b'\r' && iter.peek() == Some(&&b'\n') {
+                continue;
+            }
+            out.push(byte);
+        }
+        Cow::Owned(out)
+    }
+
+    /// Stored content as it is checked out: LF becomes CRLF where configured.
+    pub fn smudge<'a>(&self, path: &str, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.normalizes(path, bytes) || !self.checkout_crlf(path) {
+            return Cow::Borrowed(bytes);
+        }
+        let mut out = Vec::with_capacity(bytes.len() + bytes.len() / 16);
+        let mut previous = 0;
+        for &byte in bytes {
+            if byte == b'\n' && previous != b'\r' {
+                out.push(b'\r');
+            }
+            out.push(byte);
+            previous = byte;
+        }
+        Cow::Owned(out)
+    }
+}
@@ -0,0 +1,77 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::VersionId;
+
+/// What went wrong, as the library reports it. Each message is written for
+/// the user, as the command line prints it (see `exit::report`); the
+/// variants that mean stored data is damaged start with "INTEGRITY ERROR".
+#[derive(Debug, Error)]
+pub enum ScmError {
+    #[error("No SCM repository found at or above {}.", .0.display())]
+    NotARepository(PathBuf),
+    #[error("Already inside an SCM repository at {}.", .0.display())]
+    AlreadyARepository(PathBuf),
+    /// `Repository::open_with_storage` for a working tree this process
+    /// already has open.
+    #[error("The repository at {} is already open with its own storage.", .0.display())]
+    StorageInUse(PathBuf),
+    #[error("Version {0} not found.")]
+    VersionNotFound(VersionId),
+    /// The commit would record no changes and `CommitOptions::allow_empty`
+    /// wasn't set.
+    #[error("No changes to commit (use --allow-empty to commit anyway).")]
+    NothingToCommit,
+    #[error("Path {0} did not match any files.")]
+    PathNotFound(String),
+    /// An interrupted commit or revert awaits `scm recover`; this is what it was.
+    #[error("An interrupted {0} was found; run 'scm recover' to finish or roll it back.")]
+    Interrupted(String),
+    /// The version was written by a newer scm.
+    #[error("Version {version} uses manifest format {format}, but this scm only reads up to {}; upgrade scm.", crate::MANIFEST_FORMAT)]
+    NewerFormat { version: VersionId, format: u32 },
+    /// The repository is encrypted and `Repository::unlock` hasn't been
+    /// given its passphrase.
+    #[error("This repository is encrypted; unlock it with its passphrase first (on the command line, set SCM_PASSPHRASE if there is no terminal to ask on).")]
+    Locked,
+    #[error("Wrong passphrase for this repository.")]
+    WrongPassphrase,
+    /// A config file to be changed isn't valid TOML, or has a value where
+    /// the key needs a table.
+    #[error("Cannot change the settings: {0}.")]
+    Config(String),
+    #[error("INTEGRITY ERROR: The manifest of version {0} is missing or unreadable.")]
+    CorruptManifest(VersionId),
+    #[error("INTEGRITY ERROR: The stored copy of {path} in version {version} is missing.")]
+    MissingObject { version: VersionId, path: String },
+    /// Stored content that doesn't match its hash, or is otherwise damaged.
+    #[error("INTEGRITY ERROR: {0}")]
+    IntegrityFailure(String),
+    /// Work that panicked, which is a bug in scm, or was cancelled as the
+    /// runtime running it shut down (see `asynch`).
+    #[error("The repository call failed: {0}")]
+    Internal(String),
+    /// A file couldn't be read or written; `context` says which and why.
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+pub type Result<T, E = ScmError> = std::result::Result<T, E>;
+
+impl ScmError {
+    /// For `map_err`: an I/O error in doing what `context` says, e.g.
+    /// `fs::write(path, content).map_err(ScmError::io(format!("Cannot write {}", path.display())))`.
+    pub(crate) fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> ScmError {
+        move |source| ScmError::Io { context: context.into(), source }
+    }
+
+    /// Whether this means the repository itself is damaged.
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, ScmError::CorruptManifest(_) | ScmError::MissingObject { .. } | ScmError::IntegrityFailure(_))
+    }
+}
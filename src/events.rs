@@ -0,0 +1,58 @@
+use crate::{context, FileHash};
+
+/// Hears about each file as commit, revert and verify go, so a program
+/// embedding scm can show its own progress; the command line draws bars
+/// instead (see `progress`). Every method does nothing unless overridden.
+/// They are called from the thread pool, in no particular order, so they
+/// should be quick; paths are from the root of the working tree.
+pub trait Observer: Send + Sync {
+    /// A file was hashed: a working file, when a commit (or status) looks
+    /// for changes, or a stored copy, when revert or verify checks it.
+    fn on_file_hashed(&self, path: &str, hash: &FileHash) {
+        let _ = (path, hash);
+    }
+
+    /// A commit copied a file, `bytes` long, into the repository. Content
+    /// that is already stored is counted without being copied again.
+    fn on_file_copied(&self, path: &str, bytes: u64) {
+        let _ = (path, bytes);
+    }
+
+    /// A revert wrote out a file, `bytes` long. The working directory only
+    /// changes once every file has been written (see `swap`).
+    fn on_file_restored(&self, path: &str, bytes: u64) {
+        let _ = (path, bytes);
+    }
+
+    /// A file changed on both sides: by a merge or cherry-pick and in the
+    /// version it brings in, or in the working directory when a revert
+    /// replaces it.
+    fn on_conflict(&self, path: &str) {
+        let _ = path;
+    }
+}
+
+/// Tells the repository's observer, if it has one; see
+/// `Repository::set_observer`.
+fn notify(event: impl FnOnce(&dyn Observer)) {
+    let context = context::current();
+    if let Some(observer) = context.observer.read().unwrap_or_else(|poisoned| poisoned.into_inner()).as_deref() {
+        event(observer);
+    }
+}
+
+pub fn file_hashed(path: &str, hash: &FileHash) {
+    notify(|observer| observer.on_file_hashed(path, hash));
+}
+
+pub fn file_copied(path: &str, bytes: u64) {
+    notify(|observer| observer.on_file_copied(path, bytes));
+}
+
+pub fn file_restored(path: &str, bytes: u64) {
+    notify(|observer| observer.on_file_restored(path, bytes));
+}
+
+pub fn conflict(path: &str) {
+    notify(|observer| observer.on_conflict(path));
+}
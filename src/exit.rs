@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+use tracing::error;
+
+use crate::ScmError;
+
+/// What scm exits with, for scripts:
+///
+/// - 0: the command did what was asked.
+/// - 1: it couldn't: a usage mistake, no repository, an unknown version or
+///   file, a conflict or a refusal, reported as an error. `status
+///   --exit-code` also exits 1 when there are changes, and `config get`
+///   when the key isn't set. So does a file that can't be read or written.
+/// - 2: the repository is damaged: `fsck` or `verify` found problems, or
+///   stored content failed an integrity check.
+/// - 101: scm itself failed (a bug) and said where.
+pub const SUCCESS: i32 = 0;
+pub const FAILURE: i32 = 1;
+pub const CORRUPT: i32 = 2;
+
+static STATUS: AtomicI32 = AtomicI32::new(SUCCESS);
+
+/// Raises the status the command exits with to at least `status`.
+pub fn set(status: i32) {
+    STATUS.fetch_max(status, Ordering::Relaxed);
+}
+
+/// Prints an error the library returned and sets the status to go with it.
+pub fn report(error: ScmError) {
+    error!("{}", error);
+    set(if error.is_corruption() { CORRUPT } else { FAILURE });
+}
+
+/// The status the command has come to, for `main` to exit with.
+pub fn status() -> i32 {
+    STATUS.load(Ordering::Relaxed)
+}
+
+/// Makes output to a pipe that was closed, as in `scm log | head -1`, end
+/// scm quietly, the way it ends other programs in a shell pipe, rather than
+/// fail the next `println!`.
+pub fn init() {
+    #[cfg(unix)]
+    // SAFETY: restores the default action for a signal Rust ignores at
+    // startup, before any other thread runs.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+}
+
+/// Prints a command line clap couldn't parse (or `--help`, `--version`) and
+/// returns the status to exit with: `FAILURE` for a mistake rather than
+/// clap's own 2.
+pub fn usage(error: clap::Error) -> i32 {
+    let _ = error.print();
+    if error.use_stderr() { FAILURE } else { SUCCESS }
+}
@@ -0,0 +1,83 @@
+use std::fs;
+use tracing::{error, info, warn};
+
+use crate::ignore::IgnoreRules;
+use crate::{exit, gc, get_commit_path, index, list_versions, load_manifest, scm_dir, write_manifest};
+
+/// `scm filter --remove <pattern>...`: rewrites history as if paths matching
+/// the patterns (`.scmignore` syntax, so a directory takes everything in it)
+/// had never been committed. Every version keeps its id, parents, message
+/// and other files, so refs, tags and the oplog stay valid; the stored
+/// contents only the removed paths used are then deleted.
+pub fn do_filter(patterns: &[String]) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let rules = IgnoreRules::from_patterns(patterns, "--remove");
+    let before = gc::dir_size(&scm_dir());
+
+    let mut rewritten = 0;
+    let mut removed = 0;
+    for id in list_versions() {
+        let mut manifest = match load_manifest(id) {
+            Ok(manifest) => manifest,
+            Err(_) => {
+                warn!("Skipping version {}: manifest is missing or unreadable (see 'scm fsck').", id);
+                continue;
+            }
+        };
+        let doomed: Vec<String> = manifest.files.keys().filter(|name| rules.matches(name, false)).cloned().collect();
+        for filename in &doomed {
+            manifest.files.remove(filename);
+            manifest.metadata.remove(filename);
+            // Versions from before the object store keep their own copies.
+            let _ = fs::remove_file(get_commit_path(id).join(filename));
+        }
+        let mut changed = !doomed.is_empty();
+        let (removals, renames) = (manifest.removed.len(), manifest.renamed.len());
+        manifest.removed.retain(|name| !rules.matches(name, false));
+        manifest.renamed.retain(|old, new| !rules.matches(old, false) && !rules.matches(new, false));
+        changed |= manifest.removed.len() != removals || manifest.renamed.len() != renames;
+        // Dropping a path from a version and its parent drops it from the
+        // change set between them too; nothing else in it changes.
+        if let Some(changes) = &mut manifest.changes {
+            for list in [&mut changes.added, &mut changes.modified, &mut changes.deleted] {
+                let len = list.len();
+                list.retain(|name| !rules.matches(name, false));
+                changed |= list.len() != len;
+            }
+        }
+        if changed {
+            if let Err(e) = write_manifest(&manifest) {
+                return exit::report(e);
+            }
+            rewritten += 1;
+            removed += doomed.len();
+        }
+    }
+
+    // Staged entries that only point at the removed contents go too; staged
+    // copies of new edits are kept.
+    let staged = index::load().and_then(|entries| match entries {
+        Some(mut entries) => {
+            entries.retain(|name, _| !rules.matches(name, false) || index::has_staged_content(name));
+            index::save(&entries)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = staged {
+        return exit::report(e);
+    }
+
+    let objects = match gc::remove_unused_objects() {
+        Ok(objects) => objects,
+        Err(e) => return exit::report(e),
+    };
+    let after = gc::dir_size(&scm_dir());
+    info!("Removed {} file entries from {} version(s) and deleted {} unused object(s).", removed, rewritten, objects);
+    info!("Reclaimed {} bytes.", before.saturating_sub(after));
+    if rewritten > 0 {
+        info!("Matching files in the working directory are now untracked; add them to .scmignore to keep them out.");
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::progress::Progress;
+use crate::storage::storage;
+use crate::{
+    calculate_hash, chunk, color, events, exit, get_commit_path, json, list_versions, load_manifest, manifest_exists, pending_merge, refs, scm_dir,
+    stash, FileHash, Manifest, VersionId,
+};
+
+/// What `fsck` and `verify` found, as `--json` prints it.
+#[derive(Serialize)]
+pub struct Report<'a> {
+    pub versions: usize,
+    pub files: usize,
+    pub problems: &'a [String],
+}
+
+/// `scm fsck`: checks the repository's structure and stored contents without
+/// changing anything, listing every problem found rather than stopping at
+/// the first one.
+pub fn do_fsck() {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let mut problems = Vec::new();
+    let versions = check_manifests(&mut problems);
+    check_refs(&versions, &mut problems);
+    check_parents(&versions, &mut problems);
+    let objects = check_contents(&versions, &mut problems);
+    if !problems.is_empty() {
+        exit::set(exit::CORRUPT);
+    }
+
+    if json::enabled() {
+        json::print(&Report { versions: versions.len(), files: objects, problems: &problems });
+        return;
+    }
+    if problems.is_empty() {
+        info!("Checked {} version(s) and {} stored file(s); no problems found.", versions.len(), objects);
+        return;
+    }
+    for problem in &problems {
+        error!("{} {}", color::error(), problem);
+    }
+    info!("");
+    error!("{} problem(s) found in {} version(s) and {} stored file(s).", problems.len(), versions.len(), objects);
+    info!("Versions with missing or corrupt files cannot be restored; the others still can.");
+    info!("Missing objects can be copied back from a clone's .scm/objects (or .scm/large).");
+}
+
+/// Loads every version's manifest, reporting ones that are missing,
+/// unreadable or recorded under the wrong id.
+fn check_manifests(problems: &mut Vec<String>) -> BTreeMap<VersionId, Manifest> {
+    let mut versions = BTreeMap::new();
+    for id in list_versions() {
+        if !manifest_exists(id) {
+            problems.push(format!(
+                "version {}: manifest is missing; if it is a leftover from an interrupted commit, delete {}",
+                id,
+                get_commit_path(id).display()
+            ));
+            continue;
+        }
+        match load_manifest(id) {
+            Ok(manifest) if manifest.version_id != id => {
+                problems.push(format!("version {}: manifest records version id {}", id, manifest.version_id));
+                versions.insert(id, manifest);
+            }
+            Ok(manifest) => {
+                versions.insert(id, manifest);
+            }
+            Err(_) => problems.push(format!("version {}: manifest cannot be parsed", id)),
+        }
+    }
+    versions
+}
+
+/// HEAD, branches, tags, a pending merge and stashes must all point at
+/// existing versions.
+fn check_refs(versions: &BTreeMap<VersionId, Manifest>, problems: &mut Vec<String>) {
+    let exists = |id: VersionId| versions.contains_key(&id);
+    match refs::read_head_ref() {
+        Ok(refs::HeadRef::Branch(name)) => match refs::read_branch(&name) {
+            Ok(Some(id)) if !exists(id) => problems.push(format!(
+                "HEAD: branch {} points at version {}, which does not exist; move it with 'scm reset --soft <version>'",
+                name, id
+            )),
+            Ok(None) if !versions.is_empty() => problems.push(format!(
+                "HEAD: branch {} has no valid version; switch to another branch or recreate it with 'scm branch {} <version>'",
+                name, name
+            )),
+            Err(_) => problems.push(format!("HEAD: branch {} is malformed or unreadable; write a version id to .scm/refs/heads/{}", name, name)),
+            Ok(_) => {}
+        },
+        Err(_) => problems.push("HEAD: is malformed or unreadable; write 'ref: refs/heads/<branch>' or a version id to .scm/HEAD".to_string()),
+        Ok(refs::HeadRef::Detached(Some(id))) => {
+            if !exists(id) {
+                problems.push(format!("HEAD: points at version {}, which does not exist; run 'scm checkout <version>'", id));
+            }
+        }
+        Ok(refs::HeadRef::Detached(None)) => {
+            if !versions.is_empty() {
+                problems.push("HEAD: holds no version; run 'scm checkout <version>'".to_string());
+            }
+        }
+    }
+    for (name, id) in refs::list_branches() {
+        if !exists(id) {
+            problems.push(format!("branch {}: points at version {}, which does not exist; delete it with 'scm branch -d {}'", name, id, name));
+        }
+    }
+    for (name, tag) in refs::list_tags() {
+        if !exists(tag.version_id) {
+            problems.push(format!("tag {}: points at version {}, which does not exist; delete it with 'scm tag -d {}'", name, tag.version_id, name));
+        }
+    }
+    if let Some(id) = pending_merge()
+        && !exists(id)
+    {
+        problems.push(format!("MERGE_HEAD: points at version {}, which does not exist; run 'scm merge --abort'", id));
+    }
+    for index in stash::list_indices() {
+        match stash::load(index) {
+            Some(stash::StashEntry { base_version: Some(base), .. }) if !exists(base) => {
+                problems.push(format!("stash {}: based on version {}, which does not exist", index, base));
+            }
+            Some(_) => {}
+            None => problems.push(format!("stash {}: entry cannot be parsed", index)),
+        }
+    }
+}
+
+/// Parent links must point at existing, older versions. Ids only grow, so
+/// this also rules out cycles.
+fn check_parents(versions: &BTreeMap<VersionId, Manifest>, problems: &mut Vec<String>) {
+    for (&id, manifest) in versions {
+        for (kind, parent) in [("parent", manifest.parent), ("merge parent", manifest.merge_parent)] {
+            let parent = match parent {
+                Some(parent) => parent,
+                None => continue,
+            };
+            if !versions.contains_key(&parent) {
+                problems.push(format!("version {}: {} {} is missing or unreadable", id, kind, parent));
+            } else if parent >= id {
+                problems.push(format!("version {}: {} {} is not older than it, so history would loop", id, kind, parent));
+            }
+        }
+    }
+}
+
+/// Every file a version records must be stored and match its hash. Returns
+/// the number of distinct stored files checked.
+pub fn check_contents(versions: &BTreeMap<VersionId, Manifest>, problems: &mut Vec<String>) -> usize {
+    problems.extend(storage().check());
+
+    let mut checked: HashMap<FileHash, Result<(), String>> = HashMap::new();
+    let progress = Progress::new("Checking", versions.values().map(|manifest| manifest.files.len()).sum(), false);
+    for (&id, manifest) in versions {
+        let mut files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+        files.sort();
+        for (filename, hash) in files {
+            let meta = manifest.metadata.get(filename);
+            let result = checked.entry(hash.clone()).or_insert_with(|| {
+                if let Some(chunks) = meta.and_then(|m| m.chunks.as_ref()) {
+                    chunk::verify(chunks, hash)
+                } else {
+                    match storage().has(hash) {
+                        // A damaged pack index; `storage().check()` has said which.
+                        Err(_) => Err("cannot be looked up in the damaged store".to_string()),
+                        Ok(true) => storage().verify_object(hash),
+                        // Versions from before the object store keep their own copies.
+                        Ok(false) => storage().legacy_copy(id, filename).map_or(Err("missing".to_string()), |path| check_file(&path, hash)),
+                    }
+                }
+            });
+            match result {
+                Ok(()) => events::file_hashed(filename, hash),
+                Err(problem) => problems.push(format!("version {}, {}: {}", id, filename, problem)),
+            }
+            progress.inc(0);
+        }
+    }
+    progress.finish();
+    checked.len()
+}
+
+fn check_file(path: &Path, hash: &str) -> Result<(), String> {
+    if !path.is_file() {
+        return Err("missing".to_string());
+    }
+    if calculate_hash(path).map_err(|e| e.to_string())? != hash {
+        return Err("corrupted (content does not match its hash)".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::attributes::ATTRIBUTES_FILE;
+use crate::cli::MonitorCommand;
+use crate::ignore::{self, IGNORE_FILE};
+use crate::{config, crypt, exit, hash_bytes, json, scm_dir, staged_files, tracking, working_path, FileHash, Result, ScmError, SCM_DIR};
+
+/// The filesystem monitor: a background process (`scm monitor start`) that
+/// watches the working tree and writes every path it sees change to
+/// `.scm/fsmonitor`, each with the number of the batch of events it came in.
+/// `status` and `commit` keep the working files and their hashes from their
+/// last run in `.scm/fsmonitor.snapshot`, tagged with the batch number they
+/// were up to date with, and only look at the paths changed since.
+///
+/// Before trusting the monitor, a command drops a cookie file into `.scm`
+/// and waits for the monitor to delete it, which it does only after writing
+/// out every event that came before. A monitor that doesn't answer is taken
+/// for dead and the working tree is scanned as usual.
+///
+/// The monitor runs without the key, so it writes paths in the clear, and
+/// an encrypted repository doesn't use it.
+const STATE_FILE: &str = "fsmonitor";
+const SNAPSHOT_FILE: &str = "fsmonitor.snapshot";
+const STOP_FILE: &str = "fsmonitor.stop";
+const COOKIE_PREFIX: &str = "fsmonitor-cookie-";
+/// How long to wait for the monitor to answer, start or stop.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the monitor checks whether it was asked to stop.
+const POLL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    /// Tells one run of the monitor from the next.
+    id: String,
+    pid: u32,
+    /// The latest batch of events written out.
+    batch: u64,
+    /// The latest batch in which events were lost, if any.
+    overflow: u64,
+    /// Every path changed since the monitor started, with its latest batch.
+    paths: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    monitor: String,
+    batch: u64,
+    /// Hash of the settings that decide which files are working files.
+    settings: FileHash,
+    files: HashMap<String, FileHash>,
+}
+
+fn scm_path(name: &str) -> PathBuf {
+    scm_dir().join(name)
+}
+
+fn write_atomic(name: &str, content: String) -> Result<()> {
+    let partial = scm_path(name).with_extension("partial");
+    let unwritable = || ScmError::io(format!("Cannot write {}", scm_path(name).display()));
+    fs::write(&partial, content).map_err(unwritable())?;
+    fs::rename(&partial, scm_path(name)).map_err(unwritable())
+}
+
+/// `scm monitor status --json`; `pid` and `changed` (paths changed since
+/// the monitor started) are null when it isn't running.
+#[derive(Serialize)]
+struct MonitorReport {
+    running: bool,
+    pid: Option<u32>,
+    changed: Option<usize>,
+}
+
+/// `scm monitor start|stop|status`.
+pub fn do_monitor(command: &MonitorCommand) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    match command {
+        MonitorCommand::Start => start(),
+        MonitorCommand::Stop => stop(),
+        MonitorCommand::Status => match sync() {
+            sync if json::enabled() => json::print(&MonitorReport {
+                running: sync.is_some(),
+                pid: sync.as_ref().map(|sync| sync.state.pid),
+                changed: sync.as_ref().map(|sync| sync.state.paths.len()),
+            }),
+            Some(sync) => println!(
+                "Filesystem monitor running (pid {}); {} path(s) changed since it started.",
+                sync.state.pid,
+                sync.state.paths.len()
+            ),
+            None => println!("No filesystem monitor running."),
+        },
+        // What `start` runs in the background.
+        MonitorCommand::Run => {
+            if let Err(e) = run() {
+                exit::report(e);
+            }
+        }
+    }
+}
+
+fn start() {
+    // Cookies must be dropped inside the tree being watched.
+    if scm_dir() != Path::new(SCM_DIR) {
+        error!("The filesystem monitor needs the repository in {}; it can't be used with $SCM_DIR set.", SCM_DIR);
+        return;
+    }
+    if crypt::enabled() {
+        error!("The filesystem monitor records paths unencrypted, so it can't be used in an encrypted repository.");
+        return;
+    }
+    if let Some(sync) = sync() {
+        info!("Filesystem monitor already running (pid {}).", sync.state.pid);
+        return;
+    }
+    let _ = fs::remove_file(scm_path(STOP_FILE));
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return exit::report(ScmError::io("Cannot find the scm executable")(e)),
+    };
+    let mut command = Command::new(exe);
+    command.args(["monitor", "run"]).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    detach(&mut command);
+    let pid = match command.spawn() {
+        Ok(child) => child.id(),
+        Err(e) => return exit::report(ScmError::io("Cannot start the filesystem monitor")(e)),
+    };
+
+    let deadline = Instant::now() + TIMEOUT;
+    while !scm_path(STATE_FILE).exists() {
+        if Instant::now() > deadline {
+            error!("Filesystem monitor failed to start.");
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    info!("Filesystem monitor started (pid {}).", pid);
+}
+
+/// Keeps the monitor running once the terminal that started it goes away.
+#[cfg(unix)]
+fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(windows)]
+fn detach(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    command.creation_flags(DETACHED_PROCESS);
+}
+
+fn stop() {
+    if !scm_path(STATE_FILE).exists() {
+        info!("No filesystem monitor running.");
+        return;
+    }
+    if let Err(e) = fs::write(scm_path(STOP_FILE), "") {
+        return exit::report(ScmError::io("Cannot signal the filesystem monitor")(e));
+    }
+    let deadline = Instant::now() + TIMEOUT;
+    while scm_path(STATE_FILE).exists() {
+        if Instant::now() > deadline {
+            // Nothing answered; clear what a dead monitor left behind.
+            let _ = fs::remove_file(scm_path(STATE_FILE));
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let _ = fs::remove_file(scm_path(STOP_FILE));
+    let _ = fs::remove_file(scm_path(SNAPSHOT_FILE));
+    info!("Filesystem monitor stopped.");
+}
+
+/// The monitor itself: records changed paths until asked to stop or the
+/// repository goes away.
+fn run() -> Result<()> {
+    let root = env::current_dir().and_then(fs::canonicalize).map_err(ScmError::io("Cannot read the current directory"))?;
+    let (sender, receiver) = mpsc::channel();
+    let unwatchable = || ScmError::io(format!("Cannot watch {}", root.display()));
+    let mut watcher = notify::recommended_watcher(sender).map_err(|e| unwatchable()(io::Error::other(e)))?;
+    watcher.watch(&root, RecursiveMode::Recursive).map_err(|e| unwatchable()(io::Error::other(e)))?;
+
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    let mut state = State { id: format!("{}-{}", process::id(), started), pid: process::id(), batch: 0, overflow: 0, paths: BTreeMap::new() };
+    write_atomic(STATE_FILE, serde_json::to_string(&state).unwrap())?;
+    loop {
+        let first = match receiver.recv_timeout(POLL) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let batch = state.batch + 1;
+        let mut changed = false;
+        let mut cookies = Vec::new();
+        for event in first.into_iter().chain(receiver.try_iter()) {
+            let event = match event {
+                // Files being read change nothing.
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => continue,
+                Ok(event) if !event.need_rescan() => event,
+                _ => {
+                    state.overflow = batch;
+                    changed = true;
+                    continue;
+                }
+            };
+            for path in event.paths {
+                let name = match relative(&root, &path) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if name.starts_with(&format!("{}/{}", SCM_DIR, COOKIE_PREFIX)) {
+                    cookies.push(path);
+                } else if name != SCM_DIR && !name.starts_with(&format!("{}/", SCM_DIR)) {
+                    state.paths.insert(name, batch);
+                    changed = true;
+                }
+            }
+        }
+        if scm_path(STOP_FILE).exists() || !scm_dir().is_dir() {
+            break;
+        }
+        if changed {
+            state.batch = batch;
+            if let Err(e) = write_atomic(STATE_FILE, serde_json::to_string(&state).unwrap()) {
+                let _ = fs::remove_file(scm_path(STATE_FILE));
+                return Err(e);
+            }
+        }
+        for cookie in cookies {
+            let _ = fs::remove_file(cookie);
+        }
+    }
+    let _ = fs::remove_file(scm_path(STATE_FILE));
+    Ok(())
+}
+
+/// A path under the working tree in the repository's form.
+fn relative(root: &Path, path: &Path) -> Option<String> {
+    let parts: Vec<String> = path.strip_prefix(root).ok()?.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// What the monitor has seen, once it has caught up.
+pub struct Sync {
+    state: State,
+    /// Whether the snapshot is already up to date with `state`.
+    current: bool,
+}
+
+/// Waits for a running monitor to write out every change made so far.
+pub fn sync() -> Option<Sync> {
+    if crypt::enabled() || !scm_path(STATE_FILE).exists() {
+        return None;
+    }
+    let cookie = scm_path(&format!("{}{}", COOKIE_PREFIX, process::id()));
+    fs::write(&cookie, "").ok()?;
+    let deadline = Instant::now() + TIMEOUT;
+    while cookie.exists() {
+        if Instant::now() > deadline {
+            let _ = fs::remove_file(&cookie);
+            let _ = fs::remove_file(scm_path(STATE_FILE));
+            warn!("the filesystem monitor isn't responding; scanning the working tree.");
+            return None;
+        }
+        thread::sleep(Duration::from_millis(2));
+    }
+    let state: State = serde_json::from_slice(&fs::read(scm_path(STATE_FILE)).ok()?).ok()?;
+    debug!("Filesystem monitor has seen {} changed path(s)", state.paths.len());
+    Some(Sync { state, current: false })
+}
+
+/// Everything that decides which files are working files, bar the files
+/// themselves.
+fn settings() -> FileHash {
+    let mut content = config::fingerprint();
+    content.extend(fs::read(working_path(ATTRIBUTES_FILE)).unwrap_or_default());
+    content.extend(fs::read(working_path(IGNORE_FILE)).unwrap_or_default());
+    content.extend(ignore::global_ignore_file().and_then(|path| fs::read(path).ok()).unwrap_or_default());
+    content.extend(tracking::untracked().join("\n").into_bytes());
+    if !ignore::include_hidden() {
+        let mut hidden: Vec<String> = staged_files().unwrap_or_default().into_keys().filter(|name| ignore::is_hidden(name)).collect();
+        hidden.sort();
+        content.extend(hidden.join("\n").into_bytes());
+    }
+    hash_bytes(&content)
+}
+
+impl Sync {
+    /// The working files and hashes from the last snapshot, and the paths
+    /// changed since, sorted. None if the snapshot can't be used: there is
+    /// none yet, it was taken under other settings or another monitor, or
+    /// the monitor lost events.
+    pub fn changes(&mut self) -> Option<(HashMap<String, FileHash>, Vec<String>)> {
+        let snapshot: Snapshot = serde_json::from_slice(&fs::read(scm_path(SNAPSHOT_FILE)).ok()?).ok()?;
+        if snapshot.monitor != self.state.id || self.state.overflow > snapshot.batch || snapshot.settings != settings() {
+            return None;
+        }
+        let dirty: Vec<String> = self.state.paths.iter().filter(|&(_, &batch)| batch > snapshot.batch).map(|(path, _)| path.clone()).collect();
+        self.current = dirty.is_empty();
+        Some((snapshot.files, dirty))
+    }
+
+    /// Saves the working files and hashes as up to date with this sync.
+    pub fn record(&self, files: &HashMap<String, FileHash>) -> Result<()> {
+        if self.current {
+            return Ok(());
+        }
+        let snapshot = Snapshot { monitor: self.state.id.clone(), batch: self.state.batch, settings: settings(), files: files.clone() };
+        write_atomic(SNAPSHOT_FILE, serde_json::to_string(&snapshot).unwrap())
+    }
+}
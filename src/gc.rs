@@ -0,0 +1,165 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use chrono::{DateTime, Duration, Utc};
+use tracing::{error, info};
+
+use crate::storage::storage;
+use crate::{
+    ancestors, config, exit, get_head, graph, index, list_versions, load_manifest, oplog, pending_merge, refs, scm_dir, stash, FileHash, FileMeta,
+    Result, ScmError, VersionId,
+};
+
+const GRACE_KEY: &str = "gc.grace_period";
+const DEFAULT_GRACE: &str = "14d";
+
+/// `scm gc [--grace <period>]`: deletes versions that no branch, tag, HEAD,
+/// pending merge, stash or recent oplog entry can reach, then every stored
+/// object only they used. Unreachable versions newer than the grace period
+/// (`gc.grace_period`, default 14 days) are kept, and only oplog entries
+/// within it count, so recent resets and rewrites can still be undone.
+pub fn do_gc(grace: Option<&str>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let grace_spec = match grace {
+        Some(grace) => grace.to_string(),
+        None => config::get(GRACE_KEY).unwrap_or_else(|| DEFAULT_GRACE.to_string()),
+    };
+    let grace = match parse_period(&grace_spec) {
+        Some(grace) => grace,
+        None => {
+            error!("Invalid grace period {:?}; use e.g. 14d, 2w, 12h, 30m or now.", grace_spec);
+            return;
+        }
+    };
+    let cutoff = Utc::now() - grace;
+    let before = dir_size(&scm_dir());
+
+    let mut roots: Vec<_> = match get_head() {
+        Ok(head) => head.into_iter().collect(),
+        Err(e) => return exit::report(e),
+    };
+    roots.extend(refs::list_branches().into_iter().map(|(_, id)| id));
+    roots.extend(refs::list_tags().into_iter().map(|(_, tag)| tag.version_id));
+    roots.extend(pending_merge());
+    let stashes = match stashes() {
+        Ok(stashes) => stashes,
+        Err(e) => return exit::report(e),
+    };
+    roots.extend(stashes.iter().filter_map(|entry| entry.base_version));
+    for entry in oplog::read_all() {
+        if parse_time(&entry.timestamp).is_some_and(|time| time >= cutoff) {
+            roots.extend(entry.old_head);
+            roots.push(entry.new_head);
+        }
+    }
+    let reachable = ancestors(&roots);
+
+    let (kept, doomed): (Vec<VersionId>, Vec<VersionId>) = list_versions().into_iter().partition(|id| {
+        reachable.contains(id) || graph::entry(*id).and_then(|e| parse_time(&e.timestamp)).is_some_and(|time| time >= cutoff)
+    });
+    // What is in use is worked out before anything is deleted, so that an
+    // unreadable manifest stops gc rather than losing what it refers to.
+    let live = match live_objects(&kept) {
+        Ok(live) => live,
+        Err(e) => return exit::report(e),
+    };
+    for &id in &doomed {
+        if let Err(e) = storage().remove_manifest(id) {
+            return exit::report(e);
+        }
+    }
+    let deleted = doomed.len();
+
+    let removed = match storage().prune_objects(&live) {
+        Ok(removed) => removed,
+        Err(e) => return exit::report(e),
+    };
+    let after = dir_size(&scm_dir());
+    info!("Deleted {} unreachable version(s) and {} unused object(s).", deleted, removed);
+    info!("Freed {} bytes.", before.saturating_sub(after));
+}
+
+/// Deletes every stored object that no remaining version, staged file or
+/// stash uses, returning how many went. Fails, deleting nothing, if any of
+/// them can't be read.
+pub fn remove_unused_objects() -> Result<usize> {
+    storage().prune_objects(&live_objects(&list_versions())?)
+}
+
+/// The stored objects that `versions`, the staged files and the stashes use.
+fn live_objects(versions: &[VersionId]) -> Result<HashSet<FileHash>> {
+    let mut live = HashSet::new();
+    let mut mark = |hash: FileHash, meta: Option<&FileMeta>| match meta.and_then(|m| m.chunks.as_ref()) {
+        Some(chunks) => live.extend(chunks.iter().cloned()),
+        None => {
+            live.insert(hash);
+        }
+    };
+    for &id in versions {
+        let manifest = load_manifest(id)?;
+        for (filename, hash) in manifest.files {
+            mark(hash, manifest.metadata.get(&filename));
+        }
+    }
+    // Content is stored when it is staged or stashed, before any version uses it.
+    for (filename, hash) in index::load()?.unwrap_or_default() {
+        if let Some(meta) = index::staged_meta(&filename)? {
+            mark(hash, Some(&meta));
+        }
+    }
+    for entry in stashes()? {
+        for (filename, hash) in entry.files {
+            if let Some(meta) = entry.metadata.get(&filename) {
+                mark(hash, Some(meta));
+            }
+        }
+    }
+    Ok(live)
+}
+
+/// Every stash, failing if one can't be read.
+fn stashes() -> Result<Vec<stash::StashEntry>> {
+    stash::list_indices()
+        .into_iter()
+        .map(|index| stash::load(index).ok_or_else(|| ScmError::IntegrityFailure(format!("stash@{{{}}} is unreadable.", index))))
+        .collect()
+}
+
+/// A duration like "14d", "2w", "12h", "30m", "45s" or "now".
+fn parse_period(spec: &str) -> Option<Duration> {
+    let spec = spec.trim();
+    if spec == "now" {
+        return Some(Duration::zero());
+    }
+    let (digits, unit) = spec.split_at(spec.len().checked_sub(1)?);
+    let amount: i64 = digits.parse().ok()?;
+    match unit {
+        "w" => Duration::try_weeks(amount),
+        "d" => Duration::try_days(amount),
+        "h" => Duration::try_hours(amount),
+        "m" => Duration::try_minutes(amount),
+        "s" => Duration::try_seconds(amount),
+        _ => None,
+    }
+}
+
+/// Parses a timestamp as recorded in manifests and the oplog.
+pub fn parse_time(timestamp: &str) -> Option<DateTime<Utc>> {
+    timestamp.parse().ok()
+}
+
+pub fn dir_size(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| dir_size(&entry.path()))
+            .sum(),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
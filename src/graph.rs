@@ -0,0 +1,103 @@
+use std::collections::{BTreeMap, HashSet};
+use tracing::debug;
+
+use crate::storage::storage;
+use crate::{context, crypt, list_versions, load_header, Manifest, VersionId};
+
+/// The commit graph: every version's parents and timestamp in one small
+/// file, `.scm/commit-graph`, so walking history (ancestry, `describe`,
+/// merge bases, `gc`) reads one file instead of a manifest header per
+/// version. One line per version: `<id> <parent or -> <merge parent or -> <timestamp>`.
+///
+/// It is a cache: every manifest write updates it (see `save_manifest`),
+/// versions it lacks are looked up in their headers and added, and entries
+/// for versions that no longer exist are dropped when it is loaded.
+const GRAPH_FILE: &str = "commit-graph";
+
+#[derive(Clone)]
+pub struct Entry {
+    pub parent: Option<VersionId>,
+    pub merge_parent: Option<VersionId>,
+    pub timestamp: String,
+}
+
+fn with_graph<T>(f: impl FnOnce(&mut BTreeMap<VersionId, Entry>, &mut bool) -> T) -> T {
+    // The graph and whether it changed since it was loaded.
+    let context = context::current();
+    let mut guard = context.graph.lock().unwrap();
+    let (graph, dirty) = guard.get_or_insert_with(load);
+    f(graph, dirty)
+}
+
+fn load() -> (BTreeMap<VersionId, Entry>, bool) {
+    let content = storage().read_file(GRAPH_FILE).ok().and_then(|sealed| crypt::open(sealed).ok().flatten()).and_then(|content| String::from_utf8(content).ok()).unwrap_or_default();
+    let mut graph: BTreeMap<VersionId, Entry> = content.lines().filter_map(parse_line).collect();
+    let existing: HashSet<VersionId> = list_versions().into_iter().collect();
+    let before = graph.len();
+    graph.retain(|id, _| existing.contains(id));
+    let dirty = graph.len() != before;
+    (graph, dirty)
+}
+
+fn parse_line(line: &str) -> Option<(VersionId, Entry)> {
+    let mut fields = line.splitn(4, ' ');
+    let id = fields.next()?.parse().ok()?;
+    let parse_parent = |field: &str| if field == "-" { Some(None) } else { field.parse().ok().map(Some) };
+    let parent = parse_parent(fields.next()?)?;
+    let merge_parent = parse_parent(fields.next()?)?;
+    let timestamp = fields.next()?.to_string();
+    Some((id, Entry { parent, merge_parent, timestamp }))
+}
+
+/// A version's entry, from its header if the graph doesn't have it yet.
+pub fn entry(id: VersionId) -> Option<Entry> {
+    if let Some(entry) = with_graph(|graph, _| graph.get(&id).cloned()) {
+        return Some(entry);
+    }
+    let header = load_header(id)?;
+    let entry = Entry { parent: header.parent, merge_parent: header.merge_parent, timestamp: header.timestamp };
+    with_graph(|graph, dirty| {
+        graph.insert(id, entry.clone());
+        *dirty = true;
+    });
+    Some(entry)
+}
+
+/// Records a manifest just written.
+pub fn update(manifest: &Manifest) {
+    let entry = Entry { parent: manifest.parent, merge_parent: manifest.merge_parent, timestamp: manifest.timestamp.clone() };
+    with_graph(|graph, dirty| {
+        graph.insert(manifest.version_id, entry);
+        *dirty = true;
+    });
+}
+
+/// Writes the graph back if it changed and unloads it. Called once a
+/// command is done.
+pub fn save() {
+    let loaded = context::current().graph.lock().unwrap().take();
+    let (mut graph, dirty) = match loaded {
+        Some(loaded) => loaded,
+        None => return,
+    };
+    if !dirty {
+        return;
+    }
+    if !storage().exists() {
+        return;
+    }
+    // Versions deleted by this command go too.
+    let existing: HashSet<VersionId> = list_versions().into_iter().collect();
+    graph.retain(|id, _| existing.contains(id));
+    let mut content = String::new();
+    for (id, entry) in graph.iter() {
+        let field = |parent: Option<VersionId>| parent.map_or("-".to_string(), |p| p.to_string());
+        content.push_str(&format!("{} {} {} {}\n", id, field(entry.parent), field(entry.merge_parent), entry.timestamp));
+    }
+    // Only a cache: one that can't be sealed or written is rebuilt next time.
+    let Ok(sealed) = crypt::seal(content.into_bytes()) else { return };
+    let partial = format!("{}.partial", GRAPH_FILE);
+    if let Err(e) = storage().write_file(&partial, &sealed).and_then(|()| storage().rename_file(&partial, GRAPH_FILE)) {
+        debug!("Cannot write the commit graph: {}", e);
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use regex::RegexBuilder;
+use serde::Serialize;
+use tracing::error;
+
+use crate::attributes::{self, Attributes};
+use crate::cli::GrepArgs;
+use crate::{diff, exit, json, list_versions, load_manifest, read_stored, resolve_version, scm_dir, working_files, Result, ScmError, VersionId};
+
+pub fn do_grep(args: &GrepArgs) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let files_only = args.files_with_matches;
+    let regex = match RegexBuilder::new(&args.pattern).case_insensitive(args.ignore_case).build() {
+        Ok(regex) => regex,
+        Err(e) => {
+            error!("Invalid pattern: {}", e);
+            return;
+        }
+    };
+    let attributes = attributes::current();
+
+    let versions = if args.all {
+        list_versions()
+    } else if let Some(range) = &args.range {
+        match parse_range(range) {
+            Ok(Some(versions)) => versions,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Version range {} not found.", range);
+                return;
+            }
+        }
+    } else {
+        // Working tree only.
+        let files = match working_files() {
+            Ok(files) => files,
+            Err(e) => return exit::report(e),
+        };
+        for (filename, path) in files {
+            match fs::read(&path).map_err(ScmError::io(format!("Cannot read {}", filename))) {
+                Ok(content) => search(&regex, &attributes, None, &filename, &content, files_only),
+                Err(e) => return exit::report(e),
+            }
+        }
+        return;
+    };
+
+    for id in versions {
+        let manifest = match load_manifest(id) {
+            Ok(manifest) => manifest,
+            Err(_) => continue,
+        };
+        let mut filenames: Vec<&String> = manifest.files.keys().collect();
+        filenames.sort();
+        for filename in filenames {
+            let content = match read_stored(id, filename, &manifest.files[filename]) {
+                Ok(content) => content,
+                Err(e) => return exit::report(e),
+            };
+            search(&regex, &attributes, Some(id), filename, &content, files_only);
+        }
+    }
+}
+
+/// A single version, or every existing version in `from..to` (inclusive).
+pub fn parse_range(spec: &str) -> Result<Option<Vec<VersionId>>> {
+    match spec.split_once("..") {
+        Some((from, to)) => {
+            let (Some(from), Some(to)) = (resolve_version(from)?, resolve_version(to)?) else { return Ok(None) };
+            Ok(Some(list_versions().into_iter().filter(|&id| id >= from && id <= to).collect()))
+        }
+        None => Ok(resolve_version(spec)?.map(|id| vec![id])),
+    }
+}
+
+/// A match as `--json` prints it. `version` is null in the working tree;
+/// with `-l` there is no `line` or `text`.
+#[derive(Serialize)]
+struct Match<'a> {
+    version: Option<VersionId>,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+}
+
+/// Prints matching lines as `[version:]file:line:text`, skipping binary content.
+fn search(regex: &regex::Regex, attributes: &Attributes, version: Option<VersionId>, filename: &str, content: &[u8], files_only: bool) {
+    let text = match diff::text_for(attributes, filename, content) {
+        Some(text) => text,
+        None => return,
+    };
+    let prefix = version.map(|id| format!("{}:", id)).unwrap_or_default();
+
+    for (number, line) in text.lines().enumerate() {
+        if regex.is_match(line) {
+            if files_only {
+                if json::enabled() {
+                    json::print(&Match { version, path: filename, line: None, text: None });
+                } else {
+                    println!("{}{}", prefix, filename);
+                }
+                return;
+            }
+            if json::enabled() {
+                json::print(&Match { version, path: filename, line: Some(number + 1), text: Some(line) });
+            } else {
+                println!("{}{}:{}:{}", prefix, filename, number + 1, line);
+            }
+        }
+    }
+}
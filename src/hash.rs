@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use sha2::{Digest, Sha256};
+
+use crate::{config, context, FileHash};
+
+/// The content hash a repository uses for file contents, chunks and
+/// integrity checks, chosen at `init` with `--hash` and recorded as
+/// `core.hash`. Repositories without the setting predate the choice and use
+/// SHA-256. Both algorithms give 64 hex digits.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Blake3,
+}
+
+pub const NAMES: &[&str] = &["sha256", "blake3"];
+
+/// Files are read into the hasher this many bytes at a time.
+const BLOCK_SIZE: usize = 1024 * 1024;
+/// With `core.mmap = "true"`, files at least this big are hashed straight
+/// from a memory map instead. Whether that is faster depends on the OS and
+/// disk, so it is off by default.
+const MMAP_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Algorithm> {
+        match name {
+            "sha256" => Some(Algorithm::Sha256),
+            "blake3" => Some(Algorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+/// The repository's algorithm, read from the config on first use.
+pub fn current() -> Algorithm {
+    *context::current().hash.get_or_init(|| match config::get("core.hash") {
+        Some(name) => Algorithm::parse(&name).unwrap_or_else(|| panic!("Unsupported hash algorithm {:?} in .scm/config", name)),
+        None => Algorithm::Sha256,
+    })
+}
+
+/// An incremental hash in the repository's algorithm. Implements `Write`
+/// so data can be streamed into it with `io::copy`.
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        match current() {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    pub fn finish(self) -> FileHash {
+        FileHash::from_digest(match self {
+            Hasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        })
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes a file's content, streaming it in large blocks or, for big files
+/// with `core.mmap = "true"`, through a memory map.
+pub fn file(path: &Path) -> io::Result<FileHash> {
+    let mut file = File::open(path)?;
+    let mut hasher = Hasher::new();
+    if file.metadata()?.len() >= MMAP_MIN_SIZE && config::get("core.mmap").as_deref() == Some("true") {
+        // SAFETY: the map is only read, and dropped before returning. A file
+        // changed underneath it hashes to something unrecorded, as it
+        // would with plain reads.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        hasher.update(&map);
+        return Ok(hasher.finish());
+    }
+    let mut buffer = vec![0; BLOCK_SIZE];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finish()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
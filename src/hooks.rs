@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, error};
+
+use crate::scm_dir;
+
+pub const HOOKS_DIR: &str = "hooks";
+
+fn hook_path(name: &str) -> PathBuf {
+    scm_dir().join(HOOKS_DIR).join(name)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().map(|m| m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Runs `.scm/hooks/<name>` if it exists and is executable, passing `args`
+/// on the command line. Returns false only when the hook ran and failed;
+/// a missing hook counts as success.
+pub fn run(name: &str, args: &[String]) -> bool {
+    let path = hook_path(name);
+    if !path.is_file() || !is_executable(&path) {
+        return true;
+    }
+
+    debug!("Running hook {}", name);
+    match Command::new(&path).args(args).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            error!("Hook {} failed ({}).", name, status);
+            false
+        }
+        Err(e) => {
+            error!("Hook {} could not be run: {}", name, e);
+            false
+        }
+    }
+}
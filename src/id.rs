@@ -0,0 +1,177 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The identifiers the data model is built on. Each is checked wherever it
+/// is read, from a manifest, a ref or the command line, so one that got
+/// that far is well formed; each is written back exactly as it was read.
+///
+/// A version's id counts up from 1, in order of creation. "No version", as
+/// HEAD is before the first commit, is `None`, never 0. In files and on the
+/// command line an id is a plain decimal number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VersionId(NonZeroUsize);
+
+impl VersionId {
+    pub const FIRST: VersionId = VersionId(NonZeroUsize::MIN);
+
+    /// None for 0, which is no version.
+    pub fn new(id: usize) -> Option<VersionId> {
+        NonZeroUsize::new(id).map(VersionId)
+    }
+
+    pub fn get(self) -> usize {
+        self.0.get()
+    }
+
+    /// The id the version after this one gets.
+    pub fn next(self) -> VersionId {
+        VersionId(self.0.checked_add(1).expect("version ids exhausted"))
+    }
+}
+
+impl fmt::Display for VersionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Debug for VersionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for VersionId {
+    type Err = String;
+
+    /// Only digits, as written: no sign, space or leading zero.
+    fn from_str(s: &str) -> Result<VersionId, String> {
+        let invalid = || format!("{:?} is not a version id", s);
+        if s.is_empty() || s.starts_with('0') || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        s.parse().map(VersionId).map_err(|_| invalid())
+    }
+}
+
+impl Serialize for VersionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.get() as u64)
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<VersionId, D::Error> {
+        let id = usize::deserialize(deserializer)?;
+        VersionId::new(id).ok_or_else(|| serde::de::Error::custom("version id 0"))
+    }
+}
+
+/// For `#[serde(with)]` on an `Option<VersionId>` in a file that has always
+/// written no version as 0, such as the oplog and stash entries.
+pub(crate) mod or_zero {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use super::VersionId;
+
+    pub fn serialize<S: Serializer>(id: &Option<VersionId>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(id.map_or(0, VersionId::get) as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<VersionId>, D::Error> {
+        usize::deserialize(deserializer).map(VersionId::new)
+    }
+}
+
+/// Names content by its hash in the repository's algorithm (see `hash`): 64
+/// lowercase hex digits. Every file, chunk and object has one.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileHash(String);
+
+const HASH_LEN: usize = 64;
+
+impl FileHash {
+    /// A hash as `hash::Hasher` writes it, trusted to be well formed.
+    pub(crate) fn from_digest(hex: String) -> FileHash {
+        debug_assert!(is_hash(&hex), "not a hash: {:?}", hex);
+        FileHash(hex)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn is_hash(s: &str) -> bool {
+    s.len() == HASH_LEN && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+impl Deref for FileHash {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for FileHash {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for FileHash {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for FileHash {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl AsRef<str> for FileHash {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for FileHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for FileHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for FileHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FileHash, String> {
+        match is_hash(s) {
+            true => Ok(FileHash(s.to_string())),
+            false => Err(format!("{:?} is not a content hash", s)),
+        }
+    }
+}
+
+impl Serialize for FileHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FileHash, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,153 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use globset::{GlobBuilder, GlobMatcher};
+use tracing::warn;
+
+use crate::{config, working_path, SCM_DIR};
+
+pub const IGNORE_FILE: &str = ".scmignore";
+/// Config key holding extra patterns, separated by commas.
+const CONFIG_KEY: &str = "ignore.patterns";
+/// Directories never snapshotted, wherever they appear.
+const ALWAYS_IGNORED: &[&str] = &[SCM_DIR, ".git"];
+
+/// Set by `--include-hidden` for the current invocation.
+static INCLUDE_HIDDEN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_include_hidden() {
+    INCLUDE_HIDDEN.store(true, Ordering::Relaxed);
+}
+
+/// Whether new dotfiles are picked up, either for this run or through the
+/// `hidden = "include"` config key. `.scmignore` is always picked up.
+pub fn include_hidden() -> bool {
+    INCLUDE_HIDDEN.load(Ordering::Relaxed) || config::get("hidden").as_deref() == Some("include")
+}
+
+/// Whether a path's final component is a dotfile or dot-directory.
+pub fn is_hidden(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.starts_with('.') && path != IGNORE_FILE
+}
+
+/// One line of an ignore list.
+struct Rule {
+    matcher: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+}
+
+/// Decides which paths are left out of snapshots.
+///
+/// Patterns follow gitignore conventions: a pattern without a `/` matches a
+/// name at any depth, a leading `/` anchors it to the repository root, a
+/// trailing `/` restricts it to directories, and a leading `!` re-includes
+/// what an earlier pattern excluded. Rules are read from, in order:
+///
+/// 1. the user's global ignore file (see `global_ignore_file`),
+/// 2. the repository's `ignore.patterns` config key,
+/// 3. the repository's `.scmignore`,
+///
+/// and the last matching rule wins, so a repository can re-include what the
+/// global file excludes. The `.scm` and `.git` directories are always ignored.
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    pub fn load() -> Self {
+        let mut rules = Vec::new();
+        if let Some(path) = global_ignore_file() {
+            let content = fs::read_to_string(&path).unwrap_or_default();
+            let source = path.to_string_lossy();
+            for line in content.lines() {
+                push_rule(&mut rules, line.trim(), &source);
+            }
+        }
+        if let Some(patterns) = config::get(CONFIG_KEY) {
+            for pattern in patterns.split(',') {
+                push_rule(&mut rules, pattern.trim(), CONFIG_KEY);
+            }
+        }
+        let content = fs::read_to_string(working_path(IGNORE_FILE)).unwrap_or_default();
+        for line in content.lines() {
+            push_rule(&mut rules, line.trim(), IGNORE_FILE);
+        }
+        IgnoreRules { rules }
+    }
+
+    /// Rules from a plain list of patterns, such as the sparse-checkout set.
+    pub fn from_patterns(patterns: &[String], source: &str) -> Self {
+        let mut rules = Vec::new();
+        for pattern in patterns {
+            push_rule(&mut rules, pattern.trim(), source);
+        }
+        IgnoreRules { rules }
+    }
+
+    /// Whether a path relative to the repository root (with `/` separators)
+    /// is ignored. As with git, nothing inside an ignored directory can be
+    /// re-included by a negated pattern.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        if path.split('/').any(|part| ALWAYS_IGNORED.contains(&part)) {
+            return true;
+        }
+        self.matches(path, is_dir)
+    }
+
+    /// Whether the rules select a path or any directory above it.
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let parts: Vec<&str> = path.split('/').collect();
+        for end in 1..=parts.len() {
+            let prefix = parts[..end].join("/");
+            let prefix_is_dir = end < parts.len() || is_dir;
+            if self.excludes(&prefix, prefix_is_dir) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Applies the rules to a single path; the last one that matches decides.
+    fn excludes(&self, path: &str, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.matcher.is_match(path))
+            .is_some_and(|rule| !rule.negated)
+    }
+}
+
+/// The ignore file shared by all repositories: `$XDG_CONFIG_HOME/scm/ignore`,
+/// falling back to `~/.config/scm/ignore`.
+pub fn global_ignore_file() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?).join(".config"),
+    };
+    Some(config_home.join("scm").join("ignore"))
+}
+
+fn push_rule(rules: &mut Vec<Rule>, line: &str, source: &str) {
+    if line.is_empty() || line.starts_with('#') { return; }
+
+    let (pattern, negated) = match line.strip_prefix('!') {
+        Some(pattern) => (pattern, true),
+        None => (line, false),
+    };
+    let (pattern, dir_only) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let anchored = match pattern.strip_prefix('/') {
+        Some(rest) => rest.to_string(),
+        None if pattern.contains('/') => pattern.to_string(),
+        None => format!("**/{}", pattern),
+    };
+    match GlobBuilder::new(&anchored).literal_separator(true).build() {
+        Ok(glob) => rules.push(Rule { matcher: glob.compile_matcher(), negated, dir_only }),
+        Err(_) => warn!("skipping invalid pattern in {}: {}", source, line),
+    }
+}
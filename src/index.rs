@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::storage::{self, storage};
+use crate::{crypt, ensure_parent_dir, remove_empty_parents, scm_dir, FileHash, FileMeta, Result, ScmError};
+
+/// The staged tree as JSON, filename -> hash. Absent when nothing differs
+/// from HEAD.
+const INDEX_FILE: &str = "index";
+/// The metadata of each staged file, sealed like a manifest (see `crypt`).
+/// Its content goes into the object store at `scm add` time.
+const STAGE_DIR: &str = "stage";
+/// Copies of staged file contents, as scm kept them before `STAGE_DIR`.
+const LEGACY_STAGED_DIR: &str = "staged";
+
+/// The staged tree, or None if nothing has been staged since the last commit.
+pub fn load() -> Result<Option<HashMap<String, FileHash>>> {
+    let Ok(content) = storage::read_to_string(INDEX_FILE) else {
+        return Ok(None);
+    };
+    serde_json::from_str(&content).map_err(|e| ScmError::IntegrityFailure(format!("The index is unreadable: {}.", e)))
+}
+
+pub fn save(entries: &HashMap<String, FileHash>) -> Result<()> {
+    let json = serde_json::to_string_pretty(entries).unwrap();
+    storage().write_file(INDEX_FILE, json.as_bytes()).map_err(ScmError::io("Cannot write the index"))
+}
+
+fn meta_file(filename: &str) -> String {
+    format!("{}/{}", STAGE_DIR, filename)
+}
+
+/// Where an older scm kept the staged copy of a file.
+pub fn legacy_path(filename: &str) -> PathBuf {
+    scm_dir().join(LEGACY_STAGED_DIR).join(filename)
+}
+
+/// Records `meta` for a file whose content has just been stored, so the
+/// next commit takes it from the object store.
+pub fn stage(filename: &str, meta: &FileMeta) -> Result<()> {
+    unstage(filename);
+    let sealed = crypt::seal(serde_json::to_vec(meta).unwrap())?;
+    storage().write_file(&meta_file(filename), &sealed).map_err(ScmError::io(format!("Cannot stage {}", filename)))
+}
+
+/// The metadata `stage` recorded for a file, or None if its staged content
+/// is HEAD's (or, from an older scm, a copy at `legacy_path`).
+pub fn staged_meta(filename: &str) -> Result<Option<FileMeta>> {
+    let Ok(sealed) = storage().read_file(&meta_file(filename)) else {
+        return Ok(None);
+    };
+    let unreadable = || ScmError::IntegrityFailure(format!("The staged entry for {} is unreadable.", filename));
+    let json = crypt::open(sealed)?.ok_or_else(unreadable)?;
+    serde_json::from_slice(&json).map(Some).map_err(|_| unreadable())
+}
+
+/// Whether the file has content of its own staged, rather than HEAD's.
+pub fn has_staged_content(filename: &str) -> bool {
+    storage().read_file(&meta_file(filename)).is_ok() || legacy_path(filename).exists()
+}
+
+/// Forgets the content staged for a file, leaving its index entry alone.
+pub fn unstage(filename: &str) {
+    let _ = storage().remove_file(&meta_file(filename));
+    let _ = fs::remove_file(legacy_path(filename));
+}
+
+/// Moves the content staged for `src` to `dst`.
+pub fn rename_staged(src: &str, dst: &str) -> Result<()> {
+    if storage().read_file(&meta_file(src)).is_ok() {
+        storage().rename_file(&meta_file(src), &meta_file(dst)).map_err(ScmError::io(format!("Cannot move the staged entry for {}", src)))?;
+    }
+    let (from, to) = (legacy_path(src), legacy_path(dst));
+    if from.exists() {
+        ensure_parent_dir(&to)?;
+        fs::rename(&from, &to).map_err(ScmError::io(format!("Cannot move the staged entry for {}", src)))?;
+        remove_empty_parents(&from);
+    }
+    Ok(())
+}
+
+/// Drops everything staged, so the index matches HEAD again.
+pub fn clear() {
+    let _ = storage().remove_file(INDEX_FILE);
+    let _ = storage().remove_file(STAGE_DIR);
+    let _ = fs::remove_dir_all(scm_dir().join(LEGACY_STAGED_DIR));
+}
@@ -0,0 +1,27 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::Serialize;
+
+/// `--json`: what a command reports is printed as JSON instead of text, one
+/// object per line, so a list comes out as a stream of objects (NDJSON).
+/// Messages (see `logging`) become `{"level": ..., "message": ...}` lines,
+/// errors and warnings on stderr, and color and progress bars are off, so
+/// every line of output parses.
+/// Field names are part of the interface: add fields, don't rename them.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Prints `value` as one line of JSON.
+pub fn print<T: Serialize>(value: &T) {
+    println!("{}", line(value));
+}
+
+pub fn line<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("Failed to serialize output")
+}
@@ -0,0 +1,63 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use tracing::warn;
+
+use crate::{config, scm_dir, FileHash, Result, ScmError};
+
+/// Large files are those above the threshold. They are stored as chunks
+/// (see `chunk`); versions from before that keep them whole in a
+/// content-addressed store: `.scm/large/<first two hash chars>/<rest of hash>`.
+const LARGE_DIR: &str = "large";
+const THRESHOLD_KEY: &str = "large_file.threshold";
+const DEFAULT_THRESHOLD: u64 = 100 * 1024 * 1024;
+
+/// Size in bytes above which files go to the large-object store. Set with
+/// e.g. `large_file.threshold = "50M"`; K, M and G suffixes are binary.
+pub fn threshold() -> u64 {
+    match config::get(THRESHOLD_KEY) {
+        Some(value) => parse_size(&value).unwrap_or_else(|| {
+            warn!("invalid {} {:?}; using the default.", THRESHOLD_KEY, value);
+            DEFAULT_THRESHOLD
+        }),
+        None => DEFAULT_THRESHOLD,
+    }
+}
+
+pub fn object_path(hash: &str) -> PathBuf {
+    let (dir, rest) = hash.split_at(2.min(hash.len()));
+    scm_dir().join(LARGE_DIR).join(dir).join(rest)
+}
+
+/// Deletes every stored large file whose hash isn't in `live`, returning how many went.
+pub fn prune(live: &HashSet<FileHash>) -> Result<usize> {
+    let mut removed = 0;
+    let dirs = match fs::read_dir(scm_dir().join(LARGE_DIR)) {
+        Ok(dirs) => dirs,
+        Err(_) => return Ok(0),
+    };
+    for dir in dirs.filter_map(|entry| entry.ok()) {
+        let prefix = dir.file_name().to_string_lossy().to_string();
+        for entry in fs::read_dir(dir.path()).into_iter().flatten().filter_map(|entry| entry.ok()) {
+            let hash = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if !live.contains(hash.as_str()) {
+                fs::remove_file(entry.path()).map_err(ScmError::io(format!("Cannot remove {}", entry.path().display())))?;
+                removed += 1;
+            }
+        }
+        let _ = fs::remove_dir(dir.path());
+    }
+    Ok(removed)
+}
+
+/// Parses a size like "512", "64K" or "50M"; suffixes are binary.
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1024),
+        'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
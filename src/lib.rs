@@ -0,0 +1,3838 @@
+//! scm, a small version control system, as a library. `Repository` opens
+//! or creates a repository and commits, reverts, and reports status and
+//! history, returning what it finds instead of printing it, or an
+//! `ScmError` saying what went wrong. The `scm` command line is built on
+//! the same code. With the `async` feature, `asynch::Repository` offers the
+//! same for tokio applications.
+//!
+//! ```no_run
+//! let repo = scm::Repository::open(".").unwrap();
+//! let options = scm::CommitOptions::new().message("Nightly snapshot").all(true);
+//! let version = repo.commit(&options).unwrap();
+//! println!("Committed version {}.", version);
+//! for entry in repo.log().unwrap() {
+//!     println!("{} {}", entry.id, entry.message.unwrap_or_default());
+//! }
+//! ```
+
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use tracing::{debug, error, info, trace, warn};
+
+use clap::{CommandFactory, FromArgMatches};
+use cli::{Cli, Command};
+use progress::Progress;
+use storage::storage;
+pub use error::{Result, ScmError};
+pub use events::Observer;
+pub use id::{FileHash, VersionId};
+pub use repository::{Changes, CommitOptions, Repository, Status, Version};
+pub use storage::Storage;
+
+mod alias;
+mod archive;
+#[cfg(feature = "async")]
+pub mod asynch;
+mod attributes;
+mod bench;
+mod blame;
+mod bundle;
+mod chunk;
+mod cli;
+mod color;
+mod completions;
+mod config;
+mod context;
+mod crypt;
+mod delta;
+mod diff;
+mod eol;
+mod error;
+mod events;
+mod exit;
+mod filter;
+mod fsck;
+mod fsmonitor;
+mod gc;
+mod graph;
+mod grep;
+mod hash;
+mod hooks;
+mod id;
+mod ignore;
+mod index;
+mod json;
+mod large;
+mod link;
+mod logging;
+mod merge;
+mod migrate;
+mod objects;
+mod perms;
+mod oplog;
+mod pack;
+mod pager;
+mod porcelain;
+mod progress;
+mod prune;
+mod recover;
+mod refs;
+mod repack;
+mod repository;
+mod rewrite;
+mod sparse;
+mod split;
+mod stash;
+mod statcache;
+mod stats;
+mod storage;
+mod swap;
+mod symlink;
+mod tracking;
+mod verify;
+mod workdir;
+
+// --- Data Structures ---
+
+/// The manifest schema this build writes. Manifests without a
+/// `format_version` are format 1, which may lack `parent` (the previous
+/// existing version was implied) and `changes`; `load_manifest` fills both
+/// in, and `scm migrate` rewrites old manifests for good. From format 3
+/// every manifest has a `ManifestHeader` beside it.
+const MANIFEST_FORMAT: u32 = 3;
+
+/// What a version records: its details, and each file's content hash and
+/// metadata. A `Storage` backend keeps it as it likes, through serde.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    #[serde(default = "first_manifest_format")]
+    format_version: u32,
+    version_id: VersionId,
+    timestamp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author_email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<VersionId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    merge_parent: Option<VersionId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removed: Vec<String>, // Files intentionally deleted with `scm rm`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    renamed: HashMap<String, String>, // Old path -> new path, from `scm mv`
+    files: HashMap<String, FileHash>, // Filename -> content hash
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, FileMeta>, // Filename -> type, permissions, mtime
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dirs: Vec<String>, // Empty directories, with `empty_dirs = "keep"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    changes: Option<ChangeSet>, // Relative to `parent`; deleted files are tombstones
+}
+
+impl Manifest {
+    pub fn version_id(&self) -> VersionId {
+        self.version_id
+    }
+}
+
+/// A version's details without its file list, kept beside the manifest as
+/// `manifest.header` so that `log` and history walks stay fast however big
+/// the tree is. Versions without one (from before format 3) fall back to
+/// their full manifest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestHeader {
+    version_id: VersionId,
+    timestamp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author_email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<VersionId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    merge_parent: Option<VersionId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removed: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    renamed: HashMap<String, String>,
+    files: usize,
+    changes: ChangeCounts,
+}
+
+impl ManifestHeader {
+    pub fn version_id(&self) -> VersionId {
+        self.version_id
+    }
+}
+
+/// The size of a `ChangeSet`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct ChangeCounts {
+    added: usize,
+    modified: usize,
+    deleted: usize,
+}
+
+/// What a manifest entry is. Plain files are the default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum FileType {
+    #[default]
+    File,
+    Symlink,
+}
+
+impl FileType {
+    fn is_file(&self) -> bool {
+        *self == FileType::File
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct FileMeta {
+    #[serde(rename = "type", default, skip_serializing_if = "FileType::is_file")]
+    kind: FileType,
+    /// Where a symlink points. Its stored copy holds the same text, so the
+    /// entry's hash is the hash of the target path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    /// Permission bits (Unix only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    /// Modification time, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    /// Stored byte for byte as `chunks`, or, for versions from before
+    /// chunking, in the large-object store under the entry's hash; `size`
+    /// is recorded alongside.
+    #[serde(default, skip_serializing_if = "is_false")]
+    large: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// A large file's chunk hashes in order (see `chunk`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunks: Option<Vec<FileHash>>,
+}
+
+fn first_manifest_format() -> u32 {
+    1
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+const SCM_DIR: &str = ".scm";
+/// Names the directory to keep the repository in instead of `.scm`.
+const SCM_DIR_VAR: &str = "SCM_DIR";
+const COMMITS_DIR: &str = "commits";
+const HEAD_FILE: &str = "HEAD";
+const MERGE_HEAD_FILE: &str = "MERGE_HEAD";
+
+/// The repository: `.scm` in the working tree, or on the command line
+/// wherever `$SCM_DIR` says (see `context`).
+fn scm_dir() -> PathBuf {
+    context::current().scm_dir.clone()
+}
+
+/// The root of the working tree, which the names of its files are taken
+/// from: empty on the command line, which runs there (see `workdir`).
+fn root() -> PathBuf {
+    context::current().root.clone()
+}
+
+/// Where the working file `name` (from the root) is.
+fn working_path(name: &str) -> PathBuf {
+    root().join(name)
+}
+
+/// The `scm` command line, for the `scm` binary: runs the command `args`
+/// give (the program name first), printing what it does, and returns the
+/// status to exit with (see `exit`). It sets up the whole process for that,
+/// logging, the pager and signals included, so a program embedding scm
+/// uses `Repository` instead.
+pub fn run(args: Vec<String>) -> i32 {
+    completions::complete();
+    let entered = workdir::enter(&args);
+    let matches = match Cli::command().try_get_matches_from(alias::expand(args.clone())) {
+        Ok(matches) => matches,
+        Err(e) => return exit::usage(e),
+    };
+    let cli = match Cli::from_arg_matches(&matches) {
+        Ok(cli) => cli,
+        Err(e) => return exit::usage(e),
+    };
+    if cli.json {
+        json::enable();
+    }
+    color::init(if cli.json { color::When::Never } else { cli.color });
+    logging::init(cli.quiet, cli.verbose);
+    exit::init();
+    progress::enable();
+    if let Err(e) = entered {
+        error!("{}", e);
+        return exit::status();
+    }
+    let name = matches.subcommand_name().unwrap_or_default();
+    let porcelain = matches.subcommand().is_some_and(|(_, args)| args.try_get_one::<bool>("porcelain").ok().flatten() == Some(&true));
+    if !cli.no_pager && !porcelain {
+        pager::start(name);
+    }
+
+    oplog::set_command(name, &args[1..]);
+    crypt::allow_prompt();
+    run_command(name, cli.command);
+    pager::finish();
+    exit::status()
+}
+
+/// Runs the command called `name`, once `run` has set up the output.
+fn run_command(name: &str, command: Command) {
+    // A wrong passphrase stops the command before it reads anything, rather
+    // than leaving what it couldn't decrypt out of a listing.
+    if !KEYLESS.contains(&name)
+        && scm_dir().exists()
+        && let Err(e) = crypt::ensure_unlocked()
+    {
+        return exit::report(e);
+    }
+    // An interrupted commit or revert is dealt with before anything else
+    // moves HEAD or rewrites history.
+    if RECOVER_FIRST.contains(&name) && scm_dir().exists() && recover::report_pending() {
+        return;
+    }
+    match command {
+        Command::Init(args) => do_init(&args),
+        Command::Clone { source, destination } => do_clone(&workdir::resolve(source), &workdir::resolve(destination)),
+        Command::Add { include_hidden, paths } => do_add(include_hidden, &paths),
+        Command::Commit(args) => do_commit(&args),
+        Command::Revert { version, dry_run, force } => do_revert(version.as_deref(), dry_run, force),
+        Command::Checkout { version } => do_checkout(&version),
+        Command::Redo => do_redo(),
+        Command::Tag(args) => do_tag(&args),
+        Command::Describe { version } => do_describe(version.as_deref()),
+        Command::Branch(args) => do_branch(&args),
+        Command::Switch { create, branch } => do_switch(create, &branch),
+        Command::Merge { branch, abort: _ } => do_merge(branch.as_deref()),
+        Command::Stash(args) => do_stash(&args),
+        Command::Show { version, diff } => do_show(version.as_deref(), diff),
+        Command::Blame { file, version } => blame::do_blame(&file, version.as_deref()),
+        Command::Grep(args) => grep::do_grep(&args),
+        Command::Reset { soft: _, hard, prune, version } => do_reset(hard, prune, &version),
+        Command::CherryPick { version } => do_cherry_pick(&version),
+        Command::Rewrite { base, todo } => rewrite::do_rewrite(&base, todo.as_deref()),
+        Command::Rm { cached, files } => do_rm(cached, &files),
+        Command::Mv { source, destination } => do_mv(&source, &destination),
+        Command::Restore { files, from } => do_restore(&files, from.as_deref()),
+        Command::Clean { force, dry_run: _, exclude } => do_clean(force, &exclude),
+        Command::Sparse { command } => sparse::do_sparse(&command),
+        Command::Config { command } => config::do_config(&command),
+        Command::Completions { shell } => completions::do_completions(shell),
+        Command::Oplog { count } => do_oplog(count),
+        Command::Archive { version, output, prefix } => archive::do_archive(&version, &output, &prefix),
+        Command::Bundle { command } => bundle::do_bundle(&command),
+        Command::Repack { compress } => repack::do_repack(compress),
+        Command::Gc { grace } => gc::do_gc(grace.as_deref()),
+        Command::Prune(args) => prune::do_prune(&args),
+        Command::Filter { remove } => filter::do_filter(&remove),
+        Command::Split { subdir, destination } => split::do_split(&subdir, &workdir::resolve(destination)),
+        Command::Fsck => fsck::do_fsck(),
+        Command::Verify { range } => verify::do_verify(range.as_deref()),
+        Command::Stats => stats::do_stats(),
+        Command::Bench(args) => bench::do_bench(&args),
+        Command::Monitor { command } => fsmonitor::do_monitor(&command),
+        Command::Migrate => migrate::do_migrate(),
+        Command::Recover => recover::do_recover(),
+        Command::Log { count, file, porcelain } => do_log(count, file.as_deref(), porcelain),
+        Command::Status { no_cache, exit_code, porcelain } => do_status(no_cache, exit_code, porcelain),
+        Command::Diff { versions, name_only, porcelain } => do_diff(&versions, name_only, porcelain),
+    }
+    graph::save();
+}
+
+// --- Core Logic ---
+
+/// Compression methods `init` accepts; see `hash::NAMES` for hash algorithms.
+const SUPPORTED_COMPRESSION: &[&str] = &["none", "zstd"];
+
+fn do_init(args: &cli::InitArgs) {
+    let branch = &args.initial_branch;
+    if !refs::is_valid_ref_name(branch) {
+        error!("Invalid branch name: {}", branch);
+        return;
+    }
+
+    let cwd = env::current_dir().expect("Failed to read current dir");
+    let existing = match scm_dir() {
+        dir if dir == Path::new(SCM_DIR) => cwd.ancestors().find(|dir| dir.join(SCM_DIR).is_dir()).map(Path::to_path_buf),
+        dir => dir.is_dir().then(|| cwd.join(dir)),
+    };
+    if let Some(existing) = existing {
+        error!("Already inside an SCM repository at {}.", existing.display());
+        return;
+    }
+    let passphrase = match args.encrypt {
+        true => match crypt::new_passphrase() {
+            Ok(Some(passphrase)) => Some(passphrase),
+            Ok(None) => return,
+            Err(e) => return exit::report(e),
+        },
+        false => None,
+    };
+
+    let initialized = init_repo(branch)
+        .and_then(|()| config::set("core.hash", &args.hash))
+        .and_then(|()| config::set("core.compression", &args.compression))
+        .and_then(|()| passphrase.map_or(Ok(()), |passphrase| crypt::setup(&passphrase)));
+    if let Err(e) = initialized {
+        return exit::report(e);
+    }
+    info!("Initialized empty SCM repository on branch {} in {}.", branch, cwd.join(scm_dir()).display());
+}
+
+/// Stores under `.scm` whose files are never changed in place, so a clone
+/// can hardlink them instead of copying (see `link::share`).
+const CLONE_SHARED: &[&str] = &["objects", "large"];
+/// Commands that never need an encrypted repository's key.
+const KEYLESS: &[&str] = &["init", "config", "monitor", "completions"];
+/// Commands refused while an interrupted operation awaits `scm recover`.
+const RECOVER_FIRST: &[&str] =
+    &["commit", "revert", "checkout", "redo", "switch", "merge", "stash", "reset", "cherry-pick", "rewrite", "gc", "prune", "filter", "split"];
+
+/// Per-working-tree state under `.scm` that a clone shouldn't inherit.
+const CLONE_SKIP: &[&str] = &["stash", MERGE_HEAD_FILE, "removed", "renamed", "untracked", "index", "staged", "statcache", "fsmonitor", "fsmonitor.snapshot", "REWRITE_TODO", "OPERATION"];
+
+fn do_clone(src: &Path, dst: &Path) {
+    let src_scm = src.join(SCM_DIR);
+    if !src_scm.is_dir() {
+        error!("No SCM repository found in {}.", src.display());
+        return;
+    }
+    if dst.exists() && fs::read_dir(dst).map(|mut d| d.next().is_some()).unwrap_or(true) {
+        error!("Destination {} already exists and is not empty.", dst.display());
+        return;
+    }
+
+    info!("Cloning {} into {}...", src.display(), dst.display());
+    if let Err(e) = copy_repository(&src_scm, dst) {
+        return exit::report(e);
+    }
+
+    env::set_current_dir(dst).expect("Failed to enter destination dir");
+    let head = match get_head() {
+        Ok(Some(head)) => head,
+        Ok(None) => {
+            info!("Cloned an empty repository.");
+            return;
+        }
+        Err(e) => return exit::report(e),
+    };
+    if let Err(e) = restore_version(head) {
+        return exit::report(e);
+    }
+    info!("Clone complete. Checked out version {}.", head);
+}
+
+/// Gives `dst` (which must be missing or empty) a `.scm` with the history
+/// in `src_scm` but none of its working-tree state.
+fn copy_repository(src_scm: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst).map_err(ScmError::io(format!("Cannot create {}", dst.display())))?;
+    let dst_scm = dst.join(SCM_DIR);
+    fs::create_dir(&dst_scm).map_err(ScmError::io(format!("Cannot create {}", dst_scm.display())))?;
+    let unreadable = || ScmError::io(format!("Cannot read {}", src_scm.display()));
+    for entry in fs::read_dir(src_scm).map_err(unreadable())? {
+        let entry = entry.map_err(unreadable())?;
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        if CLONE_SKIP.contains(&name_str.as_ref()) { continue; }
+        copy_recursive(&entry.path(), &dst_scm.join(&name), CLONE_SHARED.contains(&name_str.as_ref()))?;
+    }
+    Ok(())
+}
+
+fn do_commit(args: &cli::CommitArgs) {
+    if args.include_hidden {
+        ignore::set_include_hidden();
+    }
+    if args.no_cache {
+        statcache::disable();
+    }
+    if let Some(jobs) = args.jobs {
+        set_jobs(jobs);
+    }
+    // Repeated -m flags become separate paragraphs, like git.
+    let mut options = CommitOptions::new().message(args.message.join("\n\n")).all(args.all).paths(&args.paths).allow_empty(args.allow_empty);
+    if let Some((name, email)) = &args.author {
+        options = options.author(name, email);
+    }
+
+    if args.dry_run && !scm_dir().exists() {
+        info!("No SCM repository found; a commit would create one.");
+        return;
+    }
+    if let Err(e) = init_repo_if_needed() {
+        return exit::report(e);
+    }
+    if args.interactive {
+        match pick_files() {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => return exit::report(e),
+        }
+    }
+    // Concluding a merge records the merged working tree as a whole.
+    options.all |= pending_merge().is_some();
+    if !options.all && options.paths.is_empty() && !args.amend && index::load().is_ok_and(|staged| staged.is_none()) {
+        info!("Nothing staged for commit. Use 'scm add <path>...' or 'scm commit -a'.");
+        return;
+    }
+    if args.dry_run {
+        if let Err(e) = plan_commit(&options, args.amend) {
+            exit::report(e);
+        }
+        return;
+    }
+    if let Err(e) = stage_commit_paths(&options) {
+        return exit::report(e);
+    }
+    if !hooks::run("pre-commit", &[]) {
+        error!("Commit aborted by pre-commit hook.");
+        return;
+    }
+    let committed = if args.amend { amend_commit(&options) } else { create_commit(&options).map(Some) };
+    let id = match committed {
+        Ok(Some(id)) => id,
+        Ok(None) => return,
+        Err(e) => return exit::report(e),
+    };
+    if let Err(e) = prune::after_commit() {
+        exit::report(e);
+    }
+    hooks::run("post-commit", &[id.to_string()]);
+}
+
+/// `commit --interactive`: asks which of the files that differ from HEAD to
+/// commit, offering those already staged ticked. The picked files are
+/// staged as `scm add` would stage them and the rest are taken back to
+/// HEAD's state in the index, so the commit records just the picked ones
+/// and their other changes stay in the working directory. False if nothing
+/// was picked or the user gave up.
+fn pick_files() -> Result<bool> {
+    if !io::stdin().is_terminal() || json::enabled() {
+        error!("Picking files needs a terminal; use 'scm add' and 'scm commit' instead.");
+        return Ok(false);
+    }
+    let head = get_head()?.map(load_manifest).transpose()?;
+    let head_files = head.as_ref().map(|m| m.files.clone()).unwrap_or_default();
+    let mut staged = staged_files()?;
+    let working: HashMap<String, PathBuf> = working_files()?.into_iter().collect();
+    let hashes = working_hashes()?;
+    let sparse = sparse::load();
+
+    let mut names: Vec<String> = head_files.keys().chain(staged.keys()).chain(hashes.keys()).cloned().collect::<HashSet<_>>().into_iter().collect();
+    names.retain(|name| {
+        let unchanged = head_files.get(name) == staged.get(name) && staged.get(name) == hashes.get(name);
+        !unchanged && (hashes.contains_key(name) || !sparse::skipped(&sparse, name))
+    });
+    names.sort();
+    if names.is_empty() {
+        info!("No changes to commit.");
+        return Ok(false);
+    }
+
+    let items: Vec<String> = names
+        .iter()
+        .map(|name| {
+            let marker = match (head_files.contains_key(name), hashes.contains_key(name)) {
+                (false, _) => "A",
+                (true, false) => "D",
+                (true, true) => "M",
+            };
+            format!("{}  {}", color::marker(marker), name)
+        })
+        .collect();
+    let ticked: Vec<bool> = names.iter().map(|name| staged.get(name) != head_files.get(name)).collect();
+    let picked = dialoguer::MultiSelect::new()
+        .with_prompt("Files to commit (space ticks, enter commits, esc gives up)")
+        .items(&items)
+        .defaults(&ticked)
+        .interact_opt()
+        .expect("Failed to read the selection");
+    let picked: HashSet<usize> = match picked {
+        Some(picked) if !picked.is_empty() => picked.into_iter().collect(),
+        _ => {
+            info!("Nothing picked; nothing was committed.");
+            return Ok(false);
+        }
+    };
+
+    for (i, name) in names.iter().enumerate() {
+        let state = if picked.contains(&i) { hashes.get(name) } else { head_files.get(name) };
+        if staged.get(name) == state {
+            continue;
+        }
+        index::unstage(name);
+        match state {
+            Some(hash) => {
+                // HEAD's content is already stored; only new content needs storing.
+                if picked.contains(&i) {
+                    stage_file(name, &working[name], hash, head.as_ref())?;
+                }
+                staged.insert(name.clone(), hash.clone());
+            }
+            None => {
+                staged.remove(name);
+            }
+        }
+    }
+    index::save(&staged)?;
+    Ok(true)
+}
+
+/// What `scm commit --dry-run --json` would record.
+#[derive(Serialize)]
+struct CommitPlan<'a> {
+    version: VersionId,
+    amend: bool,
+    parent: Option<VersionId>,
+    files: usize,
+    changes: ChangeReport<'a>,
+}
+
+/// `commit --dry-run`: lists what a commit (or amend) would record against
+/// its parent without storing or staging anything or running hooks. The
+/// stat cache is left alone as well, so every file is hashed.
+fn plan_commit(options: &CommitOptions, amend: bool) -> Result<()> {
+    statcache::disable();
+    let head = get_head()?;
+    let mut recorded: HashMap<String, String> = tracking::pending_renames().into_iter().collect();
+    let (id, parent) = if amend {
+        if pending_merge().is_some() {
+            error!("Cannot amend while a merge is in progress.");
+            return Ok(());
+        }
+        match head.and_then(load_header) {
+            Some(header) => {
+                recorded.extend(header.renamed);
+                (header.version_id, header.parent)
+            }
+            None => {
+                println!("Nothing to amend (no commits yet).");
+                return Ok(());
+            }
+        }
+    } else {
+        (next_version_id()?, head)
+    };
+
+    let mut staged = staged_files()?;
+    let paths: Vec<String> = options.paths.iter().map(|path| repo_path(path)).collect();
+    stage_paths(&mut staged, &paths, true)?;
+    let files: HashMap<String, FileHash> = if options.all {
+        let explicit = explicit_tracking();
+        working_hashes()?.into_iter().filter(|(filename, _)| !explicit || staged.contains_key(filename)).collect()
+    } else {
+        staged
+    };
+    let parent_files = version_files(parent)?;
+    let mut changes = compare_files(&parent_files, &files);
+    let renames = pair_renames(&mut changes, &parent_files, &files, &recorded);
+
+    if json::enabled() {
+        json::print(&CommitPlan { version: id, amend, parent, files: files.len(), changes: ChangeReport::new(&changes, &renames) });
+        return Ok(());
+    }
+    println!("{} version {} with {} file(s):", if amend { "Would amend" } else { "Would commit" }, id, files.len());
+    for (old, new) in &renames { println!("  {}  {} -> {}", color::marker("R"), old, new); }
+    for filename in &changes.added { println!("  {}  {}", color::marker("A"), filename); }
+    for filename in &changes.deleted { println!("  {}  {}", color::marker("D"), filename); }
+    for filename in &changes.modified { println!("  {}  {}", color::marker("M"), filename); }
+    if renames.is_empty() && changes.added.is_empty() && changes.deleted.is_empty() && changes.modified.is_empty() {
+        println!("  (no changes from version {})", parent.map_or(0, VersionId::get));
+    }
+    println!("Dry run; nothing was committed.");
+    Ok(())
+}
+
+/// Stages the paths `options` names, as `scm add` would, ready for
+/// `create_commit` or `amend_commit`.
+fn stage_commit_paths(options: &CommitOptions) -> Result<()> {
+    if options.paths.is_empty() {
+        return Ok(());
+    }
+    let paths: Vec<String> = options.paths.iter().map(|path| repo_path(path)).collect();
+    let mut staged = staged_files()?;
+    stage_paths(&mut staged, &paths, false)?;
+    index::save(&staged)
+}
+
+/// The whole working directory if `options.all` is set, otherwise the
+/// staged tree, stored and ready for a manifest.
+fn snapshot(options: &CommitOptions) -> Result<(HashMap<String, FileHash>, HashMap<String, FileMeta>)> {
+    if options.all { snapshot_working_files() } else { snapshot_index() }
+}
+
+/// Records a new version on top of HEAD as `options` say, refusing one that
+/// changes nothing unless they allow it. A pending merge (see `do_merge`)
+/// becomes the commit's second parent.
+fn create_commit(options: &CommitOptions) -> Result<VersionId> {
+    let current_head = get_head()?;
+    let new_id = next_version_id()?;
+    let pending = recover::begin(&recover::Operation::Commit { version: new_id, head: current_head })?;
+    info!("Committing version {}...", new_id);
+
+    let (file_map, metadata) = snapshot(options)?;
+    let removed = tracking::pending_removals().into_iter().filter(|f| !file_map.contains_key(f)).collect();
+    let renamed = tracking::pending_renames().into_iter().filter(|(_, new)| file_map.contains_key(new)).collect();
+
+    let merge_parent = pending_merge();
+    let head = current_head.map(load_manifest).transpose()?;
+    let mut changes = compare_files(&head.as_ref().map(|m| m.files.clone()).unwrap_or_default(), &file_map);
+    if let Some(head) = &head {
+        changes.modified.extend(type_changes(head, &file_map, &metadata));
+        changes.modified.sort();
+    }
+    if changes.is_empty() && merge_parent.is_none() && !options.allow_empty {
+        return Err(ScmError::NothingToCommit);
+    }
+    let (author_name, author_email) = match options.author.clone() {
+        Some((name, email)) => (Some(name), Some(email)),
+        None => (author_setting("SCM_AUTHOR_NAME", "author.name"), author_setting("SCM_AUTHOR_EMAIL", "author.email")),
+    };
+
+    let manifest = Manifest {
+        format_version: MANIFEST_FORMAT,
+        version_id: new_id,
+        timestamp: chrono::Utc::now().to_string(),
+        message: options.message.clone(),
+        author_name,
+        author_email,
+        parent: current_head,
+        merge_parent,
+        removed,
+        renamed,
+        files: file_map,
+        metadata,
+        dirs: snapshot_empty_dirs()?,
+        changes: Some(changes),
+    };
+
+    // The version appears in one step; see `recover`.
+    write_manifest(&manifest)?;
+    conclude_commit(new_id, current_head, merge_parent.is_some())?;
+    pending.end()?;
+    info!("Successfully committed version {}.", new_id);
+    Ok(new_id)
+}
+
+/// The rest of a commit once version `id` is in place, which can be run
+/// again after an interruption: clears the pending removals and renames,
+/// moves HEAD there unless it has left `head` already, and concludes a merge.
+fn conclude_commit(id: VersionId, head: Option<VersionId>, merged: bool) -> Result<()> {
+    tracking::clear_pending_removals();
+    tracking::clear_pending_renames();
+    if get_head()? == head {
+        set_head(id)?;
+    }
+    if merged {
+        let _ = storage().remove_file(MERGE_HEAD_FILE);
+    }
+    Ok(())
+}
+
+/// Replaces the HEAD version's snapshot with the one `options` say, keeping
+/// its id and parents. The message and author are kept unless new ones are
+/// given; an amend may change nothing.
+fn amend_commit(options: &CommitOptions) -> Result<Option<VersionId>> {
+    let old = match get_head()? {
+        Some(head) => load_manifest(head)?,
+        None => {
+            info!("Nothing to amend (no commits yet).");
+            return Ok(None);
+        }
+    };
+    if pending_merge().is_some() {
+        error!("Cannot amend while a merge is in progress.");
+        return Ok(None);
+    }
+
+    let head = old.version_id;
+    info!("Amending version {}...", head);
+    let pending = recover::begin(&recover::Operation::Amend { version: head })?;
+    let (files, metadata) = snapshot(options)?;
+
+    let mut removed = old.removed.clone();
+    removed.extend(tracking::pending_removals());
+    removed.retain(|f| !files.contains_key(f));
+    removed.sort();
+    removed.dedup();
+    let mut renamed = old.renamed.clone();
+    renamed.extend(tracking::pending_renames());
+    renamed.retain(|_, new| files.contains_key(new));
+    let changes = compare_files(&version_files(old.parent)?, &files);
+    let (author_name, author_email) = match options.author.clone() {
+        Some((name, email)) => (Some(name), Some(email)),
+        None => (old.author_name.clone(), old.author_email.clone()),
+    };
+
+    let manifest = Manifest {
+        version_id: head,
+        timestamp: chrono::Utc::now().to_string(),
+        message: options.message.clone().or(old.message),
+        author_name,
+        author_email,
+        removed,
+        renamed,
+        files,
+        metadata,
+        dirs: snapshot_empty_dirs()?,
+        changes: Some(changes),
+        ..old
+    };
+    // The new version replaces the old in one step; see `recover`.
+    write_manifest(&manifest)?;
+    conclude_amend();
+    pending.end()?;
+    info!("Successfully amended version {}.", head);
+    Ok(Some(head))
+}
+
+/// The rest of an amend once the new version is in place.
+fn conclude_amend() {
+    tracking::clear_pending_removals();
+    tracking::clear_pending_renames();
+    index::clear();
+}
+
+/// Stores every working file in the object store, returning the
+/// filename -> hash map and the metadata for a manifest. In
+/// explicit-tracking mode only files already in HEAD or staged are taken.
+fn snapshot_working_files() -> Result<(HashMap<String, FileHash>, HashMap<String, FileMeta>)> {
+    let mut file_map = HashMap::new();
+    let mut metadata = HashMap::new();
+    let tracked = explicit_tracking().then(staged_files).transpose()?;
+    let head = get_head()?.map(load_manifest).transpose()?;
+    let previous = head.as_ref().map(|m| m.files.clone()).unwrap_or_default();
+    let previous_meta = head.map(|m| m.metadata).unwrap_or_default();
+
+    let files: Vec<(String, PathBuf, FileHash)> = hashed_working_files(true)?
+        .into_iter()
+        .filter(|(filename, _, _)| tracked.as_ref().is_none_or(|tracked| tracked.contains_key(filename)))
+        .collect();
+    // Files are stored across the thread pool (see `--jobs`).
+    let progress = Progress::new("Storing", files.len(), true);
+    let stored: Result<Vec<(String, FileHash, FileMeta)>> = files
+        .into_par_iter()
+        .map(|(filename, path, hash)| {
+            let meta = store_file(&filename, &path, &hash, previous.get(&filename), previous_meta.get(&filename))?;
+            let size = fs::symlink_metadata(&path).map_or(0, |meta| meta.len());
+            events::file_copied(&filename, size);
+            progress.inc(size);
+            Ok((filename, hash, meta))
+        })
+        .collect();
+    progress.finish();
+    let stored = stored?;
+    for (filename, hash, meta) in stored {
+        metadata.insert(filename.clone(), meta);
+        file_map.insert(filename, hash);
+    }
+    // Files a sparse checkout left out are carried over unchanged.
+    let sparse = sparse::load();
+    let removals = tracking::pending_removals();
+    for (filename, hash) in previous {
+        if sparse::skipped(&sparse, &filename) && !removals.contains(&filename) {
+            if let Some(meta) = previous_meta.get(&filename) {
+                metadata.insert(filename.clone(), meta.clone());
+            }
+            file_map.insert(filename, hash);
+        }
+    }
+    statcache::save(&file_map);
+    Ok((file_map, metadata))
+}
+
+/// Stores one file's content under `hash` and returns its metadata for the
+/// manifest. A symlink is stored as an object holding its target; a large
+/// file (see `is_large`) is stored as chunks, reusing HEAD's chunk list if
+/// it is unchanged; text gets its line endings normalized (see `eol`) and
+/// may be stored as a delta against `previous`, the file's hash in HEAD,
+/// whose metadata there is `previous_meta`.
+fn store_file(filename: &str, src: &Path, hash: &str, previous: Option<&FileHash>, previous_meta: Option<&FileMeta>) -> Result<FileMeta> {
+    if symlink::is_symlink(src) {
+        let target = symlink::read_target(src)?;
+        storage().put_object(hash, target.as_bytes(), None)?;
+        return Ok(FileMeta { kind: FileType::Symlink, target: Some(target), ..Default::default() });
+    }
+    let unreadable = || ScmError::io(format!("Cannot read {}", src.display()));
+    let meta = fs::metadata(src).map_err(unreadable())?;
+    let large = is_large(filename, meta.len());
+    let mut chunks = None;
+    if large {
+        let unchanged = previous_meta.filter(|_| previous.is_some_and(|p| p == hash)).and_then(|m| m.chunks.clone());
+        chunks = Some(match unchanged {
+            Some(unchanged) => {
+                trace!("{} is already stored", filename);
+                unchanged
+            }
+            None => {
+                debug!("Storing {} ({} bytes) in chunks", filename, meta.len());
+                chunk::store(src)?
+            }
+        });
+    } else if storage().has(hash)? {
+        trace!("{} is already stored", filename);
+    } else {
+        debug!("Storing {} ({} bytes)", filename, meta.len());
+        let content = fs::read(src).map_err(unreadable())?;
+        match eol::policy().clean(filename, &content) {
+            Cow::Borrowed(_) => storage().put_object_from(src, hash, &content, previous.map(FileHash::as_str))?,
+            cleaned => storage().put_object(hash, &cleaned, previous.map(FileHash::as_str))?,
+        }
+    }
+    Ok(FileMeta {
+        mode: perms::mode(&meta),
+        mtime: perms::mtime(&meta),
+        large,
+        size: large.then_some(meta.len()),
+        chunks,
+        ..Default::default()
+    })
+}
+
+/// Writes a stored file back into the working tree, recreating symlinks and
+/// reapplying permissions and, unless `restore.mtime = "false"`, the
+/// recorded modification time.
+fn write_stored_file(id: VersionId, filename: &str, hash: &str, meta: Option<&FileMeta>, dest: &Path) -> Result<()> {
+    let unpacked =
+        meta.is_some_and(|m| m.large && m.chunks.is_none()).then(|| storage().legacy_copy(id, filename).or_else(|| storage().object_file(hash))).flatten();
+    let content = || try_read_stored(id, filename, hash)?.ok_or_else(|| ScmError::MissingObject { version: id, path: filename.to_string() });
+    write_file(filename, hash, meta, dest, unpacked.as_deref(), content)
+}
+
+/// Writes a file back as `write_stored_file` does, from wherever its
+/// content is kept: the chunks `meta` lists, `unpacked` for a large file
+/// from before chunking, or otherwise what `content` reads.
+fn write_file(filename: &str, hash: &str, meta: Option<&FileMeta>, dest: &Path, unpacked: Option<&Path>, content: impl FnOnce() -> Result<Vec<u8>>) -> Result<()> {
+    ensure_parent_dir(dest)?;
+    if let Some(FileMeta { kind: FileType::Symlink, target: Some(target), .. }) = meta {
+        return symlink::create(target, dest);
+    }
+
+    let unwritable = || ScmError::io(format!("Cannot write {}", dest.display()));
+    if symlink::is_symlink(dest) {
+        fs::remove_file(dest).map_err(unwritable())?;
+    }
+    if let Some(chunks) = meta.and_then(|m| m.chunks.as_ref()) {
+        chunk::restore(chunks, dest)?;
+    } else if let Some(unpacked) = unpacked {
+        fs::copy(unpacked, dest).map_err(unwritable())?;
+    } else {
+        let content = content()?;
+        match (eol::policy().smudge(filename, &content), storage().object_file(hash)) {
+            (Cow::Borrowed(_), Some(object)) => link::clone_file(&object, dest).map_err(unwritable())?,
+            (content, _) => fs::write(dest, content).map_err(unwritable())?,
+        }
+    }
+    if let Some(mode) = meta.and_then(|m| m.mode) {
+        perms::set_mode(dest, mode).map_err(ScmError::io(format!("Cannot set the permissions of {}", dest.display())))?;
+    }
+    if let Some(mtime) = meta.and_then(|m| m.mtime)
+        && config::get("restore.mtime").as_deref() != Some("false")
+    {
+        perms::set_mtime(dest, mtime).map_err(ScmError::io(format!("Cannot set the modification time of {}", dest.display())))?;
+    }
+    Ok(())
+}
+
+/// Stores the staged tree, returning its filename -> hash map and metadata.
+/// Files staged with `scm add` were stored then (see `stage_file`) and are
+/// checked; the rest are carried over from HEAD, whose objects are already
+/// stored.
+fn snapshot_index() -> Result<(HashMap<String, FileHash>, HashMap<String, FileMeta>)> {
+    let head = get_head()?;
+    let head_manifest = head.map(load_manifest).transpose()?;
+    let head_files = head_manifest.as_ref().map(|m| m.files.clone()).unwrap_or_default();
+    let head_metadata = head_manifest.map(|m| m.metadata).unwrap_or_default();
+    let head_by_hash: HashMap<&FileHash, &String> = head_files.iter().map(|(name, hash)| (hash, name)).collect();
+
+    let file_map = staged_files()?;
+    let entries: Vec<(&String, &FileHash)> = file_map.iter().collect();
+    let metadata = entries.into_par_iter().filter_map(|(filename, hash)| {
+        match index::staged_meta(filename) {
+            Ok(Some(meta)) => {
+                let checked = match &meta.chunks {
+                    Some(chunks) => chunk::verify(chunks, hash),
+                    None => storage().verify_object(hash),
+                };
+                if let Err(problem) = checked {
+                    return Some(Err(ScmError::IntegrityFailure(format!("The staged content of {} is damaged: {}.", filename, problem))));
+                }
+                return Some(Ok((filename.clone(), meta)));
+            }
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        // Staged by an older scm, which kept a copy.
+        let staged = index::legacy_path(filename);
+        if symlink::exists(&staged) {
+            match working_hash(filename, &staged) {
+                Ok(staged_hash) if staged_hash == *hash => {}
+                Ok(_) => return Some(Err(ScmError::IntegrityFailure(format!("Staged copy of {} corrupted!", filename)))),
+                Err(e) => return Some(Err(e)),
+            }
+            let meta = store_file(filename, &staged, hash, head_files.get(filename), head_metadata.get(filename));
+            events::file_copied(filename, fs::symlink_metadata(&staged).map_or(0, |meta| meta.len()));
+            Some(meta.map(|meta| (filename.clone(), meta)))
+        } else {
+            let (head, name) = match head.zip(head_by_hash.get(hash)) {
+                Some((head, name)) => (head, *name),
+                None => return Some(Err(ScmError::IntegrityFailure(format!("Staged copy of {} missing!", filename)))),
+            };
+            let meta = head_metadata.get(name);
+            let missing = Err(ScmError::MissingObject { version: head, path: name.clone() });
+            let stored = match meta.and_then(|m| m.chunks.as_ref()) {
+                Some(chunks) => chunks.iter().try_fold(true, |all, chunk| Ok(all && storage().has(chunk)?)),
+                None => storage().has(hash),
+            };
+            match stored {
+                Err(e) => return Some(Err(e)),
+                Ok(true) => {}
+                Ok(false) if meta.is_some_and(|m| m.chunks.is_some()) => return Some(missing),
+                // HEAD predates the object store and kept its own copy.
+                Ok(false) => {
+                    if let Err(e) = verify_stored(head, name, hash).and_then(|()| storage().put_object(hash, &read_stored(head, name, hash)?, None)) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+            meta.map(|meta| Ok((filename.clone(), meta.clone())))
+        }
+    }).collect::<Result<_>>()?;
+    Ok((file_map, metadata))
+}
+
+/// The empty directories to record in a new version: none unless
+/// `empty_dirs = "keep"` is configured.
+fn snapshot_empty_dirs() -> Result<Vec<String>> {
+    if config::get("empty_dirs").as_deref() == Some("keep") {
+        Ok(working_tree()?.1)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Whether `track = "explicit"` is configured, so that new files are only
+/// versioned once introduced with `scm add`, even by `commit -a`.
+fn explicit_tracking() -> bool {
+    config::get("track").as_deref() == Some("explicit")
+}
+
+/// Whether the working directory differs from version `head`.
+fn has_uncommitted_changes(head: Option<VersionId>) -> Result<bool> {
+    Ok(!compare_files(&version_files(head)?, &working_hashes()?).is_empty())
+}
+
+/// The tree the next plain `scm commit` would record: the index if anything
+/// is staged, otherwise HEAD's files.
+fn staged_files() -> Result<HashMap<String, FileHash>> {
+    match index::load()? {
+        Some(staged) => Ok(staged),
+        None => version_files(get_head()?),
+    }
+}
+
+fn do_add(include_hidden: bool, paths: &[String]) {
+    if let Err(e) = init_repo_if_needed() {
+        return exit::report(e);
+    }
+    if include_hidden {
+        ignore::set_include_hidden();
+    }
+
+    let paths: Vec<String> = paths.iter().map(|path| repo_path(path)).collect();
+    // Naming a file excluded with `scm rm --cached` starts tracking it again.
+    for path in &paths {
+        if tracking::is_untracked(path) && working_path(path).is_file() {
+            tracking::remove_untracked(path);
+        }
+    }
+
+    let staged = staged_files().and_then(|mut staged| stage_paths(&mut staged, &paths, false).map(|()| staged));
+    if let Err(e) = staged.and_then(|staged| index::save(&staged)) {
+        exit::report(e);
+    }
+}
+
+/// Brings the files in `staged` under each of `paths` (repository paths)
+/// in line with the working directory, storing changed content (see
+/// `stage_file`) unless this is a `dry_run`. Fails, staging nothing, if a
+/// path matches no file.
+fn stage_paths(staged: &mut HashMap<String, FileHash>, paths: &[String], dry_run: bool) -> Result<()> {
+    let working = working_files()?;
+    let sparse = sparse::load();
+    let head = if dry_run { None } else { get_head()?.map(load_manifest).transpose()? };
+    for path in paths {
+        let matched = working.iter().any(|(name, _)| path_matches(name, path))
+            || staged.keys().any(|name| path_matches(name, path));
+        if !matched {
+            return Err(ScmError::PathNotFound(path.clone()));
+        }
+    }
+
+    for path in paths {
+        for (name, file_path) in working.iter().filter(|(name, _)| path_matches(name, path)) {
+            let hash = working_hash(name, file_path)?;
+            if staged.get(name) != Some(&hash) || type_changed(name, file_path, head.as_ref())? {
+                if !dry_run {
+                    stage_file(name, file_path, &hash, head.as_ref())?;
+                    info!("Staged {}", name);
+                }
+                staged.insert(name.clone(), hash);
+            }
+        }
+        let gone: Vec<String> = staged
+            .keys()
+            .filter(|name| path_matches(name, path) && !working.iter().any(|(w, _)| w == *name) && !sparse::skipped(&sparse, name))
+            .cloned()
+            .collect();
+        for name in gone {
+            staged.remove(&name);
+            if !dry_run {
+                index::unstage(&name);
+                info!("Staged removal of {}", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a working file's type or permission bits differ from what is
+/// staged for it, or recorded in `head` (HEAD's manifest) if nothing is:
+/// a change, as after `chmod +x`, that its hash doesn't show.
+fn type_changed(filename: &str, path: &Path, head: Option<&Manifest>) -> Result<bool> {
+    let staged = match index::staged_meta(filename)? {
+        Some(meta) => meta,
+        None => head.and_then(|m| m.metadata.get(filename)).cloned().unwrap_or_default(),
+    };
+    if symlink::is_symlink(path) {
+        return Ok(staged.kind != FileType::Symlink);
+    }
+    let mode = fs::metadata(path).ok().and_then(|meta| perms::mode(&meta));
+    Ok(staged.kind != FileType::File || staged.mode.is_some_and(|staged| mode != Some(staged)))
+}
+
+/// Stores a working file's content under `hash` as `scm add` stages it, so
+/// that the commit only has to record it, and notes its metadata in the
+/// index. Deltas are taken against the file in `head`, HEAD's manifest.
+fn stage_file(filename: &str, path: &Path, hash: &FileHash, head: Option<&Manifest>) -> Result<()> {
+    let (previous, previous_meta) = (head.and_then(|m| m.files.get(filename)), head.and_then(|m| m.metadata.get(filename)));
+    let meta = store_file(filename, path, hash, previous, previous_meta)?;
+    events::file_copied(filename, fs::symlink_metadata(path).map_or(0, |meta| meta.len()));
+    index::stage(filename, &meta)
+}
+
+/// Whether `name` is `path` itself or lies inside it; an empty path (".")
+/// matches everything.
+fn path_matches(name: &str, path: &str) -> bool {
+    path.is_empty() || name == path || name.strip_prefix(path).is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn do_revert(spec: Option<&str>, dry_run: bool, force: bool) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let head = match get_head() {
+        Ok(head) => head,
+        Err(e) => return exit::report(e),
+    };
+
+    let target_id = match spec {
+        Some(spec) => match resolve_version(spec) {
+            Ok(Some(id)) => id,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Target version {} not found.", spec);
+                return;
+            }
+        },
+        None => {
+            let target_id = match head.and_then(parent_of) {
+                Some(id) => id,
+                None => {
+                    info!("Nothing to revert (already at initial state or empty).");
+                    return;
+                }
+            };
+            if !manifest_exists(target_id) {
+                error!("Target version {} not found.", target_id);
+                return;
+            }
+            target_id
+        }
+    };
+
+    // Everything that can be checked is, before anything changes.
+    if let Err(e) = load_manifest(target_id) {
+        return exit::report(e);
+    }
+    if dry_run {
+        if let Err(e) = plan_restore(target_id) {
+            exit::report(e);
+        }
+        return;
+    }
+    // Changes that are in neither version are lost for good, so a revert
+    // over them is confirmed, or with no one to ask, refused without -y.
+    let discarded = match discarded_changes(target_id) {
+        Ok(discarded) => discarded,
+        Err(e) => return exit::report(e),
+    };
+    if !discarded.is_empty() && !force {
+        let question = format!("This will discard changes to {} file(s), continue?", discarded.len());
+        match confirm(&question) {
+            Some(true) => {}
+            Some(false) => {
+                info!("Revert cancelled.");
+                return;
+            }
+            None => {
+                error!("Uncommitted changes to {} file(s) would be discarded; pass --force (-y) to revert anyway.", discarded.len());
+                return;
+            }
+        }
+    }
+    if !hooks::run("pre-revert", &[head.map_or(0, VersionId::get).to_string(), target_id.to_string()]) {
+        error!("Revert aborted by pre-revert hook.");
+        return;
+    }
+
+    info!("Reverting to version {}...", target_id);
+    if let Err(e) = move_head_to("revert", target_id) {
+        return exit::report(e);
+    }
+    info!("Revert complete. Now at version {}.", target_id);
+    hooks::run("post-revert", &[target_id.to_string()]);
+}
+
+/// Files in the working directory (those `restore_version` replaces) whose
+/// content matches neither HEAD nor version `target_id`: changes restoring
+/// it would discard.
+fn discarded_changes(target_id: VersionId) -> Result<Vec<String>> {
+    let tracked = explicit_tracking().then(staged_files).transpose()?;
+    let sparse = sparse::load();
+    let (head_files, target) = (version_files(get_head()?)?, version_files(Some(target_id))?);
+    let mut discarded: Vec<String> = working_hashes()?
+        .into_iter()
+        .filter(|(filename, _)| tracked.as_ref().is_none_or(|tracked| tracked.contains_key(filename)) && sparse::includes(&sparse, filename))
+        .filter(|(filename, hash)| head_files.get(filename) != Some(hash) && target.get(filename) != Some(hash))
+        .map(|(filename, _)| filename)
+        .collect();
+    discarded.sort();
+    Ok(discarded)
+}
+
+/// Asks a yes/no `question` on the terminal; None if there is no one to
+/// ask (stdin isn't a terminal, or output is `--json`).
+fn confirm(question: &str) -> Option<bool> {
+    if !io::stdin().is_terminal() || json::enabled() {
+        return None;
+    }
+    print!("{} [y/N] ", question);
+    io::stdout().flush().expect("Failed to write prompt");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).expect("Failed to read answer");
+    Some(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// What `scm revert --dry-run --json` would do to the working directory.
+#[derive(Serialize)]
+struct RestorePlan<'a> {
+    version: VersionId,
+    head: Option<VersionId>,
+    restore: &'a [String],
+    overwrite: &'a [String],
+    delete: &'a [String],
+}
+
+/// `revert --dry-run`: lists what restoring version `target_id` would do to
+/// the working directory (see `restore_version`) without touching it or the
+/// stat cache.
+fn plan_restore(target_id: VersionId) -> Result<()> {
+    statcache::disable();
+    let tracked = explicit_tracking().then(staged_files).transpose()?;
+    let sparse = sparse::load();
+    let working: HashMap<String, FileHash> = working_hashes()?
+        .into_iter()
+        .filter(|(filename, _)| tracked.as_ref().is_none_or(|tracked| tracked.contains_key(filename)) && sparse::includes(&sparse, filename))
+        .collect();
+    let target: HashMap<String, FileHash> = version_files(Some(target_id))?.into_iter().filter(|(filename, _)| sparse::includes(&sparse, filename)).collect();
+    let changes = compare_files(&working, &target);
+
+    if json::enabled() {
+        json::print(&RestorePlan {
+            version: target_id,
+            head: get_head()?,
+            restore: &changes.added,
+            overwrite: &changes.modified,
+            delete: &changes.deleted,
+        });
+        return Ok(());
+    }
+    println!("Reverting to version {}:", target_id);
+    for filename in &changes.added { println!("Would restore {}", filename); }
+    for filename in &changes.modified { println!("Would overwrite {}", filename); }
+    for filename in &changes.deleted { println!("Would delete {}", filename); }
+    if changes.added.is_empty() && changes.modified.is_empty() && changes.deleted.is_empty() {
+        println!("The working directory already matches it.");
+    }
+    println!("Dry run; HEAD stays at version {}.", get_head()?.map_or(0, VersionId::get));
+    Ok(())
+}
+
+fn do_checkout(spec: &str) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let target_id = match resolve_version(spec) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Target version {} not found.", spec);
+            return;
+        }
+    };
+
+    info!("Checking out version {}...", target_id);
+    if let Err(e) = move_head_to("checkout", target_id) {
+        return exit::report(e);
+    }
+    info!("Checkout complete. Now at version {}.", target_id);
+}
+
+fn do_redo() {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    // Follow the newest child of HEAD, so redo stays on the current line of history.
+    let current_head = match get_head() {
+        Ok(head) => head,
+        Err(e) => return exit::report(e),
+    };
+    let target_id = match list_versions().into_iter().rev().find(|&id| current_head.is_some() && parent_of(id) == current_head) {
+        Some(id) => id,
+        None => {
+            info!("Nothing to redo (already at the newest version).");
+            return;
+        }
+    };
+
+    info!("Rolling forward to version {}...", target_id);
+    if let Err(e) = move_head_to("redo", target_id) {
+        return exit::report(e);
+    }
+    info!("Redo complete. Now at version {}.", target_id);
+}
+
+/// Restores a version and moves HEAD there, for `command` (revert, checkout
+/// or redo), as an operation `scm recover` finishes if it is cut short. A
+/// restore that failed and put the working directory back leaves nothing
+/// to recover.
+fn move_head_to(command: &str, target_id: VersionId) -> Result<()> {
+    let pending = recover::begin(&recover::Operation::Restore { command: command.to_string(), version: target_id, applying: false })?;
+    if let Err(e) = restore_version(target_id) {
+        if !swap::is_pending() {
+            pending.end()?;
+        }
+        return Err(e);
+    }
+    set_head(target_id)?;
+    pending.end()
+}
+
+/// Verifies the stored copies of a version and replaces the working
+/// directory with them, all or nothing (see `swap`): on failure the working
+/// directory is as it was. Callers decide where HEAD goes afterwards.
+fn restore_version(target_id: VersionId) -> Result<()> {
+    restore_over(target_id, explicit_tracking().then(staged_files).transpose()?)
+}
+
+/// Restores a version as `restore_version` does, replacing only the
+/// working files in `tracked` if given and leaving any others alone.
+fn restore_over(target_id: VersionId, tracked: Option<HashMap<String, FileHash>>) -> Result<()> {
+    let manifest = load_manifest(target_id)?;
+
+    // Each phase runs across the thread pool.
+    let started = Instant::now();
+    let files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+
+    // Integrity Check
+    let progress = Progress::new("Verifying", files.len(), false);
+    let verified = files.par_iter().try_for_each(|(filename, recorded_hash)| {
+        verify_stored(target_id, filename, recorded_hash)?;
+        trace!("Verified {}", filename);
+        events::file_hashed(filename, recorded_hash);
+        progress.inc(0);
+        Ok(())
+    });
+    progress.finish();
+    verified?;
+    info!("Integrity check passed. Restoring files...");
+
+    // Write the new files aside, touching nothing in the working directory yet
+    swap::begin()?;
+    let sparse = sparse::load();
+    let included: Vec<&String> = files.iter().map(|(filename, _)| *filename).filter(|filename| sparse::includes(&sparse, filename)).collect();
+    let progress = Progress::new("Restoring", included.len(), true);
+    let restored = included
+        .par_iter()
+        .map(|filename| {
+            let staged = swap::staged_path(filename);
+            write_stored_file(target_id, filename, &manifest.files[*filename], manifest.metadata.get(*filename), &staged)?;
+            let size = fs::symlink_metadata(&staged).map_or(0, |meta| meta.len());
+            debug!("Restored {} ({} bytes)", filename, size);
+            events::file_restored(filename, size);
+            progress.inc(size);
+            Ok((1, size))
+        })
+        .try_reduce(|| (0, 0), |a, b| Ok((a.0 + b.0, a.1 + b.1)));
+    progress.finish();
+    let (count, bytes) = match restored {
+        Ok(totals) => totals,
+        Err(e) => {
+            swap::discard();
+            return Err(e);
+        }
+    };
+
+    // Swap them for the current files, leaving untracked ones alone if asked to
+    let mut replaced = working_files()?;
+    replaced.retain(|(filename, _)| tracked.as_ref().is_none_or(|tracked| tracked.contains_key(filename)));
+    recover::applying()?;
+    swap::apply(&replaced, &included)?;
+    for dir in &manifest.dirs {
+        fs::create_dir_all(working_path(dir)).map_err(ScmError::io(format!("Cannot create {}", dir)))?;
+    }
+    index::clear();
+
+    let seconds = started.elapsed().as_secs_f64();
+    info!(
+        "Restored {} file(s), {}, in {:.2}s ({}/s).",
+        count,
+        stats::human(bytes),
+        seconds,
+        stats::human((bytes as f64 / seconds.max(0.001)) as u64)
+    );
+    Ok(())
+}
+
+fn do_log(limit: Option<usize>, path: Option<&str>, porcelain: bool) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let limit = limit.unwrap_or(usize::MAX);
+    let path = path.map(repo_path);
+    let (head, current) = match get_head().and_then(|head| Ok((head, refs::current_branch()?))) {
+        Ok(refs) => refs,
+        Err(e) => return exit::report(e),
+    };
+    let current = current.as_deref();
+
+    let versions = match &path {
+        Some(path) => file_history(path, head).into_iter().map(|(id, _)| id).collect(),
+        // Newest first. Pruned versions simply don't appear in the listing.
+        None => list_versions().into_iter().rev().collect::<Vec<_>>(),
+    };
+    if versions.is_empty() {
+        match path {
+            _ if json::enabled() || porcelain => {}
+            Some(path) => println!("No history for {}.", path),
+            None => println!("No commits yet."),
+        }
+        return;
+    }
+
+    for id in versions.into_iter().take(limit) {
+        if porcelain {
+            match load_header(id) {
+                Some(header) => {
+                    let parents: Vec<String> = parents_of(id).iter().map(VersionId::to_string).collect();
+                    let subject = header.message.as_deref().and_then(|message| message.lines().next()).unwrap_or("");
+                    porcelain::print(&[
+                        &id.to_string(),
+                        &parents.join(" "),
+                        &header.timestamp,
+                        header.author_name.as_deref().unwrap_or(""),
+                        header.author_email.as_deref().unwrap_or(""),
+                        subject,
+                    ]);
+                }
+                None => error!("The manifest of version {} is unreadable.", id),
+            }
+            continue;
+        }
+        if json::enabled() {
+            match load_header(id) {
+                Some(header) => json::print(&VersionReport::new(&header, head, current)),
+                None => error!("The manifest of version {} is unreadable.", id),
+            }
+            continue;
+        }
+        let marker = decorations(id, head, current);
+        match load_header(id) {
+            Some(header) => {
+                println!("version {}{}", id, marker);
+                if let Some(author) = format_author(&header) {
+                    println!("Author: {}", author);
+                }
+                println!("Date:  {}", header.timestamp);
+                println!("Files: {}", header.files);
+                if let Some(summary) = change_summary(header.changes) {
+                    println!("Changes: {}", summary);
+                }
+                if !header.removed.is_empty() {
+                    println!("Removed: {}", header.removed.join(", "));
+                }
+                let mut renames: Vec<_> = header.renamed.iter().collect();
+                renames.sort();
+                for (old, new) in renames {
+                    println!("Renamed: {} -> {}", old, new);
+                }
+                print_message(&header);
+            }
+            None => println!("version {}{} (manifest unreadable)", id, marker),
+        }
+        println!();
+    }
+}
+
+/// Versions along HEAD's first-parent chain that changed `path`, newest first,
+/// with the name the file had in each. Recorded renames are followed backwards.
+fn file_history(path: &str, start: Option<VersionId>) -> Vec<(VersionId, String)> {
+    let mut history = Vec::new();
+    let mut name = path.to_string();
+    let mut cursor = start;
+
+    while let Some(id) = cursor {
+        let manifest = match load_manifest(id) {
+            Ok(manifest) => manifest,
+            Err(_) => break,
+        };
+        let parent = parent_of(id);
+        let old_name = manifest
+            .renamed
+            .iter()
+            .find(|(_, new)| **new == name)
+            .map(|(old, _)| old.clone())
+            .unwrap_or_else(|| name.clone());
+
+        let parent_hash = parent.and_then(|parent| load_manifest(parent).ok()).and_then(|m| m.files.get(&old_name).cloned());
+        let hash = manifest.files.get(&name).cloned();
+        if hash != parent_hash || old_name != name {
+            history.push((id, name.clone()));
+        }
+        if hash.is_none() && parent_hash.is_none() {
+            break;
+        }
+
+        name = old_name;
+        cursor = parent;
+    }
+    history
+}
+
+/// `scm status` with `--json`. New files are `unstaged.added`; `excluded`
+/// lists files kept out of commits with `scm rm --cached`.
+#[derive(Serialize)]
+struct StatusReport<'a> {
+    version: Option<VersionId>,
+    branch: Option<String>,
+    merging: Option<VersionId>,
+    interrupted: Option<String>,
+    staged: ChangeReport<'a>,
+    unstaged: ChangeReport<'a>,
+    excluded: Vec<&'a String>,
+}
+
+/// The staged tree against HEAD, its renames, and the working directory
+/// against the staged tree.
+type StatusChanges = (ChangeSet, Vec<(String, String)>, ChangeSet);
+
+/// What `status` compares: the staged tree against HEAD, with its renames
+/// paired up, and the working directory against the staged tree.
+fn status_changes() -> Result<StatusChanges> {
+    let head_files = version_files(get_head()?)?;
+    let staged = staged_files()?;
+    let mut staged_changes = compare_files(&head_files, &staged);
+    let recorded: HashMap<String, String> = tracking::pending_renames().into_iter().collect();
+    let renames = pair_renames(&mut staged_changes, &head_files, &staged, &recorded);
+    let unstaged = compare_files(&staged, &working_hashes()?);
+    Ok((staged_changes, renames, unstaged))
+}
+
+fn do_status(no_cache: bool, exit_code: bool, porcelain: bool) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    if no_cache {
+        statcache::disable();
+    }
+
+    let (head, branch) = match get_head().and_then(|head| Ok((head, refs::current_branch()?))) {
+        Ok(refs) => refs,
+        Err(e) => return exit::report(e),
+    };
+    let (staged_changes, renames, unstaged) = match status_changes() {
+        Ok(changes) => changes,
+        Err(e) => return exit::report(e),
+    };
+    let removals = tracking::pending_removals();
+    let untracked = tracking::untracked();
+    let untracked: Vec<&String> = untracked.iter().filter(|f| working_path(f).exists()).collect();
+    let clean = staged_changes.is_empty() && renames.is_empty() && unstaged.is_empty();
+    if exit_code && !clean {
+        exit::set(exit::FAILURE);
+    }
+
+    if porcelain {
+        porcelain::print_changes(Some("staged"), &staged_changes, &renames);
+        porcelain::print_changes(Some("unstaged"), &unstaged, &[]);
+        for filename in untracked {
+            porcelain::print(&["excluded", "?", filename]);
+        }
+        return;
+    }
+    if json::enabled() {
+        json::print(&StatusReport {
+            version: head,
+            branch,
+            merging: pending_merge(),
+            interrupted: recover::pending().map(|operation| operation.describe()),
+            staged: ChangeReport::new(&staged_changes, &renames),
+            unstaged: ChangeReport::new(&unstaged, &[]),
+            excluded: untracked,
+        });
+        return;
+    }
+
+    match head {
+        Some(head) => println!("On version {}.", head),
+        None => println!("No commits yet."),
+    }
+    if let Some(merging) = pending_merge() {
+        println!("Merge in progress with version {}; run 'scm commit' to conclude it.", merging);
+    }
+    if let Some(operation) = recover::pending() {
+        warn!("An interrupted {} was found; run 'scm recover' to finish or roll it back.", operation.describe());
+    }
+
+    if clean {
+        println!("Nothing to commit, working directory clean.");
+    }
+    if !staged_changes.is_empty() || !renames.is_empty() {
+        println!("Changes staged for commit:");
+        for (old, new) in &renames { println!("  {}  {} -> {}", color::marker("R"), old, new); }
+        for filename in &staged_changes.modified { println!("  {}  {}", color::marker("M"), filename); }
+        for filename in &staged_changes.added { println!("  {}  {}", color::marker("A"), filename); }
+        for filename in &staged_changes.deleted {
+            if removals.contains(filename) {
+                println!("  {}  {} (scm rm)", color::marker("D"), filename);
+            } else {
+                println!("  {}  {}", color::marker("D"), filename);
+            }
+        }
+    }
+    if !unstaged.modified.is_empty() || !unstaged.deleted.is_empty() {
+        println!("Changes not staged for commit:");
+        for filename in &unstaged.modified { println!("  {}  {}", color::marker("M"), filename); }
+        for filename in &unstaged.deleted { println!("  {}  {}", color::marker("D"), filename); }
+    }
+    if !unstaged.added.is_empty() {
+        if explicit_tracking() {
+            println!("Untracked files (use 'scm add' to track):");
+        } else {
+            println!("New files (use 'scm add' to stage):");
+        }
+        for filename in &unstaged.added { println!("     {}", filename); }
+    }
+
+    if !untracked.is_empty() {
+        println!("Untracked (excluded from commits):");
+        for filename in untracked { println!("     {}", filename); }
+    }
+}
+
+/// `scm diff` with `--json`: which files differ, without their content.
+/// `to` is null for the working directory.
+#[derive(Serialize)]
+struct DiffReport<'a> {
+    from: Option<VersionId>,
+    to: Option<VersionId>,
+    changes: ChangeReport<'a>,
+}
+
+fn do_diff(specs: &[String], name_only: bool, porcelain: bool) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let mut versions = Vec::new();
+    for arg in specs {
+        match resolve_version(arg) {
+            Ok(Some(id)) => versions.push(id),
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Version {} not found.", arg);
+                return;
+            }
+        }
+    }
+
+    // No ids: HEAD vs working tree. One id: that version vs working tree.
+    let (old_id, new_id) = match versions.as_slice() {
+        [] => match get_head() {
+            Ok(head) => (head, None),
+            Err(e) => return exit::report(e),
+        },
+        [old] => (Some(*old), None),
+        [old, new] => (Some(*old), Some(*new)),
+        _ => unreachable!("clap allows at most two versions"),
+    };
+
+    let files = version_files(old_id).and_then(|old_files| match new_id {
+        Some(id) => Ok((old_files, version_files(Some(id))?)),
+        None => Ok((old_files, working_hashes()?)),
+    });
+    let (old_files, new_files) = match files {
+        Ok(files) => files,
+        Err(e) => return exit::report(e),
+    };
+
+    let recorded = match new_id {
+        Some(id) => load_manifest(id).map(|m| m.renamed).unwrap_or_default(),
+        None => tracking::pending_renames().into_iter().collect(),
+    };
+
+    if name_only {
+        let mut changes = compare_files(&old_files, &new_files);
+        let renames = pair_renames(&mut changes, &old_files, &new_files, &recorded);
+        let mut names: Vec<&String> = changes.all().into_iter().chain(renames.iter().map(|(_, new)| new)).collect();
+        names.sort();
+        for name in names {
+            if porcelain { porcelain::print(&[name]); } else { println!("{}", name); }
+        }
+        return;
+    }
+    if porcelain {
+        let mut changes = compare_files(&old_files, &new_files);
+        let renames = pair_renames(&mut changes, &old_files, &new_files, &recorded);
+        porcelain::print_changes(None, &changes, &renames);
+        return;
+    }
+    if json::enabled() {
+        let mut changes = compare_files(&old_files, &new_files);
+        let renames = pair_renames(&mut changes, &old_files, &new_files, &recorded);
+        json::print(&DiffReport { from: old_id, to: new_id, changes: ChangeReport::new(&changes, &renames) });
+        return;
+    }
+    if let Some(new_id) = new_id {
+        let mut changes = compare_files(&old_files, &new_files);
+        let renames = pair_renames(&mut changes, &old_files, &new_files, &recorded);
+        println!("Comparing version {} -> version {}", old_id.map_or(0, VersionId::get), new_id);
+        if changes.is_empty() && renames.is_empty() {
+            println!("No differences.");
+            return;
+        }
+        for (old, new) in &renames { println!("  {}  {} -> {}", color::marker("R"), old, new); }
+        for filename in &changes.added { println!("  {}  {}", color::marker("A"), filename); }
+        for filename in &changes.deleted { println!("  {}  {}", color::marker("D"), filename); }
+        for filename in &changes.modified { println!("  {}  {}", color::marker("M"), filename); }
+        println!();
+    }
+
+    if let Err(e) = print_content_diffs(old_id, &old_files, new_id, &new_files, &recorded) {
+        exit::report(e);
+    }
+}
+
+/// Prints unified diffs for every file that differs between a version and
+/// either another version or (with `new_id` None) the working directory.
+/// Renamed files are diffed against their old path instead of shown as delete + add.
+fn print_content_diffs(
+    old_id: Option<VersionId>,
+    old_files: &HashMap<String, FileHash>,
+    new_id: Option<VersionId>,
+    new_files: &HashMap<String, FileHash>,
+    recorded_renames: &HashMap<String, String>,
+) -> Result<()> {
+    let read_old = |filename: &str| match old_id {
+        Some(id) => read_stored(id, filename, &old_files[filename]),
+        None => Ok(Vec::new()),
+    };
+    let read_new = |filename: &str| match new_id {
+        Some(id) => read_stored_file(id, filename),
+        None => fs::read(working_path(filename)).map_err(ScmError::io(format!("Cannot read {}", filename))),
+    };
+
+    let mut changes = compare_files(old_files, new_files);
+    for (old_name, new_name) in pair_renames(&mut changes, old_files, new_files, recorded_renames) {
+        println!("rename from {}", old_name);
+        println!("rename to {}", new_name);
+        if old_files[&old_name] != new_files[&new_name] {
+            let old = read_old(&old_name)?;
+            diff::print_file_diff(&new_name, &format!("a/{}", old_name), &format!("b/{}", new_name), &old, &read_new(&new_name)?);
+        }
+    }
+
+    for filename in changes.all() {
+        let (old_label, old) = if old_files.contains_key(filename) {
+            (format!("a/{}", filename), read_old(filename)?)
+        } else {
+            ("/dev/null".to_string(), Vec::new())
+        };
+        let (new_label, new) = if new_files.contains_key(filename) {
+            (format!("b/{}", filename), read_new(filename)?)
+        } else {
+            ("/dev/null".to_string(), Vec::new())
+        };
+
+        diff::print_file_diff(filename, &old_label, &new_label, &old, &new);
+    }
+    Ok(())
+}
+
+fn do_show(spec: Option<&str>, show_diff: bool) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let spec = spec.unwrap_or("HEAD");
+    let id = match resolve_version(spec) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Version {} not found.", spec);
+            return;
+        }
+    };
+    let (manifest, head, current) = match load_manifest(id).and_then(|manifest| Ok((manifest, get_head()?, refs::current_branch()?))) {
+        Ok(loaded) => loaded,
+        Err(e) => return exit::report(e),
+    };
+    let current = current.as_deref();
+    if json::enabled() {
+        show_json(id, &manifest, head, current);
+        return;
+    }
+
+    println!("version {}{}", id, decorations(id, head, current));
+    let parents = parents_of(id);
+    if !parents.is_empty() {
+        let parents: Vec<String> = parents.iter().map(|p| p.to_string()).collect();
+        println!("Parent: {}", parents.join(" "));
+    }
+    let header = header_of(&manifest);
+    if let Some(author) = format_author(&header) {
+        println!("Author: {}", author);
+    }
+    println!("Date:  {}", manifest.timestamp);
+    print_message(&header);
+
+    if !manifest.removed.is_empty() {
+        println!();
+        println!("Removed: {}", manifest.removed.join(", "));
+    }
+    let changes = version_changes(&manifest);
+    if !changes.is_empty() || !manifest.renamed.is_empty() {
+        println!();
+        println!("Changes:");
+        let mut renames: Vec<_> = manifest.renamed.iter().collect();
+        renames.sort();
+        for (old, new) in renames { println!("  {}  {} -> {}", color::marker("R"), old, new); }
+        for filename in &changes.modified { println!("  {}  {}", color::marker("M"), filename); }
+        for filename in &changes.added {
+            if !manifest.renamed.values().any(|new| new == filename) { println!("  {}  {}", color::marker("A"), filename); }
+        }
+        for filename in &changes.deleted {
+            if !manifest.renamed.contains_key(filename) { println!("  {}  {}", color::marker("D"), filename); }
+        }
+    }
+    println!();
+    println!("Files ({}):", manifest.files.len());
+    let mut files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+    files.sort();
+    for (filename, hash) in files {
+        let size = match manifest.metadata.get(filename).and_then(|m| m.size) {
+            Some(size) => size.to_string(),
+            None => match try_read_stored(id, filename, hash) {
+                Ok(Some(content)) => content.len().to_string(),
+                Ok(None) => "missing".to_string(),
+                Err(_) => "damaged".to_string(),
+            },
+        };
+        println!("  {}  {:>10}  {}", hash, size, filename);
+    }
+
+    if show_diff {
+        let parent = parent_of(id);
+        println!();
+        let shown = version_files(parent).and_then(|parent_files| print_content_diffs(parent, &parent_files, Some(id), &manifest.files, &manifest.renamed));
+        if let Err(e) = shown {
+            exit::report(e);
+        }
+    }
+}
+
+/// `scm show` with `--json`: the version as in `log`, what it changed and
+/// every file in it.
+#[derive(Serialize)]
+struct ShowReport<'a> {
+    #[serde(flatten)]
+    version: VersionReport<'a>,
+    changed: ChangeReport<'a>,
+    tree: Vec<TreeEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct TreeEntry<'a> {
+    path: &'a str,
+    hash: &'a str,
+    /// Null if the content is missing from the store or unreadable.
+    size: Option<u64>,
+}
+
+fn show_json(id: VersionId, manifest: &Manifest, head: Option<VersionId>, current: Option<&str>) {
+    let header = header_of(manifest);
+    let mut renames: Vec<(String, String)> = manifest.renamed.clone().into_iter().collect();
+    renames.sort();
+    let mut changes = version_changes(manifest);
+    changes.added.retain(|filename| !manifest.renamed.values().any(|new| new == filename));
+    changes.deleted.retain(|filename| !manifest.renamed.contains_key(filename));
+
+    let mut files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+    files.sort();
+    let tree = files
+        .into_iter()
+        .map(|(path, hash)| {
+            let size = match manifest.metadata.get(path).and_then(|m| m.size) {
+                Some(size) => Some(size),
+                None => try_read_stored(id, path, hash).ok().flatten().map(|content| content.len() as u64),
+            };
+            TreeEntry { path, hash: hash.as_str(), size }
+        })
+        .collect();
+    json::print(&ShowReport { version: VersionReport::new(&header, head, current), changed: ChangeReport::new(&changes, &renames), tree });
+}
+
+/// A tag as `scm tag --json` lists it; a lightweight tag has no tagger,
+/// timestamp or message.
+#[derive(Serialize)]
+struct TagReport<'a> {
+    name: &'a str,
+    version: VersionId,
+    tagger: Option<&'a str>,
+    timestamp: Option<&'a str>,
+    message: Option<&'a str>,
+}
+
+fn do_tag(args: &cli::TagArgs) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    if let Some(name) = &args.delete {
+        if refs::delete_tag(name) {
+            info!("Deleted tag {}.", name);
+        } else {
+            error!("Tag {} not found.", name);
+        }
+        return;
+    }
+    let name = match &args.name {
+        Some(name) => name,
+        None => {
+            let tags = refs::list_tags();
+            if tags.is_empty() && !json::enabled() {
+                println!("No tags.");
+            }
+            for (name, tag) in tags {
+                if json::enabled() {
+                    json::print(&TagReport {
+                        name: &name,
+                        version: tag.version_id,
+                        tagger: tag.tagger.as_deref(),
+                        timestamp: tag.timestamp.as_deref(),
+                        message: tag.message.as_deref(),
+                    });
+                    continue;
+                }
+                match tag.message.as_deref().and_then(|m| m.lines().next()) {
+                    Some(summary) => println!("{:<20} {:>6}  {}", name, tag.version_id, summary),
+                    None => println!("{:<20} {:>6}", name, tag.version_id),
+                }
+            }
+            return;
+        }
+    };
+    if !refs::is_valid_ref_name(name) {
+        error!("Invalid tag name: {}", name);
+        return;
+    }
+    if refs::read_tag(name).is_some() {
+        error!("Tag {} already exists.", name);
+        return;
+    }
+
+    let version_id = match &args.version {
+        Some(spec) => match resolve_version(spec) {
+            Ok(Some(id)) => id,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Version {} not found.", spec);
+                return;
+            }
+        },
+        None => match get_head() {
+            Ok(Some(id)) => id,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                info!("Nothing to tag (no commits yet).");
+                return;
+            }
+        },
+    };
+
+    let message = args.message.clone();
+    let annotated = message.is_some();
+    let tag = refs::Tag {
+        version_id,
+        tagger: if annotated { format_author_setting() } else { None },
+        timestamp: if annotated { Some(chrono::Utc::now().to_string()) } else { None },
+        message,
+    };
+    if let Err(e) = refs::write_tag(name, &tag) {
+        return exit::report(e);
+    }
+    info!("Tagged version {} as {}.", version_id, name);
+}
+
+/// `scm describe --json`; `tag` and `distance` are null with no tag in reach.
+#[derive(Serialize)]
+struct DescribeReport<'a> {
+    version: VersionId,
+    tag: Option<&'a str>,
+    distance: Option<usize>,
+}
+
+/// Names a version after the nearest tag reachable from it, e.g. `v1.2-5`
+/// for five versions after `v1.2`, or just `v1.2` when it is tagged itself.
+fn do_describe(spec: Option<&str>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let id = match spec {
+        None => match get_head() {
+            Ok(Some(id)) => id,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                info!("Nothing to describe (no commits yet).");
+                return;
+            }
+        },
+        Some(spec) => match resolve_version(spec) {
+            Ok(Some(id)) => id,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Version {} not found.", spec);
+                return;
+            }
+        },
+    };
+
+    let mut tags_by_version: HashMap<VersionId, Vec<String>> = HashMap::new();
+    for (name, tag) in refs::list_tags() {
+        tags_by_version.entry(tag.version_id).or_default().push(name);
+    }
+
+    // Walk outwards from the version so the first tagged one found is nearest.
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([id]);
+    while let Some(current) = queue.pop_front() {
+        if !seen.insert(current) { continue; }
+        if let Some(names) = tags_by_version.get(&current) {
+            let name = names.iter().min().unwrap();
+            let tagged = ancestors(&[current]);
+            let distance = ancestors(&[id]).difference(&tagged).count();
+            if json::enabled() {
+                json::print(&DescribeReport { version: id, tag: Some(name), distance: Some(distance) });
+            } else if distance == 0 {
+                println!("{}", name);
+            } else {
+                println!("{}-{}", name, distance);
+            }
+            return;
+        }
+        queue.extend(parents_of(current));
+    }
+    if json::enabled() {
+        json::print(&DescribeReport { version: id, tag: None, distance: None });
+    } else {
+        println!("No tags reachable from version {}.", id);
+    }
+}
+
+/// A branch as `scm branch --json` lists it; `version` is null for a
+/// branch with no commits yet.
+#[derive(Serialize)]
+struct BranchReport<'a> {
+    name: &'a str,
+    version: Option<VersionId>,
+    current: bool,
+}
+
+fn do_branch(args: &cli::BranchArgs) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    match (&args.delete, &args.name) {
+        (None, None) => {
+            let (current, head) = match refs::current_branch().and_then(|current| Ok((current, get_head()?))) {
+                Ok(refs) => refs,
+                Err(e) => return exit::report(e),
+            };
+            let branches = refs::list_branches();
+            if let Some(name) = &current
+                && !branches.iter().any(|(b, _)| b == name)
+            {
+                if json::enabled() {
+                    json::print(&BranchReport { name, version: None, current: true });
+                } else {
+                    println!("* {} (no commits yet)", name);
+                }
+            }
+            for (name, id) in branches {
+                if json::enabled() {
+                    json::print(&BranchReport { name: &name, version: Some(id), current: current.as_ref() == Some(&name) });
+                    continue;
+                }
+                let marker = if current.as_deref() == Some(name.as_str()) { '*' } else { ' ' };
+                println!("{} {:<20} {:>6}", marker, name, id);
+            }
+            if current.is_none() && !json::enabled() {
+                println!("HEAD is detached at version {}.", head.map_or(0, VersionId::get));
+            }
+        }
+        (Some(name), _) => {
+            match refs::current_branch() {
+                Ok(current) if current.as_deref() == Some(name.as_str()) => {
+                    error!("Cannot delete the current branch {}.", name);
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => return exit::report(e),
+            }
+            if refs::delete_branch(name) {
+                info!("Deleted branch {}.", name);
+            } else {
+                error!("Branch {} not found.", name);
+            }
+        }
+        (None, Some(name)) => {
+            let start = match &args.version {
+                Some(spec) => match resolve_version(spec) {
+                    Ok(Some(id)) => id,
+                    Err(e) => return exit::report(e),
+                    Ok(None) => {
+                        error!("Version {} not found.", spec);
+                        return;
+                    }
+                },
+                None => match get_head() {
+                    Ok(Some(id)) => id,
+                    Err(e) => return exit::report(e),
+                    Ok(None) => {
+                        error!("Cannot create a branch before the first commit.");
+                        return;
+                    }
+                },
+            };
+            if create_branch(name, start) {
+                info!("Created branch {} at version {}.", name, start);
+            }
+        }
+    }
+}
+
+fn do_switch(create: bool, name: &str) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let (old_head, current) = match get_head().and_then(|head| Ok((head, refs::current_branch()?))) {
+        Ok(refs) => refs,
+        Err(e) => return exit::report(e),
+    };
+    if create {
+        let Some(head) = old_head else {
+            error!("Cannot create a branch before the first commit.");
+            return;
+        };
+        if !create_branch(name, head) { return; }
+    }
+    let target_id = match refs::read_branch(name) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Branch {} not found.", name);
+            return;
+        }
+    };
+    if current.as_deref() == Some(name) {
+        info!("Already on branch {}.", name);
+        return;
+    }
+
+    if Some(target_id) != old_head
+        && let Err(e) = restore_version(target_id)
+    {
+        return exit::report(e);
+    }
+    let moved = refs::write_head_ref(&refs::HeadRef::Branch(name.to_string())).and_then(|()| oplog::record(Some(name.to_string()), old_head, target_id));
+    if let Err(e) = moved {
+        return exit::report(e);
+    }
+    info!("Switched to branch {} (version {}).", name, target_id);
+}
+
+fn do_merge(spec: Option<&str>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let head = match get_head() {
+        Ok(Some(head)) => head,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Nothing to merge into (no commits yet).");
+            return;
+        }
+    };
+    // No branch means --abort.
+    let spec = match spec {
+        Some(spec) => spec,
+        None => {
+            let Some(merging) = pending_merge() else {
+                info!("No merge in progress.");
+                return;
+            };
+            // Only what either side tracks is put back; untracked files were left alone by the merge.
+            let tracked = version_files(Some(head)).and_then(|mut tracked| {
+                tracked.extend(version_files(Some(merging))?);
+                Ok(tracked)
+            });
+            if let Err(e) = tracked.and_then(|tracked| restore_over(head, Some(tracked))) {
+                return exit::report(e);
+            }
+            if let Err(e) = storage().remove_file(MERGE_HEAD_FILE).map_err(ScmError::io("Cannot clear MERGE_HEAD")) {
+                return exit::report(e);
+            }
+            info!("Merge aborted. Back at version {}.", head);
+            return;
+        }
+    };
+
+    if pending_merge().is_some() {
+        error!("A merge is already in progress. Commit it or run 'scm merge --abort'.");
+        return;
+    }
+    let theirs = match resolve_version(spec) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Version {} not found.", spec);
+            return;
+        }
+    };
+
+    let base = match merge_base(head, theirs) {
+        Some(base) => base,
+        None => {
+            error!("Versions {} and {} share no history.", head, theirs);
+            return;
+        }
+    };
+    if base == theirs {
+        info!("Already up to date.");
+        return;
+    }
+    let sides = version_files(Some(base)).and_then(|base_files| Ok((base_files, version_files(Some(head))?, version_files(Some(theirs))?)));
+    let (base_files, our_files, their_files) = match sides {
+        Ok(sides) => sides,
+        Err(e) => return exit::report(e),
+    };
+
+    // Untracked files only stand in the way if the merge would write over them.
+    let changes = match working_hashes() {
+        Ok(working) => compare_files(&our_files, &working),
+        Err(e) => return exit::report(e),
+    };
+    if !changes.modified.is_empty() || !changes.deleted.is_empty() {
+        error!("You have uncommitted changes. Commit them before merging.");
+        return;
+    }
+    let overwritten: Vec<&String> =
+        changes.added.iter().filter(|name| their_files.get(*name).is_some_and(|hash| base_files.get(*name) != Some(hash))).collect();
+    if !overwritten.is_empty() {
+        for name in overwritten {
+            error!("Untracked file {} would be overwritten by the merge.", name);
+        }
+        error!("Move or remove them before merging.");
+        return;
+    }
+
+    if base == head {
+        info!("Fast-forwarding to version {}...", theirs);
+        if let Err(e) = restore_over(theirs, Some(our_files)).and_then(|()| set_head(theirs)) {
+            return exit::report(e);
+        }
+        info!("Now at version {}.", theirs);
+        return;
+    }
+
+    info!("Merging version {} into version {} (common ancestor: version {})...", theirs, head, base);
+    if let Err(e) = swap::begin() {
+        return exit::report(e);
+    }
+    let plan = match plan_merge(spec, head, theirs, base, &base_files, &our_files, &their_files) {
+        Ok(plan) => plan,
+        Err(e) => {
+            swap::discard();
+            return exit::report(e);
+        }
+    };
+    let written: Vec<&String> = plan.written.iter().collect();
+    if let Err(e) = recover::applying().and_then(|()| swap::apply(&plan.replaced, &written)) {
+        return exit::report(e);
+    }
+
+    if let Err(e) = storage().write_file(MERGE_HEAD_FILE, theirs.to_string().as_bytes()).map_err(ScmError::io("Cannot record MERGE_HEAD")) {
+        return exit::report(e);
+    }
+    if plan.conflicts.is_empty() {
+        let target = match refs::current_branch() {
+            Ok(current) => current.unwrap_or_else(|| format!("version {}", head)),
+            Err(e) => return exit::report(e),
+        };
+        // Stage just what the merge touched, so untracked files stay out of the commit.
+        let mut touched: Vec<String> = plan.written.iter().chain(plan.replaced.iter().map(|(name, _)| name)).cloned().collect();
+        touched.sort();
+        touched.dedup();
+        let staged = staged_files().and_then(|mut staged| stage_paths(&mut staged, &touched, false).map(|()| staged));
+        let committed = staged
+            .and_then(|staged| index::save(&staged))
+            .and_then(|()| create_commit(&CommitOptions::new().message(format!("Merge {} into {}", spec, target))));
+        if let Err(e) = committed {
+            exit::report(e);
+        }
+    } else {
+        error!("Automatic merge failed in {} file(s); fix the conflicts and run 'scm commit', or 'scm merge --abort'.", plan.conflicts.len());
+    }
+}
+
+/// What a three-way merge does to the working tree, for `swap::apply`.
+struct MergePlan {
+    /// Files written to their `swap::staged_path`.
+    written: Vec<String>,
+    /// Working files those replace, or that the merge deletes.
+    replaced: Vec<(String, PathBuf)>,
+    conflicts: Vec<String>,
+}
+
+/// Merges `theirs` into `head` file by file, writing the results aside
+/// (see `swap`) and reporting conflicts as it finds them. Nothing in the
+/// working directory changes until the plan is applied.
+fn plan_merge(
+    spec: &str,
+    head: VersionId,
+    theirs: VersionId,
+    base: VersionId,
+    base_files: &HashMap<String, FileHash>,
+    our_files: &HashMap<String, FileHash>,
+    their_files: &HashMap<String, FileHash>,
+) -> Result<MergePlan> {
+    let their_metadata = load_manifest(theirs).map(|m| m.metadata).unwrap_or_default();
+    let mut names: Vec<&String> = base_files.keys().chain(our_files.keys()).chain(their_files.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let mut plan = MergePlan { written: Vec::new(), replaced: Vec::new(), conflicts: Vec::new() };
+    let replace = |plan: &mut MergePlan, name: &String| {
+        let path = working_path(name);
+        if fs::symlink_metadata(&path).is_ok() {
+            plan.replaced.push((name.clone(), path));
+        }
+    };
+    for name in names {
+        let (b, o, t) = (base_files.get(name), our_files.get(name), their_files.get(name));
+        if o == t || b == t {
+            continue; // Working tree already holds our side.
+        }
+        if b == o {
+            // Only their side changed: take it, including deletions.
+            if let Some(hash) = t {
+                write_stored_file(theirs, name, hash, their_metadata.get(name), &swap::staged_path(name))?;
+                plan.written.push(name.clone());
+            }
+            replace(&mut plan, name);
+            continue;
+        }
+
+        if o.is_none() || t.is_none() {
+            // Deleted on one side, modified on the other: leave the surviving content in place.
+            if let Some(hash) = t {
+                write_stored_file(theirs, name, hash, their_metadata.get(name), &swap::staged_path(name))?;
+                plan.written.push(name.clone());
+            }
+            error!("{} (modify/delete): {}", color::paint(color::RED, "CONFLICT"), name);
+            events::conflict(name);
+            plan.conflicts.push(name.clone());
+            continue;
+        }
+
+        let base_content = read_stored_or_empty(Some(base), base_files, name)?;
+        let our_content = read_stored_or_empty(Some(head), our_files, name)?;
+        let their_content = read_stored_or_empty(Some(theirs), their_files, name)?;
+        let outcome = merge::merge_contents(
+            name,
+            &base_content,
+            &our_content,
+            &their_content,
+            &format!("version {}", head),
+            &format!("{} (version {})", spec, theirs),
+        );
+        let content = match outcome {
+            merge::MergeOutcome::Clean(content) => {
+                info!("Auto-merged {}", name);
+                content
+            }
+            merge::MergeOutcome::Conflict(content) => {
+                error!("{} (content): {}", color::paint(color::RED, "CONFLICT"), name);
+                events::conflict(name);
+                plan.conflicts.push(name.clone());
+                content
+            }
+            merge::MergeOutcome::Binary => {
+                error!("{} (binary): {} changed on both sides; kept our version", color::paint(color::RED, "CONFLICT"), name);
+                events::conflict(name);
+                plan.conflicts.push(name.clone());
+                continue;
+            }
+        };
+        let staged = swap::staged_path(name);
+        ensure_parent_dir(&staged)?;
+        fs::write(&staged, content).map_err(ScmError::io(format!("Cannot write {}", name)))?;
+        plan.written.push(name.clone());
+        replace(&mut plan, name);
+    }
+    Ok(plan)
+}
+
+/// A stash as `scm stash list --json` lists it, newest first.
+#[derive(Serialize)]
+struct StashReport<'a> {
+    index: usize,
+    base_version: Option<VersionId>,
+    timestamp: &'a str,
+    message: Option<&'a str>,
+    changed: Vec<&'a String>,
+    deleted: &'a [String],
+}
+
+fn do_stash(args: &cli::StashArgs) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    // Bare `scm stash` and `scm stash -m ...` mean push.
+    let done = match &args.command {
+        None => stash_push(args.push.message.clone()),
+        Some(cli::StashCommand::Push(push)) => stash_push(push.message.clone()),
+        Some(cli::StashCommand::Pop) => stash_pop(),
+        Some(cli::StashCommand::List) => {
+            let indices = stash::list_indices();
+            if indices.is_empty() && !json::enabled() {
+                println!("No stashed changes.");
+            }
+            for index in indices.into_iter().rev() {
+                match stash::load(index) {
+                    Some(entry) if json::enabled() => {
+                        let mut changed: Vec<&String> = entry.files.keys().collect();
+                        changed.sort();
+                        json::print(&StashReport {
+                            index,
+                            base_version: entry.base_version,
+                            timestamp: &entry.timestamp,
+                            message: entry.message.as_deref(),
+                            changed,
+                            deleted: &entry.deleted,
+                        });
+                    }
+                    None if json::enabled() => error!("stash@{{{}}} is unreadable.", index),
+                    Some(entry) => println!(
+                        "stash@{{{}}}: on version {}, {} changed, {} deleted{}",
+                        index,
+                        entry.base_version.map_or(0, VersionId::get),
+                        entry.files.len(),
+                        entry.deleted.len(),
+                        entry.message.map(|m| format!(": {}", m)).unwrap_or_default()
+                    ),
+                    None => println!("stash@{{{}}}: (unreadable)", index),
+                }
+            }
+            Ok(())
+        }
+    };
+    if let Err(e) = done {
+        exit::report(e);
+    }
+}
+
+fn stash_push(message: Option<String>) -> Result<()> {
+    let head = get_head()?;
+    let head_manifest = head.map(load_manifest).transpose()?;
+    let head_files = head_manifest.as_ref().map(|m| m.files.clone()).unwrap_or_default();
+    let mut working = working_hashes()?;
+    if explicit_tracking() {
+        let tracked = staged_files()?;
+        working.retain(|name, _| tracked.contains_key(name) || head_files.contains_key(name));
+    }
+    let changes = compare_files(&head_files, &working);
+    if changes.is_empty() {
+        info!("No local changes to stash.");
+        return Ok(());
+    }
+
+    // Changed files are stored as a commit stores them.
+    let names: Vec<&String> = changes.modified.iter().chain(&changes.added).collect();
+    let metadata: HashMap<String, FileMeta> = names
+        .into_par_iter()
+        .map(|name| {
+            let (previous, previous_meta) = (head_files.get(name), head_manifest.as_ref().and_then(|m| m.metadata.get(name)));
+            Ok((name.clone(), store_file(name, &working_path(name), &working[name], previous, previous_meta)?))
+        })
+        .collect::<Result<_>>()?;
+    let entry = stash::StashEntry {
+        base_version: head,
+        timestamp: chrono::Utc::now().to_string(),
+        message,
+        files: metadata.keys().map(|name| (name.clone(), working[name].clone())).collect(),
+        metadata,
+        deleted: changes.deleted.clone(),
+    };
+    let index = stash::save(&entry)?;
+
+    // Put the working directory back to HEAD.
+    match head {
+        Some(head) => restore_version(head)?,
+        None => changes.added.iter().try_for_each(|name| remove_working_file(&working_path(name)))?,
+    }
+    info!("Saved {} change(s) as stash@{{{}}}.", changes.all().len(), index);
+    Ok(())
+}
+
+fn stash_pop() -> Result<()> {
+    let index = match stash::list_indices().last() {
+        Some(&index) => index,
+        None => {
+            info!("No stashed changes.");
+            return Ok(());
+        }
+    };
+    let entry = stash::load(index).ok_or_else(|| ScmError::IntegrityFailure(format!("stash@{{{}}} is unreadable.", index)))?;
+
+    // A file conflicts if it was changed since HEAD and doesn't already match the stash.
+    let head_files = version_files(get_head()?)?;
+    let working = working_hashes()?;
+    let mut conflicts = Vec::new();
+    let stashed = entry.files.iter().map(|(name, hash)| (name, Some(hash)));
+    let deleted = entry.deleted.iter().map(|name| (name, None));
+    for (name, stashed_hash) in stashed.chain(deleted) {
+        let current = working.get(name);
+        if current != head_files.get(name) && current != stashed_hash {
+            conflicts.push(name.clone());
+        }
+    }
+    if !conflicts.is_empty() {
+        conflicts.sort();
+        error!("Cannot pop stash@{{{}}}; these files have conflicting local changes:", index);
+        for name in conflicts { error!("  {}", name); }
+        return Ok(());
+    }
+
+    // Every stashed file is checked before any is written.
+    let damaged = |name: &str, problem: String| ScmError::IntegrityFailure(format!("The stashed copy of {} in stash@{{{}}} is damaged: {}.", name, index, problem));
+    for (name, hash) in &entry.files {
+        let checked = match entry.metadata.get(name) {
+            Some(FileMeta { chunks: Some(chunks), .. }) => chunk::verify(chunks, hash),
+            Some(_) => storage().verify_object(hash),
+            None if working_hash(name, &stash::copy_path(index, name))? != *hash => Err("corrupted (content does not match its hash)".to_string()),
+            None => Ok(()),
+        };
+        checked.map_err(|problem| damaged(name, problem))?;
+    }
+    for (name, hash) in &entry.files {
+        let dest = working_path(name);
+        match entry.metadata.get(name) {
+            Some(meta) => write_file(name, hash, Some(meta), &dest, None, || storage().get_object(hash)?.ok_or_else(|| damaged(name, "missing".to_string())))?,
+            None => {
+                ensure_parent_dir(&dest)?;
+                symlink::copy(&stash::copy_path(index, name), &dest)?;
+            }
+        }
+    }
+    for name in &entry.deleted {
+        if symlink::exists(&working_path(name)) {
+            remove_working_file(&working_path(name))?;
+        }
+    }
+
+    stash::remove(index)?;
+    info!("Restored stash@{{{}}} ({} change(s)).", index, entry.files.len() + entry.deleted.len());
+    Ok(())
+}
+
+fn do_reset(hard: bool, prune: bool, spec: &str) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let target_id = match resolve_version(spec) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Version {} not found.", spec);
+            return;
+        }
+    };
+
+    let old_head = match get_head() {
+        Ok(head) => head,
+        Err(e) => return exit::report(e),
+    };
+    if hard {
+        info!("Resetting to version {} (hard)...", target_id);
+        if let Err(e) = restore_version(target_id) {
+            return exit::report(e);
+        }
+    } else {
+        info!("Resetting to version {} (soft); working files left untouched.", target_id);
+    }
+    if let Err(e) = set_head(target_id) {
+        return exit::report(e);
+    }
+
+    if prune {
+        let orphans = match orphaned_versions(old_head) {
+            Ok(orphans) => orphans,
+            Err(e) => return exit::report(e),
+        };
+        for id in &orphans {
+            if let Err(e) = storage().remove_manifest(*id) {
+                return exit::report(e);
+            }
+        }
+        info!("Deleted {} orphaned version(s).", orphans.len());
+    } else if old_head > Some(target_id) {
+        info!("Newer versions were kept; use 'scm redo' or 'scm checkout' to return to them.");
+    }
+    info!("Reset complete. Now at version {}.", target_id);
+}
+
+/// Versions in `old_head`'s history that no branch, tag or HEAD can reach
+/// any more, newest first.
+fn orphaned_versions(old_head: Option<VersionId>) -> Result<Vec<VersionId>> {
+    let mut roots: Vec<_> = get_head()?.into_iter().collect();
+    roots.extend(refs::list_branches().into_iter().map(|(_, id)| id));
+    roots.extend(refs::list_tags().into_iter().map(|(_, tag)| tag.version_id));
+    let reachable = ancestors(&roots);
+
+    let mut orphans: Vec<VersionId> = ancestors(old_head.as_slice())
+        .into_iter()
+        .filter(|id| !reachable.contains(id))
+        .collect();
+    orphans.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(orphans)
+}
+
+fn do_cherry_pick(spec: &str) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let picked = match resolve_version(spec) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Version {} not found.", spec);
+            return;
+        }
+    };
+
+    let head = match get_head() {
+        Ok(head) => head,
+        Err(e) => return exit::report(e),
+    };
+    if pending_merge().is_some() {
+        error!("A merge is in progress. Commit it or run 'scm merge --abort'.");
+        return;
+    }
+    match has_uncommitted_changes(head) {
+        Err(e) => return exit::report(e),
+        Ok(true) => {
+            error!("You have uncommitted changes. Commit them before cherry-picking.");
+            return;
+        }
+        Ok(false) => {}
+    }
+
+    let parent = parent_of(picked);
+    let sides = version_files(parent).and_then(|parent_files| Ok((parent_files, version_files(Some(picked))?, version_files(head)?)));
+    let (parent_files, picked_files, head_files) = match sides {
+        Ok(sides) => sides,
+        Err(e) => return exit::report(e),
+    };
+    let changes = compare_files(&parent_files, &picked_files);
+    if changes.is_empty() {
+        info!("Version {} made no changes.", picked);
+        return;
+    }
+
+    // Work out every file's result first so a conflict leaves the tree untouched.
+    let mut writes: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut deletes: Vec<String> = Vec::new();
+    let mut conflicts = Vec::new();
+    for name in changes.all() {
+        let (base, ours, theirs) = (parent_files.get(name), head_files.get(name), picked_files.get(name));
+        if ours == theirs {
+            continue; // Already applied.
+        }
+        if ours == base {
+            match theirs {
+                Some(_) => match read_stored_or_empty(Some(picked), &picked_files, name) {
+                    Ok(content) => writes.push((name.clone(), content)),
+                    Err(e) => return exit::report(e),
+                },
+                None => deletes.push(name.clone()),
+            }
+            continue;
+        }
+        if ours.is_none() || theirs.is_none() {
+            events::conflict(name);
+            conflicts.push(format!("{} (modify/delete)", name));
+            continue;
+        }
+
+        let contents = read_stored_or_empty(parent, &parent_files, name).and_then(|base_content| {
+            Ok((base_content, read_stored_or_empty(head, &head_files, name)?, read_stored_or_empty(Some(picked), &picked_files, name)?))
+        });
+        let (base_content, our_content, their_content) = match contents {
+            Ok(contents) => contents,
+            Err(e) => return exit::report(e),
+        };
+        let outcome = merge::merge_contents(
+            name,
+            &base_content,
+            &our_content,
+            &their_content,
+            "HEAD",
+            &format!("version {}", picked),
+        );
+        let kind = match outcome {
+            merge::MergeOutcome::Clean(content) => {
+                writes.push((name.clone(), content));
+                continue;
+            }
+            merge::MergeOutcome::Conflict(_) => "content",
+            merge::MergeOutcome::Binary => "binary",
+        };
+        events::conflict(name);
+        conflicts.push(format!("{} ({})", name, kind));
+    }
+
+    if !conflicts.is_empty() {
+        error!("Cannot cherry-pick version {}; these files conflict with HEAD:", picked);
+        for conflict in conflicts { error!("  {}", conflict); }
+        error!("No files were changed.");
+        return;
+    }
+    if writes.is_empty() && deletes.is_empty() {
+        info!("Changes from version {} are already present.", picked);
+        return;
+    }
+
+    for (name, content) in &writes {
+        if let Err(e) = ensure_parent_dir(&working_path(name)) {
+            return exit::report(e);
+        }
+        fs::write(working_path(name), content).expect("Failed to write file");
+    }
+    for name in &deletes {
+        if let Err(e) = remove_working_file(&working_path(name)) {
+            return exit::report(e);
+        }
+    }
+    info!("Applied {} change(s) from version {}.", writes.len() + deletes.len(), picked);
+
+    let original = load_manifest(picked).ok().and_then(|m| m.message);
+    let message = match original {
+        Some(message) => format!("{}\n\n(cherry picked from version {})", message, picked),
+        None => format!("Cherry-pick version {}", picked),
+    };
+    if let Err(e) = create_commit(&CommitOptions::new().message(message).all(true)) {
+        exit::report(e);
+    }
+}
+
+fn do_rm(cached: bool, files: &[String]) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let filenames: Vec<String> = files.iter().map(|file| repo_path(file)).collect();
+
+    let (head_files, mut staged) = match get_head().and_then(version_files).and_then(|head_files| Ok((head_files, staged_files()?))) {
+        Ok(files) => files,
+        Err(e) => return exit::report(e),
+    };
+    for filename in &filenames {
+        if !head_files.contains_key(filename) && !symlink::exists(&working_path(filename)) {
+            error!("{} is not tracked.", filename);
+            return;
+        }
+    }
+
+    for filename in &filenames {
+        staged.remove(filename);
+        if cached {
+            tracking::add_untracked(filename);
+            info!("Stopped tracking {} (kept on disk).", filename);
+        } else {
+            if symlink::exists(&working_path(filename))
+                && let Err(e) = remove_working_file(&working_path(filename))
+            {
+                return exit::report(e);
+            }
+            info!("Removed {}.", filename);
+        }
+        if head_files.contains_key(filename) {
+            tracking::add_pending_removal(filename);
+        }
+    }
+    if let Err(e) = index::save(&staged) {
+        exit::report(e);
+    }
+}
+
+fn do_mv(src: &str, dst: &str) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let (src, dst) = (repo_path(src), repo_path(dst));
+    let working = match working_files() {
+        Ok(working) => working,
+        Err(e) => return exit::report(e),
+    };
+    if !working.iter().any(|(name, _)| *name == src) {
+        error!("{} is not a tracked file.", src);
+        return;
+    }
+    if symlink::exists(&working_path(&dst)) {
+        error!("{} already exists.", dst);
+        return;
+    }
+
+    if let Err(e) = ensure_parent_dir(&working_path(&dst)) {
+        return exit::report(e);
+    }
+    fs::rename(working_path(&src), working_path(&dst)).expect("Failed to move file");
+    remove_empty_parents(&working_path(&src));
+    tracking::add_pending_rename(&src, &dst);
+
+    // The staged entry moves too, keeping whatever content was staged.
+    if let Err(e) = move_staged(&src, &dst) {
+        return exit::report(e);
+    }
+    info!("Renamed {} -> {}.", src, dst);
+}
+
+/// Moves `src`'s staged entry, with any content staged for it, to `dst`.
+fn move_staged(src: &str, dst: &str) -> Result<()> {
+    let mut staged = staged_files()?;
+    let Some(hash) = staged.remove(src) else {
+        return Ok(());
+    };
+    index::rename_staged(src, dst)?;
+    staged.insert(dst.to_string(), hash);
+    index::save(&staged)
+}
+
+fn do_restore(files: &[String], from: Option<&str>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let filenames: Vec<String> = files.iter().map(|file| repo_path(file)).collect();
+    let spec = from.unwrap_or("HEAD");
+    let id = match resolve_version(spec) {
+        Ok(Some(id)) => id,
+        Err(e) => return exit::report(e),
+        Ok(None) => {
+            error!("Version {} not found.", spec);
+            return;
+        }
+    };
+    let files = match version_files(Some(id)) {
+        Ok(files) => files,
+        Err(e) => return exit::report(e),
+    };
+
+    // Check everything before writing anything.
+    for filename in &filenames {
+        let recorded_hash = match files.get(filename) {
+            Some(hash) => hash,
+            None => {
+                error!("{} is not in version {}.", filename, id);
+                return;
+            }
+        };
+        if let Err(e) = verify_stored(id, filename, recorded_hash) {
+            return exit::report(e);
+        }
+    }
+
+    let metadata = load_manifest(id).map(|m| m.metadata).unwrap_or_default();
+    for filename in &filenames {
+        if let Err(e) = write_stored_file(id, filename, &files[filename], metadata.get(filename), &working_path(filename)) {
+            return exit::report(e);
+        }
+        info!("Restored {} from version {}.", filename, id);
+    }
+}
+
+/// What `scm clean --json` would remove without `-f`.
+#[derive(Serialize)]
+struct CleanPlan<'a> {
+    remove: Vec<&'a String>,
+}
+
+fn do_clean(force: bool, patterns: &[String]) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let mut excludes = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        match globset::Glob::new(pattern) {
+            Ok(glob) => { excludes.add(glob); }
+            Err(e) => {
+                error!("Invalid pattern {}: {}", pattern, e);
+                return;
+            }
+        }
+    }
+    let excludes = excludes.build().expect("Failed to build exclude patterns");
+
+    let head_files = match get_head().and_then(version_files) {
+        Ok(files) => files,
+        Err(e) => return exit::report(e),
+    };
+    let working = match working_files() {
+        Ok(working) => working,
+        Err(e) => return exit::report(e),
+    };
+    let doomed: Vec<(String, PathBuf)> = working
+        .into_iter()
+        .filter(|(name, _)| !head_files.contains_key(name) && !excludes.is_match(name))
+        .collect();
+
+    if doomed.is_empty() {
+        info!("Nothing to clean.");
+        return;
+    }
+    if !force && json::enabled() {
+        json::print(&CleanPlan { remove: doomed.iter().map(|(name, _)| name).collect() });
+        return;
+    }
+    for (name, path) in &doomed {
+        if force {
+            if let Err(e) = remove_working_file(path) {
+                return exit::report(e);
+            }
+            info!("Removed {}", name);
+        } else {
+            println!("Would remove {}", name);
+        }
+    }
+    if !force {
+        println!("Dry run; use -f to delete these files.");
+    }
+}
+
+fn do_oplog(limit: Option<usize>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let limit = limit.unwrap_or(usize::MAX);
+
+    let entries = oplog::read_all();
+    if entries.is_empty() && !json::enabled() {
+        println!("No operations recorded.");
+        return;
+    }
+    for entry in entries.iter().rev().take(limit) {
+        if json::enabled() {
+            json::print(entry);
+            continue;
+        }
+        let date: String = entry.timestamp.chars().take(19).collect();
+        let target = entry.branch.as_deref().unwrap_or("HEAD");
+        println!("{}  {}: {} -> {}  {}", date, target, entry.old_head.map_or(0, VersionId::get), entry.new_head, entry.command);
+    }
+}
+
+/// Creates a branch pointing at `start`, reporting why if it can't.
+fn create_branch(name: &str, start: VersionId) -> bool {
+    if !refs::is_valid_ref_name(name) {
+        error!("Invalid branch name: {}", name);
+        return false;
+    }
+    match refs::read_branch(name) {
+        Ok(Some(_)) => {
+            error!("Branch {} already exists.", name);
+            return false;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            exit::report(e);
+            return false;
+        }
+    }
+    if let Err(e) = refs::write_branch(name, start) {
+        exit::report(e);
+        return false;
+    }
+    true
+}
+
+// --- Helpers ---
+
+/// Files that differ between two filename -> hash maps, each list sorted.
+/// Manifests record one against the first parent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ChangeSet {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modified: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    added: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deleted: Vec<String>,
+}
+
+impl ChangeSet {
+    fn is_empty(&self) -> bool {
+        self.modified.is_empty() && self.added.is_empty() && self.deleted.is_empty()
+    }
+
+    /// Every changed filename, sorted.
+    fn all(&self) -> Vec<&String> {
+        let mut all: Vec<&String> = self.modified.iter().chain(&self.added).chain(&self.deleted).collect();
+        all.sort();
+        all
+    }
+}
+
+/// A change set and its renames as `--json` prints them; unlike in a
+/// manifest, every list is there even when empty.
+#[derive(Serialize)]
+struct ChangeReport<'a> {
+    added: &'a [String],
+    modified: &'a [String],
+    deleted: &'a [String],
+    renamed: Vec<Rename<'a>>,
+}
+
+#[derive(Serialize)]
+struct Rename<'a> {
+    from: &'a str,
+    to: &'a str,
+}
+
+impl<'a> ChangeReport<'a> {
+    fn new(changes: &'a ChangeSet, renames: &'a [(String, String)]) -> ChangeReport<'a> {
+        let renamed = renames.iter().map(|(from, to)| Rename { from, to }).collect();
+        ChangeReport { added: &changes.added, modified: &changes.modified, deleted: &changes.deleted, renamed }
+    }
+}
+
+/// A version as `log` and `show` print it with `--json`.
+#[derive(Serialize)]
+struct VersionReport<'a> {
+    version: VersionId,
+    parents: Vec<VersionId>,
+    refs: Vec<String>,
+    author_name: Option<&'a str>,
+    author_email: Option<&'a str>,
+    timestamp: &'a str,
+    message: Option<&'a str>,
+    files: usize,
+    changes: ChangeCounts,
+    removed: &'a [String],
+    renamed: Vec<Rename<'a>>,
+}
+
+impl<'a> VersionReport<'a> {
+    fn new(header: &'a ManifestHeader, head: Option<VersionId>, current: Option<&str>) -> VersionReport<'a> {
+        let mut renamed: Vec<Rename> = header.renamed.iter().map(|(from, to)| Rename { from, to }).collect();
+        renamed.sort_by_key(|rename| rename.from);
+        VersionReport {
+            version: header.version_id,
+            parents: parents_of(header.version_id),
+            refs: ref_labels(header.version_id, head, current),
+            author_name: header.author_name.as_deref(),
+            author_email: header.author_email.as_deref(),
+            timestamp: &header.timestamp,
+            message: header.message.as_deref(),
+            files: header.files,
+            changes: header.changes,
+            removed: &header.removed,
+            renamed,
+        }
+    }
+}
+
+/// What a version changed relative to its first parent.
+fn version_changes(manifest: &Manifest) -> ChangeSet {
+    manifest.changes.clone().unwrap_or_default()
+}
+
+/// "2 added, 1 deleted" style summary, or None for an empty change set.
+fn change_summary(changes: ChangeCounts) -> Option<String> {
+    let parts: Vec<String> = [(changes.added, "added"), (changes.modified, "modified"), (changes.deleted, "deleted")]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, label)| format!("{} {}", count, label))
+        .collect();
+    if parts.is_empty() { None } else { Some(parts.join(", ")) }
+}
+
+fn compare_files(old: &HashMap<String, FileHash>, new: &HashMap<String, FileHash>) -> ChangeSet {
+    let mut changes = ChangeSet { modified: Vec::new(), added: Vec::new(), deleted: Vec::new() };
+
+    for (filename, hash) in new {
+        match old.get(filename) {
+            Some(old_hash) if old_hash != hash => changes.modified.push(filename.clone()),
+            Some(_) => {}
+            None => changes.added.push(filename.clone()),
+        }
+    }
+    for filename in old.keys() {
+        if !new.contains_key(filename) {
+            changes.deleted.push(filename.clone());
+        }
+    }
+
+    changes.modified.sort();
+    changes.added.sort();
+    changes.deleted.sort();
+    changes
+}
+
+/// Files whose content is the same in `old` as in `files` but whose type or
+/// permission bits differ, as after `chmod +x`: changes `compare_files`,
+/// going by hashes alone, can't see. A version that didn't record the
+/// mode is taken to have had the same.
+fn type_changes(old: &Manifest, files: &HashMap<String, FileHash>, metadata: &HashMap<String, FileMeta>) -> Vec<String> {
+    let default = FileMeta::default();
+    files
+        .iter()
+        .filter(|&(filename, hash)| old.files.get(filename) == Some(hash))
+        .map(|(filename, _)| (filename, old.metadata.get(filename).unwrap_or(&default), metadata.get(filename).unwrap_or(&default)))
+        .filter(|(_, before, after)| before.kind != after.kind || before.target != after.target || before.mode.is_some_and(|mode| after.mode != Some(mode)))
+        .map(|(filename, _, _)| filename.clone())
+        .collect()
+}
+
+/// Moves deleted/added pairs that are really renames out of `changes`: those
+/// recorded by `scm mv` (old -> new), then any with identical content.
+fn pair_renames(
+    changes: &mut ChangeSet,
+    old_files: &HashMap<String, FileHash>,
+    new_files: &HashMap<String, FileHash>,
+    recorded: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    for old in changes.deleted.clone() {
+        let new = match recorded.get(&old) {
+            Some(new) if changes.added.contains(new) => Some(new.clone()),
+            _ => changes.added.iter().find(|added| new_files.get(*added) == old_files.get(&old)).cloned(),
+        };
+        if let Some(new) = new {
+            changes.deleted.retain(|f| *f != old);
+            changes.added.retain(|f| *f != new);
+            pairs.push((old, new));
+        }
+    }
+    pairs
+}
+
+/// Hashes every file in the working directory that would be snapshotted.
+/// Staged files left out by a sparse checkout count as unchanged.
+fn working_hashes() -> Result<HashMap<String, FileHash>> {
+    let mut hashes: HashMap<String, FileHash> = hashed_working_files(false)?.into_iter().map(|(filename, _, hash)| (filename, hash)).collect();
+    statcache::save(&hashes);
+    let sparse = sparse::load();
+    if sparse.is_some() {
+        for (filename, hash) in staged_files()? {
+            if sparse::skipped(&sparse, &filename) {
+                hashes.entry(filename).or_insert(hash);
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Every working file (see `working_files`) with its hash. While the
+/// filesystem monitor runs (see `fsmonitor`) the tree isn't walked: the
+/// files from the last call are taken as they were, bar the paths the
+/// monitor saw change since, which are looked at and hashed again. With
+/// `show_progress`, hashing gets a progress bar (see `progress`).
+fn hashed_working_files(show_progress: bool) -> Result<Vec<(String, PathBuf, FileHash)>> {
+    let mut sync = statcache::enabled().then(fsmonitor::sync).flatten();
+    let mut unchanged = HashMap::new();
+    let changed = match sync.as_mut().and_then(fsmonitor::Sync::changes) {
+        Some((previous, dirty)) => {
+            unchanged = previous;
+            changed_working_files(&mut unchanged, &dirty)?
+        }
+        None => working_files()?,
+    };
+    let progress = show_progress.then(|| Progress::new("Hashing", changed.len(), true));
+    let hashed: Result<Vec<(String, PathBuf, FileHash)>> = changed
+        .into_par_iter()
+        .map(|(filename, path)| {
+            let hash = cached_working_hash(&filename, &path)?;
+            events::file_hashed(&filename, &hash);
+            if let Some(progress) = &progress {
+                progress.inc(fs::symlink_metadata(&path).map_or(0, |meta| meta.len()));
+            }
+            Ok((filename, path, hash))
+        })
+        .collect();
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    let mut files = hashed?;
+    files.extend(unchanged.into_iter().map(|(filename, hash)| (filename.clone(), working_path(&filename), hash)));
+    files.sort();
+    if let Some(sync) = sync {
+        sync.record(&files.iter().map(|(filename, _, hash)| (filename.clone(), hash.clone())).collect())?;
+    }
+    Ok(files)
+}
+
+/// Drops the files at or under the `dirty` paths from `previous`, the
+/// working files and hashes of the last snapshot, and lists the working
+/// files now found there, which need hashing.
+fn changed_working_files(previous: &mut HashMap<String, FileHash>, dirty: &[String]) -> Result<Vec<(String, PathBuf)>> {
+    let dirty_set: HashSet<&str> = dirty.iter().map(String::as_str).collect();
+    let touched = |name: &str| {
+        let mut path = name;
+        loop {
+            if dirty_set.contains(path) {
+                return true;
+            }
+            match path.rsplit_once('/') {
+                Some((parent, _)) => path = parent,
+                None => return false,
+            }
+        }
+    };
+    previous.retain(|name, _| !touched(name));
+
+    let ignore_rules = ignore::IgnoreRules::load();
+    let tracked = (!ignore::include_hidden()).then(|| staged_files().unwrap_or_default());
+    let mut files = Vec::new();
+    let mut empty_dirs = Vec::new();
+    for name in dirty {
+        let mut parents = name.match_indices('/').map(|(i, _)| &name[..i]);
+        if !parents.all(|dir| working_entry(dir, true, &ignore_rules, tracked.as_ref())) {
+            continue;
+        }
+        match fs::symlink_metadata(working_path(name)) {
+            Ok(meta) if meta.is_dir() && working_entry(name, true, &ignore_rules, tracked.as_ref()) => {
+                collect_working_files(&working_path(name), &format!("{}/", name), &ignore_rules, tracked.as_ref(), &mut files, &mut empty_dirs)?;
+            }
+            Ok(meta) if (meta.is_file() || meta.is_symlink()) && working_entry(name, false, &ignore_rules, tracked.as_ref()) => {
+                files.push((name.clone(), working_path(name)));
+            }
+            _ => {}
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Hashes a working file as it would be stored, i.e. after line-ending
+/// normalization. Large files are stored as-is and hashed as-is.
+fn working_hash(filename: &str, path: &Path) -> Result<FileHash> {
+    if symlink::is_symlink(path) || !eol::policy().may_convert(filename) {
+        return calculate_hash(path);
+    }
+    let unreadable = || ScmError::io(format!("Cannot read {}", path.display()));
+    if is_large(filename, fs::metadata(path).map_err(unreadable())?.len()) {
+        return calculate_hash(path);
+    }
+    let content = fs::read(path).map_err(unreadable())?;
+    Ok(hash_bytes(&eol::policy().clean(filename, &content)))
+}
+
+/// `working_hash`, taken from the stat cache (see `statcache`) when the
+/// file's size and mtime show it hasn't changed since it was last hashed.
+fn cached_working_hash(filename: &str, path: &Path) -> Result<FileHash> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return working_hash(filename, path),
+    };
+    if let Some(hash) = statcache::lookup(filename, &meta) {
+        trace!("{} unchanged since it was last hashed", filename);
+        return Ok(hash);
+    }
+    let hash = working_hash(filename, path)?;
+    trace!("Hashed {}: {}", filename, hash);
+    statcache::record(filename, &meta, &hash);
+    Ok(hash)
+}
+
+/// Whether a file is kept in the large-object store: the `large` attribute
+/// decides if set, otherwise the size threshold.
+fn is_large(filename: &str, size: u64) -> bool {
+    attributes::current().large_override(filename).unwrap_or_else(|| size > large::threshold())
+}
+
+/// Lists the files in the working tree that would be snapshotted, as
+/// (relative path with `/` separators, path) pairs sorted by name.
+fn working_files() -> Result<Vec<(String, PathBuf)>> {
+    Ok(working_tree()?.0)
+}
+
+/// The working files (see `working_files`) and the directories that hold
+/// none of them.
+type WorkingTree = (Vec<(String, PathBuf)>, Vec<String>);
+
+/// The working files plus the directories that hold none of them, sorted.
+fn working_tree() -> Result<WorkingTree> {
+    let ignore_rules = ignore::IgnoreRules::load();
+    // Unless hidden files are included, only dotfiles already tracked are kept.
+    let tracked = (!ignore::include_hidden()).then(|| staged_files().unwrap_or_default());
+    let mut files = Vec::new();
+    let mut empty_dirs = Vec::new();
+    collect_working_files(&working_path("."), "", &ignore_rules, tracked.as_ref(), &mut files, &mut empty_dirs)?;
+    files.sort();
+    empty_dirs.sort();
+    Ok((files, empty_dirs))
+}
+
+fn collect_working_files(
+    dir: &Path,
+    prefix: &str,
+    ignore_rules: &ignore::IgnoreRules,
+    tracked: Option<&HashMap<String, FileHash>>,
+    files: &mut Vec<(String, PathBuf)>,
+    empty_dirs: &mut Vec<String>,
+) -> Result<()> {
+    let unreadable = || ScmError::io(format!("Cannot read {}", dir.display()));
+    let entries = fs::read_dir(dir).map_err(unreadable())?;
+
+    for entry in entries {
+        let entry = entry.map_err(unreadable())?;
+        let path = entry.path();
+
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        // Symlinks are entries in their own right, never followed.
+        let file_type = entry.file_type().map_err(ScmError::io(format!("Cannot read {}", path.display())))?;
+        if file_type.is_dir() {
+            if !working_entry(&name, true, ignore_rules, tracked) { continue; }
+            let (files_before, dirs_before) = (files.len(), empty_dirs.len());
+            collect_working_files(&path, &format!("{}/", name), ignore_rules, tracked, files, empty_dirs)?;
+            if files.len() == files_before && empty_dirs.len() == dirs_before {
+                empty_dirs.push(name);
+            }
+        } else if file_type.is_file() || file_type.is_symlink() {
+            if !working_entry(&name, false, ignore_rules, tracked) { continue; }
+            files.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file, or a directory if `is_dir`, belongs in the working tree:
+/// it isn't untracked or ignored, and isn't a dotfile left out for not being
+/// tracked (see `collect_working_files`).
+fn working_entry(name: &str, is_dir: bool, ignore_rules: &ignore::IgnoreRules, tracked: Option<&HashMap<String, FileHash>>) -> bool {
+    if !is_dir && tracking::is_untracked(name) {
+        return false;
+    }
+    if ignore_rules.is_ignored(name, is_dir) {
+        return false;
+    }
+    !tracked.is_some_and(|tracked| {
+        ignore::is_hidden(name) && !tracked.keys().any(|t| if is_dir { path_matches(t, name) } else { t == name })
+    })
+}
+
+/// Turns a path given on the command line, relative to where scm was
+/// started, into the repository's form: relative to the root, with `/`
+/// separators and no `.` or `..` parts.
+fn repo_path(arg: &str) -> String {
+    // An absolute path inside the tree is taken from the root.
+    let root = Some(root()).filter(|root| root.is_absolute()).or_else(|| env::current_dir().ok());
+    let path = match root.and_then(|root| Some(Path::new(arg).strip_prefix(root).ok()?.to_string_lossy().into_owned())) {
+        Some(path) => path.replace('\\', "/"),
+        None => format!("{}/{}", workdir::prefix(), arg.replace('\\', "/")),
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/').filter(|part| !part.is_empty() && *part != ".") {
+        match part {
+            ".." if parts.last().is_some_and(|last| *last != "..") => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Creates any missing parent directories of `path`.
+fn ensure_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent).map_err(ScmError::io(format!("Cannot create {}", parent.display()))),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes a working file, then any parent directories it leaves empty.
+fn remove_working_file(path: &Path) -> Result<()> {
+    fs::remove_file(path).map_err(ScmError::io(format!("Cannot delete {}", path.display())))?;
+    remove_empty_parents(path);
+    Ok(())
+}
+
+fn remove_empty_parents(path: &Path) {
+    let root = root();
+    let mut dir = path.parent();
+    while let Some(parent) = dir
+        && parent != root
+        && fs::remove_dir(parent).is_ok()
+    {
+        dir = parent.parent();
+    }
+}
+
+fn init_repo_if_needed() -> Result<()> {
+    if !scm_dir().exists() {
+        init_repo(refs::DEFAULT_BRANCH)?;
+        info!("Initialized empty SCM repository.");
+    }
+    Ok(())
+}
+
+/// Creates the `.scm` layout, or whatever the storage keeps instead (see
+/// `Storage::init`), with HEAD on an unborn `branch`.
+fn init_repo(branch: &str) -> Result<()> {
+    storage().init()?;
+    if scm_dir().is_dir() {
+        let hooks = scm_dir().join(hooks::HOOKS_DIR);
+        fs::create_dir(&hooks).map_err(ScmError::io(format!("Cannot create {}", hooks.display())))?;
+    }
+    refs::write_head_ref(&refs::HeadRef::Branch(branch.to_string()))
+}
+
+/// Reads an author field, preferring the environment variable over `.scm/config`.
+fn author_setting(env_var: &str, config_key: &str) -> Option<String> {
+    env::var(env_var)
+        .ok()
+        .or_else(|| config::get(config_key))
+        .filter(|value| !value.trim().is_empty())
+}
+
+/// The configured author as "name <email>", for records other than commits.
+fn format_author_setting() -> Option<String> {
+    let name = author_setting("SCM_AUTHOR_NAME", "author.name");
+    let email = author_setting("SCM_AUTHOR_EMAIL", "author.email");
+    join_author(name.as_deref(), email.as_deref())
+}
+
+fn format_author(header: &ManifestHeader) -> Option<String> {
+    join_author(header.author_name.as_deref(), header.author_email.as_deref())
+}
+
+/// Prints a commit message indented under a blank line, if there is one.
+fn print_message(header: &ManifestHeader) {
+    if let Some(message) = &header.message {
+        println!();
+        for line in message.lines() {
+            if line.is_empty() { println!(); } else { println!("    {}", line); }
+        }
+    }
+}
+
+fn join_author(name: Option<&str>, email: Option<&str>) -> Option<String> {
+    match (name, email) {
+        (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+        (Some(name), None) => Some(name.to_string()),
+        (None, Some(email)) => Some(format!("<{}>", email)),
+        (None, None) => None,
+    }
+}
+
+fn get_commit_path(id: VersionId) -> PathBuf {
+    scm_dir().join(COMMITS_DIR).join(id.to_string())
+}
+
+/// Every version's id, ascending.
+fn list_versions() -> Vec<VersionId> {
+    storage().list_versions()
+}
+
+/// The id for a new version. Versions newer than HEAD may still exist after a
+/// revert or on other branches, so ids are never reused.
+fn next_version_id() -> Result<VersionId> {
+    Ok(list_versions().last().copied().max(get_head()?).map_or(VersionId::FIRST, VersionId::next))
+}
+
+/// Stores a version's manifest (see `Storage::put_manifest`), replacing
+/// any it had, and brings the commit graph up to date.
+fn write_manifest(manifest: &Manifest) -> Result<()> {
+    storage().put_manifest(manifest)?;
+    graph::update(manifest);
+    Ok(())
+}
+
+fn header_of(manifest: &Manifest) -> ManifestHeader {
+    let changes = version_changes(manifest);
+    ManifestHeader {
+        version_id: manifest.version_id,
+        timestamp: manifest.timestamp.clone(),
+        message: manifest.message.clone(),
+        author_name: manifest.author_name.clone(),
+        author_email: manifest.author_email.clone(),
+        parent: manifest.parent,
+        merge_parent: manifest.merge_parent,
+        removed: manifest.removed.clone(),
+        renamed: manifest.renamed.clone(),
+        files: manifest.files.len(),
+        changes: ChangeCounts { added: changes.added.len(), modified: changes.modified.len(), deleted: changes.deleted.len() },
+    }
+}
+
+/// A version's header (see `ManifestHeader`), read from the full manifest
+/// for versions written before headers existed. None if it is missing or
+/// unreadable.
+fn load_header(id: VersionId) -> Option<ManifestHeader> {
+    storage().get_header(id).or_else(|| load_manifest(id).ok().map(|manifest| header_of(&manifest)))
+}
+
+/// A version's manifest, upgraded to the current format (see
+/// `MANIFEST_FORMAT`). `CorruptManifest` if it is missing or unreadable.
+fn load_manifest(id: VersionId) -> Result<Manifest> {
+    let mut manifest = storage().get_manifest(id)?.ok_or(ScmError::CorruptManifest(id))?;
+    if manifest.format_version > MANIFEST_FORMAT {
+        return Err(ScmError::NewerFormat { version: id, format: manifest.format_version });
+    }
+    if manifest.format_version < 2 {
+        if manifest.parent.is_none() {
+            manifest.parent = list_versions().into_iter().rev().find(|&v| v < id);
+        }
+        if manifest.changes.is_none() {
+            let parent_files = match manifest.parent {
+                Some(parent) => storage().get_manifest(parent)?.map(|m| m.files).unwrap_or_default(),
+                None => HashMap::new(),
+            };
+            manifest.changes = Some(compare_files(&parent_files, &manifest.files));
+        }
+    }
+    manifest.format_version = MANIFEST_FORMAT;
+    Ok(manifest)
+}
+
+fn manifest_exists(id: VersionId) -> bool {
+    storage().has_manifest(id)
+}
+
+/// Resolves a version given on the command line (a numeric id, a tag name or
+/// `HEAD`), returning it only if that version exists in the repository.
+/// A malformed HEAD or branch is an error rather than no such version.
+fn resolve_version(spec: &str) -> Result<Option<VersionId>> {
+    let id = match spec.parse() {
+        Ok(id) => Some(id),
+        Err(_) if spec == "HEAD" => get_head()?,
+        Err(_) => match refs::read_branch(spec)? {
+            Some(id) => Some(id),
+            None => refs::read_tag(spec).map(|tag| tag.version_id),
+        },
+    };
+    Ok(id.filter(|&id| manifest_exists(id)))
+}
+
+/// The filename -> hash map recorded for a version, or an empty map for
+/// none (the state before the first commit).
+fn version_files(id: Option<VersionId>) -> Result<HashMap<String, FileHash>> {
+    match id {
+        Some(id) => Ok(load_manifest(id)?.files),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// A file's stored content in a version (see `read_stored`), looking its
+/// hash up in the version's manifest.
+fn read_stored_file(id: VersionId, filename: &str) -> Result<Vec<u8>> {
+    match load_manifest(id)?.files.get(filename) {
+        Some(hash) => read_stored(id, filename, hash),
+        None => Err(ScmError::PathNotFound(filename.to_string())),
+    }
+}
+
+/// The content recorded as `hash` for a file in a version, from the object
+/// store, its chunks, or the version's own copy for versions committed
+/// before the object store existed.
+fn read_stored(id: VersionId, filename: &str, hash: &str) -> Result<Vec<u8>> {
+    try_read_stored(id, filename, hash)?.ok_or_else(|| ScmError::MissingObject { version: id, path: filename.to_string() })
+}
+
+/// `read_stored`, with None for content that is missing rather than damaged.
+fn try_read_stored(id: VersionId, filename: &str, hash: &str) -> Result<Option<Vec<u8>>> {
+    if let Some(content) = storage().get_object(hash)? {
+        return Ok(Some(content));
+    }
+    if let Some(chunks) = stored_chunks(id, filename, hash) {
+        return chunk::read(&chunks);
+    }
+    Ok(storage().legacy_copy(id, filename).and_then(|path| fs::read(path).ok()))
+}
+
+/// The chunk list a version's manifest records for a file stored as chunks.
+fn stored_chunks(id: VersionId, filename: &str, hash: &str) -> Option<Vec<FileHash>> {
+    let mut manifest = load_manifest(id).ok()?;
+    if manifest.files.get(filename)? != hash {
+        return None;
+    }
+    manifest.metadata.remove(filename)?.chunks
+}
+
+/// Checks that a version's stored content for a file exists and hashes to
+/// `hash`. Large files are hashed without reading them into memory.
+fn verify_stored(id: VersionId, filename: &str, hash: &str) -> Result<()> {
+    let missing = || ScmError::MissingObject { version: id, path: filename.to_string() };
+    let corrupted = |problem: String| match problem.as_str() {
+        "missing" => missing(),
+        _ => ScmError::IntegrityFailure(format!("The stored copy of {} in version {} is damaged: {}.", filename, id, problem)),
+    };
+    let actual = if storage().has(hash)? {
+        return storage().verify_object(hash).map_err(corrupted);
+    } else if let Some(chunks) = stored_chunks(id, filename, hash) {
+        return chunk::verify(&chunks, hash).map_err(corrupted);
+    } else {
+        calculate_hash(&storage().legacy_copy(id, filename).ok_or_else(missing)?)?
+    };
+    if *actual != *hash { Err(corrupted("corrupted (content does not match its hash)".to_string())) } else { Ok(()) }
+}
+
+/// A file's stored content in a version, or nothing if `files` (that
+/// version's manifest) doesn't track it or there is no version.
+fn read_stored_or_empty(id: Option<VersionId>, files: &HashMap<String, FileHash>, name: &str) -> Result<Vec<u8>> {
+    match (id, files.get(name)) {
+        (Some(id), Some(hash)) => read_stored(id, name, hash),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The version HEAD currently resolves to, or None before the first commit.
+fn get_head() -> Result<Option<VersionId>> {
+    match refs::read_head_ref()? {
+        refs::HeadRef::Branch(name) => refs::read_branch(&name),
+        refs::HeadRef::Detached(id) => Ok(id),
+    }
+}
+
+/// Moves HEAD to a version, resetting the staging area, and records the move
+/// in the oplog. On a branch this advances only that branch.
+fn set_head(id: VersionId) -> Result<()> {
+    let old_head = get_head()?;
+    index::clear();
+    match refs::read_head_ref()? {
+        refs::HeadRef::Branch(name) => {
+            refs::write_branch(&name, id)?;
+            oplog::record(Some(name), old_head, id)
+        }
+        refs::HeadRef::Detached(_) => {
+            refs::write_head_ref(&refs::HeadRef::Detached(Some(id)))?;
+            oplog::record(None, old_head, id)
+        }
+    }
+}
+
+/// The parent of a version.
+fn parent_of(id: VersionId) -> Option<VersionId> {
+    graph::entry(id)?.parent
+}
+
+/// Every parent of a version, including the second parent of merge commits.
+fn parents_of(id: VersionId) -> Vec<VersionId> {
+    let mut parents: Vec<VersionId> = parent_of(id).into_iter().collect();
+    if let Some(merge_parent) = graph::entry(id).and_then(|e| e.merge_parent) {
+        parents.push(merge_parent);
+    }
+    parents
+}
+
+/// Every version reachable from the given ones through parent links,
+/// including the starting versions themselves.
+fn ancestors(start: &[VersionId]) -> HashSet<VersionId> {
+    let mut seen = HashSet::new();
+    let mut queue: VecDeque<VersionId> = start.iter().copied().collect();
+    while let Some(id) = queue.pop_front() {
+        if seen.insert(id) {
+            queue.extend(parents_of(id));
+        }
+    }
+    seen
+}
+
+/// The nearest version reachable from both `a` and `b` through parent links.
+fn merge_base(a: VersionId, b: VersionId) -> Option<VersionId> {
+    let ancestors_of_a = ancestors(&[a]);
+
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([b]);
+    while let Some(id) = queue.pop_front() {
+        if ancestors_of_a.contains(&id) {
+            return Some(id);
+        }
+        if seen.insert(id) {
+            queue.extend(parents_of(id));
+        }
+    }
+    None
+}
+
+/// The version being merged in, if a conflicted merge awaits its commit.
+fn pending_merge() -> Option<VersionId> {
+    let content = storage::read_to_string(MERGE_HEAD_FILE).ok()?;
+    content.trim().parse().ok()
+}
+
+/// Labels like " (HEAD -> main, tag: v1)" for the log.
+fn decorations(id: VersionId, head: Option<VersionId>, current: Option<&str>) -> String {
+    let labels = ref_labels(id, head, current);
+    if labels.is_empty() { String::new() } else { format!(" ({})", labels.join(", ")) }
+}
+
+/// What points at version `id`: HEAD, which is at `head` and on branch
+/// `current` if any, other branches and tags.
+fn ref_labels(id: VersionId, head: Option<VersionId>, current: Option<&str>) -> Vec<String> {
+    let mut labels = Vec::new();
+    if Some(id) == head && current.is_none() {
+        labels.push("HEAD".to_string());
+    }
+    for (name, branch_id) in refs::list_branches() {
+        if branch_id != id { continue; }
+        if current == Some(name.as_str()) {
+            labels.push(format!("HEAD -> {}", name));
+        } else {
+            labels.push(name);
+        }
+    }
+    for (name, tag) in refs::list_tags() {
+        if tag.version_id == id {
+            labels.push(format!("tag: {}", name));
+        }
+    }
+    labels
+}
+
+/// Hashes a file's contents with the repository's algorithm (see `hash`);
+/// for a symlink, the path it points at.
+fn calculate_hash(path: &Path) -> Result<FileHash> {
+    if symlink::is_symlink(path) {
+        return Ok(hash_bytes(symlink::read_target(path)?.as_bytes()));
+    }
+    hash::file(path).map_err(ScmError::io(format!("Cannot read {}", path.display())))
+}
+
+/// Copies a file, or a directory and everything below it, as reflinks
+/// where possible; with `share`, as hardlinks (see `link`).
+fn copy_recursive(src: &Path, dst: &Path, share: bool) -> Result<()> {
+    let failed = || ScmError::io(format!("Cannot copy {}", src.display()));
+    if src.is_dir() {
+        fs::create_dir_all(dst).map_err(ScmError::io(format!("Cannot create {}", dst.display())))?;
+        for entry in fs::read_dir(src).map_err(failed())? {
+            let entry = entry.map_err(failed())?;
+            copy_recursive(&entry.path(), &dst.join(entry.file_name()), share)?;
+        }
+        Ok(())
+    } else if share {
+        link::share(src, dst).map_err(failed())
+    } else {
+        link::clone_file(src, dst).map_err(failed())
+    }
+}
+
+/// Limits how many files are hashed and stored at once. By default there is
+/// one thread per CPU.
+fn set_jobs(jobs: usize) {
+    rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global().expect("Failed to start threads");
+}
+
+fn hash_bytes(content: &[u8]) -> FileHash {
+    let mut hasher = hash::Hasher::new();
+    hasher.update(content);
+    hasher.finish()
+}
@@ -0,0 +1,25 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Copies `src` to `dst` without duplicating data where possible: on
+/// copy-on-write filesystems (Btrfs, XFS, APFS, ReFS) the copy is a reflink
+/// sharing the original's blocks until either side changes; elsewhere it is
+/// a plain copy.
+///
+/// Working files are never hardlinked into storage, since an editor that
+/// saves in place would silently change history too; see `share`.
+pub fn clone_file(src: &Path, dst: &Path) -> io::Result<()> {
+    if dst.exists() {
+        // A reflink can't replace an existing file.
+        fs::remove_file(dst)?;
+    }
+    reflink_copy::reflink_or_copy(src, dst).map(|_| ())
+}
+
+/// Gives `dst` the content of `src`, a file that is never modified in place
+/// (objects and packs are only ever replaced by rename): a hardlink if
+/// possible, otherwise as `clone_file`.
+pub fn share(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::hard_link(src, dst).or_else(|_| clone_file(src, dst))
+}
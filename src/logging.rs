@@ -0,0 +1,158 @@
+use std::env;
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::panic;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::{color, config, exit, json, scm_dir};
+
+/// Messages go through `tracing`. Errors and warnings are printed to
+/// stderr, and the usual notes on what a command is doing (`info`) to
+/// stdout; per-file detail
+/// (`debug`) only with `-v` and finer detail (`trace`) with `-vv`, while
+/// `-q` leaves nothing but errors. What a command prints as its result
+/// (listings, logs, diffs) is printed directly and isn't affected.
+///
+/// With `log.level` set in the config (say "debug"), events at that level
+/// and above also go to `.scm/log`, with timestamps, each command line and
+/// any panic, so a failed operation can be looked into afterwards.
+const LOG_FILE: &str = "log";
+const LEVEL_KEY: &str = "log.level";
+/// Events for the log file only.
+const PANIC_TARGET: &str = "panic";
+
+/// Whether per-file detail is printed, which progress bars would garble.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Sets up printing for `-q` and `-v` (given `verbose` times) and, if the
+/// config asks for it, the log file.
+pub fn init(quiet: bool, verbose: u8) {
+    let console = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    VERBOSE.store(console > LevelFilter::INFO, Ordering::Relaxed);
+
+    let mut invalid = None;
+    let log_file = match scm_dir().is_dir().then(|| config::get(LEVEL_KEY)).flatten() {
+        Some(value) => match LevelFilter::from_str(&value) {
+            Ok(level) => open_log().map(|file| LogFile(Mutex::new(file)).with_filter(level)),
+            Err(_) => {
+                invalid = Some(value);
+                None
+            }
+        },
+        None => None,
+    };
+    let logging = log_file.is_some();
+    tracing_subscriber::registry().with(Console.with_filter(console)).with(log_file).init();
+
+    if let Some(value) = invalid {
+        tracing::warn!("invalid {} {:?}; not logging to .scm/{}.", LEVEL_KEY, value, LOG_FILE);
+    }
+    if logging {
+        let report = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            report(info);
+            tracing::error!(target: PANIC_TARGET, "{}", info);
+        }));
+    }
+}
+
+fn open_log() -> Option<File> {
+    let mut file = OpenOptions::new().create(true).append(true).open(scm_dir().join(LOG_FILE)).ok()?;
+    let command: Vec<String> = env::args().skip(1).collect();
+    let _ = writeln!(file, "{} ----- scm {}", timestamp(), command.join(" "));
+    Some(file)
+}
+
+fn timestamp() -> String {
+    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+/// An event's message.
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        }
+    }
+}
+
+fn message(event: &Event<'_>) -> String {
+    let mut message = Message(String::new());
+    event.record(&mut message);
+    message.0
+}
+
+/// Prints events as plain lines, warnings marked as such, or with `--json`
+/// as objects: errors and warnings to stderr, the rest to stdout.
+struct Console;
+
+#[derive(Serialize)]
+struct JsonEvent {
+    level: String,
+    message: String,
+}
+
+impl<S: Subscriber> Layer<S> for Console {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if event.metadata().target() == PANIC_TARGET {
+            return;
+        }
+        // A command that reports an error has failed; see `exit`.
+        if *event.metadata().level() == Level::ERROR {
+            exit::set(exit::FAILURE);
+        }
+        let level = *event.metadata().level();
+        let line = match level {
+            _ if json::enabled() => json::line(&JsonEvent { level: level.as_str().to_lowercase(), message: message(event) }),
+            Level::WARN => format!("{} {}", color::warning(), message(event)),
+            _ => message(event),
+        };
+        match level {
+            Level::ERROR | Level::WARN => eprintln!("{}", line),
+            _ => println!("{}", line),
+        }
+    }
+}
+
+/// Appends events to `.scm/log`, without color.
+struct LogFile(Mutex<File>);
+
+impl<S: Subscriber> Layer<S> for LogFile {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let line = format!("{} {:<5} {}\n", timestamp(), event.metadata().level(), strip_color(&message(event)));
+        let _ = self.0.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+/// `text` without the escape sequences `color` adds.
+fn strip_color(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("\x1b[") {
+        plain.push_str(&rest[..start]);
+        rest = &rest[start..];
+        rest = rest.find('m').map_or("", |end| &rest[end + 1..]);
+    }
+    plain.push_str(rest);
+    plain
+}
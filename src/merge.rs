@@ -0,0 +1,163 @@
+use std::fs;
+use std::process::Command;
+use similar::{MergeResolution, TextMerge};
+use tracing::{error, warn};
+
+use crate::attributes::{self, AttrValue};
+use crate::diff::as_text;
+use crate::{config, scm_dir};
+
+/// Scratch files handed to a merge driver for the base, ours and theirs.
+const DRIVER_FILES: [&str; 3] = ["MERGE_DRIVER_BASE", "MERGE_DRIVER_OURS", "MERGE_DRIVER_THEIRS"];
+
+/// Result of merging one file's content from both sides.
+pub enum MergeOutcome {
+    /// Both sides' changes combined without overlap.
+    Clean(Vec<u8>),
+    /// Overlapping edits; the content carries conflict markers.
+    Conflict(Vec<u8>),
+    /// At least one side isn't text, so no content merge was attempted.
+    Binary,
+}
+
+/// Three-way merge of `ours` and `theirs` against their common ancestor
+/// `base` (empty when both sides added the file independently). The `merge`
+/// attribute picks the driver:
+///
+/// - `merge=text` (the default): line-based, with conflict markers;
+/// - `merge=binary` or `-merge`: never merged, like binary content;
+/// - `merge=union`: line-based, keeping both sides' lines on overlap;
+/// - `merge=<name>`: the `merge.<name>.driver` command from the config.
+///
+/// Without a `merge` attribute, files declared binary in `.scmattributes`,
+/// or that look binary, are never text-merged.
+pub fn merge_contents(path: &str, base: &[u8], ours: &[u8], theirs: &[u8], ours_label: &str, theirs_label: &str) -> MergeOutcome {
+    let attributes = attributes::current();
+    let union = match attributes.get(path, "merge") {
+        Some(AttrValue::Unset) => return MergeOutcome::Binary,
+        Some(AttrValue::Value(driver)) => match driver.as_str() {
+            "binary" => return MergeOutcome::Binary,
+            "union" => true,
+            "text" => false,
+            name => match config::get(&format!("merge.{}.driver", name)) {
+                Some(command) => return run_driver(&command, path, base, ours, theirs),
+                None => {
+                    warn!("merge driver '{}' for {} is not configured; merging as text.", name, path);
+                    false
+                }
+            },
+        },
+        Some(AttrValue::Set) => false,
+        None if attributes.binary_override(path) == Some(true) => return MergeOutcome::Binary,
+        None => false,
+    };
+    let (base, ours, theirs) = match (as_text(base), as_text(ours), as_text(theirs)) {
+        (Some(b), Some(o), Some(t)) => (b, o, t),
+        _ => return MergeOutcome::Binary,
+    };
+
+    let mut merge = TextMerge::from_lines(base, ours, theirs);
+    if union {
+        return MergeOutcome::Clean(union_lines(&merge).into_bytes());
+    }
+    merge.labels("base", ours_label, theirs_label);
+    let merged = merge.to_string().into_bytes();
+
+    if merge.is_conflicted() {
+        MergeOutcome::Conflict(merged)
+    } else {
+        MergeOutcome::Clean(merged)
+    }
+}
+
+/// The merge with each conflicting region replaced by our lines followed by
+/// theirs, as git's union driver does.
+fn union_lines(merge: &TextMerge<'_, '_, '_, str>) -> String {
+    let mut out = String::new();
+    let mut push = |line: &str| {
+        out.push_str(line);
+        if !line.ends_with('\n') {
+            out.push('\n');
+        }
+    };
+    for region in merge.regions() {
+        match region.resolution() {
+            MergeResolution::Theirs => region.theirs_range().for_each(|i| push(merge.theirs_line(i).unwrap())),
+            MergeResolution::Conflict => {
+                region.ours_range().for_each(|i| push(merge.ours_line(i).unwrap()));
+                region.theirs_range().for_each(|i| push(merge.theirs_line(i).unwrap()));
+            }
+            _ => region.ours_range().for_each(|i| push(merge.ours_line(i).unwrap())),
+        }
+    }
+    out
+}
+
+/// Runs a configured merge driver, git style: the three sides are written to
+/// scratch files and `%O`, `%A`, `%B` and `%P` in the command are replaced
+/// with the base, ours and theirs files and the path. The driver leaves its
+/// result in the ours file and exits non-zero if conflicts remain.
+fn run_driver(command: &str, path: &str, base: &[u8], ours: &[u8], theirs: &[u8]) -> MergeOutcome {
+    let files = DRIVER_FILES.map(|name| scm_dir().join(name));
+    for (file, content) in files.iter().zip([base, ours, theirs]) {
+        fs::write(file, content).expect("Failed to write merge driver input");
+    }
+    let command = command
+        .replace("%O", &files[0].to_string_lossy())
+        .replace("%A", &files[1].to_string_lossy())
+        .replace("%B", &files[2].to_string_lossy())
+        .replace("%P", path);
+
+    let status = Command::new("sh").arg("-c").arg(&command).status();
+    let merged = fs::read(&files[1]).expect("Failed to read merge driver result");
+    for file in &files {
+        let _ = fs::remove_file(file);
+    }
+    match status {
+        Ok(status) if status.success() => MergeOutcome::Clean(merged),
+        Ok(_) => MergeOutcome::Conflict(merged),
+        Err(e) => {
+            error!("Merge driver '{}' could not be run: {}", command, e);
+            MergeOutcome::Conflict(ours.to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::Repository;
+
+    /// Merges in a fresh in-memory repository, so `merge_contents` has the
+    /// (empty) attributes of one to go by.
+    fn merge(name: &str, base: &str, ours: &str, theirs: &str) -> MergeOutcome {
+        let dir = env::temp_dir().join(format!("scm-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init_in_memory(&dir).unwrap();
+        repo.run(|| Ok(merge_contents("a.txt", base.as_bytes(), ours.as_bytes(), theirs.as_bytes(), "ours", "theirs"))).unwrap()
+    }
+
+    #[test]
+    fn separate_edits_merge_cleanly() {
+        let outcome = merge("clean-merge", "one\ntwo\nthree\n", "ONE\ntwo\nthree\n", "one\ntwo\nTHREE\n");
+        assert!(matches!(outcome, MergeOutcome::Clean(content) if content == b"ONE\ntwo\nTHREE\n"));
+    }
+
+    #[test]
+    fn overlapping_edits_conflict() {
+        let MergeOutcome::Conflict(content) = merge("conflicted-merge", "one\ntwo\n", "uno\ntwo\n", "eins\ntwo\n") else {
+            panic!("expected a conflict");
+        };
+        let content = String::from_utf8(content).unwrap();
+        assert!(content.contains("<<<<<<< ours\nuno\n"), "{}", content);
+        assert!(content.contains("eins\n>>>>>>> theirs\ntwo\n"), "{}", content);
+    }
+
+    #[test]
+    fn binary_content_is_not_merged() {
+        assert!(matches!(merge("binary-merge", "one\n", "one\0\n", "two\n"), MergeOutcome::Binary));
+    }
+}
@@ -0,0 +1,34 @@
+use tracing::{error, info, warn};
+
+use crate::storage::storage;
+use crate::{exit, list_versions, load_manifest, scm_dir, write_manifest, ScmError, MANIFEST_FORMAT};
+
+/// `scm migrate`: rewrites every manifest older than the current format (see
+/// `MANIFEST_FORMAT`) in that format, so they no longer need upgrading each
+/// time they are read, and writes any missing manifest header. Current
+/// compression and encryption settings apply.
+pub fn do_migrate() {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let mut migrated = 0;
+    for id in list_versions() {
+        let stored = match storage().get_manifest(id) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) | Err(ScmError::CorruptManifest(_)) => {
+                warn!("Skipping version {}: manifest is missing or unreadable (see 'scm fsck').", id);
+                continue;
+            }
+            Err(e) => return exit::report(e),
+        };
+        if stored.format_version < MANIFEST_FORMAT || storage().get_header(id).is_none() {
+            if let Err(e) = load_manifest(id).and_then(|manifest| write_manifest(&manifest)) {
+                return exit::report(e);
+            }
+            migrated += 1;
+        }
+    }
+    info!("Migrated {} manifest(s) to format {}.", migrated, MANIFEST_FORMAT);
+}
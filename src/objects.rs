@@ -0,0 +1,350 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{config, crypt, delta, diff, hash_bytes, large, link, pack, scm_dir, FileHash, Result, ScmError};
+
+/// Content-addressed store for file contents, shared by all versions:
+/// `.scm/objects/<first two hash chars>/<rest of hash>`. Manifests refer to
+/// contents by hash, so a file that doesn't change is stored only once.
+///
+/// With `core.compression = "zstd"` new objects are written zstd-compressed
+/// under the same name plus `.zst`; the suffix is the per-object flag, so
+/// objects written before compression was turned on still read.
+///
+/// Text files of at least `delta.min_size` (default 16K) may instead be
+/// stored as `<name>.delta`, a delta against the previous version's object
+/// (see `delta`). After `delta.max_chain` (default 10) deltas in a row the
+/// next version is stored in full again, so reads stay bounded.
+///
+/// Objects are "loose" files as above until `scm repack` moves them into a
+/// pack (see `pack`); reads look in both. In an encrypted repository every
+/// object is sealed as the last step before writing (see `crypt`).
+const OBJECTS_DIR: &str = "objects";
+const COMPRESSED_SUFFIX: &str = ".zst";
+const DELTA_SUFFIX: &str = ".delta";
+const ZSTD_LEVEL: i32 = 3;
+const DEFAULT_DELTA_MIN_SIZE: u64 = 16 * 1024;
+const DEFAULT_DELTA_MAX_CHAIN: usize = 10;
+
+pub fn object_path(hash: &str) -> PathBuf {
+    let (dir, rest) = hash.split_at(2.min(hash.len()));
+    scm_dir().join(OBJECTS_DIR).join(dir).join(rest)
+}
+
+/// The file an object is stored in for a given form.
+fn stored_path(hash: &str, is_delta: bool, compressed: bool) -> PathBuf {
+    let mut path = object_path(hash);
+    if is_delta {
+        path = with_suffix(&path, DELTA_SUFFIX);
+    }
+    if compressed {
+        path = with_suffix(&path, COMPRESSED_SUFFIX);
+    }
+    path
+}
+
+/// The forms an object may be stored in, as (delta, compressed) flags.
+const FORMS: [(bool, bool); 4] = [(false, false), (false, true), (true, false), (true, true)];
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// `path` with `.zst` appended, for files stored compressed.
+pub fn compressed_name(path: &Path) -> PathBuf {
+    with_suffix(path, COMPRESSED_SUFFIX)
+}
+
+/// Whether newly stored objects and manifests are compressed.
+pub fn compression_enabled() -> bool {
+    config::get("core.compression").as_deref() == Some("zstd")
+}
+
+pub fn exists(hash: &str) -> Result<bool> {
+    Ok(FORMS.iter().any(|&(is_delta, compressed)| stored_path(hash, is_delta, compressed).exists()) || pack::contains(hash)?)
+}
+
+/// The object's bytes as stored, loose or packed and decrypted, with its
+/// (delta, compressed) flags.
+fn read_stored_form(hash: &str) -> Result<Option<(Vec<u8>, bool, bool)>> {
+    let Some((sealed, is_delta, compressed)) = read_sealed_form(hash)? else {
+        return Ok(None);
+    };
+    let bytes = crypt::open(sealed)?.ok_or_else(|| damaged(hash, "cannot be decrypted (corrupt or tampered with)"))?;
+    Ok(Some((bytes, is_delta, compressed)))
+}
+
+fn damaged(hash: &str, problem: &str) -> ScmError {
+    ScmError::IntegrityFailure(format!("Object {}: {}.", hash, problem))
+}
+
+/// Like `read_stored_form`, but still encrypted in an encrypted repository.
+fn read_sealed_form(hash: &str) -> Result<Option<(Vec<u8>, bool, bool)>> {
+    let loose = FORMS.iter().find_map(|&(is_delta, compressed)| Some((fs::read(stored_path(hash, is_delta, compressed)).ok()?, is_delta, compressed)));
+    match loose {
+        Some(loose) => Ok(Some(loose)),
+        None => Ok(pack::read(hash)?.map(|(bytes, entry)| (bytes, entry.delta, entry.compressed))),
+    }
+}
+
+/// The object's stored bytes, decompressed, and whether they are a delta.
+fn read_raw(hash: &str) -> Result<Option<(Vec<u8>, bool)>> {
+    let Some((bytes, is_delta, compressed)) = read_stored_form(hash)? else {
+        return Ok(None);
+    };
+    let raw = if compressed { try_decompress(&bytes).ok_or_else(|| damaged(hash, "cannot be decompressed"))? } else { bytes };
+    Ok(Some((raw, is_delta)))
+}
+
+/// An object's content, or None if it isn't stored. Deltas are applied to
+/// their base and the result is checked against the hash; an object that
+/// can't be read back is an `IntegrityFailure`.
+pub fn read(hash: &str) -> Result<Option<Vec<u8>>> {
+    let Some((raw, is_delta)) = read_raw(hash)? else {
+        return Ok(None);
+    };
+    if !is_delta {
+        return Ok(Some(raw));
+    }
+    let header = delta::header(&raw).ok_or_else(|| damaged(hash, "corrupt delta header"))?;
+    let base = read(&header.base)?.ok_or_else(|| damaged(hash, &format!("delta base {} missing", header.base)))?;
+    let content = delta::apply(&base, &raw).ok_or_else(|| damaged(hash, "corrupt delta"))?;
+    if hash_bytes(&content) != hash {
+        return Err(damaged(hash, "corrupted (content does not match its hash)"));
+    }
+    Ok(Some(content))
+}
+
+/// Checks that an object can be read back and matches its hash, describing
+/// what is wrong instead of panicking.
+pub fn verify(hash: &str) -> Result<(), String> {
+    let (sealed, is_delta, compressed) = read_sealed_form(hash).map_err(|e| e.to_string())?.ok_or("missing")?;
+    let bytes = crypt::open(sealed).map_err(|e| e.to_string())?.ok_or("cannot be decrypted (corrupt or tampered with)")?;
+    let raw = if compressed { try_decompress(&bytes).ok_or("cannot be decompressed")? } else { bytes };
+    let content = if is_delta {
+        let header = delta::header(&raw).ok_or("corrupt delta header")?;
+        verify(&header.base).map_err(|e| format!("delta base {}: {}", header.base, e))?;
+        let base = read(&header.base).ok().flatten().ok_or("delta base changed while being read")?;
+        delta::apply(&base, &raw).ok_or("corrupt delta")?
+    } else {
+        raw
+    };
+    if hash_bytes(&content) != hash {
+        return Err("corrupted (content does not match its hash)".to_string());
+    }
+    Ok(())
+}
+
+/// The object a delta object is stored against, if it is one.
+pub fn delta_base(hash: &str) -> Option<FileHash> {
+    match read_raw(hash).ok().flatten()? {
+        (raw, true) => delta::header(&raw).and_then(|header| header.base.parse().ok()),
+        _ => None,
+    }
+}
+
+pub fn try_decompress(compressed: &[u8]) -> Option<Vec<u8>> {
+    zstd::decode_all(compressed).ok()
+}
+
+pub fn compress(content: &[u8]) -> Vec<u8> {
+    zstd::encode_all(content, ZSTD_LEVEL).expect("Failed to compress")
+}
+
+/// Stores content unless an object with its hash is already there, as a
+/// delta against the object `base` (the previous version of the same file)
+/// when that is allowed and saves at least half the size.
+pub fn store(content: &[u8], hash: &str, base: Option<&str>) -> Result<()> {
+    if exists(hash)? {
+        return Ok(());
+    }
+    match base.and_then(|base| make_delta(content, base)) {
+        Some(delta) => write_object(hash, true, &delta),
+        None => write_object(hash, false, content),
+    }
+}
+
+/// Like `store`, for content read unchanged from the file `src`. If the
+/// object would hold exactly those bytes (no delta, compression or
+/// encryption), it is made as a copy of `src` that shares its blocks on
+/// copy-on-write filesystems (see `link`).
+pub fn store_from(src: &Path, content: &[u8], hash: &str, base: Option<&str>) -> Result<()> {
+    if exists(hash)? {
+        return Ok(());
+    }
+    if let Some(delta) = base.and_then(|base| make_delta(content, base)) {
+        write_object(hash, true, &delta)
+    } else if compression_enabled() || crypt::enabled() {
+        write_object(hash, false, content)
+    } else {
+        let dest = stored_path(hash, false, false);
+        create_parent(&dest)?;
+        let partial = partial_path(&dest);
+        link::clone_file(src, &partial).map_err(ScmError::io(format!("Cannot store {}", src.display())))?;
+        fs::rename(&partial, &dest).map_err(ScmError::io(format!("Cannot store {}", src.display())))
+    }
+}
+
+/// The loose file holding an object byte for byte, if there is one: one
+/// that isn't a delta, compressed, encrypted or packed.
+pub fn plain_path(hash: &str) -> Option<PathBuf> {
+    let path = stored_path(hash, false, false);
+    (!crypt::enabled() && path.is_file()).then_some(path)
+}
+
+/// Writes content into the store in full unless an object with its hash is already there.
+pub fn store_bytes(content: &[u8], hash: &str) -> Result<()> {
+    store(content, hash, None)
+}
+
+fn make_delta(content: &[u8], base_hash: &str) -> Option<Vec<u8>> {
+    if (content.len() as u64) < delta_min_size() || diff::looks_binary(content) {
+        return None;
+    }
+    // A base that can't be read back is no base; the content is stored in full.
+    let (raw, is_delta) = read_raw(base_hash).ok().flatten()?;
+    let depth = if is_delta { delta::header(&raw)?.depth + 1 } else { 1 };
+    if depth > delta_max_chain() {
+        return None;
+    }
+    let base = if is_delta { read(base_hash).ok().flatten()? } else { raw };
+    let encoded = delta::encode(base_hash, depth, &base, content);
+    (encoded.len() < content.len() / 2).then_some(encoded)
+}
+
+fn delta_min_size() -> u64 {
+    config::get("delta.min_size").and_then(|v| large::parse_size(&v)).unwrap_or(DEFAULT_DELTA_MIN_SIZE)
+}
+
+fn delta_max_chain() -> usize {
+    config::get("delta.max_chain").and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_DELTA_MAX_CHAIN)
+}
+
+fn write_object(hash: &str, is_delta: bool, content: &[u8]) -> Result<()> {
+    if compression_enabled() {
+        write_atomic(&stored_path(hash, is_delta, true), &crypt::seal(compress(content))?)
+    } else {
+        write_atomic(&stored_path(hash, is_delta, false), &crypt::seal(content.to_vec())?)
+    }
+}
+
+/// Moves every object, loose or already packed, into a single new pack and
+/// deletes the loose files and old packs, returning the number of objects.
+/// With `compress_all`, objects not yet compressed are compressed on the way.
+pub fn repack(compress_all: bool) -> Result<usize> {
+    let hashes = list()?;
+    if hashes.is_empty() {
+        return Ok(0);
+    }
+    pack::write(&hashes, |hash| {
+        if compress_all {
+            let (bytes, is_delta, compressed) = read_stored_form(hash)?.ok_or_else(|| damaged(hash, "missing"))?;
+            if !compressed {
+                return Ok((crypt::seal(compress(&bytes))?, is_delta, true));
+            }
+        }
+        read_sealed_form(hash)?.ok_or_else(|| damaged(hash, "missing"))
+    })?;
+
+    for hash in &hashes {
+        remove_loose(hash);
+    }
+    remove_empty_dirs();
+    Ok(hashes.len())
+}
+
+/// Deletes every object not in `live`, loose or packed, returning how many
+/// went. Packs holding any are rewritten with just the live objects.
+pub fn prune(live: &HashSet<FileHash>) -> Result<usize> {
+    let mut removed = 0;
+    for hash in loose_list() {
+        if !live.contains(&hash) {
+            remove_loose(&hash);
+            removed += 1;
+        }
+    }
+    remove_empty_dirs();
+
+    let packed = pack::list()?;
+    let (keep, dead): (Vec<FileHash>, Vec<FileHash>) = packed.into_iter().partition(|hash| live.contains(hash));
+    if !dead.is_empty() {
+        let mut keep = keep;
+        keep.sort();
+        pack::write(&keep, |hash| read_sealed_form(hash)?.ok_or_else(|| damaged(hash, "missing")))?;
+        removed += dead.len();
+    }
+    Ok(removed)
+}
+
+fn remove_loose(hash: &str) {
+    for &(is_delta, compressed) in &FORMS {
+        let _ = fs::remove_file(stored_path(hash, is_delta, compressed));
+    }
+}
+
+/// Drops the fan-out directories that removing loose objects leaves empty.
+fn remove_empty_dirs() {
+    if let Ok(entries) = fs::read_dir(scm_dir().join(OBJECTS_DIR)) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+}
+
+/// The hashes of every object in the store, loose or packed.
+pub fn list() -> Result<Vec<FileHash>> {
+    let mut hashes = loose_list();
+    hashes.extend(pack::list()?);
+    hashes.sort();
+    hashes.dedup();
+    Ok(hashes)
+}
+
+/// Files under `objects` whose names don't make a hash are left out.
+fn loose_list() -> Vec<FileHash> {
+    let mut hashes = Vec::new();
+    let dirs = match fs::read_dir(scm_dir().join(OBJECTS_DIR)) {
+        Ok(dirs) => dirs,
+        Err(_) => return hashes,
+    };
+    for dir in dirs.filter_map(|entry| entry.ok()) {
+        let prefix = dir.file_name().to_string_lossy().to_string();
+        if prefix == "pack" { continue; }
+        for entry in fs::read_dir(dir.path()).into_iter().flatten().filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".partial") { continue; }
+            let rest = name.strip_suffix(COMPRESSED_SUFFIX).unwrap_or(&name);
+            let rest = rest.strip_suffix(DELTA_SUFFIX).unwrap_or(rest);
+            hashes.extend(format!("{}{}", prefix, rest).parse::<FileHash>());
+        }
+    }
+    hashes.sort();
+    hashes.dedup();
+    hashes
+}
+
+fn write_atomic(dest: &Path, content: &[u8]) -> Result<()> {
+    create_parent(dest)?;
+    // Write under a temporary name so an interrupted write never looks complete.
+    let partial = partial_path(dest);
+    fs::write(&partial, content).map_err(ScmError::io(format!("Cannot write {}", partial.display())))?;
+    fs::rename(&partial, dest).map_err(ScmError::io(format!("Cannot write {}", dest.display())))
+}
+
+fn create_parent(dest: &Path) -> Result<()> {
+    let dir = dest.parent().expect("objects are in a directory");
+    fs::create_dir_all(dir).map_err(ScmError::io(format!("Cannot create {}", dir.display())))
+}
+
+/// A temporary name beside `dest`, unique to this write: files are stored
+/// from several threads, and two of them may hold the same content.
+fn partial_path(dest: &Path) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    with_suffix(dest, &format!(".{}-{}.partial", process::id(), NEXT.fetch_add(1, Ordering::Relaxed)))
+}
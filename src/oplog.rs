@@ -0,0 +1,48 @@
+use std::sync::OnceLock;
+use serde::{Serialize, Deserialize};
+
+use crate::storage::{self, storage};
+use crate::{crypt, id, Result, ScmError, VersionId};
+
+const OPLOG_FILE: &str = "oplog";
+
+/// The running invocation, recorded with each entry: its command line, or
+/// in an encrypted repository only the command's name, as the rest can hold
+/// a message or paths.
+static COMMAND: OnceLock<(String, String)> = OnceLock::new();
+
+/// One HEAD movement. Stored as a line of JSON in `.scm/oplog`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpLogEntry {
+    pub timestamp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// None if HEAD had no version yet; written as 0.
+    #[serde(with = "id::or_zero")]
+    pub old_head: Option<VersionId>,
+    pub new_head: VersionId,
+    pub command: String,
+}
+
+pub fn set_command(name: &str, args: &[String]) {
+    let _ = COMMAND.set((format!("scm {}", name), format!("scm {}", args.join(" "))));
+}
+
+pub fn record(branch: Option<String>, old_head: Option<VersionId>, new_head: VersionId) -> Result<()> {
+    let entry = OpLogEntry {
+        timestamp: chrono::Utc::now().to_string(),
+        branch,
+        old_head,
+        new_head,
+        command: COMMAND.get().map(|(name, line)| if crypt::enabled() { name } else { line }).cloned().unwrap_or_default(),
+    };
+
+    let line = serde_json::to_string(&entry).unwrap();
+    storage().append_file(OPLOG_FILE, format!("{}\n", line).as_bytes()).map_err(ScmError::io("Cannot write the oplog"))
+}
+
+/// All readable entries, oldest first.
+pub fn read_all() -> Vec<OpLogEntry> {
+    let content = storage::read_to_string(OPLOG_FILE).unwrap_or_default();
+    content.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+}
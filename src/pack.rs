@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{context, is_false, scm_dir, FileHash, Result, ScmError};
+
+/// Pack files consolidate many objects into one file, since thousands of
+/// small files are slow on many filesystems. `objects/pack/pack-<id>.pack`
+/// holds the objects' stored bytes back to back after a short header, and
+/// `pack-<id>.idx` (JSON) maps each hash to its offset, length and form.
+/// A pack only counts once its index exists, so the index is written last.
+const PACK_DIR: &str = "objects/pack";
+const MAGIC: &[u8] = b"SCMPACK1";
+
+/// Where one object sits in a pack, and in which form (see `objects`).
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PackEntry {
+    pub offset: u64,
+    pub length: u64,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub delta: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub compressed: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PackIndex {
+    objects: HashMap<String, PackEntry>,
+}
+
+pub struct Pack {
+    path: PathBuf,
+    index: PackIndex,
+}
+
+fn pack_dir() -> PathBuf {
+    scm_dir().join(PACK_DIR)
+}
+
+fn load_packs() -> Result<Vec<Pack>> {
+    index_files().into_iter().map(|idx| Ok(Pack { path: idx.with_extension("pack"), index: load_index(&idx)? })).collect()
+}
+
+/// A pack's index; one that isn't valid JSON is an `IntegrityFailure`.
+fn load_index(idx: &Path) -> Result<PackIndex> {
+    let content = fs::read_to_string(idx).map_err(ScmError::io(format!("Cannot read {}", idx.display())))?;
+    serde_json::from_str(&content).map_err(|e| ScmError::IntegrityFailure(format!("Pack index {} is corrupt: {}.", idx.display(), e)))
+}
+
+/// The `.idx` files of every complete pack, sorted.
+fn index_files() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(pack_dir()) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort();
+    files
+}
+
+fn with_packs<T>(f: impl FnOnce(&[Pack]) -> T) -> Result<T> {
+    // The packs' indexes, loaded on first use and reloaded after a repack.
+    let context = context::current();
+    let mut packs = context.packs.lock().unwrap();
+    if packs.is_none() {
+        *packs = Some(load_packs()?);
+    }
+    Ok(f(packs.as_deref().unwrap_or_default()))
+}
+
+pub fn contains(hash: &str) -> Result<bool> {
+    with_packs(|packs| packs.iter().any(|pack| pack.index.objects.contains_key(hash)))
+}
+
+/// An object's stored bytes from whichever pack holds it, with its entry.
+/// None if no pack lists it or its pack file is missing or truncated.
+pub fn read(hash: &str) -> Result<Option<(Vec<u8>, PackEntry)>> {
+    let found = with_packs(|packs| {
+        packs.iter().find_map(|pack| pack.index.objects.get(hash).map(|entry| (pack.path.clone(), entry.clone())))
+    })?;
+    Ok(found.and_then(|(path, entry)| {
+        let mut file = File::open(&path).ok()?;
+        file.seek(SeekFrom::Start(entry.offset)).ok()?;
+        let mut bytes = vec![0; entry.length as usize];
+        file.read_exact(&mut bytes).ok()?;
+        Some((bytes, entry))
+    }))
+}
+
+/// Problems with the packs themselves: an index that can't be read or
+/// parsed, a missing pack file or one too short for the offsets its index
+/// lists. Each index is loaded afresh, so one damaged index doesn't hide
+/// the state of the others.
+pub fn check() -> Vec<String> {
+    let mut problems = Vec::new();
+    for idx in index_files() {
+        let index = match load_index(&idx) {
+            Ok(index) => index,
+            Err(ScmError::IntegrityFailure(_)) => {
+                problems.push(format!("pack index {} is corrupt; the objects in its pack cannot be found", idx.display()));
+                continue;
+            }
+            Err(e) => {
+                problems.push(format!("pack index {}: {}", idx.display(), e));
+                continue;
+            }
+        };
+        let path = idx.with_extension("pack");
+        let end = index.objects.values().map(|entry| entry.offset + entry.length).max().unwrap_or(0);
+        match fs::metadata(&path) {
+            Ok(meta) if meta.len() >= end => {}
+            Ok(_) => problems.push(format!("pack {} is truncated", path.display())),
+            Err(_) => problems.push(format!("pack {} is missing but its index is present", path.display())),
+        }
+    }
+    problems
+}
+
+/// The hashes of every packed object. An index entry whose name isn't a
+/// hash is left out, as nothing can refer to it.
+pub fn list() -> Result<Vec<FileHash>> {
+    with_packs(|packs| packs.iter().flat_map(|pack| pack.index.objects.keys().filter_map(|hash| hash.parse().ok())).collect())
+}
+
+/// Writes a pack of the given objects, taking each one's stored bytes and
+/// (delta, compressed) flags from `load`, and removes every other pack.
+/// Callers remove the loose copies afterwards. With no objects, the old
+/// packs are just removed. If `load` fails, the old packs are left as they
+/// were.
+pub fn write(hashes: &[FileHash], mut load: impl FnMut(&str) -> Result<(Vec<u8>, bool, bool)>) -> Result<()> {
+    if hashes.is_empty() {
+        remove_packs_except(None)?;
+        *context::current().packs.lock().unwrap() = None;
+        return Ok(());
+    }
+    fs::create_dir_all(pack_dir()).map_err(ScmError::io(format!("Cannot create {}", pack_dir().display())))?;
+    let mut id = Sha256::new();
+    for hash in hashes {
+        id.update(hash.as_bytes());
+    }
+    let name = format!("pack-{}", hex::encode(id.finalize()));
+    let pack_path = pack_dir().join(format!("{}.pack", name));
+    let idx_path = pack_dir().join(format!("{}.idx", name));
+
+    let partial = pack_dir().join(format!("{}.pack.partial", name));
+    let unwritable = || ScmError::io(format!("Cannot write {}", partial.display()));
+    let mut writer = BufWriter::new(File::create(&partial).map_err(unwritable())?);
+    writer.write_all(MAGIC).map_err(unwritable())?;
+    let mut index = PackIndex::default();
+    let mut offset = MAGIC.len() as u64;
+    for hash in hashes {
+        let loaded = load(hash).and_then(|(bytes, delta, compressed)| {
+            writer.write_all(&bytes).map_err(unwritable())?;
+            Ok((bytes.len() as u64, delta, compressed))
+        });
+        let (length, delta, compressed) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+        index.objects.insert(hash.to_string(), PackEntry { offset, length, delta, compressed });
+        offset += length;
+    }
+    writer.into_inner().map_err(|e| e.into_error()).and_then(|file| file.sync_all()).map_err(unwritable())?;
+    fs::rename(&partial, &pack_path).map_err(unwritable())?;
+
+    let partial = pack_dir().join(format!("{}.idx.partial", name));
+    let unwritable = ScmError::io(format!("Cannot write {}", idx_path.display()));
+    fs::write(&partial, serde_json::to_string(&index).unwrap()).and_then(|()| fs::rename(&partial, &idx_path)).map_err(unwritable)?;
+
+    remove_packs_except(Some(&idx_path))?;
+    *context::current().packs.lock().unwrap() = None;
+    Ok(())
+}
+
+/// Removes every pack but the one indexed by `keep`, index first.
+fn remove_packs_except(keep: Option<&PathBuf>) -> Result<()> {
+    for old in index_files().into_iter().filter(|idx| Some(idx) != keep) {
+        fs::remove_file(&old).map_err(ScmError::io(format!("Cannot remove {}", old.display())))?;
+        let _ = fs::remove_file(old.with_extension("pack"));
+    }
+    Ok(())
+}
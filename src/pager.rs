@@ -0,0 +1,77 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+use crate::config;
+
+/// `log`, `diff` and `show` print through a pager when stdout is a terminal,
+/// as git does: `$SCM_PAGER`, the `core.pager` setting, `$PAGER`, or else
+/// `less`, run with `LESS=FRX` unless that is set, so output that fits on one
+/// screen is just printed. `--no-pager`, a pager of "" or "cat", or
+/// `pager.<command> = false` in the config turn it off.
+pub const PAGED: &[&str] = &["log", "diff", "show"];
+const PAGER_KEY: &str = "core.pager";
+
+static PAGER: Mutex<Option<Child>> = Mutex::new(None);
+
+fn pager_command(command: &str) -> Option<String> {
+    if config::get(&format!("pager.{}", command)).is_some_and(|value| value == "false") {
+        return None;
+    }
+    let pager = env::var("SCM_PAGER").ok().or_else(|| config::get(PAGER_KEY)).or_else(|| env::var("PAGER").ok()).unwrap_or_else(|| "less".to_string());
+    let pager = pager.trim().to_string();
+    (!pager.is_empty() && pager != "cat").then_some(pager)
+}
+
+/// Sends the rest of stdout through the pager, if `command` is paged and
+/// stdout is a terminal.
+#[cfg(unix)]
+pub fn start(command: &str) {
+    use std::os::fd::AsRawFd;
+
+    if !PAGED.contains(&command) || !io::stdout().is_terminal() {
+        return;
+    }
+    let pager = match pager_command(command) {
+        Some(pager) => pager,
+        None => return,
+    };
+    let mut process = Command::new("sh");
+    process.arg("-c").arg(&pager).stdin(Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        process.env("LESS", "FRX");
+    }
+    let mut child = match process.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::warn!("could not run pager {:?}: {}", pager, e);
+            return;
+        }
+    };
+    let input = child.stdin.take().expect("pager stdin is piped");
+    // SAFETY: a plain call on file descriptors this process owns. Once the
+    // pager quits, writing to it ends scm quietly (see `exit::init`).
+    unsafe {
+        libc::dup2(input.as_raw_fd(), libc::STDOUT_FILENO);
+    }
+    drop(input);
+    *PAGER.lock().unwrap() = Some(child);
+}
+
+#[cfg(not(unix))]
+pub fn start(_command: &str) {}
+
+/// Lets the pager see the end of the output and waits for the user to
+/// leave it.
+pub fn finish() {
+    if let Some(mut child) = PAGER.lock().unwrap().take() {
+        let _ = io::stdout().flush();
+        #[cfg(unix)]
+        // SAFETY: stdout is the pager's pipe and nothing is written after this.
+        unsafe {
+            libc::close(libc::STDOUT_FILENO);
+        }
+        let _ = child.wait();
+    }
+}
@@ -0,0 +1,54 @@
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Permission bits of a file, where the platform has them.
+#[cfg(unix)]
+pub fn mode(meta: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn mode(_meta: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Modification time in whole seconds since the Unix epoch.
+pub fn mtime(meta: &Metadata) -> Option<i64> {
+    let modified = meta.modified().ok()?;
+    match modified.duration_since(UNIX_EPOCH) {
+        Ok(since) => Some(since.as_secs() as i64),
+        Err(before) => Some(-(before.duration().as_secs() as i64)),
+    }
+}
+
+pub fn set_mtime(path: &Path, secs: i64) -> io::Result<()> {
+    let time = if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    };
+    set_modified(path, time)
+}
+
+/// Gives `dest` the same modification time as `src`.
+pub fn copy_mtime(src: &Path, dest: &Path) -> io::Result<()> {
+    set_modified(dest, fs::metadata(src)?.modified()?)
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> io::Result<()> {
+    File::options().write(true).open(path)?.set_modified(time)
+}
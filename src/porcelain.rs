@@ -0,0 +1,65 @@
+use crate::ChangeSet;
+
+/// `--porcelain` output, for scripts and editor plugins. Unlike the human
+/// output, this format is frozen: it will not change between versions of
+/// scm, and is never colored or paged.
+///
+/// Each line is a record of fields separated by tabs. A backslash, tab,
+/// carriage return or newline within a field is written `\\`, `\t`, `\r` or
+/// `\n`; nothing else is escaped. Empty fields are kept, so every record of
+/// a kind has the same number of fields. Nothing is printed when there is
+/// nothing to report. Below, fields are shown with spaces between them.
+///
+/// `scm status --porcelain`: `<area> <code> <path>`, or for a rename
+/// `<area> R <old path> <new path>`. `<area>` is `staged` (in the next
+/// commit), `unstaged` (changed in the working directory only) or
+/// `excluded` (kept out of commits by `scm rm --cached`, with code `?`).
+/// `<code>` is `A` added, `M` modified, `D` deleted or `R` renamed. Areas
+/// come in that order, and each is sorted by path.
+///
+/// `scm diff --porcelain`: `<code> <path>`, or `R <old path> <new path>`,
+/// sorted by path. With `--name-only`, just `<path>` (the new one for a
+/// rename).
+///
+/// `scm log --porcelain`: one record per version, newest first:
+/// `<version> <parents> <timestamp> <author name> <author email> <subject>`.
+/// `<parents>` is the parent ids separated by spaces (empty for the first
+/// version); `<subject>` is the first line of the message.
+const SEPARATOR: &str = "\t";
+
+/// Prints one record.
+pub fn print(fields: &[&str]) {
+    let fields: Vec<String> = fields.iter().map(|field| escape(field)).collect();
+    println!("{}", fields.join(SEPARATOR));
+}
+
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Prints a change set and its renames, sorted by path, each record led by
+/// `area` if given.
+pub fn print_changes(area: Option<&str>, changes: &ChangeSet, renames: &[(String, String)]) {
+    let mut records: Vec<Vec<&str>> = Vec::new();
+    for (code, filenames) in [("A", &changes.added), ("M", &changes.modified), ("D", &changes.deleted)] {
+        records.extend(filenames.iter().map(|filename| vec![code, filename.as_str()]));
+    }
+    records.extend(renames.iter().map(|(old, new)| vec!["R", old.as_str(), new.as_str()]));
+    records.sort_by_key(|record| record[1]);
+    for mut record in records {
+        if let Some(area) = area {
+            record.insert(0, area);
+        }
+        print(&record);
+    }
+}
@@ -0,0 +1,70 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+
+use crate::{json, logging, stats};
+
+const DISABLE_VAR: &str = "SCM_NO_PROGRESS";
+
+/// Set by the command line; the library on its own draws nothing.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// A progress bar for the long phases of commit, revert and verify: files
+/// done out of the total, the bytes they came to and how fast, and the time
+/// left. Drawn on stdout only when it is a terminal and neither `-v` nor
+/// `--json` is printing over it, and cleared when done, so output piped or
+/// redirected elsewhere is the same as without it. Setting `$SCM_NO_PROGRESS`
+/// turns it off altogether, e.g. for a terminal that is only recorded.
+/// Safe to advance from the thread pool.
+pub struct Progress {
+    bar: ProgressBar,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Progress {
+    /// A bar for `files` files labelled `label`, counting bytes if `bytes`.
+    pub fn new(label: &str, files: usize, bytes: bool) -> Progress {
+        let counted = Arc::new(AtomicU64::new(0));
+        let mut template = String::from("{prefix:>9} [{bar:30}] {pos}/{len} files");
+        if bytes {
+            template.push_str(", {done} ({rate})");
+        }
+        template.push_str(", ETA {eta}");
+
+        let (done, rate) = (counted.clone(), counted.clone());
+        let style = ProgressStyle::with_template(&template)
+            .expect("Invalid progress template")
+            .progress_chars("=> ")
+            .with_key("done", move |_: &ProgressState, w: &mut dyn std::fmt::Write| {
+                let _ = write!(w, "{}", stats::human(done.load(Ordering::Relaxed)));
+            })
+            .with_key("rate", move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                let per_second = rate.load(Ordering::Relaxed) as f64 / state.elapsed().as_secs_f64().max(0.001);
+                let _ = write!(w, "{}/s", stats::human(per_second as u64));
+            });
+        let shown = ENABLED.load(Ordering::Relaxed)
+            && io::stdout().is_terminal()
+            && !logging::verbose()
+            && !json::enabled()
+            && env::var_os(DISABLE_VAR).is_none_or(|value| value.is_empty());
+        let target = if shown { ProgressDrawTarget::stdout() } else { ProgressDrawTarget::hidden() };
+        let bar = ProgressBar::with_draw_target(Some(files as u64), target).with_style(style).with_prefix(label.to_string());
+        Progress { bar, bytes: counted }
+    }
+
+    /// Marks one more file done, `bytes` long.
+    pub fn inc(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.bar.inc(1);
+    }
+
+    pub fn finish(self) {
+        self.bar.finish_and_clear();
+    }
+}
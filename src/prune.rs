@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use chrono::Local;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::cli::PruneArgs;
+use crate::storage::storage;
+use crate::{
+    compare_files, config, exit, gc, get_head, graph, json, list_versions, load_manifest, parent_of, pending_merge, refs, scm_dir,
+    stash, version_files, write_manifest, Result, VersionId,
+};
+
+/// What `scm prune --dry-run --json` would delete.
+#[derive(Serialize)]
+struct PrunePlan {
+    delete: Vec<Doomed>,
+}
+
+#[derive(Serialize)]
+struct Doomed {
+    version: VersionId,
+    timestamp: Option<String>,
+}
+
+/// Config key for shallow history: with `history.depth = "N"`, every commit
+/// trims the history to the last N versions on each branch.
+const DEPTH_KEY: &str = "history.depth";
+
+/// Retention rules as (config key, bucket), in the order of the
+/// `--keep-<period>` options. Each `--keep-<period> N` keeps the newest
+/// version in each of the N most recent periods (local time) that have one,
+/// as borg and restic do; `scm prune` without options uses the
+/// `prune.keep_<period>` config keys instead.
+const RETENTION: [(&str, &str); 5] = [
+    ("prune.keep_hourly", "%Y-%m-%d %H"),
+    ("prune.keep_daily", "%Y-%m-%d"),
+    ("prune.keep_weekly", "%G-W%V"),
+    ("prune.keep_monthly", "%Y-%m"),
+    ("prune.keep_yearly", "%Y"),
+];
+
+/// `scm prune [--depth <n>] [--keep-<period> <n>]...`: deletes every version
+/// that neither the depth (the last `n` versions on every branch) nor a
+/// retention rule keeps, then the objects only they used. Branch tips,
+/// HEAD and the versions in `protected` are always kept.
+pub fn do_prune(args: &PruneArgs) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let counts = [args.keep_hourly, args.keep_daily, args.keep_weekly, args.keep_monthly, args.keep_yearly];
+    let mut rules: Vec<(&str, usize)> = RETENTION.iter().zip(counts).filter_map(|(&(_, bucket), count)| Some((bucket, count?))).collect();
+    if args.depth.is_none() && rules.is_empty() {
+        rules = configured_rules();
+        if rules.is_empty() {
+            info!("Nothing to prune by; pass --depth or --keep-<period>, or set retention rules in the config, e.g. prune.keep_daily = \"7\".");
+            return;
+        }
+    }
+
+    let mut keep = match protected() {
+        Ok(keep) => keep,
+        Err(e) => return exit::report(e),
+    };
+    if let Some(depth) = args.depth {
+        match within_depth(depth) {
+            Ok(within) => keep.extend(within),
+            Err(e) => return exit::report(e),
+        }
+    }
+    keep.extend(retained(&rules));
+    let doomed: HashSet<VersionId> = list_versions().into_iter().filter(|id| !keep.contains(id)).collect();
+    if args.dry_run {
+        let mut doomed: Vec<VersionId> = doomed.into_iter().collect();
+        doomed.sort();
+        if json::enabled() {
+            let delete = doomed.iter().map(|&id| Doomed { version: id, timestamp: graph::entry(id).map(|entry| entry.timestamp) }).collect();
+            json::print(&PrunePlan { delete });
+            return;
+        }
+        for &id in &doomed {
+            match graph::entry(id) {
+                Some(entry) => println!("Would delete version {} ({})", id, entry.timestamp),
+                None => println!("Would delete version {}", id),
+            }
+        }
+        println!("Dry run; {} version(s) and the objects only they use would be deleted.", doomed.len());
+        return;
+    }
+    match remove_versions(&doomed) {
+        Ok((versions, objects)) => info!("Deleted {} version(s) and {} unused object(s).", versions, objects),
+        Err(e) => exit::report(e),
+    }
+}
+
+/// Applies `history.depth`, if set, after a commit.
+pub fn after_commit() -> Result<()> {
+    let depth = match config::get(DEPTH_KEY) {
+        Some(value) => match value.parse::<usize>() {
+            Ok(depth) if depth > 0 => depth,
+            _ => {
+                warn!("invalid {} {:?}; not pruning.", DEPTH_KEY, value);
+                return Ok(());
+            }
+        },
+        None => return Ok(()),
+    };
+    let mut keep = protected()?;
+    keep.extend(within_depth(depth)?);
+    let doomed: HashSet<VersionId> = list_versions().into_iter().filter(|id| !keep.contains(id)).collect();
+    let (versions, _) = remove_versions(&doomed)?;
+    if versions > 0 {
+        info!("Pruned {} version(s) beyond history.depth {}.", versions, depth);
+    }
+    Ok(())
+}
+
+fn configured_rules() -> Vec<(&'static str, usize)> {
+    let mut rules = Vec::new();
+    for (key, bucket) in RETENTION {
+        if let Some(value) = config::get(key) {
+            match value.parse::<usize>() {
+                Ok(count) if count > 0 => rules.push((bucket, count)),
+                _ => warn!("invalid {} {:?}; ignoring it.", key, value),
+            }
+        }
+    }
+    rules
+}
+
+/// The last `depth` versions on HEAD's and every branch's first-parent line.
+fn within_depth(depth: usize) -> Result<HashSet<VersionId>> {
+    let mut keep = HashSet::new();
+    let mut tips: Vec<_> = get_head()?.into_iter().collect();
+    tips.extend(refs::list_branches().into_iter().map(|(_, id)| id));
+    for tip in tips {
+        let mut cursor = Some(tip);
+        for _ in 0..depth {
+            let id = match cursor {
+                Some(id) => id,
+                None => break,
+            };
+            keep.insert(id);
+            cursor = parent_of(id);
+        }
+    }
+    Ok(keep)
+}
+
+/// The versions the retention rules keep: for each (bucket format, count),
+/// the newest version in each of the `count` newest buckets. Versions whose
+/// timestamps can't be read are kept to be safe.
+fn retained(rules: &[(&str, usize)]) -> HashSet<VersionId> {
+    let mut keep = HashSet::new();
+    if rules.is_empty() {
+        return keep;
+    }
+    let mut dated = Vec::new();
+    for id in list_versions() {
+        match graph::entry(id).and_then(|e| gc::parse_time(&e.timestamp)) {
+            Some(time) => dated.push((time.with_timezone(&Local), id)),
+            None => {
+                keep.insert(id);
+            }
+        }
+    }
+    dated.sort_by(|a, b| b.cmp(a));
+    for &(bucket, count) in rules {
+        let mut buckets = HashSet::new();
+        for (time, id) in &dated {
+            if buckets.len() == count {
+                break;
+            }
+            if buckets.insert(time.format(bucket).to_string()) {
+                keep.insert(*id);
+            }
+        }
+    }
+    keep
+}
+
+/// Versions that pruning never deletes: HEAD, branch tips, tagged versions,
+/// stash bases and the other side of a merge in progress.
+pub fn protected() -> Result<HashSet<VersionId>> {
+    let mut keep: HashSet<VersionId> = refs::list_tags().into_iter().map(|(_, tag)| tag.version_id).collect();
+    keep.extend(get_head()?);
+    keep.extend(refs::list_branches().into_iter().map(|(_, id)| id));
+    keep.extend(stash::list_indices().into_iter().filter_map(stash::load).filter_map(|entry| entry.base_version));
+    keep.extend(pending_merge());
+    Ok(keep)
+}
+
+/// Deletes the given versions and then every object only they used,
+/// returning how many of each went. A kept version whose parent is deleted
+/// becomes a base snapshot: its parent link is dropped and its change set
+/// lists all its files as added, so the remaining history stays consistent.
+pub fn remove_versions(doomed: &HashSet<VersionId>) -> Result<(usize, usize)> {
+    if doomed.is_empty() {
+        return Ok((0, 0));
+    }
+    for id in list_versions() {
+        if doomed.contains(&id) { continue; }
+        let mut manifest = load_manifest(id)?;
+        let orphaned = manifest.parent.is_some_and(|parent| doomed.contains(&parent));
+        let merge_orphaned = manifest.merge_parent.is_some_and(|parent| doomed.contains(&parent));
+        if orphaned {
+            manifest.parent = nearest_kept(manifest.parent, doomed);
+            manifest.changes = Some(compare_files(&version_files(manifest.parent)?, &manifest.files));
+        }
+        if merge_orphaned {
+            manifest.merge_parent = nearest_kept(manifest.merge_parent, doomed).filter(|&p| Some(p) != manifest.parent);
+        }
+        if orphaned || merge_orphaned {
+            write_manifest(&manifest)?;
+        }
+    }
+    for &id in doomed {
+        storage().remove_manifest(id)?;
+    }
+    Ok((doomed.len(), gc::remove_unused_objects()?))
+}
+
+/// The first version on `start`'s first-parent line that isn't doomed.
+fn nearest_kept(start: Option<VersionId>, doomed: &HashSet<VersionId>) -> Option<VersionId> {
+    let mut cursor = start;
+    while let Some(id) = cursor
+        && doomed.contains(&id)
+    {
+        cursor = parent_of(id);
+    }
+    cursor
+}
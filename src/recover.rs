@@ -0,0 +1,195 @@
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::storage::{self, storage};
+use crate::{
+    conclude_amend, conclude_commit, exit, id, load_manifest, manifest_exists, restore_version, scm_dir, set_head, swap, Result, ScmError,
+    VersionId, COMMITS_DIR,
+};
+
+/// Commits and reverts leave a marker in `.scm/OPERATION` while they run,
+/// so one cut short (a crash, a kill, a full disk) is found afterwards and
+/// `scm recover` can finish or roll it back.
+///
+/// A new version is written to `commits/<id>.partial` and renamed into
+/// place in one step, which is the point past which it counts as committed.
+/// An amend writes HEAD's version the same way, moving the old one aside to
+/// `commits/<id>.old` while the two are swapped. A revert (or checkout or
+/// redo) that fails puts the working directory back itself (see `swap`).
+/// One cut short is rolled back if it hadn't started replacing files yet,
+/// and finished if it had, since some may be replaced already.
+const OPERATION_FILE: &str = "OPERATION";
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Operation {
+    /// A new version on top of `head`, which is None (written as 0) for the
+    /// first commit.
+    Commit {
+        version: VersionId,
+        #[serde(with = "id::or_zero")]
+        head: Option<VersionId>,
+    },
+    /// HEAD's version, `version`, being replaced.
+    Amend { version: VersionId },
+    /// The working tree being replaced with `version`'s files by `command`,
+    /// before HEAD moves there; `applying` once files start being replaced.
+    Restore {
+        command: String,
+        version: VersionId,
+        #[serde(default)]
+        applying: bool,
+    },
+}
+
+impl Operation {
+    pub fn describe(&self) -> String {
+        match self {
+            Operation::Commit { version, .. } => format!("commit of version {}", version),
+            Operation::Amend { version } => format!("amend of version {}", version),
+            Operation::Restore { command, version, .. } => format!("{} to version {}", command, version),
+        }
+    }
+
+    fn staged(&self) -> Option<VersionId> {
+        match self {
+            Operation::Commit { version, .. } | Operation::Amend { version } => Some(*version),
+            Operation::Restore { .. } => None,
+        }
+    }
+}
+
+/// Where a version is written before it is renamed into place.
+pub fn staging_path(id: VersionId) -> PathBuf {
+    scm_dir().join(COMMITS_DIR).join(format!("{}.partial", id))
+}
+
+/// Where an amended version's old directory waits while it is replaced.
+pub fn backup_path(id: VersionId) -> PathBuf {
+    scm_dir().join(COMMITS_DIR).join(format!("{}.old", id))
+}
+
+/// The operation that was cut short, if any.
+pub fn pending() -> Option<Operation> {
+    serde_json::from_str(&storage::read_to_string(OPERATION_FILE).ok()?).ok()
+}
+
+/// Whether an interrupted operation has to be dealt with first. Says so if it does.
+pub fn report_pending() -> bool {
+    match pending() {
+        Some(operation) => {
+            error!("An interrupted {} was found; run 'scm recover' to finish or roll it back.", operation.describe());
+            true
+        }
+        None => false,
+    }
+}
+
+/// An operation recorded by `begin`. Dropped without `end`, as when a
+/// commit or amend fails before its version is in place, it undoes what
+/// was staged and clears the marker, so nothing is left to recover.
+#[must_use]
+pub struct Pending {
+    staged: Option<VersionId>,
+}
+
+impl Pending {
+    /// Records that the operation is complete.
+    pub fn end(mut self) -> Result<()> {
+        self.staged = None;
+        clear()
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(id) = self.staged.take()
+            && let Ok(true) = storage().roll_back_manifest(id)
+        {
+            let _ = clear();
+        }
+    }
+}
+
+/// Records that `operation` is starting, with storage ready for the
+/// version it writes (see `Storage::begin_manifest`).
+pub fn begin(operation: &Operation) -> Result<Pending> {
+    let staged = operation.staged();
+    if let Some(id) = staged {
+        storage().begin_manifest(id)?;
+    }
+    write(operation)?;
+    Ok(Pending { staged })
+}
+
+fn write(operation: &Operation) -> Result<()> {
+    storage().write_file(OPERATION_FILE, serde_json::to_string(operation).unwrap().as_bytes()).map_err(ScmError::io("Cannot record the operation"))
+}
+
+/// Records that the restore under way, if any, is about to replace working
+/// files, past which `scm recover` finishes it rather than rolling it back.
+pub fn applying() -> Result<()> {
+    match pending() {
+        Some(Operation::Restore { command, version, applying: false }) => write(&Operation::Restore { command, version, applying: true }),
+        _ => Ok(()),
+    }
+}
+
+fn clear() -> Result<()> {
+    storage().remove_file(OPERATION_FILE).map_err(ScmError::io("Cannot clear the operation"))
+}
+
+/// `scm recover`: finishes an interrupted operation if it got far enough,
+/// otherwise rolls it back.
+pub fn do_recover() {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let operation = match pending() {
+        Some(operation) => operation,
+        None => {
+            info!("Nothing to recover.");
+            return;
+        }
+    };
+
+    match &operation {
+        Operation::Commit { version, head } => match storage().roll_back_manifest(*version) {
+            Err(e) => return exit::report(e),
+            Ok(false) if manifest_exists(*version) => {
+                info!("Finishing interrupted {}...", operation.describe());
+                let merged = load_manifest(*version).is_ok_and(|m| m.merge_parent.is_some());
+                if let Err(e) = conclude_commit(*version, *head, merged) {
+                    return exit::report(e);
+                }
+                info!("Recovered: version {} is committed.", version);
+            }
+            Ok(_) => info!("Rolled back interrupted {}; nothing was committed.", operation.describe()),
+        },
+        Operation::Amend { version } => match storage().roll_back_manifest(*version) {
+            Err(e) => return exit::report(e),
+            Ok(true) => info!("Rolled back interrupted {}; version {} is as it was.", operation.describe(), version),
+            Ok(false) => {
+                info!("Finishing interrupted {}...", operation.describe());
+                conclude_amend();
+                info!("Recovered: version {} is amended.", version);
+            }
+        },
+        Operation::Restore { applying: false, .. } => {
+            swap::discard();
+            info!("Rolled back interrupted {}; the working directory and HEAD are as they were.", operation.describe());
+        }
+        Operation::Restore { version, .. } => {
+            info!("Finishing interrupted {}...", operation.describe());
+            if let Err(e) = restore_version(*version).and_then(|()| set_head(*version)) {
+                return exit::report(e);
+            }
+            info!("Recovered: now at version {}.", version);
+        }
+    }
+    if let Err(e) = clear() {
+        exit::report(e);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use serde::{Serialize, Deserialize};
+
+use crate::storage::{self, storage};
+use crate::{Result, ScmError, VersionId, HEAD_FILE};
+
+const TAGS_DIR: &str = "refs/tags";
+const HEADS_DIR: &str = "refs/heads";
+const SYMREF_PREFIX: &str = "ref: refs/heads/";
+
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// What the HEAD file points at: normally a branch, but repositories created
+/// before branches existed store a bare version id ("detached"), which is
+/// None before the first commit. Such a HEAD is an empty file, or 0 in
+/// repositories written before that.
+pub enum HeadRef {
+    Branch(String),
+    Detached(Option<VersionId>),
+}
+
+/// A tag pointing at a version. Lightweight tags are stored as a bare version
+/// id; annotated tags are stored as JSON with a message, tagger and date.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tag {
+    pub version_id: VersionId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tagger: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+}
+
+impl Tag {
+    pub fn is_annotated(&self) -> bool {
+        self.message.is_some()
+    }
+}
+
+fn tag_file(name: &str) -> String {
+    format!("{}/{}", TAGS_DIR, name)
+}
+
+fn branch_file(name: &str) -> String {
+    format!("{}/{}", HEADS_DIR, name)
+}
+
+/// Tag and branch names become file names and must not be confused with version ids.
+pub fn is_valid_ref_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && !name.starts_with('.')
+        && !name.chars().all(|c| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+pub fn read_tag(name: &str) -> Option<Tag> {
+    if !is_valid_ref_name(name) { return None; }
+    let content = storage::read_to_string(&tag_file(name)).ok()?;
+    let content = content.trim();
+
+    match content.parse() {
+        Ok(version_id) => Some(Tag { version_id, message: None, tagger: None, timestamp: None }),
+        Err(_) => serde_json::from_str(content).ok(),
+    }
+}
+
+pub fn write_tag(name: &str, tag: &Tag) -> Result<()> {
+    let content = if tag.is_annotated() {
+        serde_json::to_string_pretty(tag).unwrap()
+    } else {
+        tag.version_id.to_string()
+    };
+    storage().write_file(&tag_file(name), content.as_bytes()).map_err(ScmError::io(format!("Cannot write tag {}", name)))
+}
+
+/// Returns false if the tag didn't exist.
+pub fn delete_tag(name: &str) -> bool {
+    is_valid_ref_name(name) && storage().remove_file(&tag_file(name)).is_ok()
+}
+
+/// All readable tags, sorted by name.
+pub fn list_tags() -> Vec<(String, Tag)> {
+    let names = match storage().list_files(TAGS_DIR) {
+        Ok(names) => names,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut tags: Vec<(String, Tag)> = names.into_iter().filter_map(|name| read_tag(&name).map(|tag| (name, tag))).collect();
+    tags.sort_by(|a, b| a.0.cmp(&b.0));
+    tags
+}
+
+/// A HEAD that is neither a branch nor a version id is damaged, not "no
+/// version": reading it as that would make the next commit a new root.
+pub fn read_head_ref() -> Result<HeadRef> {
+    let content = match storage::read_to_string(HEAD_FILE) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(ScmError::io("Cannot read HEAD")(e)),
+    };
+    let content = content.trim();
+
+    match content.strip_prefix(SYMREF_PREFIX) {
+        Some(branch) if is_valid_ref_name(branch) => Ok(HeadRef::Branch(branch.to_string())),
+        Some(_) => Err(malformed_head()),
+        None if content.is_empty() || content == "0" => Ok(HeadRef::Detached(None)),
+        None => content.parse().map(|id| HeadRef::Detached(Some(id))).map_err(|_| malformed_head()),
+    }
+}
+
+fn malformed_head() -> ScmError {
+    ScmError::IntegrityFailure("HEAD is malformed.".to_string())
+}
+
+pub fn write_head_ref(head: &HeadRef) -> Result<()> {
+    let content = match head {
+        HeadRef::Branch(name) => format!("{}{}", SYMREF_PREFIX, name),
+        HeadRef::Detached(id) => id.map(|id| id.to_string()).unwrap_or_default(),
+    };
+    storage().write_file(HEAD_FILE, content.as_bytes()).map_err(ScmError::io("Cannot write HEAD"))
+}
+
+pub fn current_branch() -> Result<Option<String>> {
+    match read_head_ref()? {
+        HeadRef::Branch(name) => Ok(Some(name)),
+        HeadRef::Detached(_) => Ok(None),
+    }
+}
+
+/// The version a branch points at. A branch with no commits yet has no file;
+/// one whose file doesn't hold a version id is damaged.
+pub fn read_branch(name: &str) -> Result<Option<VersionId>> {
+    if !is_valid_ref_name(name) { return Ok(None); }
+    let content = match storage::read_to_string(&branch_file(name)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ScmError::io(format!("Cannot read branch {}", name))(e)),
+    };
+    content.trim().parse().map(Some).map_err(|_| ScmError::IntegrityFailure(format!("Branch {} is malformed.", name)))
+}
+
+pub fn write_branch(name: &str, id: VersionId) -> Result<()> {
+    storage().write_file(&branch_file(name), id.to_string().as_bytes()).map_err(ScmError::io(format!("Cannot write branch {}", name)))
+}
+
+/// Returns false if the branch didn't exist.
+pub fn delete_branch(name: &str) -> bool {
+    is_valid_ref_name(name) && storage().remove_file(&branch_file(name)).is_ok()
+}
+
+/// All readable branches with their head versions, sorted by name.
+pub fn list_branches() -> Vec<(String, VersionId)> {
+    let names = match storage().list_files(HEADS_DIR) {
+        Ok(names) => names,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut branches: Vec<(String, VersionId)> = names.into_iter().filter_map(|name| read_branch(&name).ok().flatten().map(|id| (name, id))).collect();
+    branches.sort();
+    branches
+}
@@ -0,0 +1,38 @@
+use tracing::{error, info};
+
+use crate::storage::storage;
+use crate::{config, exit, list_versions, load_manifest, scm_dir, write_manifest};
+
+/// `scm repack [--compress]`: moves every stored file into a single pack (see
+/// `pack`), along with the copies versions from before the object store
+/// kept of their files. With `--compress`, compression is turned on for
+/// good and everything already stored, manifests included, is compressed
+/// too.
+pub fn do_repack(compress: bool) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    if compress
+        && let Err(e) = config::set("core.compression", "zstd")
+    {
+        return exit::report(e);
+    }
+
+    let packed = match storage().repack(compress) {
+        Ok(packed) => packed,
+        Err(e) => return exit::report(e),
+    };
+    let versions = list_versions();
+    if compress {
+        for &id in &versions {
+            if let Err(e) = load_manifest(id).and_then(|manifest| write_manifest(&manifest)) {
+                return exit::report(e);
+            }
+        }
+    }
+    info!("Packed {} object(s) into one pack file.", packed);
+    if compress {
+        info!("Compressed {} manifest(s); core.compression is now \"zstd\".", versions.len());
+    }
+}
@@ -0,0 +1,511 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::context::{self, Context};
+use crate::{
+    config, create_commit, crypt, discarded_changes, events, get_head, graph, hash, init_repo, join_author, list_versions, load_header, manifest_exists,
+    move_head_to, parents_of, prune, recover, refs, stage_commit_paths, status_changes, storage, verify, ChangeSet, Observer, Result, ScmError,
+    Storage, VersionId, SCM_DIR, SUPPORTED_COMPRESSION,
+};
+
+/// A repository, for programs that embed scm rather than run it. Each call
+/// does what the command of the same name does, but prints nothing and
+/// returns what it found.
+///
+/// Each works on its own working tree, by its path, and leaves the current
+/// directory alone, so a process can have several open. Those opened on the
+/// same tree share what they have read of it, and calls to them from
+/// several threads take turns.
+///
+/// Every call returns a `Result`; its `ScmError` says what went wrong in
+/// words fit to show the user.
+pub struct Repository {
+    context: Arc<Context>,
+    /// Where each call does its work, every thread of it on `context`.
+    pool: ThreadPool,
+}
+
+/// Whether `dir` is the root of a working tree. One kept in memory has no
+/// `.scm` to tell by, but is open.
+fn is_root(dir: &Path) -> bool {
+    dir.join(SCM_DIR).is_dir() || context::is_open(dir)
+}
+
+/// What changed between two trees, each list sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Old and new names.
+    pub renamed: Vec<(String, String)>,
+}
+
+impl Changes {
+    fn new(changes: ChangeSet, renamed: Vec<(String, String)>) -> Changes {
+        Changes { added: changes.added, modified: changes.modified, deleted: changes.deleted, renamed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.deleted.is_empty() && self.renamed.is_empty()
+    }
+}
+
+/// What `Repository::status` finds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// The version HEAD is at; None before the first commit.
+    pub version: Option<VersionId>,
+    pub branch: Option<String>,
+    /// The staged tree against HEAD.
+    pub staged: Changes,
+    /// The working directory against the staged tree; new files are added.
+    pub unstaged: Changes,
+}
+
+impl Status {
+    /// Whether there is nothing to commit.
+    pub fn is_clean(&self) -> bool {
+        self.staged.is_empty() && self.unstaged.is_empty()
+    }
+}
+
+/// What `Repository::commit` records, built up from `CommitOptions::new()`;
+/// `scm commit` builds one from its flags. Unless told otherwise a commit
+/// records the staged tree, with the configured author, and is refused if
+/// it would change nothing.
+#[derive(Debug, Clone, Default)]
+pub struct CommitOptions {
+    pub(crate) message: Option<String>,
+    pub(crate) all: bool,
+    pub(crate) paths: Vec<String>,
+    pub(crate) allow_empty: bool,
+    /// Name and email.
+    pub(crate) author: Option<(String, String)>,
+}
+
+impl CommitOptions {
+    pub fn new() -> CommitOptions {
+        CommitOptions::default()
+    }
+
+    /// A blank message is the same as none.
+    pub fn message(mut self, message: impl Into<String>) -> CommitOptions {
+        let message = message.into();
+        self.message = (!message.trim().is_empty()).then_some(message);
+        self
+    }
+
+    /// Whether to record the whole working directory, as `scm commit -a`
+    /// does, rather than the staged tree.
+    pub fn all(mut self, all: bool) -> CommitOptions {
+        self.all = all;
+        self
+    }
+
+    /// Files or directories to stage first, as `scm add` would, so the
+    /// commit records them as they are in the working directory along with
+    /// whatever else is staged. Each is taken from the root of the working
+    /// tree, or on the command line from where scm runs.
+    pub fn paths<P: Into<String>>(mut self, paths: impl IntoIterator<Item = P>) -> CommitOptions {
+        self.paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether to record a version that changes nothing from its parent.
+    pub fn allow_empty(mut self, allow_empty: bool) -> CommitOptions {
+        self.allow_empty = allow_empty;
+        self
+    }
+
+    /// The author to record, over `author.name` and `author.email`.
+    pub fn author(mut self, name: impl Into<String>, email: impl Into<String>) -> CommitOptions {
+        self.author = Some((name.into(), email.into()));
+        self
+    }
+}
+
+/// A version, as `Repository::log` lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub id: VersionId,
+    /// The first parent, then the merged one, if any.
+    pub parents: Vec<VersionId>,
+    pub timestamp: String,
+    /// "Name <email>", or whichever of the two was set.
+    pub author: Option<String>,
+    pub message: Option<String>,
+    /// How many files it holds.
+    pub files: usize,
+}
+
+impl Repository {
+    /// Creates a repository in `path`, making the directory if need be, on
+    /// the default branch with the default hash and compression.
+    pub fn init(path: impl AsRef<Path>) -> Result<Repository> {
+        Repository::create(path.as_ref(), None)
+    }
+
+    fn create(path: &Path, storage: Option<Arc<dyn Storage>>) -> Result<Repository> {
+        fs::create_dir_all(path).map_err(ScmError::io(format!("Cannot create {}", path.display())))?;
+        let root = path.canonicalize().map_err(ScmError::io(format!("Cannot open {}", path.display())))?;
+        if let Some(existing) = root.ancestors().find(|dir| is_root(dir)) {
+            return Err(ScmError::AlreadyARepository(existing.to_path_buf()));
+        }
+        let repo = Repository::at(root, storage)?;
+        repo.run(|| {
+            init_repo(refs::DEFAULT_BRANCH)?;
+            config::set("core.hash", hash::NAMES[0])?;
+            config::set("core.compression", SUPPORTED_COMPRESSION[0])
+        })?;
+        Ok(repo)
+    }
+
+    /// Like `init`, but the repository keeps its versions, their contents,
+    /// HEAD, branches and settings in memory, and writes no `.scm`: for
+    /// tests, and for throwaway snapshots such as an app's undo history.
+    /// It lasts while a `Repository` for `path` is open, and `open` finds
+    /// it until then. See `init_with_storage`.
+    pub fn init_in_memory(path: impl AsRef<Path>) -> Result<Repository> {
+        Repository::init_with_storage(path, storage::MemoryStorage::default())
+    }
+
+    /// Like `init`, but the repository keeps its versions and their contents
+    /// in `storage` rather than under `.scm`. The working tree is still
+    /// `path`, and HEAD, branches and settings are still written to its
+    /// `.scm` unless `storage` keeps them too (see `Storage::read_file`).
+    pub fn init_with_storage(path: impl AsRef<Path>, storage: impl Storage + 'static) -> Result<Repository> {
+        Repository::create(path.as_ref(), Some(Arc::new(storage)))
+    }
+
+    /// Like `open`, for a repository created with `init_with_storage`,
+    /// whose versions are in `storage`. Fails with `ScmError::StorageInUse`
+    /// while another `Repository` has it open.
+    pub fn open_with_storage(path: impl AsRef<Path>, storage: impl Storage + 'static) -> Result<Repository> {
+        Repository::find(path.as_ref(), Some(Arc::new(storage)))
+    }
+
+    /// Opens the repository `path` is in: the nearest directory at or above
+    /// it that has a `.scm`, or that another `Repository` has open.
+    pub fn open(path: impl AsRef<Path>) -> Result<Repository> {
+        Repository::find(path.as_ref(), None)
+    }
+
+    fn find(path: &Path, storage: Option<Arc<dyn Storage>>) -> Result<Repository> {
+        let start = path.canonicalize().map_err(ScmError::io(format!("Cannot open {}", path.display())))?;
+        match start.ancestors().find(|dir| is_root(dir)) {
+            Some(root) => Repository::at(root.to_path_buf(), storage),
+            None => Err(ScmError::NotARepository(path.to_path_buf())),
+        }
+    }
+
+    fn at(root: PathBuf, storage: Option<Arc<dyn Storage>>) -> Result<Repository> {
+        let context = context::open(&root, storage)?;
+        let entered = Arc::downgrade(&context);
+        let pool = ThreadPoolBuilder::new()
+            .start_handler(move |_| context::enter(entered.clone()))
+            .build()
+            .map_err(|e| ScmError::io("Cannot start worker threads")(io::Error::other(e)))?;
+        Ok(Repository { context, pool })
+    }
+
+    /// The root of the working tree.
+    pub fn root(&self) -> &Path {
+        &self.context.root
+    }
+
+    /// Tells `observer` about each file as commits, reverts and verifies
+    /// go, in place of any set before. Every `Repository` open on this
+    /// working tree shares it.
+    pub fn set_observer(&self, observer: impl Observer + 'static) {
+        *self.context.observer.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Arc::new(observer));
+    }
+
+    pub fn clear_observer(&self) {
+        *self.context.observer.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+    }
+
+    /// Gives an encrypted repository its passphrase; see `crypt`. Until
+    /// then every other call fails with `ScmError::Locked`, and a wrong one
+    /// fails with `ScmError::WrongPassphrase`.
+    pub fn unlock(&self, passphrase: &str) -> Result<()> {
+        self.run(|| crypt::unlock(passphrase))
+    }
+
+    /// Does `work` on this repository's threads, once no other call to it
+    /// is going on.
+    pub(crate) fn run<T: Send>(&self, work: impl FnOnce() -> Result<T> + Send) -> Result<T> {
+        let _busy = self.context.busy.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.pool.install(work)
+    }
+
+    /// Like `run`, but fails with `ScmError::Locked` until the key is known.
+    fn run_unlocked<T: Send>(&self, work: impl FnOnce() -> Result<T> + Send) -> Result<T> {
+        self.run(|| {
+            crypt::ensure_unlocked()?;
+            work()
+        })
+    }
+
+    /// Refuses to go on over an interrupted commit or revert, as the command
+    /// line does; `scm recover` deals with it.
+    fn check_pending() -> Result<()> {
+        match recover::pending() {
+            Some(operation) => Err(ScmError::Interrupted(operation.describe())),
+            None => Ok(()),
+        }
+    }
+
+    /// Commits as `options` say, as `scm commit` does but without running
+    /// hooks, and returns the new version's id. With nothing staged, pass
+    /// `all(true)` or `paths` to record the working directory's changes.
+    pub fn commit(&self, options: &CommitOptions) -> Result<VersionId> {
+        self.run_unlocked(|| {
+            Repository::check_pending()?;
+            stage_commit_paths(options)?;
+            let id = create_commit(options)?;
+            prune::after_commit()?;
+            graph::save();
+            Ok(id)
+        })
+    }
+
+    /// Restores the working directory to `version` and moves HEAD there, as
+    /// `scm revert <version> --force` does: uncommitted changes are lost,
+    /// and each file that had them is reported to the observer as a
+    /// conflict first.
+    pub fn revert(&self, version: VersionId) -> Result<()> {
+        self.run_unlocked(|| {
+            Repository::check_pending()?;
+            if !manifest_exists(version) {
+                return Err(ScmError::VersionNotFound(version));
+            }
+            for filename in discarded_changes(version)? {
+                events::conflict(&filename);
+            }
+            move_head_to("revert", version)?;
+            graph::save();
+            Ok(())
+        })
+    }
+
+    /// Checks every stored file of every version against its hash, as `scm
+    /// verify` does, and returns the problems found, worded as it prints
+    /// them: none if all is intact.
+    pub fn verify(&self) -> Result<Vec<String>> {
+        self.run_unlocked(|| {
+            let mut problems = Vec::new();
+            verify::check(list_versions(), &mut problems);
+            Ok(problems)
+        })
+    }
+
+    /// What `scm status` shows.
+    pub fn status(&self) -> Result<Status> {
+        self.run_unlocked(|| {
+            let (staged, renames, unstaged) = status_changes()?;
+            Ok(Status {
+                version: get_head()?,
+                branch: refs::current_branch()?,
+                staged: Changes::new(staged, renames),
+                unstaged: Changes::new(unstaged, Vec::new()),
+            })
+        })
+    }
+
+    /// Every version, newest first, as `scm log` lists them.
+    pub fn log(&self) -> Result<Vec<Version>> {
+        self.run_unlocked(|| {
+            let mut versions = Vec::new();
+            for id in list_versions().into_iter().rev() {
+                let header = load_header(id).ok_or(ScmError::CorruptManifest(id))?;
+                versions.push(Version {
+                    id,
+                    parents: parents_of(id),
+                    author: join_author(header.author_name.as_deref(), header.author_email.as_deref()),
+                    timestamp: header.timestamp,
+                    message: header.message,
+                    files: header.files,
+                });
+            }
+            graph::save();
+            Ok(versions)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::load_manifest;
+    use crate::storage::MemoryStorage;
+
+    /// An empty directory for one test's working tree.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("scm-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Like `init_in_memory`, keeping hold of the storage so a test can damage it.
+    fn in_memory(dir: &Path) -> (Repository, Arc<MemoryStorage>) {
+        let storage = Arc::new(MemoryStorage::default());
+        let repo = Repository::create(dir, Some(storage.clone())).unwrap();
+        (repo, storage)
+    }
+
+    fn commit_all(repo: &Repository, message: &str) -> VersionId {
+        repo.commit(&CommitOptions::new().message(message).all(true)).unwrap()
+    }
+
+    fn read(dir: &Path, name: &str) -> Option<String> {
+        fs::read_to_string(dir.join(name)).ok()
+    }
+
+    #[test]
+    fn commit_and_revert_round_trip() {
+        let dir = scratch("round-trip");
+        let repo = Repository::init_in_memory(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "one").unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/b.txt"), "b").unwrap();
+        let first = commit_all(&repo, "first");
+        fs::write(dir.join("a.txt"), "two").unwrap();
+        fs::remove_file(dir.join("sub/b.txt")).unwrap();
+        fs::write(dir.join("c.txt"), "c").unwrap();
+        let second = commit_all(&repo, "second");
+
+        repo.revert(first).unwrap();
+        assert_eq!(read(&dir, "a.txt").as_deref(), Some("one"));
+        assert_eq!(read(&dir, "sub/b.txt").as_deref(), Some("b"));
+        assert_eq!(read(&dir, "c.txt"), None);
+        assert_eq!(repo.status().unwrap().version, Some(first));
+
+        repo.revert(second).unwrap();
+        assert_eq!(read(&dir, "a.txt").as_deref(), Some("two"));
+        assert!(!dir.join("sub").exists());
+        assert_eq!(read(&dir, "c.txt").as_deref(), Some("c"));
+        let status = repo.status().unwrap();
+        assert!(status.is_clean());
+        assert_eq!(status.version, Some(second));
+        let log: Vec<(VersionId, Option<String>)> = repo.log().unwrap().into_iter().map(|version| (version.id, version.message)).collect();
+        assert_eq!(log, [(second, Some("second".to_string())), (first, Some("first".to_string()))]);
+        assert!(!dir.join(SCM_DIR).exists());
+    }
+
+    #[test]
+    fn merge_leaves_untracked_files_alone() {
+        // `scm merge` looks for the repository on disk.
+        let dir = scratch("merge-untracked");
+        let repo = Repository::init(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        let first = commit_all(&repo, "first");
+        fs::write(dir.join("a.txt"), "ONE\ntwo\nthree\n").unwrap();
+        let second = commit_all(&repo, "second");
+        repo.revert(first).unwrap();
+        fs::write(dir.join("a.txt"), "one\ntwo\nTHREE\n").unwrap();
+        let third = commit_all(&repo, "third");
+
+        fs::write(dir.join("notes.txt"), "mine").unwrap();
+        repo.run(|| {
+            crate::do_merge(Some(&second.to_string()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(read(&dir, "a.txt").as_deref(), Some("ONE\ntwo\nTHREE\n"));
+        assert_eq!(read(&dir, "notes.txt").as_deref(), Some("mine"));
+        let status = repo.status().unwrap();
+        assert_ne!(status.version, Some(third));
+        let files = repo.run(|| load_manifest(status.version.unwrap())).unwrap().files;
+        assert!(files.keys().eq(["a.txt"]), "{:?}", files);
+    }
+
+    #[test]
+    fn in_memory_repositories_last_while_open() {
+        let (one, two) = (scratch("open-one"), scratch("open-two"));
+        let first = Repository::init_in_memory(&one).unwrap();
+        let second = Repository::init_in_memory(&two).unwrap();
+        fs::write(one.join("a.txt"), "a").unwrap();
+        commit_all(&first, "first");
+        assert_eq!(second.log().unwrap().len(), 0);
+        assert!(matches!(Repository::init_in_memory(one.join("sub")), Err(ScmError::AlreadyARepository(_))));
+
+        let reopened = Repository::open(&one).unwrap();
+        assert_eq!(reopened.log().unwrap().len(), 1);
+        drop((first, reopened));
+        assert!(matches!(Repository::open(&one), Err(ScmError::NotARepository(_))));
+        assert_eq!(Repository::init_in_memory(&one).unwrap().log().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn verify_finds_damaged_and_missing_objects() {
+        let dir = scratch("verify");
+        let (repo, storage) = in_memory(&dir);
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        let id = commit_all(&repo, "first");
+        assert!(repo.verify().unwrap().is_empty());
+
+        let files = repo.run(|| load_manifest(id)).unwrap().files;
+        storage.damage(&files["a.txt"], b"not a");
+        storage.prune_objects(&HashSet::from([files["a.txt"].clone()])).unwrap();
+        let problems = repo.verify().unwrap();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems.iter().any(|problem| problem.contains("a.txt") && problem.contains("corrupted")), "{:?}", problems);
+        assert!(problems.iter().any(|problem| problem.contains("b.txt") && problem.contains("missing")), "{:?}", problems);
+    }
+
+    #[test]
+    fn revert_to_damaged_version_changes_nothing() {
+        let dir = scratch("damaged-revert");
+        let (repo, storage) = in_memory(&dir);
+        fs::write(dir.join("a.txt"), "one").unwrap();
+        let first = commit_all(&repo, "first");
+        fs::write(dir.join("a.txt"), "two").unwrap();
+        let second = commit_all(&repo, "second");
+
+        let hash = repo.run(|| load_manifest(first)).unwrap().files["a.txt"].clone();
+        storage.damage(&hash, b"garbage");
+        assert!(matches!(repo.revert(first), Err(ScmError::IntegrityFailure(_))));
+        assert_eq!(read(&dir, "a.txt").as_deref(), Some("two"));
+        assert_eq!(repo.status().unwrap().version, Some(second));
+
+        // Once the object is whole again, so is the version.
+        storage.damage(&hash, b"one");
+        repo.revert(first).unwrap();
+        assert_eq!(read(&dir, "a.txt").as_deref(), Some("one"));
+    }
+
+    #[test]
+    fn failed_commit_leaves_nothing_to_recover() {
+        let dir = scratch("failed-commit");
+        let repo = Repository::init_in_memory(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let first = commit_all(&repo, "first");
+        assert!(matches!(repo.commit(&CommitOptions::new().all(true)), Err(ScmError::NothingToCommit)));
+
+        fs::write(dir.join("a.txt"), "b").unwrap();
+        let second = commit_all(&repo, "second");
+        assert_eq!(repo.log().unwrap().iter().map(|version| version.id).collect::<Vec<_>>(), [second, first]);
+        assert!(repo.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn interrupted_operation_is_refused() {
+        let dir = scratch("interrupted");
+        let (repo, storage) = in_memory(&dir);
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let first = commit_all(&repo, "first");
+        repo.run(|| recover::begin(&recover::Operation::Amend { version: first }).map(std::mem::forget)).unwrap();
+        assert!(storage.read_file("OPERATION").is_ok());
+        assert!(matches!(repo.commit(&CommitOptions::new().all(true).allow_empty(true)), Err(ScmError::Interrupted(_))));
+        assert!(matches!(repo.revert(first), Err(ScmError::Interrupted(_))));
+    }
+}
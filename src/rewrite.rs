@@ -0,0 +1,249 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+use tracing::{error, info};
+
+use crate::storage::storage;
+use crate::{
+    compare_files, exit, get_head, has_uncommitted_changes, load_manifest, next_version_id, parent_of, pending_merge,
+    read_stored, resolve_version, restore_version, scm_dir, set_head, verify_stored, version_files, workdir, write_manifest, Manifest, Result,
+    ScmError, VersionId,
+};
+
+const TODO_FILE: &str = "REWRITE_TODO";
+
+enum Action {
+    Pick,
+    Squash,
+    Drop,
+    Reword(String),
+}
+
+struct Step {
+    action: Action,
+    version: VersionId,
+}
+
+pub fn do_rewrite(base_spec: &str, todo_file: Option<&str>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let base = match base_spec {
+        "root" => None,
+        spec => match resolve_version(spec) {
+            Ok(Some(id)) => Some(id),
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Version {} not found.", spec);
+                return;
+            }
+        },
+    };
+
+    let head = match get_head() {
+        Ok(head) => head,
+        Err(e) => return exit::report(e),
+    };
+    if pending_merge().is_some() {
+        error!("A merge is in progress. Commit it or run 'scm merge --abort'.");
+        return;
+    }
+    match has_uncommitted_changes(head) {
+        Err(e) => return exit::report(e),
+        Ok(true) => {
+            error!("You have uncommitted changes. Commit them before rewriting history.");
+            return;
+        }
+        Ok(false) => {}
+    }
+
+    // First-parent chain from HEAD back to (not including) the base, oldest first.
+    let mut chain = Vec::new();
+    let mut cursor = head;
+    while let Some(id) = cursor {
+        if Some(id) == base { break; }
+        chain.push(id);
+        cursor = parent_of(id);
+    }
+    if let Some(base) = base.filter(|&base| cursor != Some(base)) {
+        error!("Version {} is not an ancestor of HEAD.", base);
+        return;
+    }
+    chain.reverse();
+    if chain.is_empty() {
+        info!("Nothing to rewrite.");
+        return;
+    }
+
+    let plan = match todo_file {
+        Some(file) => match fs::read_to_string(workdir::resolve(file)).map_err(ScmError::io(format!("Cannot read {}", file))) {
+            Ok(plan) => plan,
+            Err(e) => return exit::report(e),
+        },
+        None => match edit_plan(&chain) {
+            Some(plan) => plan,
+            None => return,
+        },
+    };
+    let steps = match parse_plan(&plan, &chain) {
+        Ok(steps) => steps,
+        Err(e) => {
+            error!("Invalid rewrite plan: {}", e);
+            return;
+        }
+    };
+
+    // Collapse squashes into the step before them: the later snapshot wins and messages are joined.
+    let mut rebuilt: Vec<(VersionId, Option<String>)> = Vec::new();
+    for step in steps {
+        let message = match load_manifest(step.version) {
+            Ok(manifest) => manifest.message,
+            Err(e) => return exit::report(e),
+        };
+        match step.action {
+            Action::Drop => {}
+            Action::Pick => rebuilt.push((step.version, message)),
+            Action::Reword(text) => rebuilt.push((step.version, Some(text))),
+            Action::Squash => match rebuilt.pop() {
+                Some((_, previous)) => {
+                    let joined = match (previous, message) {
+                        (Some(a), Some(b)) => Some(format!("{}\n\n{}", a, b)),
+                        (a, b) => a.or(b),
+                    };
+                    rebuilt.push((step.version, joined));
+                }
+                None => {
+                    error!("Invalid rewrite plan: cannot squash into nothing.");
+                    return;
+                }
+            },
+        }
+    }
+
+    let mut parent = base;
+    for (old_id, message) in &rebuilt {
+        let new_id = match copy_version(*old_id, parent, message.clone()) {
+            Ok(new_id) => new_id,
+            Err(e) => return exit::report(e),
+        };
+        info!("  {} -> {}", old_id, new_id);
+        parent = Some(new_id);
+    }
+
+    // The chain is not empty, so HEAD is a version.
+    let Some(head) = head else { return };
+    let new_tip = match parent {
+        Some(new_tip) => new_tip,
+        None => {
+            info!("Every version was dropped; HEAD left at version {}.", head);
+            return;
+        }
+    };
+    let same_files = version_files(Some(head)).and_then(|files| Ok(compare_files(&files, &version_files(Some(new_tip))?).is_empty()));
+    if let Err(e) = same_files.and_then(|same| if same { Ok(()) } else { restore_version(new_tip) }) {
+        return exit::report(e);
+    }
+    if let Err(e) = set_head(new_tip) {
+        return exit::report(e);
+    }
+    info!("Rewrote {} version(s) into {}. Now at version {}.", chain.len(), rebuilt.len(), new_tip);
+    info!("The original versions are kept; 'scm reset --hard {}' undoes the rewrite.", head);
+}
+
+/// Writes the default plan to a todo file, opens it in $VISUAL/$EDITOR and
+/// returns the edited text.
+fn edit_plan(chain: &[VersionId]) -> Option<String> {
+    let mut plan = String::new();
+    for id in chain {
+        let summary = load_manifest(*id)
+            .ok()
+            .and_then(|m| m.message)
+            .and_then(|m| m.lines().next().map(str::to_string))
+            .unwrap_or_default();
+        plan.push_str(&format!("pick {} {}\n", id, summary));
+    }
+    plan.push_str("\n# Commands:\n");
+    plan.push_str("#  pick <version>            keep the version\n");
+    plan.push_str("#  reword <version> <text>   keep it with a new message\n");
+    plan.push_str("#  squash <version>          fold the previous line's snapshot into this one\n");
+    plan.push_str("#  drop <version>            remove the version (its changes carry into the next one)\n");
+
+    let todo_path = scm_dir().join(TODO_FILE);
+    if let Err(e) = fs::write(&todo_path, plan).map_err(ScmError::io(format!("Cannot write {}", todo_path.display()))) {
+        exit::report(e);
+        return None;
+    }
+
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    let status = Command::new(&editor).arg(&todo_path).status();
+    let result = match status {
+        Ok(status) if status.success() => fs::read_to_string(&todo_path).ok(),
+        _ => {
+            error!("Editor '{}' failed; rewrite aborted.", editor);
+            None
+        }
+    };
+    let _ = fs::remove_file(todo_path);
+    result
+}
+
+fn parse_plan(plan: &str, chain: &[VersionId]) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    for line in plan.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+
+        let mut parts = line.splitn(3, ' ');
+        let command = parts.next().unwrap_or_default();
+        let version: VersionId = parts
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| format!("missing version in '{}'", line))?;
+        if !chain.contains(&version) {
+            return Err(format!("version {} is not being rewritten", version));
+        }
+        if steps.iter().any(|s: &Step| s.version == version) {
+            return Err(format!("version {} listed twice", version));
+        }
+        let rest = parts.next().unwrap_or_default().trim().to_string();
+
+        let action = match command {
+            "pick" | "p" => Action::Pick,
+            "squash" | "s" => Action::Squash,
+            "drop" | "d" => Action::Drop,
+            "reword" | "r" if !rest.is_empty() => Action::Reword(rest),
+            "reword" | "r" => return Err(format!("reword of version {} needs a message", version)),
+            other => return Err(format!("unknown command '{}'", other)),
+        };
+        steps.push(Step { action, version });
+    }
+
+    if steps.is_empty() {
+        return Err("the plan is empty".to_string());
+    }
+    Ok(steps)
+}
+
+/// Records a version's snapshot under a fresh id with a new parent and
+/// message, verifying every file against the original manifest.
+fn copy_version(old_id: VersionId, parent: Option<VersionId>, message: Option<String>) -> Result<VersionId> {
+    let old = load_manifest(old_id)?;
+    let new_id = next_version_id()?;
+
+    for (filename, hash) in &old.files {
+        // Large files stay shared in the large-object store.
+        if old.metadata.get(filename).is_some_and(|m| m.large) { continue; }
+        verify_stored(old_id, filename, hash)?;
+        // Versions from before the object store keep their own copies; share them now.
+        if !storage().has(hash)? {
+            storage().put_object(hash, &read_stored(old_id, filename, hash)?, None)?;
+        }
+    }
+
+    // The change record is relative to the new parent.
+    let changes = compare_files(&version_files(parent)?, &old.files);
+    write_manifest(&Manifest { version_id: new_id, parent, message, changes: Some(changes), ..old })?;
+    Ok(new_id)
+}
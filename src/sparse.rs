@@ -0,0 +1,123 @@
+use std::fs;
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::cli::SparseCommand;
+use crate::ignore::IgnoreRules;
+use crate::storage::{self, storage};
+use crate::{exit, get_head, json, load_manifest, remove_working_file, scm_dir, working_hash, working_path, write_stored_file, FileHash};
+
+/// Sparse checkout: while `.scm/sparse` lists patterns (gitignore syntax,
+/// one per line), checkouts only write the files they match. Commits still
+/// record the full tree: a file outside the set that is missing from the
+/// working tree counts as unchanged rather than deleted.
+const SPARSE_FILE: &str = "sparse";
+
+pub struct Sparse {
+    rules: IgnoreRules,
+}
+
+impl Sparse {
+    fn includes(&self, path: &str) -> bool {
+        self.rules.matches(path, false)
+    }
+}
+
+/// The sparse set, or None for a full checkout.
+pub fn load() -> Option<Sparse> {
+    let patterns = patterns()?;
+    Some(Sparse { rules: IgnoreRules::from_patterns(&patterns, SPARSE_FILE) })
+}
+
+fn patterns() -> Option<Vec<String>> {
+    let content = storage::read_to_string(SPARSE_FILE).ok()?;
+    Some(content.lines().map(str::to_string).filter(|line| !line.trim().is_empty()).collect())
+}
+
+/// Whether a tracked file is absent only because it is outside the sparse set.
+pub fn skipped(sparse: &Option<Sparse>, path: &str) -> bool {
+    sparse.as_ref().is_some_and(|sparse| !sparse.includes(path) && fs::symlink_metadata(working_path(path)).is_err())
+}
+
+/// Whether checkouts write a path.
+pub fn includes(sparse: &Option<Sparse>, path: &str) -> bool {
+    sparse.as_ref().is_none_or(|sparse| sparse.includes(path))
+}
+
+/// `scm sparse list --json`; `patterns` is null when sparse checkout is off.
+#[derive(Serialize)]
+struct SparseReport {
+    patterns: Option<Vec<String>>,
+}
+
+/// `scm sparse set <pattern>... | list | disable`
+pub fn do_sparse(command: &SparseCommand) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    match command {
+        SparseCommand::Set { patterns } => {
+            storage().write_file(SPARSE_FILE, format!("{}\n", patterns.join("\n")).as_bytes()).expect("Failed to write sparse file");
+            apply();
+        }
+        SparseCommand::List => match patterns() {
+            patterns if json::enabled() => json::print(&SparseReport { patterns }),
+            Some(patterns) => patterns.iter().for_each(|pattern| println!("{}", pattern)),
+            None => println!("Sparse checkout is off; every file is checked out."),
+        },
+        SparseCommand::Disable => {
+            let _ = storage().remove_file(SPARSE_FILE);
+            apply();
+        }
+    }
+}
+
+/// Brings the working tree in line with the sparse set: writes HEAD's files
+/// that are now included but missing, and removes those now excluded,
+/// keeping any with uncommitted changes.
+fn apply() {
+    let head = match get_head() {
+        Ok(Some(head)) => head,
+        Ok(None) => return,
+        Err(e) => return exit::report(e),
+    };
+    let manifest = match load_manifest(head) {
+        Ok(manifest) => manifest,
+        Err(e) => return exit::report(e),
+    };
+    let sparse = load();
+    let (mut added, mut removed, mut kept) = (0, 0, Vec::new());
+    let mut files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+    files.sort();
+    for (filename, hash) in files {
+        let path = working_path(filename);
+        let present = fs::symlink_metadata(&path).is_ok();
+        if includes(&sparse, filename) {
+            if !present {
+                if let Err(e) = write_stored_file(head, filename, hash, manifest.metadata.get(filename), &path) {
+                    return exit::report(e);
+                }
+                added += 1;
+            }
+        } else if present {
+            match working_hash(filename, &path) {
+                Ok(working) if &working == hash => {
+                    if let Err(e) = remove_working_file(&path) {
+                        return exit::report(e);
+                    }
+                    removed += 1;
+                }
+                Ok(_) => kept.push(filename),
+                Err(e) => return exit::report(e),
+            }
+        }
+    }
+    info!("Checked out {} file(s) and removed {} outside the sparse set.", added, removed);
+    if !kept.is_empty() {
+        info!("Kept {} file(s) outside the sparse set because they have uncommitted changes:", kept.len());
+        for filename in kept {
+            info!("  {}", filename);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::Path;
+use tracing::{error, info};
+
+use crate::{
+    compare_files, copy_repository, exit, gc, get_commit_path, get_head, list_versions, load_manifest, objects, prune, restore_version, scm_dir,
+    version_files, write_manifest, ScmError, VersionId,
+};
+
+/// `scm split <subdir> <new-repo>`: creates a repository at `<new-repo>`
+/// whose history is this one's as seen from inside `<subdir>`: the same
+/// versions, ids, messages and refs, holding only the files under it, moved
+/// up to the root. Versions that changed nothing there are dropped unless a
+/// ref points at them. This repository is left as it is.
+pub fn do_split(subdir: &str, dst: &Path) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let prefix = format!("{}/", subdir.trim_matches('/'));
+    if prefix == "/" || prefix.split('/').any(|part| part == "." || part == "..") {
+        error!("{} is not a subdirectory of the repository.", subdir);
+        return;
+    }
+    if !list_versions().into_iter().filter_map(|id| load_manifest(id).ok()).any(|m| m.files.keys().any(|name| name.starts_with(&prefix))) {
+        error!("No version has files under {}.", prefix);
+        return;
+    }
+    if dst.exists() && fs::read_dir(dst).map(|mut d| d.next().is_some()).unwrap_or(true) {
+        error!("Destination {} already exists and is not empty.", dst.display());
+        return;
+    }
+
+    info!("Splitting {} into {}...", prefix, dst.display());
+    if let Err(e) = copy_repository(&scm_dir(), dst) {
+        return exit::report(e);
+    }
+    env::set_current_dir(dst).expect("Failed to enter destination dir");
+    // Sparse patterns name paths from the old layout.
+    let _ = fs::remove_file(scm_dir().join("sparse"));
+
+    // Parents have lower ids, so each is rewritten before its children.
+    let mut unchanged = HashSet::new();
+    for id in list_versions() {
+        let mut manifest = match load_manifest(id) {
+            Ok(manifest) => manifest,
+            Err(_) => continue,
+        };
+        let strip = |name: &String| name.strip_prefix(&prefix).map(str::to_string);
+        let mut files = HashMap::new();
+        let mut metadata = HashMap::new();
+        for (name, hash) in &manifest.files {
+            let new_name = match strip(name) {
+                Some(new_name) => new_name,
+                None => continue,
+            };
+            // Versions from before the object store keep their own copies,
+            // under the old names; the object store takes them instead,
+            // unless it has them already (see `objects::store`).
+            let legacy = get_commit_path(id).join(name);
+            if legacy.is_file()
+                && let Err(e) = fs::read(&legacy).map_err(ScmError::io(format!("Cannot read {}", legacy.display()))).and_then(|content| objects::store_bytes(&content, hash))
+            {
+                return exit::report(e);
+            }
+            if let Some(meta) = manifest.metadata.remove(name) {
+                metadata.insert(new_name.clone(), meta);
+            }
+            files.insert(new_name, hash.clone());
+        }
+        remove_legacy_copies(id);
+        manifest.files = files;
+        manifest.metadata = metadata;
+        manifest.dirs = manifest.dirs.iter().filter_map(strip).collect();
+        manifest.removed = manifest.removed.iter().filter_map(strip).collect();
+        manifest.renamed = manifest.renamed.iter().filter_map(|(old, new)| Some((strip(old)?, strip(new)?))).collect();
+        let changes = match version_files(manifest.parent) {
+            Ok(parent_files) => compare_files(&parent_files, &manifest.files),
+            Err(e) => return exit::report(e),
+        };
+        if changes.added.is_empty() && changes.modified.is_empty() && changes.deleted.is_empty() {
+            unchanged.insert(id);
+        }
+        manifest.changes = Some(changes);
+        if let Err(e) = write_manifest(&manifest) {
+            return exit::report(e);
+        }
+    }
+
+    let protected = match prune::protected() {
+        Ok(protected) => protected,
+        Err(e) => return exit::report(e),
+    };
+    let doomed = unchanged.into_iter().filter(|id| !protected.contains(id)).collect();
+    let (versions, _) = match prune::remove_versions(&doomed) {
+        Ok(removed) => removed,
+        Err(e) => return exit::report(e),
+    };
+    let kept = list_versions().len();
+    if let Err(e) = gc::remove_unused_objects() {
+        return exit::report(e);
+    }
+
+    if let Err(e) = get_head().and_then(|head| head.map_or(Ok(()), restore_version)) {
+        return exit::report(e);
+    }
+    info!("Split complete: {} version(s) kept, {} that changed nothing under {} dropped.", kept, versions, prefix);
+}
+
+/// Deletes the whole-file copies an old version keeps beside its manifest.
+fn remove_legacy_copies(id: VersionId) {
+    for entry in fs::read_dir(get_commit_path(id)).expect("Failed to read commit dir").filter_map(|entry| entry.ok()) {
+        if entry.file_name().to_string_lossy().starts_with("manifest.") {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            fs::remove_dir_all(path).expect("Failed to remove stored copy");
+        } else {
+            fs::remove_file(path).expect("Failed to remove stored copy");
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+
+use crate::{crypt, id, scm_dir, FileHash, FileMeta, Result, ScmError, VersionId};
+
+const STASH_DIR: &str = "stash";
+const STASH_MANIFEST: &str = "stash.json";
+
+/// A set of uncommitted changes set aside by `scm stash`. Changed and new
+/// files go into the object store, as a commit's do, and this mini-manifest
+/// is sealed like a manifest (see `crypt`); deletions are only listed.
+/// Stashes from before kept copies of the files next to it instead.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StashEntry {
+    /// None if stashed before the first commit; written as 0.
+    #[serde(with = "id::or_zero")]
+    pub base_version: Option<VersionId>,
+    pub timestamp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub files: HashMap<String, FileHash>, // Filename -> content hash
+    /// As in a manifest; empty for a stash that kept copies.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, FileMeta>,
+    #[serde(default)]
+    pub deleted: Vec<String>,
+}
+
+fn stash_root() -> PathBuf {
+    scm_dir().join(STASH_DIR)
+}
+
+fn entry_path(index: usize) -> PathBuf {
+    stash_root().join(index.to_string())
+}
+
+/// Stash indices, oldest first.
+pub fn list_indices() -> Vec<usize> {
+    let entries = match fs::read_dir(stash_root()) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut indices: Vec<usize> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_string_lossy().parse().ok())
+        .collect();
+    indices.sort_unstable();
+    indices
+}
+
+pub fn load(index: usize) -> Option<StashEntry> {
+    let sealed = fs::read(entry_path(index).join(STASH_MANIFEST)).ok()?;
+    serde_json::from_slice(&crypt::open(sealed).ok()??).ok()
+}
+
+/// Saves an entry whose files are already stored in a new stash slot and
+/// returns its index.
+pub fn save(entry: &StashEntry) -> Result<usize> {
+    let index = list_indices().last().map_or(0, |&i| i + 1);
+    let path = entry_path(index);
+    fs::create_dir_all(&path).map_err(ScmError::io("Failed to create the stash directory"))?;
+    let sealed = crypt::seal(serde_json::to_vec_pretty(entry).unwrap())?;
+    fs::write(path.join(STASH_MANIFEST), sealed).map_err(ScmError::io("Failed to write the stash manifest"))?;
+    Ok(index)
+}
+
+/// Where a stash from before kept its copy of a file.
+pub fn copy_path(index: usize, filename: &str) -> PathBuf {
+    entry_path(index).join(filename)
+}
+
+pub fn remove(index: usize) -> Result<()> {
+    fs::remove_dir_all(entry_path(index)).map_err(ScmError::io("Failed to remove the stash entry"))
+}
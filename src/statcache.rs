@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::attributes::ATTRIBUTES_FILE;
+use crate::storage::storage;
+use crate::{config, context, crypt, hash_bytes, working_path, FileHash};
+
+/// The stat cache remembers the hash of each working file along with its
+/// size and modification time, so `commit` and `status` only read files
+/// whose size or mtime changed. It is thrown away whenever the config or
+/// `.scmattributes` changes, since those decide how files are hashed. It
+/// names every working file, so it is sealed like a manifest (see `crypt`).
+///
+/// A file modified within `RACY_WINDOW` of being hashed could change again
+/// without its mtime moving, so such files aren't cached until they have
+/// been left alone for a while.
+const CACHE_FILE: &str = "statcache";
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize)]
+pub struct Cache {
+    /// Hash of the settings the cached hashes were computed under.
+    settings: FileHash,
+    entries: HashMap<String, Entry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct Entry {
+    size: u64,
+    mtime_ns: u128,
+    hash: FileHash,
+}
+
+/// Set by `--no-cache`: hash every file and leave the cache alone.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    !DISABLED.load(Ordering::Relaxed)
+}
+
+fn settings() -> FileHash {
+    let mut content = config::fingerprint();
+    content.extend(fs::read(working_path(ATTRIBUTES_FILE)).unwrap_or_default());
+    hash_bytes(&content)
+}
+
+fn with_cache<T>(f: impl FnOnce(&mut Cache, &mut bool) -> T) -> T {
+    // The cache and whether it changed since it was loaded.
+    let context = context::current();
+    let mut guard = context.statcache.lock().unwrap();
+    let (cache, dirty) = guard.get_or_insert_with(|| {
+        let settings = settings();
+        let cache = storage().read_file(CACHE_FILE).ok().and_then(|sealed| crypt::open(sealed).ok().flatten()).and_then(|content| serde_json::from_slice::<Cache>(&content).ok());
+        match cache {
+            Some(cache) if cache.settings == settings => (cache, false),
+            _ => (Cache { settings, entries: HashMap::new() }, true),
+        }
+    });
+    f(cache, dirty)
+}
+
+fn mtime_ns(meta: &Metadata) -> Option<u128> {
+    Some(meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+/// The cached hash of a working file, if its size and mtime still match.
+pub fn lookup(filename: &str, meta: &Metadata) -> Option<FileHash> {
+    if DISABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mtime_ns = mtime_ns(meta)?;
+    with_cache(|cache, _| {
+        cache.entries.get(filename).filter(|entry| entry.size == meta.len() && entry.mtime_ns == mtime_ns).map(|entry| entry.hash.clone())
+    })
+}
+
+/// Remembers a working file's hash, unless its mtime is too recent to trust.
+pub fn record(filename: &str, meta: &Metadata, hash: &FileHash) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mtime_ns = match mtime_ns(meta) {
+        Some(mtime_ns) => mtime_ns,
+        None => return,
+    };
+    let settled = SystemTime::now().duration_since(UNIX_EPOCH).is_ok_and(|now| now.as_nanos() >= mtime_ns + RACY_WINDOW.as_nanos());
+    let entry = Entry { size: meta.len(), mtime_ns, hash: hash.clone() };
+    with_cache(|cache, dirty| {
+        if !settled {
+            *dirty |= cache.entries.remove(filename).is_some();
+        } else if cache.entries.get(filename) != Some(&entry) {
+            cache.entries.insert(filename.to_string(), entry);
+            *dirty = true;
+        }
+    });
+}
+
+/// Writes the cache back if it changed, dropping files no longer in the
+/// working tree.
+pub fn save(working: &HashMap<String, FileHash>) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    with_cache(|cache, dirty| {
+        let before = cache.entries.len();
+        cache.entries.retain(|name, _| working.contains_key(name));
+        if *dirty || cache.entries.len() != before {
+            // Only a cache: one that can't be sealed or written is rebuilt next time.
+            if let Ok(sealed) = crypt::seal(serde_json::to_vec(&cache).unwrap())
+                && let Err(e) = storage().write_file(CACHE_FILE, &sealed)
+            {
+                debug!("Cannot write the stat cache: {}", e);
+            }
+            *dirty = false;
+        }
+    });
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use tracing::error;
+
+use crate::{gc, json, list_versions, load_manifest, scm_dir, try_read_stored, FileHash, VersionId};
+
+/// How many of the largest files to list.
+const LARGEST: usize = 10;
+
+#[derive(Serialize)]
+struct Stats {
+    versions: usize,
+    /// Every version's files added up, as if each were stored in full.
+    total_size: u64,
+    /// Each distinct content counted once.
+    deduplicated_size: u64,
+    /// The object store (loose objects, packs and large files) on disk.
+    stored_size: u64,
+    /// The whole `.scm` directory on disk.
+    repository_size: u64,
+    /// `deduplicated_size / stored_size`: what deltas and zstd save.
+    compression_ratio: f64,
+    largest_files: Vec<LargeFile>,
+    growth: Vec<Growth>,
+}
+
+#[derive(Serialize)]
+struct LargeFile {
+    path: String,
+    size: u64,
+    /// The first version with this content.
+    version: VersionId,
+}
+
+#[derive(Serialize)]
+struct Growth {
+    version: VersionId,
+    files: usize,
+    size: u64,
+    /// Content this version was the first to record.
+    new_size: u64,
+}
+
+/// `scm stats`: version count, logical and deduplicated size,
+/// size on disk, compression ratio, the largest files and how much new
+/// content each version added, to help decide when to prune or repack.
+pub fn do_stats() {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+
+    let stats = collect();
+    if json::enabled() {
+        json::print(&stats);
+        return;
+    }
+    println!("Versions:           {}", stats.versions);
+    println!("Total size:         {}", human(stats.total_size));
+    println!("Deduplicated size:  {}", human(stats.deduplicated_size));
+    println!("Stored size:        {}", human(stats.stored_size));
+    println!("Repository on disk: {}", human(stats.repository_size));
+    println!("Compression ratio:  {:.2}", stats.compression_ratio);
+    if !stats.largest_files.is_empty() {
+        println!();
+        println!("Largest files:");
+        for file in &stats.largest_files {
+            println!("  {:>10}  {} (version {})", human(file.size), file.path, file.version);
+        }
+    }
+    if !stats.growth.is_empty() {
+        println!();
+        println!("Growth per version:");
+        println!("  {:>7}  {:>6}  {:>10}  {:>10}", "version", "files", "size", "new");
+        for growth in &stats.growth {
+            println!("  {:>7}  {:>6}  {:>10}  {:>10}", growth.version, growth.files, human(growth.size), human(growth.new_size));
+        }
+    }
+}
+
+fn collect() -> Stats {
+    let mut sizes: HashMap<FileHash, u64> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut largest: Vec<LargeFile> = Vec::new();
+    let mut growth = Vec::new();
+    for id in list_versions() {
+        let manifest = match load_manifest(id) {
+            Ok(manifest) => manifest,
+            Err(_) => continue,
+        };
+        let mut files: Vec<(&String, &FileHash)> = manifest.files.iter().collect();
+        files.sort();
+        let (mut size, mut new_size) = (0, 0);
+        for (filename, hash) in files {
+            let file_size = *sizes.entry(hash.clone()).or_insert_with(|| {
+                match manifest.metadata.get(filename).and_then(|m| m.size) {
+                    Some(size) => size,
+                    None => try_read_stored(id, filename, hash).ok().flatten().map_or(0, |content| content.len() as u64),
+                }
+            });
+            size += file_size;
+            if seen.insert(hash.clone()) {
+                new_size += file_size;
+                largest.push(LargeFile { path: filename.clone(), size: file_size, version: id });
+            }
+        }
+        growth.push(Growth { version: id, files: manifest.files.len(), size, new_size });
+    }
+    largest.sort_by(|a, b| b.size.cmp(&a.size).then(a.path.cmp(&b.path)));
+    largest.truncate(LARGEST);
+
+    let scm = scm_dir();
+    let stored_size = gc::dir_size(&scm.join("objects")) + gc::dir_size(&scm.join("large"));
+    let deduplicated_size = sizes.values().sum();
+    Stats {
+        versions: growth.len(),
+        total_size: growth.iter().map(|g| g.size).sum(),
+        deduplicated_size,
+        stored_size,
+        repository_size: gc::dir_size(&scm),
+        compression_ratio: if stored_size == 0 { 1.0 } else { deduplicated_size as f64 / stored_size as f64 },
+        largest_files: largest,
+        growth,
+    }
+}
+
+/// A byte count in the largest unit that keeps it at least 1, e.g. "3.4 MiB".
+pub fn human(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
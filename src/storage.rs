@@ -0,0 +1,599 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, RwLock};
+
+use tracing::info;
+
+use crate::{
+    calculate_hash, config, context, copy_recursive, crypt, get_commit_path, hash_bytes, header_of, large, objects, pack, read_stored, recover, scm_dir,
+    swap, verify_stored, FileHash, Manifest, ManifestHeader, Result, ScmError, VersionId, COMMITS_DIR,
+};
+
+/// Where a repository keeps its versions and the file contents they refer
+/// to. Commit, revert and log, and `gc`, `fsck`, `repack` and `bundle`
+/// with them, reach them only through `storage()`, so another backend
+/// needs nothing more than this trait (see `Repository::init_with_storage`);
+/// `FsStorage`, the layout under `.scm`, is the one repositories use unless
+/// told otherwise. A backend keeps manifests as it likes: they serialize
+/// with serde.
+///
+/// Objects are named by the hash of their content, and reads check it as
+/// the filesystem does; a manifest is stored and replaced in one step, so a
+/// version is either there in full or not at all.
+pub trait Storage: Send + Sync {
+    /// Stores `content` as the object `hash` unless it is there already,
+    /// perhaps as a delta against the object `base` (the previous version
+    /// of the same file).
+    fn put_object(&self, hash: &str, content: &[u8], base: Option<&str>) -> Result<()>;
+
+    /// Like `put_object`, for content read unchanged from the file `src`,
+    /// which a backend on the same filesystem may copy more cheaply.
+    fn put_object_from(&self, src: &Path, hash: &str, content: &[u8], base: Option<&str>) -> Result<()> {
+        let _ = src;
+        self.put_object(hash, content, base)
+    }
+
+    /// An object's content, or None if it isn't stored. One that can't be
+    /// read back is an `IntegrityFailure`.
+    fn get_object(&self, hash: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Whether an object is stored. Storage that can't tell, such as a
+    /// pack whose index is damaged, is an error.
+    fn has(&self, hash: &str) -> Result<bool>;
+
+    /// Checks that an object can be read back and matches its hash,
+    /// describing what is wrong instead of panicking.
+    fn verify_object(&self, hash: &str) -> Result<(), String> {
+        match self.get_object(hash) {
+            Err(e) => Err(e.to_string()),
+            Ok(None) => Err("missing".to_string()),
+            Ok(Some(content)) if hash_bytes(&content) != hash => Err("corrupted (content does not match its hash)".to_string()),
+            Ok(Some(_)) => Ok(()),
+        }
+    }
+
+    /// A file holding the object byte for byte, for restores to copy
+    /// instead of writing the content out, if the backend has one.
+    fn object_file(&self, hash: &str) -> Option<PathBuf> {
+        let _ = hash;
+        None
+    }
+
+    /// The copy of a file that a version from before the object store
+    /// keeps itself, if the backend has such versions.
+    fn legacy_copy(&self, id: VersionId, filename: &str) -> Option<PathBuf> {
+        let _ = (id, filename);
+        None
+    }
+
+    /// Deletes every object not in `live`, returning how many went. Objects
+    /// that one in `live` needs to be read, such as a delta's base, stay.
+    fn prune_objects(&self, live: &HashSet<FileHash>) -> Result<usize>;
+
+    /// Rewrites the stored objects in the form that reads fastest, also
+    /// compressing those that aren't with `compress_all`, and returns how
+    /// many there are. A backend with nothing to rearrange does nothing.
+    fn repack(&self, compress_all: bool) -> Result<usize> {
+        let _ = compress_all;
+        Ok(0)
+    }
+
+    /// Problems with the store as a whole that checking objects one by one
+    /// would miss, such as a truncated pack file.
+    fn check(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Gets ready for a commit or amend to store version `id`, which
+    /// `recover` may have to finish or roll back if it is cut short.
+    fn begin_manifest(&self, id: VersionId) -> Result<()> {
+        let _ = id;
+        Ok(())
+    }
+
+    /// Stores a version's manifest, replacing any it had, in one step.
+    fn put_manifest(&self, manifest: &Manifest) -> Result<()>;
+
+    /// Undoes `begin_manifest` for a commit or amend of version `id` that
+    /// stopped before `put_manifest` was done, leaving the version as it
+    /// was; false if it was done, and there is nothing to undo.
+    fn roll_back_manifest(&self, id: VersionId) -> Result<bool>;
+
+    /// A version's manifest exactly as stored, in whatever format it was
+    /// written, or None if there is none. One that can't be read back is a
+    /// `CorruptManifest`.
+    fn get_manifest(&self, id: VersionId) -> Result<Option<Manifest>>;
+
+    /// A version's header, if the backend keeps one apart from its manifest
+    /// (see `ManifestHeader`); `load_header` falls back to the manifest.
+    fn get_header(&self, id: VersionId) -> Option<ManifestHeader> {
+        let _ = id;
+        None
+    }
+
+    fn has_manifest(&self, id: VersionId) -> bool;
+
+    /// Deletes a version, with anything kept beside its manifest.
+    fn remove_manifest(&self, id: VersionId) -> Result<()>;
+
+    /// Every version's id, ascending.
+    fn list_versions(&self) -> Vec<VersionId>;
+
+    /// Sets up a new repository's storage.
+    fn init(&self) -> Result<()> {
+        let dir = scm_dir();
+        fs::create_dir(&dir).map_err(ScmError::io(format!("Cannot create {}", dir.display())))
+    }
+
+    /// Whether `init` has been done.
+    fn exists(&self) -> bool {
+        scm_dir().is_dir()
+    }
+
+    /// One of the repository's own small files, such as `config`, `HEAD`,
+    /// `index` or `refs/heads/main`, by its path under `.scm` with `/`
+    /// separators. Unless a backend keeps them itself they are kept there,
+    /// on disk beside the working tree.
+    fn read_file(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(scm_dir().join(name))
+    }
+
+    /// Replaces a file (see `read_file`), making any directories above it.
+    fn write_file(&self, name: &str, content: &[u8]) -> io::Result<()> {
+        let path = scm_dir().join(name);
+        fs::create_dir_all(path.parent().expect("under .scm"))?;
+        fs::write(path, content)
+    }
+
+    fn append_file(&self, name: &str, content: &[u8]) -> io::Result<()> {
+        OpenOptions::new().create(true).append(true).open(scm_dir().join(name))?.write_all(content)
+    }
+
+    fn rename_file(&self, from: &str, to: &str) -> io::Result<()> {
+        let to = scm_dir().join(to);
+        fs::create_dir_all(to.parent().expect("under .scm"))?;
+        fs::rename(scm_dir().join(from), to)
+    }
+
+    /// Deletes a file, or a directory of them with everything in it.
+    fn remove_file(&self, name: &str) -> io::Result<()> {
+        let path = scm_dir().join(name);
+        if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) }
+    }
+
+    /// The names of the files directly in the directory `dir`.
+    fn list_files(&self, dir: &str) -> io::Result<Vec<String>> {
+        let entries = fs::read_dir(scm_dir().join(dir))?.filter_map(|entry| entry.ok());
+        Ok(entries.filter(|entry| entry.path().is_file()).map(|entry| entry.file_name().to_string_lossy().into_owned()).collect())
+    }
+
+    /// A directory on disk, on the working tree's filesystem if it can be,
+    /// for files on their way into it (see `swap`).
+    fn scratch_dir(&self) -> PathBuf {
+        scm_dir().join(swap::SWAP_DIR)
+    }
+}
+
+/// `Storage::read_file` as text, as `fs::read_to_string` reads.
+pub(crate) fn read_to_string(name: &str) -> io::Result<String> {
+    String::from_utf8(storage().read_file(name)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The storage of the repository being worked on (see `context`):
+/// `FsStorage` unless it was opened with other storage.
+pub(crate) fn storage() -> Arc<dyn Storage> {
+    context::current().storage.clone()
+}
+
+/// The layout under `.scm`: objects as `objects` keeps them, loose or
+/// packed, and each version in `commits/<id>`, its manifest beside a
+/// `manifest.header`. Versions from before the object store also keep
+/// copies of their files there, and files kept whole from before chunking
+/// are objects of the large-object store (see `large`).
+pub(crate) struct FsStorage;
+
+const HEADER_FILE: &str = "manifest.header";
+
+impl FsStorage {
+    fn header_path(id: VersionId) -> PathBuf {
+        get_commit_path(id).join(HEADER_FILE)
+    }
+
+    /// Writes a manifest and its header into `dir` in the configured
+    /// encoding, compressed when `core.compression` is on and encrypted in
+    /// an encrypted repository, replacing any other form.
+    fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+        let cbor = cbor_manifests();
+        let compressed = objects::compression_enabled();
+        let unwritable = || ScmError::io(format!("Cannot write the manifest of version {}", manifest.version_id));
+        let mut bytes = Vec::new();
+        if cbor {
+            ciborium::into_writer(manifest, &mut bytes).map_err(io::Error::other).map_err(unwritable())?;
+        } else {
+            bytes = serde_json::to_vec_pretty(manifest).map_err(io::Error::from).map_err(unwritable())?;
+        }
+        if compressed {
+            bytes = objects::compress(&bytes);
+        }
+        let sealed = crypt::seal(bytes)?;
+        for (path, form_cbor, form_compressed) in manifest_forms_in(dir) {
+            if (form_cbor, form_compressed) == (cbor, compressed) {
+                fs::write(path, &sealed).map_err(unwritable())?;
+            } else {
+                let _ = fs::remove_file(path);
+            }
+        }
+        let header = serde_json::to_vec(&header_of(manifest)).map_err(io::Error::from).map_err(unwritable())?;
+        fs::write(dir.join(HEADER_FILE), crypt::seal(header)?).map_err(unwritable())
+    }
+
+    /// Moves the copies that versions from before the object store keep
+    /// of their files into it, returning how many there were.
+    fn absorb_legacy_copies(&self) -> Result<usize> {
+        let mut moved = 0;
+        for id in self.list_versions() {
+            let Some(manifest) = self.get_manifest(id)? else { continue };
+            let commit_path = get_commit_path(id);
+            for (filename, hash) in &manifest.files {
+                let legacy = commit_path.join(filename);
+                if manifest.metadata.get(filename).is_some_and(|m| m.large) || !legacy.is_file() { continue; }
+                verify_stored(id, filename, hash)?;
+                if !objects::exists(hash)? {
+                    objects::store_bytes(&read_stored(id, filename, hash)?, hash)?;
+                }
+                fs::remove_file(&legacy).map_err(ScmError::io(format!("Cannot remove {}", legacy.display())))?;
+                moved += 1;
+            }
+            let unreadable = ScmError::io(format!("Cannot read {}", commit_path.display()));
+            for entry in fs::read_dir(&commit_path).map_err(unreadable)?.filter_map(|entry| entry.ok()) {
+                if entry.path().is_dir() {
+                    fs::remove_dir_all(entry.path()).map_err(ScmError::io(format!("Cannot remove {}", entry.path().display())))?;
+                }
+            }
+        }
+        Ok(moved)
+    }
+}
+
+impl Storage for FsStorage {
+    fn put_object(&self, hash: &str, content: &[u8], base: Option<&str>) -> Result<()> {
+        objects::store(content, hash, base)
+    }
+
+    fn put_object_from(&self, src: &Path, hash: &str, content: &[u8], base: Option<&str>) -> Result<()> {
+        objects::store_from(src, content, hash, base)
+    }
+
+    fn get_object(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        match objects::read(hash)? {
+            Some(content) => Ok(Some(content)),
+            None => Ok(fs::read(large::object_path(hash)).ok()),
+        }
+    }
+
+    fn has(&self, hash: &str) -> Result<bool> {
+        Ok(objects::exists(hash)? || large::object_path(hash).is_file())
+    }
+
+    /// Large files are hashed without reading them into memory.
+    fn verify_object(&self, hash: &str) -> Result<(), String> {
+        let large = large::object_path(hash);
+        if objects::exists(hash).map_err(|e| e.to_string())? || !large.is_file() {
+            return objects::verify(hash);
+        }
+        match calculate_hash(&large) {
+            Ok(actual) if *actual == *hash => Ok(()),
+            Ok(_) => Err("corrupted (content does not match its hash)".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Files in the large-object store are always kept as they are.
+    fn object_file(&self, hash: &str) -> Option<PathBuf> {
+        objects::plain_path(hash).or_else(|| Some(large::object_path(hash)).filter(|path| path.is_file()))
+    }
+
+    fn legacy_copy(&self, id: VersionId, filename: &str) -> Option<PathBuf> {
+        Some(get_commit_path(id).join(filename)).filter(|path| path.exists())
+    }
+
+    /// Deltas are followed down to their bases before anything goes.
+    fn prune_objects(&self, live: &HashSet<FileHash>) -> Result<usize> {
+        let mut live = live.clone();
+        let mut pending: Vec<FileHash> = live.iter().cloned().collect();
+        while let Some(hash) = pending.pop() {
+            if let Some(base) = objects::delta_base(&hash)
+                && live.insert(base.clone())
+            {
+                pending.push(base);
+            }
+        }
+        Ok(objects::prune(&live)? + large::prune(&live)?)
+    }
+
+    /// Moves the copies kept by versions from before the object store into
+    /// it, then every object, loose or packed, into a single pack (see
+    /// `pack`).
+    fn repack(&self, compress_all: bool) -> Result<usize> {
+        let moved = self.absorb_legacy_copies()?;
+        if moved > 0 {
+            info!("Moved {} file copies from old versions into the object store.", moved);
+        }
+        objects::repack(compress_all)
+    }
+
+    fn check(&self) -> Vec<String> {
+        pack::check()
+    }
+
+    /// Makes `.scm` with an empty `commits`.
+    fn init(&self) -> Result<()> {
+        for dir in [scm_dir(), scm_dir().join(COMMITS_DIR)] {
+            fs::create_dir(&dir).map_err(ScmError::io(format!("Cannot create {}", dir.display())))?;
+        }
+        Ok(())
+    }
+
+    /// Starts `commits/<id>.partial` afresh; while it is there, the version
+    /// in `commits/<id>` is still the old one (see `recover`).
+    fn begin_manifest(&self, id: VersionId) -> Result<()> {
+        let staging = recover::staging_path(id);
+        if staging.exists() {
+            fs::remove_dir_all(&staging).map_err(ScmError::io(format!("Cannot clear {}", staging.display())))?;
+        }
+        fs::create_dir_all(&staging).map_err(ScmError::io(format!("Cannot create {}", staging.display())))
+    }
+
+    /// Writes the version to `commits/<id>.partial` and renames it into
+    /// place. A version that is already there is moved aside to
+    /// `commits/<id>.old` while the two are swapped, and any copies kept
+    /// beside its manifest go along. See `recover` for finishing either
+    /// after a crash.
+    fn put_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let id = manifest.version_id;
+        let (current, staging, backup) = (get_commit_path(id), recover::staging_path(id), recover::backup_path(id));
+        let unwritable = || ScmError::io(format!("Cannot write version {}", id));
+        fs::create_dir_all(&staging).map_err(unwritable())?;
+        FsStorage::write_manifest(&staging, manifest)?;
+        if !current.exists() {
+            return fs::rename(&staging, &current).map_err(unwritable());
+        }
+        for entry in fs::read_dir(&current).map_err(unwritable())?.filter_map(|entry| entry.ok()) {
+            if !entry.file_name().to_string_lossy().starts_with("manifest.") {
+                copy_recursive(&entry.path(), &staging.join(entry.file_name()), true)?;
+            }
+        }
+        fs::rename(&current, &backup).map_err(unwritable())?;
+        fs::rename(&staging, &current).map_err(unwritable())?;
+        fs::remove_dir_all(&backup).map_err(unwritable())
+    }
+
+    /// The version was put in place once `commits/<id>.partial` is gone; an
+    /// old one moved aside by then is thrown away, and otherwise put back.
+    fn roll_back_manifest(&self, id: VersionId) -> Result<bool> {
+        let (current, staging, backup) = (get_commit_path(id), recover::staging_path(id), recover::backup_path(id));
+        let unwritable = || ScmError::io(format!("Cannot roll back version {}", id));
+        let staged = staging.exists();
+        if staged {
+            if !current.exists() && backup.exists() {
+                fs::rename(&backup, &current).map_err(unwritable())?;
+            }
+            fs::remove_dir_all(&staging).map_err(unwritable())?;
+        } else if backup.exists() {
+            fs::remove_dir_all(&backup).map_err(unwritable())?;
+        }
+        Ok(staged)
+    }
+
+    fn get_manifest(&self, id: VersionId) -> Result<Option<Manifest>> {
+        let Some((path, cbor, compressed)) = manifest_forms_in(&get_commit_path(id)).into_iter().find(|(path, _, _)| path.exists()) else {
+            return Ok(None);
+        };
+        let corrupt = || ScmError::CorruptManifest(id);
+        let content = fs::read(&path).map_err(ScmError::io(format!("Cannot read {}", path.display())))?;
+        let mut content = crypt::open(content)?.ok_or_else(corrupt)?;
+        if compressed {
+            content = objects::try_decompress(&content).ok_or_else(corrupt)?;
+        }
+        let manifest = if cbor { ciborium::from_reader(content.as_slice()).ok() } else { serde_json::from_slice(&content).ok() };
+        manifest.map(Some).ok_or_else(corrupt)
+    }
+
+    fn get_header(&self, id: VersionId) -> Option<ManifestHeader> {
+        let content = crypt::open(fs::read(FsStorage::header_path(id)).ok()?).ok()??;
+        serde_json::from_slice(&content).ok()
+    }
+
+    fn has_manifest(&self, id: VersionId) -> bool {
+        manifest_forms_in(&get_commit_path(id)).iter().any(|(path, _, _)| path.exists())
+    }
+
+    /// Removes `commits/<id>`, copies and all.
+    fn remove_manifest(&self, id: VersionId) -> Result<()> {
+        let path = get_commit_path(id);
+        fs::remove_dir_all(&path).map_err(ScmError::io(format!("Cannot delete {}", path.display())))
+    }
+
+    /// The versions with a directory under `commits`.
+    fn list_versions(&self) -> Vec<VersionId> {
+        let entries = match fs::read_dir(scm_dir().join(COMMITS_DIR)) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut ids: Vec<VersionId> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_string_lossy().parse().ok())
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+}
+
+/// Versions and contents kept in the process's memory, gone when it exits:
+/// for tests, and for throwaway snapshots such as an app's undo history.
+/// Objects are kept whole, and manifests as CBOR, so what is read back is a
+/// copy. Nothing is ever half written, so all `recover` needs to know is
+/// which versions were begun and not yet put.
+///
+/// The repository's own files are kept here too, so nothing is written
+/// outside the working tree but the files of a revert on their way into
+/// it, under the system's temporary directory.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    objects: RwLock<HashMap<String, Vec<u8>>>,
+    manifests: RwLock<BTreeMap<VersionId, Vec<u8>>>,
+    begun: RwLock<HashSet<VersionId>>,
+    /// By path under `.scm`.
+    files: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    /// Replaces an object's content behind scm's back, as a failing disk
+    /// would.
+    pub(crate) fn damage(&self, hash: &str, content: &[u8]) {
+        self.objects.write().unwrap().insert(hash.to_string(), content.to_vec());
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name))
+}
+
+impl Storage for MemoryStorage {
+    fn put_object(&self, hash: &str, content: &[u8], _base: Option<&str>) -> Result<()> {
+        self.objects.write().unwrap().entry(hash.to_string()).or_insert_with(|| content.to_vec());
+        Ok(())
+    }
+
+    fn get_object(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.objects.read().unwrap().get(hash).cloned())
+    }
+
+    fn has(&self, hash: &str) -> Result<bool> {
+        Ok(self.objects.read().unwrap().contains_key(hash))
+    }
+
+    fn prune_objects(&self, live: &HashSet<FileHash>) -> Result<usize> {
+        let mut objects = self.objects.write().unwrap();
+        let before = objects.len();
+        objects.retain(|hash, _| live.contains(hash.as_str()));
+        Ok(before - objects.len())
+    }
+
+    fn put_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(manifest, &mut bytes)
+            .map_err(io::Error::other)
+            .map_err(ScmError::io(format!("Cannot write the manifest of version {}", manifest.version_id)))?;
+        self.manifests.write().unwrap().insert(manifest.version_id, bytes);
+        self.begun.write().unwrap().remove(&manifest.version_id);
+        Ok(())
+    }
+
+    fn begin_manifest(&self, id: VersionId) -> Result<()> {
+        self.begun.write().unwrap().insert(id);
+        Ok(())
+    }
+
+    fn roll_back_manifest(&self, id: VersionId) -> Result<bool> {
+        Ok(self.begun.write().unwrap().remove(&id))
+    }
+
+    fn get_manifest(&self, id: VersionId) -> Result<Option<Manifest>> {
+        match self.manifests.read().unwrap().get(&id) {
+            Some(bytes) => ciborium::from_reader(bytes.as_slice()).map(Some).map_err(|_| ScmError::CorruptManifest(id)),
+            None => Ok(None),
+        }
+    }
+
+    fn has_manifest(&self, id: VersionId) -> bool {
+        self.manifests.read().unwrap().contains_key(&id)
+    }
+
+    fn remove_manifest(&self, id: VersionId) -> Result<()> {
+        self.manifests.write().unwrap().remove(&id);
+        Ok(())
+    }
+
+    fn list_versions(&self) -> Vec<VersionId> {
+        self.manifests.read().unwrap().keys().copied().collect()
+    }
+
+    fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn exists(&self) -> bool {
+        true
+    }
+
+    fn read_file(&self, name: &str) -> io::Result<Vec<u8>> {
+        self.files.read().unwrap().get(name).cloned().ok_or_else(|| not_found(name))
+    }
+
+    fn write_file(&self, name: &str, content: &[u8]) -> io::Result<()> {
+        self.files.write().unwrap().insert(name.to_string(), content.to_vec());
+        Ok(())
+    }
+
+    fn append_file(&self, name: &str, content: &[u8]) -> io::Result<()> {
+        self.files.write().unwrap().entry(name.to_string()).or_default().extend_from_slice(content);
+        Ok(())
+    }
+
+    fn rename_file(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        let content = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_string(), content);
+        Ok(())
+    }
+
+    fn remove_file(&self, name: &str) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        let before = files.len();
+        let dir = format!("{}/", name);
+        files.retain(|path, _| path != name && !path.starts_with(&dir));
+        if files.len() == before { Err(not_found(name)) } else { Ok(()) }
+    }
+
+    fn list_files(&self, dir: &str) -> io::Result<Vec<String>> {
+        let dir = format!("{}/", dir);
+        let files = self.files.read().unwrap();
+        Ok(files.keys().filter_map(|path| path.strip_prefix(&dir)).filter(|name| !name.contains('/')).map(str::to_string).collect())
+    }
+
+    /// One for each storage, while it is open.
+    fn scratch_dir(&self) -> PathBuf {
+        env::temp_dir().join(format!("scm-{}-{:p}", process::id(), self))
+    }
+}
+
+/// Whether new manifests are written as CBOR (`manifest.cbor`), which is
+/// smaller and much faster to parse for huge trees, rather than JSON
+/// (`manifest.json`, the default). Set with `core.manifest_format = "cbor"`;
+/// both are always read, so history can mix them.
+fn cbor_manifests() -> bool {
+    config::get("core.manifest_format").as_deref() == Some("cbor")
+}
+
+fn manifest_path(dir: &Path, cbor: bool) -> PathBuf {
+    dir.join(if cbor { "manifest.cbor" } else { "manifest.json" })
+}
+
+/// Every file a version's manifest may be stored in, as (path, cbor,
+/// compressed).
+fn manifest_forms_in(dir: &Path) -> Vec<(PathBuf, bool, bool)> {
+    let mut forms = Vec::new();
+    for cbor in [false, true] {
+        let plain = manifest_path(dir, cbor);
+        forms.push((objects::compressed_name(&plain), cbor, true));
+        forms.push((plain, cbor, false));
+    }
+    forms
+}
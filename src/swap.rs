@@ -0,0 +1,132 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+use crate::storage::storage;
+use crate::{ensure_parent_dir, link, perms, remove_empty_parents, symlink, working_path, Result, ScmError};
+
+/// Replacing working files all at once, so that a restore either happens in
+/// full or leaves the working directory as it was. The new files are
+/// written under `.scm/swap/new` first (see `Storage::scratch_dir`), where
+/// a failure (a missing object, a full disk) costs nothing. Only then are
+/// the files being replaced moved aside to `.scm/swap/old` and the new
+/// ones renamed into place; if any of that fails, each move is undone in
+/// reverse.
+///
+/// A swap cut short by a crash is finished by `scm recover`, which redoes
+/// the whole restore; whatever is left here is cleared when the next swap
+/// starts.
+pub const SWAP_DIR: &str = "swap";
+const NEW_DIR: &str = "new";
+const OLD_DIR: &str = "old";
+
+fn swap_dir() -> PathBuf {
+    storage().scratch_dir()
+}
+
+/// Clears what an earlier swap left behind, ready for new files to be
+/// written to their `staged_path`.
+pub fn begin() -> Result<()> {
+    let dir = swap_dir();
+    if dir.exists() {
+        fs::remove_dir_all(&dir).map_err(ScmError::io(format!("Cannot clear {}", dir.display())))?;
+    }
+    Ok(())
+}
+
+/// Where a new file is written before `apply` moves it into place.
+pub fn staged_path(filename: &str) -> PathBuf {
+    swap_dir().join(NEW_DIR).join(filename)
+}
+
+/// A move `apply` has made, to undo if a later one fails.
+enum Step {
+    /// A working file moved aside to `old/<name>`.
+    Aside(PathBuf, String),
+    /// A staged file moved into place.
+    Placed(String),
+}
+
+/// Moves the working files in `removed` (name and path) aside and the
+/// staged `added` files into place, then deletes the old ones. On failure,
+/// puts everything back before returning the error. If even that fails the
+/// files still set aside are left in `.scm/swap/old`, and `is_pending` says
+/// so.
+pub fn apply(removed: &[(String, PathBuf)], added: &[&String]) -> Result<()> {
+    let old = swap_dir().join(OLD_DIR);
+    let mut done = Vec::new();
+    if let Err(e) = swap(removed, added, &old, &mut done) {
+        if roll_back(done, &old) {
+            discard();
+        }
+        return Err(e);
+    }
+    discard();
+    Ok(())
+}
+
+fn swap(removed: &[(String, PathBuf)], added: &[&String], old: &Path, done: &mut Vec<Step>) -> Result<()> {
+    for (name, path) in removed {
+        move_file(path, &old.join(name))?;
+        remove_empty_parents(path);
+        done.push(Step::Aside(path.clone(), name.clone()));
+    }
+    for name in added {
+        move_file(&staged_path(name), &working_path(name))?;
+        done.push(Step::Placed(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Undoes `done` in reverse, returning whether all of it could be.
+fn roll_back(done: Vec<Step>, old: &Path) -> bool {
+    let mut complete = true;
+    for step in done.into_iter().rev() {
+        let undone = match step {
+            Step::Placed(name) => {
+                let path = working_path(&name);
+                let removed = fs::remove_file(&path).map_err(ScmError::io(format!("Cannot remove {}", name)));
+                remove_empty_parents(&path);
+                removed
+            }
+            Step::Aside(path, name) => move_file(&old.join(&name), &path),
+        };
+        if let Err(e) = undone {
+            error!("{}", e);
+            complete = false;
+        }
+    }
+    if !complete {
+        error!("Some files could not be put back; they are in {}.", old.display());
+    }
+    complete
+}
+
+/// Whether a swap failed and couldn't be rolled back, or was cut short.
+pub fn is_pending() -> bool {
+    swap_dir().exists()
+}
+
+/// Throws away the files written for a swap, or those left from one.
+pub fn discard() {
+    let _ = fs::remove_dir_all(swap_dir());
+}
+
+/// Renames `src` to `dest`, or copies it across filesystems, as when
+/// `$SCM_DIR` is on another one than the working tree.
+fn move_file(src: &Path, dest: &Path) -> Result<()> {
+    let failed = || ScmError::io(format!("Cannot move {} to {}", src.display(), dest.display()));
+    ensure_parent_dir(dest)?;
+    match fs::rename(src, dest) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if symlink::is_symlink(src) {
+                symlink::create(&symlink::read_target(src)?, dest)?;
+            } else {
+                link::clone_file(src, dest).and_then(|()| perms::copy_mtime(src, dest)).map_err(failed())?;
+            }
+            fs::remove_file(src).map_err(failed())
+        }
+        result => result.map_err(failed()),
+    }
+}
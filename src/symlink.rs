@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+use crate::{link, Result, ScmError};
+
+/// Whether `path` is itself a symbolic link (without following it).
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).map(|m| m.file_type().is_symlink()).unwrap_or(false)
+}
+
+/// Whether anything, including a dangling symlink, is at `path`.
+pub fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// The target a link points at, as stored in manifests.
+pub fn read_target(path: &Path) -> Result<String> {
+    let target = fs::read_link(path).map_err(ScmError::io(format!("Cannot read {}", path.display())))?;
+    Ok(target.to_string_lossy().replace('\\', "/"))
+}
+
+/// Creates a link at `link` pointing at `target`. Where links can't be made
+/// (no platform support or no permission), writes a plain file holding the
+/// target path instead, as git does with `core.symlinks = false`.
+pub fn create(target: &str, link: &Path) -> Result<()> {
+    if fs::symlink_metadata(link).is_ok() {
+        fs::remove_file(link).map_err(ScmError::io(format!("Cannot replace {}", link.display())))?;
+    }
+    if let Err(e) = make_link(target, link) {
+        warn!("could not create symlink {} ({}); writing its target as a file.", link.display(), e);
+        fs::write(link, target).map_err(ScmError::io(format!("Cannot write {}", link.display())))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_link(target: &str, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn make_link(target: &str, link: &Path) -> std::io::Result<()> {
+    let resolved = link.parent().unwrap_or(Path::new(".")).join(target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn make_link(_target: &str, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "symlinks are not supported"))
+}
+
+/// Copies a file (see `link::clone_file`), recreating a symlink as a
+/// symlink rather than copying what it points at.
+pub fn copy(src: &Path, dst: &Path) -> Result<()> {
+    if is_symlink(src) {
+        read_target(src).and_then(|target| create(&target, dst))
+    } else {
+        link::clone_file(src, dst).map_err(ScmError::io(format!("Cannot copy {} to {}", src.display(), dst.display())))
+    }
+}
@@ -0,0 +1,84 @@
+use crate::storage::{self, storage};
+
+/// Files removed with `scm rm` since the last commit.
+const REMOVED_FILE: &str = "removed";
+/// Renames made with `scm mv` since the last commit, one "old\tnew" per line.
+const RENAMED_FILE: &str = "renamed";
+/// Files that stay on disk but are no longer snapshotted (`scm rm --cached`).
+const UNTRACKED_FILE: &str = "untracked";
+
+fn read_list(name: &str) -> Vec<String> {
+    let content = storage::read_to_string(name).unwrap_or_default();
+    content.lines().filter(|l| !l.is_empty()).map(str::to_string).collect()
+}
+
+fn write_list(name: &str, mut items: Vec<String>) {
+    items.sort();
+    items.dedup();
+    if items.is_empty() {
+        let _ = storage().remove_file(name);
+        return;
+    }
+    let mut content = items.join("\n");
+    content.push('\n');
+    storage().write_file(name, content.as_bytes()).expect("Failed to write tracking list");
+}
+
+pub fn pending_removals() -> Vec<String> {
+    read_list(REMOVED_FILE)
+}
+
+pub fn add_pending_removal(filename: &str) {
+    let mut removed = pending_removals();
+    removed.push(filename.to_string());
+    write_list(REMOVED_FILE, removed);
+}
+
+pub fn clear_pending_removals() {
+    write_list(REMOVED_FILE, Vec::new());
+}
+
+pub fn untracked() -> Vec<String> {
+    read_list(UNTRACKED_FILE)
+}
+
+pub fn is_untracked(filename: &str) -> bool {
+    untracked().iter().any(|f| f == filename)
+}
+
+pub fn add_untracked(filename: &str) {
+    let mut untracked = untracked();
+    untracked.push(filename.to_string());
+    write_list(UNTRACKED_FILE, untracked);
+}
+
+pub fn remove_untracked(filename: &str) {
+    let mut untracked = untracked();
+    untracked.retain(|f| f != filename);
+    write_list(UNTRACKED_FILE, untracked);
+}
+
+/// Pending renames as (old, new) pairs.
+pub fn pending_renames() -> Vec<(String, String)> {
+    read_list(RENAMED_FILE)
+        .into_iter()
+        .filter_map(|line| line.split_once('\t').map(|(old, new)| (old.to_string(), new.to_string())))
+        .collect()
+}
+
+/// Records a rename, collapsing chains so a -> b followed by b -> c is a -> c.
+pub fn add_pending_rename(old: &str, new: &str) {
+    let mut renames = pending_renames();
+    let origin = match renames.iter().position(|(_, n)| n == old) {
+        Some(index) => renames.remove(index).0,
+        None => old.to_string(),
+    };
+    if origin != new {
+        renames.push((origin, new.to_string()));
+    }
+    write_list(RENAMED_FILE, renames.into_iter().map(|(o, n)| format!("{}\t{}", o, n)).collect());
+}
+
+pub fn clear_pending_renames() {
+    write_list(RENAMED_FILE, Vec::new());
+}
@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+use tracing::{error, info};
+
+use crate::{color, exit, fsck, grep, json, list_versions, load_manifest, scm_dir, VersionId};
+
+/// `scm verify [<version> | <from>..<to>]`: re-hashes every stored file of
+/// every (or the selected) version against its manifest. Prints nothing but
+/// a summary when all is well and exits with status 2 on any problem, so it
+/// can run unattended from cron.
+pub fn do_verify(range: Option<&str>) {
+    if !scm_dir().exists() {
+        error!("No SCM repository found.");
+        return;
+    }
+    let selected = match range {
+        None => list_versions(),
+        Some(spec) => match grep::parse_range(spec) {
+            Ok(Some(selected)) => selected,
+            Err(e) => return exit::report(e),
+            Ok(None) => {
+                error!("Unknown version or range: {}", spec);
+                return;
+            }
+        },
+    };
+
+    let mut problems = Vec::new();
+    let (versions, files) = check(selected, &mut problems);
+    if !problems.is_empty() {
+        exit::set(exit::CORRUPT);
+    }
+
+    if json::enabled() {
+        json::print(&fsck::Report { versions, files, problems: &problems });
+        return;
+    }
+    for problem in &problems {
+        error!("{} {}", color::error(), problem);
+    }
+    if problems.is_empty() {
+        info!("Verified {} version(s) and {} stored file(s); all intact.", versions, files);
+    } else {
+        error!("{} problem(s) found in {} version(s) and {} stored file(s).", problems.len(), versions, files);
+    }
+}
+
+/// Checks the `selected` versions, adding what is wrong to `problems`.
+/// Returns the number of versions whose manifest could be read and of
+/// distinct stored files checked.
+pub fn check(selected: Vec<VersionId>, problems: &mut Vec<String>) -> (usize, usize) {
+    let mut versions = BTreeMap::new();
+    for id in selected {
+        match load_manifest(id) {
+            Ok(manifest) => {
+                versions.insert(id, manifest);
+            }
+            Err(_) => problems.push(format!("version {}: manifest is missing or cannot be parsed", id)),
+        }
+    }
+    let files = fsck::check_contents(&versions, problems);
+    (versions.len(), files)
+}
@@ -0,0 +1,116 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use clap::{Command, CommandFactory};
+
+use crate::alias;
+use crate::cli::Cli;
+use crate::{scm_dir, SCM_DIR};
+
+/// Where scm runs. `-C <dir>` (or `--repo <dir>`) runs it as if it had been
+/// started in `dir`, e.g. from cron: `scm -C ~/project commit -a -m
+/// nightly`. Like git's, it goes before the subcommand, and given more than
+/// once, each directory is taken relative to the one before.
+///
+/// From there, scm looks for `.scm` in each parent directory in turn and
+/// moves to the first that has one, the root of the working tree, so every
+/// command sees the whole tree wherever it is started. File names on the
+/// command line are taken relative to where it was started (see `prefix`
+/// and `resolve`); file names scm prints are relative to the root. With
+/// `$SCM_DIR` set, the directory scm is started in is the root.
+///
+/// All this happens before the command line is parsed, since even the
+/// aliases to expand come from the repository's config.
+const REPO_LONG: &str = "repo";
+const REPO_SHORT: char = 'C';
+
+/// Where scm was started (after `-C`), if that wasn't the root.
+static STARTED: OnceLock<(PathBuf, String)> = OnceLock::new();
+
+/// Changes to the directories given with `-C` in `args` (the whole command
+/// line), in order, and then to the root of the working tree.
+pub fn enter(args: &[String]) -> Result<(), String> {
+    let mut command = Cli::command();
+    command.build();
+    for dir in directories(&command, args) {
+        env::set_current_dir(&dir).map_err(|e| format!("Cannot change to {}: {}", dir, e))?;
+    }
+    find_root();
+    Ok(())
+}
+
+/// Moves to the nearest directory above that has a `.scm`, if any.
+pub fn find_root() {
+    if scm_dir() != Path::new(SCM_DIR) || scm_dir().is_dir() {
+        return;
+    }
+    let cwd = match env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(_) => return,
+    };
+    if let Some(root) = cwd.ancestors().skip(1).find(|dir| dir.join(SCM_DIR).is_dir())
+        && env::set_current_dir(root).is_ok()
+    {
+        let below: Vec<String> = cwd.strip_prefix(root).expect("root is an ancestor").iter().map(|part| part.to_string_lossy().into_owned()).collect();
+        let _ = STARTED.set((cwd.clone(), below.join("/")));
+    }
+}
+
+/// Where scm was started relative to the root, with `/` separators: "" at
+/// the root itself.
+pub fn prefix() -> &'static str {
+    STARTED.get().map_or("", |(_, prefix)| prefix)
+}
+
+/// A file outside the working tree named on the command line, e.g. an
+/// archive to write, as it can be opened from the root.
+pub fn resolve(path: impl AsRef<Path>) -> PathBuf {
+    match STARTED.get() {
+        Some((started, _)) => started.join(path),
+        None => path.as_ref().to_path_buf(),
+    }
+}
+
+fn directories(command: &Command, args: &[String]) -> Vec<String> {
+    let takes_value = |arg: &clap::Arg| arg.get_action().takes_values();
+    let end = alias::subcommand_position(command, args).unwrap_or(args.len());
+    let mut dirs = Vec::new();
+    let mut i = 1;
+    while i < end {
+        let arg = &args[i];
+        if let Some(long) = arg.strip_prefix("--") {
+            match long.split_once('=') {
+                Some((REPO_LONG, dir)) => dirs.push(dir.to_string()),
+                Some(_) => {}
+                None => {
+                    if command.get_arguments().any(|a| a.get_long() == Some(long) && takes_value(a)) {
+                        i += 1;
+                        if long == REPO_LONG {
+                            dirs.extend(args.get(i).cloned());
+                        }
+                    }
+                }
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-') {
+            // As in `alias::subcommand_position`: the first flag in a
+            // cluster that takes a value takes the rest, or the next argument.
+            for (at, c) in shorts.char_indices() {
+                if command.get_arguments().any(|a| a.get_short() == Some(c) && takes_value(a)) {
+                    let rest = &shorts[at + c.len_utf8()..];
+                    let value = if rest.is_empty() {
+                        i += 1;
+                        args.get(i).cloned()
+                    } else {
+                        Some(rest.strip_prefix('=').unwrap_or(rest).to_string())
+                    };
+                    if c == REPO_SHORT {
+                        dirs.extend(value);
+                    }
+                    break;
+                }
+            }
+        }
+        i += 1;
+    }
+    dirs
+}